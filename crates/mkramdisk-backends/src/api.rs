@@ -0,0 +1,75 @@
+//! The version of mkramdisk's machine-readable output: results printed as JSON or YAML
+//! (`--json`, `--output`, `--print-actions json`) and the events written to sinks,
+//! a `unix:` socket included. Each result and event carries `"api_version"`.
+//!
+//! Within a version fields are only ever added, so scripts should ignore fields they
+//! don't know. Renaming or removing a field or an event, or changing what a field
+//! holds, takes a new version, and each older version stays available through
+//! `--api-version` for at least two minor releases after it is superseded. A script
+//! that passes the version it was written against keeps working until then, and fails
+//! outright rather than misreading output once it is gone.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde_json::Value;
+
+/// The version output has unless another is asked for.
+pub const CURRENT: u32 = 1;
+
+/// Every version this mkramdisk can produce, oldest first.
+pub const SUPPORTED: &[u32] = &[1];
+
+// The version asked for with --api-version; 0 when none was
+static REQUESTED: AtomicU32 = AtomicU32::new(0);
+
+/// Produce output at the version `requested` (`--api-version`), if it is one this
+/// mkramdisk supports.
+pub fn negotiate(requested: &str) -> Result<u32, String> {
+    let version: u32 = requested
+        .parse()
+        .map_err(|_| format!("Invalid API version: {} (expected a number, e.g. {})", requested, CURRENT))?;
+    if !SUPPORTED.contains(&version) {
+        let supported: Vec<String> = SUPPORTED.iter().map(u32::to_string).collect();
+        let hint = if version > CURRENT { "; a newer mkramdisk may support it" } else { "" };
+        return Err(format!("API version {} is not supported (this mkramdisk supports {}){}", version, supported.join(", "), hint));
+    }
+    REQUESTED.store(version, Ordering::Relaxed);
+    Ok(version)
+}
+
+/// The version output is produced at.
+pub fn version() -> u32 {
+    requested().unwrap_or(CURRENT)
+}
+
+/// The version asked for with `--api-version`, if one was, to pass on to mkramdisk on
+/// another host.
+pub fn requested() -> Option<u32> {
+    match REQUESTED.load(Ordering::Relaxed) {
+        0 => None,
+        version => Some(version),
+    }
+}
+
+/// `value` with its `api_version`, if it is an object.
+pub fn stamp(mut value: Value) -> Value {
+    if let Value::Object(fields) = &mut value {
+        fields.insert("api_version".to_string(), Value::from(version()));
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("1").unwrap(), 1);
+        assert_eq!(version(), 1);
+        assert_eq!(negotiate("2").unwrap_err(), "API version 2 is not supported (this mkramdisk supports 1); a newer mkramdisk may support it");
+        assert!(negotiate("v1").unwrap_err().starts_with("Invalid API version: v1"));
+        assert_eq!(stamp(serde_json::json!({ "event": "created" }))["api_version"], 1);
+        assert_eq!(stamp(serde_json::json!([1])), serde_json::json!([1]));
+    }
+}
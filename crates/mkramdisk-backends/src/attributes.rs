@@ -0,0 +1,195 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Which file metadata a copy carries over besides contents and permissions
+/// (`--preserve`). Extended attributes include resource forks and quarantine flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preserve {
+    pub xattrs: bool,
+    pub acls: bool,
+    pub flags: bool,
+    pub times: bool,
+}
+
+pub const PRESERVABLE: &[&str] = &["xattr", "acl", "flags", "times"];
+
+// Everything, as the Finder and cp -p keep it
+impl Default for Preserve {
+    fn default() -> Self {
+        Self { xattrs: true, acls: true, flags: true, times: true }
+    }
+}
+
+impl Preserve {
+    /// Contents and permissions only, so copies of the same files come out the same
+    /// (`--strip`).
+    pub fn none() -> Self {
+        Self { xattrs: false, acls: false, flags: false, times: false }
+    }
+
+    /// Parse a comma-separated list such as "xattr,times"; "all" and "none" also work.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        match list {
+            "all" => return Ok(Self::default()),
+            "none" => return Ok(Self::none()),
+            _ => {}
+        }
+        let mut preserve = Self::none();
+        for item in list.split(',').map(str::trim) {
+            match item {
+                "xattr" | "xattrs" => preserve.xattrs = true,
+                "acl" | "acls" => preserve.acls = true,
+                "flags" => preserve.flags = true,
+                "times" => preserve.times = true,
+                _ => {
+                    return Err(format!(
+                        "Unknown attribute to preserve: '{}' (expected {}, all or none)",
+                        item,
+                        PRESERVABLE.join(", ")
+                    ));
+                }
+            }
+        }
+        Ok(preserve)
+    }
+
+    /// Whether fs::copy may be used as is: on macOS it clones or copies with every
+    /// attribute, which would keep what was asked to be dropped.
+    pub fn keeps_attributes(&self) -> bool {
+        self.xattrs && self.acls
+    }
+}
+
+/// Give `to` the metadata of `from` that `preserve` asks for. Directories should be
+/// done after their contents, or copying into them would touch their times again.
+pub fn copy(from: &Path, to: &Path, meta: &fs::Metadata, preserve: Preserve) -> io::Result<()> {
+    if preserve.xattrs || preserve.acls {
+        copy_xattrs(from, to, preserve)?;
+    }
+    if preserve.times {
+        let times = fs::FileTimes::new().set_accessed(meta.accessed()?).set_modified(meta.modified()?);
+        #[cfg(target_os = "macos")]
+        let times = {
+            use std::os::macos::fs::FileTimesExt;
+            times.set_created(meta.created()?)
+        };
+        fs::File::open(to)?.set_times(times)?;
+    }
+    // Last, since a flag like uchg would stop the others from being set
+    if preserve.flags {
+        copy_flags(to, meta)?;
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+// copyfile(3) copies resource forks and Finder info along with the other attributes
+#[cfg(target_os = "macos")]
+fn copy_xattrs(from: &Path, to: &Path, preserve: Preserve) -> io::Result<()> {
+    let mut flags = libc::COPYFILE_NOFOLLOW;
+    if preserve.xattrs {
+        flags |= libc::COPYFILE_XATTR;
+    }
+    if preserve.acls {
+        flags |= libc::COPYFILE_ACL;
+    }
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // SAFETY: both paths are NUL-terminated and outlive the call; no state is passed
+    if unsafe { libc::copyfile(from.as_ptr(), to.as_ptr(), std::ptr::null_mut(), flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Linux keeps ACLs as extended attributes too, so both are copied name by name
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &Path, to: &Path, preserve: Preserve) -> io::Result<()> {
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // SAFETY (all three calls): the paths and names are NUL-terminated and the buffers
+    // are as long as the sizes passed with them
+    let names = match read_sized(|buf, size| unsafe { libc::llistxattr(from.as_ptr(), buf.cast(), size) }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty()) {
+        let wanted = if name.starts_with(b"system.posix_acl_") { preserve.acls } else { preserve.xattrs };
+        if !wanted {
+            continue;
+        }
+        let name = std::ffi::CString::new(name).map_err(io::Error::other)?;
+        let value = read_sized(|buf, size| unsafe { libc::lgetxattr(from.as_ptr(), name.as_ptr(), buf.cast(), size) })?;
+        if unsafe { libc::lsetxattr(to.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) } < 0 {
+            let e = io::Error::last_os_error();
+            // The disk's filesystem may not take every namespace (security.*, trusted.*)
+            if !matches!(e.raw_os_error(), Some(libc::ENOTSUP | libc::EPERM)) {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Ask for the size first, then fill a buffer of that size
+#[cfg(target_os = "linux")]
+fn read_sized(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    let size = call(std::ptr::null_mut(), 0);
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buffer = vec![0; size as usize];
+    let read = call(buffer.as_mut_ptr(), buffer.len());
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buffer.truncate(read as usize);
+    Ok(buffer)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn copy_xattrs(_from: &Path, _to: &Path, _preserve: Preserve) -> io::Result<()> {
+    Ok(())
+}
+
+// BSD file flags (hidden, uchg, ...) exist on macOS only
+#[cfg(target_os = "macos")]
+fn copy_flags(to: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    use std::os::macos::fs::MetadataExt;
+    if meta.st_flags() == 0 {
+        return Ok(());
+    }
+    let to = c_path(to)?;
+    // SAFETY: the path is NUL-terminated and outlives the call
+    if unsafe { libc::chflags(to.as_ptr(), meta.st_flags()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn copy_flags(_to: &Path, _meta: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_preserve() {
+        assert_eq!(Preserve::parse("all").unwrap(), Preserve::default());
+        assert_eq!(Preserve::parse("none").unwrap(), Preserve::none());
+        assert_eq!(
+            Preserve::parse("xattr, times").unwrap(),
+            Preserve { xattrs: true, acls: false, flags: false, times: true }
+        );
+        assert!(Preserve::parse("xattr,owner").unwrap_err().contains("'owner'"));
+        assert!(!Preserve::parse("xattr").unwrap().keeps_attributes());
+    }
+}
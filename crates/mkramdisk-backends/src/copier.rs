@@ -0,0 +1,794 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::attributes::{self, Preserve};
+use crate::progress::Progress;
+
+/// Totals for a completed copy.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CopyStats {
+    pub files: u64,
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// Further names of files already copied, recreated as hard links to the copy.
+    pub hardlinks: u64,
+    pub bytes: u64,
+    /// Files left as they were, already holding what was to be copied (`copy_tree_over`).
+    pub unchanged: u64,
+}
+
+/// What a copy does with symlinks (`--links`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LinkPolicy {
+    /// Copy what the link points to in its place.
+    Follow,
+    /// Recreate the link as it is, pointing at the same target.
+    #[default]
+    Preserve,
+    /// Leave the link out.
+    Skip,
+}
+
+impl LinkPolicy {
+    pub fn parse(policy: &str) -> Result<Self, String> {
+        match policy {
+            "follow" => Ok(Self::Follow),
+            "preserve" => Ok(Self::Preserve),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!("Unknown link policy: '{}' (expected follow, preserve or skip)", policy)),
+        }
+    }
+}
+
+/// How `copy_tree` treats what it copies besides file contents.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CopyOptions {
+    pub preserve: Preserve,
+    pub links: LinkPolicy,
+}
+
+// Bookkeeping macOS (and mkramdisk) keeps at the root of every volume; copying a volume
+// leaves it behind
+const VOLUME_METADATA: &[&str] = &[".fseventsd", ".Spotlight-V100", ".Trashes", ".TemporaryItems", ".DocumentRevisions-V100", MARKER_FILE, MANIFEST_FILE, PROTECTED_FILE];
+
+/// The marker at the root of every volume mkramdisk creates, holding its entry in the
+/// state file. It describes the volume it is on, so copies leave it behind.
+pub const MARKER_FILE: &str = ".mkramdisk-disk.json";
+
+/// The marker at the root of a disk created with `--protected`. The protection is the
+/// disk's, so copies leave it behind too.
+pub const PROTECTED_FILE: &str = ".mkramdisk-protected";
+
+/// Whether `entry`, at the root of a volume, is bookkeeping of macOS's or mkramdisk's
+/// rather than a file.
+pub fn is_volume_metadata(entry: &fs::DirEntry) -> bool {
+    VOLUME_METADATA.iter().any(|name| entry.file_name() == *name)
+}
+
+fn copy_error(path: &Path, e: io::Error) -> String {
+    format!("Failed to copy {}: {}", path.display(), e)
+}
+
+// Makes way for `src` at `dst` when copying over an existing tree: anything already
+// there is removed unless both are directories, so links don't fail with EEXIST and
+// a file is never written through a symlink or into another file's hard link.
+fn clear_destination(dst: &Path, dir: bool) -> io::Result<()> {
+    match fs::symlink_metadata(dst) {
+        Ok(existing) if existing.is_dir() && dir => Ok(()),
+        Ok(existing) if existing.is_dir() => fs::remove_dir_all(dst),
+        Ok(_) => fs::remove_file(dst),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks are not supported on this platform"))
+}
+
+// Whether `dst` could be made a clone of `src`, sharing its blocks (and its holes).
+// Anything in the way of one, from another volume to a filesystem without clones,
+// leaves the caller to copy the data instead.
+#[cfg(target_os = "macos")]
+fn clone_file(src: &Path, dst: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    const CLONE_NOFOLLOW: u32 = 0x0001;
+    let (Ok(src), Ok(dst)) = (std::ffi::CString::new(src.as_os_str().as_bytes()), std::ffi::CString::new(dst.as_os_str().as_bytes())) else {
+        return false;
+    };
+    unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), CLONE_NOFOLLOW) == 0 }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn clone_file(_src: &Path, _dst: &Path) -> bool {
+    false
+}
+
+// A file with fewer blocks allocated than its length has holes worth preserving
+#[cfg(unix)]
+fn is_sparse(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.blocks() * 512 < meta.len()
+}
+
+#[cfg(not(unix))]
+fn is_sparse(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// The (start, end) byte ranges of `file` that hold data, found with SEEK_DATA and
+/// SEEK_HOLE; everything between them is a hole. A filesystem that can't report holes
+/// gets the whole file as one range.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn data_regions(file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let seek = |offset: u64, whence| {
+        // SAFETY: lseek only moves the file offset of a descriptor `file` keeps open
+        let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result as u64) }
+    };
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek(offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+            // No data after `offset`: the rest of the file is a hole
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && offset == 0 => return Ok(vec![(0, len)]),
+            Err(e) => return Err(e),
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.min(len);
+        regions.push((start, end));
+        offset = end;
+    }
+    Ok(regions)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn data_regions(_file: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(0, len)])
+}
+
+/// A file's identity, if it has other names (hard links) that a copy should share.
+#[cfg(unix)]
+fn linked_inode(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn linked_inode(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The metadata of what to copy for `path`: the link itself, or under `Follow` what
+/// it points to. None if it is a link to skip.
+fn entry_metadata(path: &Path, links: LinkPolicy) -> io::Result<Option<fs::Metadata>> {
+    let meta = fs::symlink_metadata(path)?;
+    match links {
+        _ if !meta.is_symlink() => Ok(Some(meta)),
+        LinkPolicy::Follow => fs::metadata(path).map(Some),
+        LinkPolicy::Preserve => Ok(Some(meta)),
+        LinkPolicy::Skip => Ok(None),
+    }
+}
+
+/// Start copying a directory reached through `path`, refusing one that is already
+/// being copied further up: following a link to it would never end.
+fn enter_dir(path: &Path, ancestors: &mut Vec<PathBuf>) -> Result<(), String> {
+    let canonical = fs::canonicalize(path).map_err(|e| copy_error(path, e))?;
+    if ancestors.contains(&canonical) {
+        return Err(format!("Failed to copy {}: it links back to {}", path.display(), canonical.display()));
+    }
+    ancestors.push(canonical);
+    Ok(())
+}
+
+/// Count what `copy_tree` would copy from `src` with `links`, as totals for progress
+/// reporting. Hard-linked files count their bytes once, as the copy takes their space once.
+pub fn scan(src: &Path, links: LinkPolicy) -> Result<CopyStats, String> {
+    let mut stats = CopyStats::default();
+    let mut walk = Walk { links, seen: HashSet::new(), ancestors: Vec::new() };
+    enter_dir(src, &mut walk.ancestors)?;
+    scan_dir(src, true, &mut stats, &mut walk)?;
+    Ok(stats)
+}
+
+struct Walk {
+    links: LinkPolicy,
+    seen: HashSet<(u64, u64)>,
+    ancestors: Vec<PathBuf>,
+}
+
+fn scan_dir(src: &Path, root: bool, stats: &mut CopyStats, walk: &mut Walk) -> Result<(), String> {
+    for entry in fs::read_dir(src).map_err(|e| copy_error(src, e))? {
+        let entry = entry.map_err(|e| copy_error(src, e))?;
+        if root && is_volume_metadata(&entry) {
+            continue;
+        }
+        let Some(meta) = entry_metadata(&entry.path(), walk.links).map_err(|e| copy_error(&entry.path(), e))? else {
+            continue;
+        };
+        if meta.is_symlink() {
+            stats.symlinks += 1;
+        } else if meta.is_dir() {
+            stats.dirs += 1;
+            enter_dir(&entry.path(), &mut walk.ancestors)?;
+            scan_dir(&entry.path(), false, stats, walk)?;
+            walk.ancestors.pop();
+        } else if linked_inode(&meta).is_some_and(|inode| !walk.seen.insert(inode)) {
+            stats.hardlinks += 1;
+        } else {
+            stats.files += 1;
+            stats.bytes += meta.len();
+        }
+    }
+    Ok(())
+}
+
+/// Where a seeded disk keeps the manifest of its seed, at its root.
+pub const MANIFEST_FILE: &str = ".mkramdisk-seed.sha256";
+
+/// SHA-256 of every file a copy wrote, in `shasum -a 256` format so the copy can be
+/// checked later (`shasum -c`) without re-reading the source.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    /// (hex digest, path relative to the copied tree), in copy order.
+    pub entries: Vec<(String, PathBuf)>,
+}
+
+impl Manifest {
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        // The paths' own bytes, as sha256sum writes them, so names that aren't UTF-8 still check
+        let contents: Vec<u8> = self
+            .entries
+            .iter()
+            .flat_map(|(digest, file)| [format!("{}  ", digest).into_bytes(), crate::path_bytes(file), b"\n".to_vec()].concat())
+            .collect();
+        fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// The manifest `write` wrote to `path`, or None if there is none.
+    pub fn read(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let mut entries = Vec::new();
+        for line in contents.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()) {
+            let (digest, file) = line
+                .split_at_checked(64)
+                .and_then(|(digest, rest)| Some((std::str::from_utf8(digest).ok()?, rest.strip_prefix(b"  ")?)))
+                .filter(|(digest, _)| digest.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                .ok_or_else(|| format!("Invalid {}: expected \"<sha256>  <path>\" on every line", path.display()))?;
+            entries.push((digest.to_string(), crate::path_from_bytes(file)));
+        }
+        Ok(Some(Self { entries }))
+    }
+}
+
+// The SHA-256 of a file's contents, as the manifest has it
+fn file_digest(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Recursively copy the contents of `src` into `dst`, creating `dst` if needed.
+/// Symlinks are recreated, followed or skipped as `options.links` says, and if `src` is a
+/// volume, the system's bookkeeping directories at its root are skipped. Holes in
+/// sparse files stay holes, so a mostly-empty VM image doesn't fill the disk, and
+/// other files are cloned where the filesystem allows (within one APFS volume).
+/// Hard-linked files are copied once and linked again, as in a ccache or pnpm store.
+/// `options.preserve` picks the metadata carried over with the contents and permissions.
+pub fn copy_tree(src: &Path, dst: &Path, options: &CopyOptions, progress: &mut Progress) -> Result<CopyStats, String> {
+    let mut copy = TreeCopy::new(src, options, progress, None);
+    copy.run(dst)?;
+    Ok(copy.stats)
+}
+
+/// Like `copy_tree`, also hashing each file as it is copied, in the same pass.
+pub fn copy_tree_with_manifest(
+    src: &Path,
+    dst: &Path,
+    options: &CopyOptions,
+    progress: &mut Progress,
+) -> Result<(CopyStats, Manifest), String> {
+    let mut copy = TreeCopy::new(src, options, progress, Some(Manifest::default()));
+    copy.run(dst)?;
+    Ok((copy.stats, copy.manifest.unwrap_or_default()))
+}
+
+/// Like `copy_tree`, copying `src` over a tree an earlier copy of it may have left at
+/// `dst`: a file there that already has the digest `known` (the manifest of `src`)
+/// records for it is left alone, with only its metadata brought up to date, so only
+/// what changed since is read from `src`.
+pub fn copy_tree_over(src: &Path, dst: &Path, known: &Manifest, options: &CopyOptions, progress: &mut Progress) -> Result<CopyStats, String> {
+    let mut copy = TreeCopy::new(src, options, progress, None);
+    copy.known = known.entries.iter().map(|(digest, file)| (file.clone(), digest.clone())).collect();
+    copy.run(dst)?;
+    Ok(copy.stats)
+}
+
+struct TreeCopy<'a> {
+    src: &'a Path,
+    options: CopyOptions,
+    progress: &'a mut Progress,
+    stats: CopyStats,
+    manifest: Option<Manifest>,
+    /// Copies of files with several names, by source identity, so each later name
+    /// becomes a hard link (with the digest the manifest recorded for the first).
+    linked: HashMap<(u64, u64), (PathBuf, Option<String>)>,
+    /// Directories being copied, from `src` down, to catch followed links that loop.
+    ancestors: Vec<PathBuf>,
+    /// Digests of the files of `src`, by path relative to it, for `copy_tree_over`.
+    known: HashMap<PathBuf, String>,
+    /// Bytes of the files left alone, which count as done for progress.
+    unchanged_bytes: u64,
+}
+
+impl<'a> TreeCopy<'a> {
+    fn new(src: &'a Path, options: &CopyOptions, progress: &'a mut Progress, manifest: Option<Manifest>) -> Self {
+        Self {
+            src,
+            options: *options,
+            progress,
+            stats: CopyStats::default(),
+            manifest,
+            linked: HashMap::new(),
+            ancestors: Vec::new(),
+            known: HashMap::new(),
+            unchanged_bytes: 0,
+        }
+    }
+
+    fn run(&mut self, dst: &Path) -> Result<(), String> {
+        fs::create_dir_all(dst).map_err(|e| copy_error(dst, e))?;
+        enter_dir(self.src, &mut self.ancestors)?;
+        self.copy_dir_contents(self.src, dst, true)?;
+        self.progress.finish(self.stats.files + self.stats.unchanged, self.stats.bytes + self.unchanged_bytes);
+        Ok(())
+    }
+
+    // Whether the file at `to` already holds what `from` does, going by the digest
+    // `known` has for `from`; a file with no known digest is always copied.
+    fn is_unchanged(&self, from: &Path, to: &Path, meta: &fs::Metadata) -> bool {
+        let Some(digest) = self.known.get(from.strip_prefix(self.src).unwrap_or(from)) else {
+            return false;
+        };
+        fs::symlink_metadata(to).is_ok_and(|existing| existing.is_file() && existing.len() == meta.len())
+            && file_digest(to).is_ok_and(|found| found == *digest)
+    }
+
+    fn copy_dir_contents(&mut self, src: &Path, dst: &Path, root: bool) -> Result<(), String> {
+        let entries = fs::read_dir(src).map_err(|e| copy_error(src, e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| copy_error(src, e))?;
+            if root && is_volume_metadata(&entry) {
+                continue;
+            }
+            let from = entry.path();
+            let to = dst.join(entry.file_name());
+            let Some(meta) = entry_metadata(&from, self.options.links).map_err(|e| copy_error(&from, e))? else {
+                continue;
+            };
+            // A further name of a file copied already stays a link to it
+            let inode = linked_inode(&meta);
+            let linked = inode.is_some_and(|inode| self.linked.contains_key(&inode));
+            if meta.is_file() && !linked && self.is_unchanged(&from, &to, &meta) {
+                attributes::copy(&from, &to, &meta, self.options.preserve).map_err(|e| copy_error(&from, e))?;
+                self.stats.unchanged += 1;
+                self.unchanged_bytes += meta.len();
+                if let Some(inode) = inode {
+                    self.linked.insert(inode, (to, None));
+                }
+                continue;
+            }
+            clear_destination(&to, meta.is_dir()).map_err(|e| copy_error(&to, e))?;
+
+            if meta.is_symlink() {
+                copy_symlink(&from, &to).map_err(|e| copy_error(&from, e))?;
+                self.stats.symlinks += 1;
+            } else if meta.is_dir() {
+                fs::create_dir_all(&to).map_err(|e| copy_error(&to, e))?;
+                self.stats.dirs += 1;
+                enter_dir(&from, &mut self.ancestors)?;
+                self.copy_dir_contents(&from, &to, false)?;
+                self.ancestors.pop();
+                attributes::copy(&from, &to, &meta, self.options.preserve).map_err(|e| copy_error(&from, e))?;
+            } else {
+                if let Some(first) = inode.and_then(|inode| self.linked.get(&inode)) {
+                    let (first, digest) = first.clone();
+                    fs::hard_link(&first, &to).map_err(|e| copy_error(&from, e))?;
+                    if let (Some(manifest), Some(digest)) = (&mut self.manifest, digest) {
+                        manifest.entries.push((digest, from.strip_prefix(self.src).unwrap_or(&from).to_path_buf()));
+                    }
+                    self.stats.hardlinks += 1;
+                    continue;
+                }
+                self.stats.bytes += self.copy_file(&from, &to).map_err(|e| copy_error(&from, e))?;
+                self.stats.files += 1;
+                self.progress.update(self.stats.files + self.stats.unchanged, self.stats.bytes + self.unchanged_bytes);
+                if let Some(inode) = inode {
+                    let digest = self.manifest.as_ref().and_then(|m| m.entries.last()).map(|(digest, _)| digest.clone());
+                    self.linked.insert(inode, (to, digest));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path) -> io::Result<u64> {
+        let reader = fs::File::open(from)?;
+        let meta = reader.metadata()?;
+        let bytes = self.copy_contents(reader, &meta, from, to)?;
+        attributes::copy(from, to, &meta, self.options.preserve)?;
+        Ok(bytes)
+    }
+
+    // fs::copy clones on APFS and falls back to copying; it's only bypassed to keep
+    // holes, to hash the data on its way through, or to leave attributes behind.
+    // Clones, like holes, are kept where clonefile(2) can share the blocks, which
+    // is only ever within one volume: onto a RAM disk the data is always copied.
+    fn copy_contents(&mut self, reader: fs::File, meta: &fs::Metadata, from: &Path, to: &Path) -> io::Result<u64> {
+        let keeps_attributes = self.options.preserve.keeps_attributes();
+        if self.manifest.is_none() && !is_sparse(meta) && keeps_attributes {
+            return fs::copy(from, to);
+        }
+        // clonefile brings the attributes along, so it's no use when they're left behind
+        if keeps_attributes && clone_file(from, to) {
+            self.transfer(reader, meta, from, None)?;
+            return Ok(meta.len());
+        }
+        let mut writer = fs::File::create(to)?;
+        self.transfer(reader, meta, from, Some(&mut writer))?;
+        // Extends the file over a trailing hole without writing it
+        writer.set_len(meta.len())?;
+        writer.set_permissions(meta.permissions())?;
+        Ok(meta.len())
+    }
+
+    // Reads the data regions of `reader`, writing them to `writer` at the same offsets
+    // (if there is one to fill) and hashing them into the manifest (if there is one).
+    fn transfer(&mut self, mut reader: fs::File, meta: &fs::Metadata, from: &Path, mut writer: Option<&mut fs::File>) -> io::Result<()> {
+        if writer.is_none() && self.manifest.is_none() {
+            return Ok(());
+        }
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 20];
+        let mut position = 0;
+        for (start, end) in data_regions(&reader, meta.len())? {
+            self.hash_zeros(&mut hasher, start - position);
+            reader.seek(SeekFrom::Start(start))?;
+            if let Some(writer) = writer.as_mut() {
+                writer.seek(SeekFrom::Start(start))?;
+            }
+            let mut remaining = end - start;
+            while remaining > 0 {
+                let chunk = buffer.len().min(remaining as usize);
+                let read = reader.read(&mut buffer[..chunk])?;
+                if read == 0 {
+                    break;
+                }
+                if self.manifest.is_some() {
+                    hasher.update(&buffer[..read]);
+                }
+                if let Some(writer) = writer.as_mut() {
+                    writer.write_all(&buffer[..read])?;
+                }
+                remaining -= read as u64;
+            }
+            position = end;
+        }
+        self.hash_zeros(&mut hasher, meta.len() - position);
+
+        if let Some(manifest) = &mut self.manifest {
+            let digest: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+            let relative = from.strip_prefix(self.src).unwrap_or(from).to_path_buf();
+            manifest.entries.push((digest, relative));
+        }
+        Ok(())
+    }
+
+    // A hole reads as zeros, so that is what the manifest hashes for it
+    fn hash_zeros(&self, hasher: &mut Sha256, mut len: u64) {
+        if self.manifest.is_none() {
+            return;
+        }
+        let zeros = [0; 1 << 16];
+        while len > 0 {
+            let chunk = zeros.len().min(len as usize);
+            hasher.update(&zeros[..chunk]);
+            len -= chunk as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn scratch(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mkramdisk-copier-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let root = scratch("tree");
+        let src = root.join("src");
+        fs::create_dir_all(src.join("nested/deeper")).unwrap();
+        fs::write(src.join("a.txt"), "hello").unwrap();
+        fs::write(src.join("nested/deeper/b.txt"), "world!").unwrap();
+        fs::create_dir_all(src.join(".fseventsd")).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a.txt", src.join("link")).unwrap();
+
+        let dst = root.join("dst");
+        let stats = copy_tree(&src, &dst, &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+
+        assert_eq!((stats.files, stats.dirs, stats.bytes), (2, 2, 11));
+        assert_eq!(scan(&src, LinkPolicy::Preserve).unwrap(), stats);
+        assert!(!dst.join(".fseventsd").exists());
+        assert_eq!(fs::read_to_string(dst.join("nested/deeper/b.txt")).unwrap(), "world!");
+        #[cfg(unix)]
+        assert_eq!(fs::read_link(dst.join("link")).unwrap(), PathBuf::from("a.txt"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sparse_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = scratch("sparse");
+        let src = root.join("src");
+        fs::create_dir_all(&src).unwrap();
+        let mut image = fs::File::create(src.join("vm.img")).unwrap();
+        image.set_len(64 << 20).unwrap();
+        image.seek(SeekFrom::Start(32 << 20)).unwrap();
+        image.write_all(b"data").unwrap();
+        drop(image);
+
+        for (name, with_manifest) in [("dst", false), ("hashed", true)] {
+            let dst = root.join(name);
+            let (stats, manifest) = if with_manifest {
+                copy_tree_with_manifest(&src, &dst, &CopyOptions::default(), &mut Progress::hidden()).unwrap()
+            } else {
+                (copy_tree(&src, &dst, &CopyOptions::default(), &mut Progress::hidden()).unwrap(), Manifest::default())
+            };
+            assert_eq!(stats.bytes, 64 << 20);
+            let copied = fs::read(dst.join("vm.img")).unwrap();
+            if with_manifest {
+                let digest: String = Sha256::digest(&copied).iter().map(|byte| format!("{:02x}", byte)).collect();
+                assert_eq!(manifest.entries, [(digest, PathBuf::from("vm.img"))]);
+            }
+            assert_eq!(copied.len(), 64 << 20);
+            assert_eq!(&copied[32 << 20..(32 << 20) + 4], b"data");
+            assert!(copied[..32 << 20].iter().all(|&byte| byte == 0));
+            // Only if the temp filesystem keeps holes itself
+            if is_sparse(&fs::metadata(src.join("vm.img")).unwrap()) {
+                assert!(fs::metadata(dst.join("vm.img")).unwrap().blocks() * 512 < 1 << 20);
+            }
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_clone_or_copy() {
+        let root = scratch("clone");
+        let src = root.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("image"), "cloned or copied").unwrap();
+
+        // On APFS this clones, being within one volume; elsewhere it has to copy, to the same result
+        let (stats, manifest) = copy_tree_with_manifest(&src, &root.join("dst"), &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        assert_eq!((stats.files, stats.bytes), (1, 16));
+        assert_eq!(fs::read_to_string(root.join("dst/image")).unwrap(), "cloned or copied");
+        let digest: String = Sha256::digest(b"cloned or copied").iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(manifest.entries, [(digest, PathBuf::from("image"))]);
+        // Where no clone can be made, the copy falls back to writing the data
+        assert!(!clone_file(&src.join("image"), &root.join("missing/image")));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    fn xattr(path: &Path, name: &str, value: Option<&[u8]>) -> Option<Vec<u8>> {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+        let mut buffer = [0u8; 64];
+        // SAFETY: NUL-terminated strings and a buffer of the length given
+        let result = unsafe {
+            match value {
+                Some(value) => libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) as isize,
+                None => libc::getxattr(path.as_ptr(), name.as_ptr(), buffer.as_mut_ptr().cast(), buffer.len()),
+            }
+        };
+        (result >= 0).then(|| buffer[..result as usize].to_vec())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_preserve() {
+        use std::time::{Duration, SystemTime};
+
+        let root = scratch("preserve");
+        let src = root.join("src");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::write(src.join("nested/a.txt"), "abc").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let file = fs::File::open(src.join("nested/a.txt")).unwrap();
+        file.set_times(fs::FileTimes::new().set_modified(modified)).unwrap();
+        // Not every filesystem the tests run on takes user attributes
+        let has_xattr = xattr(&src.join("nested/a.txt"), "user.origin", Some(b"download")).is_some();
+
+        copy_tree(&src, &root.join("kept"), &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        copy_tree(&src, &root.join("stripped"), &CopyOptions { preserve: Preserve::none(), ..CopyOptions::default() }, &mut Progress::hidden()).unwrap();
+
+        let kept = root.join("kept/nested/a.txt");
+        let stripped = root.join("stripped/nested/a.txt");
+        assert_eq!(fs::metadata(&kept).unwrap().modified().unwrap(), modified);
+        assert_ne!(fs::metadata(&stripped).unwrap().modified().unwrap(), modified);
+        if has_xattr {
+            assert_eq!(xattr(&kept, "user.origin", None).as_deref(), Some(&b"download"[..]));
+            assert_eq!(xattr(&stripped, "user.origin", None), None);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hardlinks() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = scratch("hardlinks");
+        let src = root.join("src");
+        fs::create_dir_all(src.join("store")).unwrap();
+        fs::write(src.join("store/blob"), "shared").unwrap();
+        fs::hard_link(src.join("store/blob"), src.join("project.txt")).unwrap();
+
+        let dst = root.join("dst");
+        let (stats, manifest) = copy_tree_with_manifest(&src, &dst, &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        assert_eq!((stats.files, stats.hardlinks, stats.bytes), (1, 1, 6));
+        assert_eq!(scan(&src, LinkPolicy::Preserve).unwrap(), stats);
+        let (first, second) = (fs::metadata(dst.join("store/blob")).unwrap(), fs::metadata(dst.join("project.txt")).unwrap());
+        assert_eq!(first.ino(), second.ino());
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[0].0, manifest.entries[1].0);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_over_existing_tree() {
+        let root = scratch("over");
+        let src = root.join("src");
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("dir/file"), "new").unwrap();
+        fs::write(src.join("target"), "new").unwrap();
+        fs::hard_link(src.join("target"), src.join("twin")).unwrap();
+        std::os::unix::fs::symlink("target", src.join("link")).unwrap();
+        fs::write(src.join("was-dir"), "new").unwrap();
+
+        let dst = root.join("dst");
+        let outside = root.join("outside");
+        fs::write(&outside, "untouched").unwrap();
+        fs::create_dir_all(dst.join("dir")).unwrap();
+        fs::write(dst.join("dir/file"), "old").unwrap();
+        fs::write(dst.join("dir/stale"), "old").unwrap();
+        std::os::unix::fs::symlink(&outside, dst.join("target")).unwrap();
+        fs::write(dst.join("twin"), "old").unwrap();
+        std::os::unix::fs::symlink("elsewhere", dst.join("link")).unwrap();
+        fs::create_dir_all(dst.join("was-dir/inner")).unwrap();
+
+        copy_tree(&src, &dst, &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        for name in ["dir/file", "target", "twin", "was-dir"] {
+            assert_eq!(fs::read_to_string(dst.join(name)).unwrap(), "new", "{}", name);
+        }
+        assert!(dst.join("dir/stale").exists());
+        assert_eq!(fs::read_link(dst.join("link")).unwrap(), PathBuf::from("target"));
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "untouched");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_policy() {
+        let root = scratch("links");
+        let src = root.join("src");
+        let outside = root.join("outside");
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("big.bin"), "0123456789").unwrap();
+        std::os::unix::fs::symlink(&outside, src.join("data")).unwrap();
+
+        for (links, name) in [(LinkPolicy::Preserve, "preserve"), (LinkPolicy::Follow, "follow"), (LinkPolicy::Skip, "skip")] {
+            let dst = root.join(name);
+            let options = CopyOptions { links, ..CopyOptions::default() };
+            let stats = copy_tree(&src, &dst, &options, &mut Progress::hidden()).unwrap();
+            assert_eq!(scan(&src, links).unwrap(), stats);
+            let copied = fs::symlink_metadata(dst.join("data"));
+            match links {
+                LinkPolicy::Preserve => assert_eq!(fs::read_link(dst.join("data")).unwrap(), outside),
+                LinkPolicy::Follow => {
+                    assert!(copied.unwrap().is_dir());
+                    assert_eq!(fs::read_to_string(dst.join("data/big.bin")).unwrap(), "0123456789");
+                    assert_eq!((stats.files, stats.dirs, stats.bytes), (1, 1, 10));
+                }
+                LinkPolicy::Skip => assert!(copied.is_err()),
+            }
+        }
+
+        // A link back up the tree would be followed forever
+        std::os::unix::fs::symlink("..", outside.join("up")).unwrap();
+        let options = CopyOptions { links: LinkPolicy::Follow, ..CopyOptions::default() };
+        assert!(scan(&src, LinkPolicy::Follow).unwrap_err().contains("links back"));
+        assert!(copy_tree(&src, &root.join("loop"), &options, &mut Progress::hidden()).unwrap_err().contains("links back"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_manifest() {
+        let root = scratch("manifest");
+        let src = root.join("src");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::write(src.join("nested/a.txt"), "abc").unwrap();
+
+        let (stats, manifest) = copy_tree_with_manifest(&src, &root.join("dst"), &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        assert_eq!(stats.bytes, 3);
+        assert_eq!(fs::read_to_string(root.join("dst/nested/a.txt")).unwrap(), "abc");
+        manifest.write(&root.join("manifest")).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("manifest")).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  nested/a.txt\n"
+        );
+        assert_eq!(Manifest::read(&root.join("manifest")).unwrap(), Some(manifest));
+        assert_eq!(Manifest::read(&root.join("missing")).unwrap(), None);
+        fs::write(root.join("manifest"), "not a digest  nested/a.txt\n").unwrap();
+        assert!(Manifest::read(&root.join("manifest")).unwrap_err().starts_with("Invalid"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_copy_tree_over() {
+        let root = scratch("over");
+        let src = root.join("src");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::write(src.join("a.txt"), "abc").unwrap();
+        fs::write(src.join("nested/b.txt"), "def").unwrap();
+        let dst = root.join("dst");
+        let (_, manifest) = copy_tree_with_manifest(&src, &dst, &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+
+        // Only what differs from the manifest is copied again
+        fs::write(dst.join("nested/b.txt"), "changed").unwrap();
+        let stats = copy_tree_over(&src, &dst, &manifest, &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        assert_eq!((stats.files, stats.unchanged, stats.bytes), (1, 1, 3));
+        assert_eq!(fs::read_to_string(dst.join("nested/b.txt")).unwrap(), "def");
+        // A file the manifest doesn't know is always copied
+        let stats = copy_tree_over(&src, &dst, &Manifest::default(), &CopyOptions::default(), &mut Progress::hidden()).unwrap();
+        assert_eq!((stats.files, stats.unchanged), (2, 0));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::runner;
+
+/// diskutil personalities mkramdisk can format a RAM disk with unattended.
+/// Encrypted variants are left out because they prompt for a passphrase.
+pub const SAFE_PERSONALITIES: &[&str] = &[
+    "APFS",
+    "Case-sensitive APFS",
+    "HFS+",
+    "Journaled HFS+",
+    "Case-sensitive HFS+",
+    "Case-sensitive Journaled HFS+",
+    "ExFAT",
+    "MS-DOS FAT32",
+    "MS-DOS FAT16",
+];
+
+/// Personalities only offered with `--experimental`, for filesystem developers using
+/// RAM disks as scratch targets. Not all are available on every macOS version.
+pub const EXPERIMENTAL_PERSONALITIES: &[&str] = &["MS-DOS FAT12", "UFS", RAW];
+
+/// Leaves the device attached with no filesystem and nothing mounted.
+pub const RAW: &str = "Free Space";
+
+/// Filesystems of the backends that format with mkfs or newfs rather than diskutil;
+/// the first of each is its default.
+pub const BACKEND_FILESYSTEMS: &[(&str, &[&str])] = &[
+    ("zram", &["ext4", "btrfs"]),
+    ("md", &["ufs"]),
+    ("imdisk", &["ntfs", "exfat", "fat32"]),
+];
+
+/// Short names for the common personalities.
+pub const ALIASES: &[(&str, &str)] = &[
+    ("apfs", "APFS"),
+    ("hfs+", "HFS+"),
+    ("hfs", "HFS+"),
+    ("fat32", "MS-DOS FAT32"),
+    ("msdos", "MS-DOS FAT32"),
+    ("exfat", "ExFAT"),
+];
+
+/// Resolve a `--format` value to the personality diskutil expects. Accepts an alias,
+/// the number `mkramdisk formats` lists it under, or a personality name, matched
+/// loosely: case, punctuation and word order don't matter, and words may be left out
+/// as long as only one personality fits ("sensitive apfs").
+pub fn personality(filesystem: &str) -> Result<&'static str, String> {
+    if let Ok(number) = filesystem.parse::<usize>() {
+        return number
+            .checked_sub(1)
+            .and_then(|index| SAFE_PERSONALITIES.get(index))
+            .copied()
+            .ok_or_else(|| format!("No filesystem number {} (see `mkramdisk formats`)", number));
+    }
+    if let Some((_, personality)) = ALIASES.iter().find(|(alias, _)| alias.eq_ignore_ascii_case(filesystem)) {
+        return Ok(personality);
+    }
+
+    let wanted = words(filesystem);
+    if let Some(exact) = SAFE_PERSONALITIES.iter().find(|p| words(p) == wanted) {
+        return Ok(exact);
+    }
+    let fits: Vec<&'static str> = SAFE_PERSONALITIES
+        .iter()
+        .filter(|p| !wanted.is_empty() && wanted.iter().all(|word| words(p).contains(word)))
+        .copied()
+        .collect();
+    let fewest_extra = fits.iter().map(|p| words(p).len()).min();
+    let best: Vec<&'static str> = fits.iter().filter(|p| Some(words(p).len()) == fewest_extra).copied().collect();
+    match best[..] {
+        [only] => Ok(only),
+        [] => Err(unsupported(filesystem, closest(filesystem).map(|p| format!("Did you mean {}?", p)))),
+        _ => Err(unsupported(filesystem, Some(format!("It could be any of: {}", best.join(", "))))),
+    }
+}
+
+/// The filesystems `backend` formats with itself, if it doesn't use diskutil.
+pub fn backend_filesystems(backend: &str) -> Option<&'static [&'static str]> {
+    BACKEND_FILESYSTEMS.iter().find(|(name, _)| *name == backend).map(|(_, filesystems)| *filesystems)
+}
+
+/// Resolve a `--format` value for one of the backends in BACKEND_FILESYSTEMS.
+pub fn backend_filesystem(backend: &str, filesystem: &str) -> Result<&'static str, String> {
+    let filesystems = backend_filesystems(backend).unwrap_or_default();
+    filesystems
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(filesystem))
+        .copied()
+        .ok_or_else(|| format!("Unsupported filesystem for {}: {}\nSupported filesystems: {}", backend, filesystem, filesystems.join(", ")))
+}
+
+/// Match `filesystem` against the experimental personalities (ignoring case and punctuation).
+pub fn experimental_personality(filesystem: &str) -> Option<&'static str> {
+    let wanted = words(filesystem);
+    EXPERIMENTAL_PERSONALITIES.iter().find(|p| words(p) == wanted).copied()
+}
+
+fn unsupported(filesystem: &str, hint: Option<String>) -> String {
+    let mut message = format!(
+        "Unsupported filesystem: {}\nSupported filesystems: apfs, hfs+, fat32, exfat \
+         (run `mkramdisk formats` for more)",
+        filesystem
+    );
+    if let Some(hint) = hint {
+        message = format!("{}\n{}", message, hint);
+    }
+    message
+}
+
+// Lowercased words with the punctuation dropped, except the + that tells HFS+ from HFS
+fn words(name: &str) -> Vec<String> {
+    let mut words: Vec<String> = name
+        .split(|c: char| !(c.is_alphanumeric() || c == '+'))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    words.sort();
+    words
+}
+
+// The alias or personality within a couple of typos of `filesystem`, if any
+fn closest(filesystem: &str) -> Option<&'static str> {
+    let filesystem = filesystem.to_lowercase();
+    ALIASES
+        .iter()
+        .map(|(alias, _)| *alias)
+        .chain(SAFE_PERSONALITIES.iter().copied())
+        .map(|candidate| (edit_distance(&filesystem, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[derive(Debug, Deserialize)]
+struct ListedFilesystem {
+    #[serde(rename = "Personality")]
+    personality: String,
+}
+
+/// Which of `candidates` this system's diskutil can actually create, in their order.
+pub fn available(candidates: &[&'static str]) -> Result<Vec<&'static str>, String> {
+    let output = runner::output(Command::new("diskutil").args(["listFilesystems", "-plist"]))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "diskutil listFilesystems failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let listed = parse_list_filesystems(&output.stdout)?;
+    Ok(candidates
+        .iter()
+        .filter(|candidate| listed.iter().any(|listed| listed == *candidate))
+        .copied()
+        .collect())
+}
+
+fn parse_list_filesystems(plist: &[u8]) -> Result<Vec<String>, String> {
+    let listed: Vec<ListedFilesystem> = plist::from_bytes(plist)
+        .map_err(|e| format!("Unexpected diskutil listFilesystems output: {}", e))?;
+    Ok(listed.into_iter().map(|filesystem| filesystem.personality).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_personality() {
+        assert_eq!(personality("APFS").unwrap(), "APFS");
+        assert_eq!(personality("case-sensitive apfs").unwrap(), "Case-sensitive APFS");
+        assert_eq!(personality("hfs").unwrap(), "HFS+");
+        assert!(personality("APFS (Encrypted)").is_err());
+        assert!(personality("free space").is_err());
+        assert_eq!(experimental_personality("free space"), Some(RAW));
+        assert_eq!(backend_filesystem("zram", "BTRFS").unwrap(), "btrfs");
+        assert_eq!(backend_filesystem("md", "ufs").unwrap(), "ufs");
+        assert!(backend_filesystem("zram", "apfs").is_err());
+        assert_eq!(backend_filesystems("ram"), None);
+    }
+
+    #[test]
+    fn test_personality_fuzzy() {
+        assert_eq!(personality("2").unwrap(), "Case-sensitive APFS");
+        assert!(personality("0").is_err());
+        assert_eq!(personality("apfs case sensitive").unwrap(), "Case-sensitive APFS");
+        assert_eq!(personality("journaled hfs+").unwrap(), "Journaled HFS+");
+        assert_eq!(personality("FAT16").unwrap(), "MS-DOS FAT16");
+        assert_eq!(personality("sensitive hfs+").unwrap(), "Case-sensitive HFS+");
+        assert!(personality("case").unwrap_err().ends_with("any of: Case-sensitive APFS, Case-sensitive HFS+"));
+        assert!(personality("apsf").unwrap_err().ends_with("Did you mean apfs?"));
+    }
+
+    #[test]
+    fn test_parse_list_filesystems() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<array>
+    <dict>
+        <key>FilesystemName</key><string>apfs</string>
+        <key>Personality</key><string>Case-sensitive APFS</string>
+    </dict>
+    <dict>
+        <key>Personality</key><string>Free Space</string>
+    </dict>
+</array>
+</plist>"#;
+        assert_eq!(parse_list_filesystems(plist).unwrap(), ["Case-sensitive APFS", "Free Space"]);
+    }
+}
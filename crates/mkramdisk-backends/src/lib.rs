@@ -0,0 +1,222 @@
+//! Device providers for mkramdisk (see `provider`) and the plumbing they run on:
+//! external commands, tracing, progress events and session recording, with the size
+//! and volume name rules the providers share. `mkramdisk-core` builds on this and
+//! re-exports it, so most users want that crate instead.
+
+use std::path::{Path, PathBuf};
+
+pub mod api;
+pub mod attributes;
+pub mod copier;
+pub mod formats;
+pub mod partitions;
+pub mod pool;
+pub mod progress;
+pub mod provider;
+pub mod remote;
+pub mod runner;
+pub mod session;
+pub mod trace;
+
+/// A path as the bytes the filesystem has for it, for output that must not mangle names
+/// that aren't UTF-8. Elsewhere than Unix paths are Unicode, and this is their UTF-8.
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().into_owned().into_bytes()
+    }
+}
+
+/// The path whose bytes `path_bytes` gave, as read back from a file it was written to.
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+pub fn sanitize_volume_name(name: &str) -> String {
+    // Remove characters that could cause issues with volume names
+    name.chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-' || *c == ' ')
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+const SIZE_UNITS: [(&str, u64); 5] = [
+    ("T", 1 << 40),
+    ("G", 1 << 30),
+    ("M", 1 << 20),
+    ("K", 1 << 10),
+    ("", 1),
+];
+
+// Longest volume label FAT32 allows
+pub const FAT_LABEL_MAX: usize = 11;
+
+/// Parse a size such as "512M" into bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.to_uppercase();
+    if size.starts_with('-') {
+        return Err("Size cannot be negative".to_string());
+    }
+    let (number_str, suffix) = if let Some(pos) = size.find(|c: char| c.is_alphabetic()) {
+        (&size[..pos], &size[pos..])
+    } else {
+        (size.as_str(), "")
+    };
+    
+    let number: u64 = number_str.parse()
+        .map_err(|_| format!("Invalid number in size: {}", number_str))?;
+    
+    if number == 0 {
+        return Err("Size cannot be zero".to_string());
+    }
+    
+    let multiplier = match suffix {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(format!("Unknown size suffix: {}", suffix)),
+    };
+    number.checked_mul(multiplier)
+        .ok_or_else(|| format!("Size too large (maximum {}T)", u64::MAX >> 40))
+}
+
+/// Map a unit as people tend to type it ("GiB", "gigs", "mb") to our suffix.
+pub fn size_unit(unit: &str) -> Option<&'static str> {
+    let unit = unit.trim_end_matches('S');
+    let unit = unit.strip_suffix("BYTE").unwrap_or(unit);
+    SIZE_UNITS.iter().map(|(suffix, _)| *suffix).find(|suffix| {
+        let Some(rest) = unit.strip_prefix(suffix) else {
+            return false;
+        };
+        match *suffix {
+            "" => matches!(rest, "" | "B"),
+            "K" => matches!(rest, "" | "B" | "I" | "IB" | "ILO"),
+            "M" => matches!(rest, "" | "B" | "I" | "IB" | "EG" | "EGA"),
+            "G" => matches!(rest, "" | "B" | "I" | "IB" | "IG" | "IGA"),
+            _ => matches!(rest, "" | "B" | "I" | "IB" | "ERA"),
+        }
+    })
+}
+
+/// A "did you mean" hint for a size that failed to parse, built by re-reading it
+/// leniently: whitespace and digit separators are dropped, a letter O among the
+/// digits is read as a zero, units may be spelled out or binary-prefixed, and
+/// fractional sizes are converted to a whole number of a smaller unit.
+fn suggest_size(size: &str) -> Option<String> {
+    let cleaned: String = size
+        .trim_start_matches(['-', '+'])
+        .chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, ',' | '_' | '\''))
+        .collect::<String>()
+        .to_uppercase();
+    let split = cleaned
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == 'O'))
+        .unwrap_or(cleaned.len());
+    let (number, unit) = cleaned.split_at(split);
+    if !number.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let number = number.replace('O', "0");
+    let suffix = size_unit(unit)?;
+    let multiplier = SIZE_UNITS.iter().find(|(s, _)| *s == suffix)?.1;
+
+    let suggestion = match number.split_once('.') {
+        None => format!("{}{}", number, suffix),
+        Some((whole, fraction)) => {
+            let value: f64 = format!("{}.{}", whole, fraction).parse().ok()?;
+            let bytes = value * multiplier as f64;
+            let (unit, unit_bytes) = SIZE_UNITS
+                .iter()
+                .find(|(_, unit_bytes)| (bytes / *unit_bytes as f64).fract() == 0.0)?;
+            format!("{}{}", (bytes / *unit_bytes as f64) as u64, unit)
+        }
+    };
+    if suggestion.eq_ignore_ascii_case(size) || parse_size(&suggestion).is_err() {
+        return None;
+    }
+
+    let mut hint = format!("Did you mean {}?", suggestion);
+    if unit.strip_prefix(suffix).is_some_and(|rest| matches!(rest, "I" | "IB")) {
+        hint.push_str(&format!(
+            " Sizes are already binary: 1{0} is 1024^{1} bytes, what some tools write as 1{0}iB.",
+            suffix,
+            multiplier.trailing_zeros() / 10
+        ));
+    }
+    Some(hint)
+}
+
+pub fn size_to_bytes(size: &str) -> Result<u64, String> {
+    parse_size(size).map_err(|e| match suggest_size(size) {
+        Some(hint) => format!("{}\n{}", e, hint),
+        None => e,
+    })
+}
+
+pub fn size_to_sectors(size: &str) -> Result<u64, String> {
+    let bytes = size_to_bytes(size)?;
+    if bytes < 512 {
+        return Err("Size too small (minimum 512 bytes)".to_string());
+    }
+    Ok(bytes.div_ceil(512))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_size_to_sectors() {
+        assert_eq!(size_to_sectors("1024").unwrap(), 2);
+        assert_eq!(size_to_sectors("1K").unwrap(), 2);
+        assert_eq!(size_to_sectors("1KB").unwrap(), 2);
+        assert_eq!(size_to_sectors("1M").unwrap(), 2048);
+        assert_eq!(size_to_sectors("1MB").unwrap(), 2048);
+        assert_eq!(size_to_sectors("1G").unwrap(), 2097152);
+        assert_eq!(size_to_sectors("1GB").unwrap(), 2097152);
+        
+        assert!(size_to_sectors("invalid").is_err());
+        assert!(size_to_sectors("1X").is_err());
+        assert!(size_to_sectors("0").is_err());
+        assert!(size_to_sectors("100").is_err());
+    }
+    
+    #[test]
+    fn test_size_suggestions() {
+        let error = |size: &str| size_to_sectors(size).unwrap_err();
+        assert!(error("512 M").ends_with("\nDid you mean 512M?"));
+        assert_eq!(error("-1G"), "Size cannot be negative\nDid you mean 1G?");
+        assert!(error("2GiB").ends_with("Did you mean 2G? Sizes are already binary: 1G is 1024^3 bytes, what some tools write as 1GiB."));
+        assert!(error("1.5G").ends_with("Did you mean 1536M?"));
+        assert!(error("1,024 megabytes").ends_with("Did you mean 1024M?"));
+        assert!(error("1O24M").ends_with("Did you mean 1024M?"));
+        assert!(error("4 gigs").ends_with("Did you mean 4G?"));
+        assert_eq!(error("0"), "Size cannot be zero");
+        assert_eq!(error("1X"), "Unknown size suffix: X");
+        assert!(error("99999999T").starts_with("Size too large"));
+    }
+    
+    #[test]
+    fn test_sanitize_volume_name() {
+        assert_eq!(sanitize_volume_name("Test Disk"), "Test Disk");
+        assert_eq!(sanitize_volume_name("Test/Disk"), "TestDisk");
+        assert_eq!(sanitize_volume_name("Test:Disk"), "TestDisk");
+        assert_eq!(sanitize_volume_name("Test-Disk_2"), "Test-Disk_2");
+    }
+}
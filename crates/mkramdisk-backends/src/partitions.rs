@@ -0,0 +1,151 @@
+use std::fmt;
+
+use crate::{formats, parse_size, sanitize_volume_name};
+
+/// Partition map written before formatting, from `--scheme`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    /// No map: the filesystem goes straight onto the whole device.
+    Bare,
+    Gpt,
+    Mbr,
+}
+
+impl Scheme {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(Scheme::Bare),
+            "gpt" => Ok(Scheme::Gpt),
+            "mbr" => Ok(Scheme::Mbr),
+            _ => Err(format!("Unknown partition scheme: {} (expected gpt, mbr or none)", value)),
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scheme::Bare => write!(f, "none"),
+            Scheme::Gpt => write!(f, "GPT"),
+            Scheme::Mbr => write!(f, "MBR"),
+        }
+    }
+}
+
+/// One partition of a `--partitions` layout, e.g. `p1:apfs:1G`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    pub name: String,
+    pub personality: String,
+    /// None takes whatever is left of the disk.
+    pub bytes: Option<u64>,
+}
+
+/// Name diskutil gives the EFI system partition it puts on a GPT disk.
+pub const ESP_NAME: &str = "EFI";
+
+// The size diskutil gives an EFI system partition
+const ESP_BYTES: u64 = 200 << 20;
+
+// Room for the GPT itself and diskutil's alignment of each partition
+const OVERHEAD_PER_PARTITION: u64 = 2 << 20;
+
+/// Parse a comma-separated `name:filesystem:size` list.
+pub fn parse(spec: &str) -> Result<Vec<Partition>, String> {
+    let mut partitions: Vec<Partition> = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let [name, filesystem, size] = entry.split(':').collect::<Vec<_>>()[..] else {
+            return Err(format!("Invalid partition '{}': expected name:filesystem:size", entry));
+        };
+        let name = sanitize_volume_name(name);
+        if name.is_empty() {
+            return Err(format!("Invalid partition '{}': the name is empty", entry));
+        }
+        if partitions.iter().any(|p| p.name == name) {
+            return Err(format!("Partition name '{}' is used more than once", name));
+        }
+        let bytes = parse_size(size).map_err(|e| format!("Invalid partition '{}': {}", entry, e))?;
+        partitions.push(Partition { name, personality: formats::personality(filesystem)?.to_string(), bytes: Some(bytes) });
+    }
+    if partitions.is_empty() {
+        return Err("--partitions needs at least one name:filesystem:size entry".to_string());
+    }
+    Ok(partitions)
+}
+
+/// The partitions to ask for the structure of a bootable disk, with nothing installed:
+/// just the data partition, filling the disk, since diskutil puts a real EFI system
+/// partition (type EFI, not a FAT data partition) ahead of it on any GPT map it writes.
+pub fn bootable(name: &str, personality: &str) -> Vec<Partition> {
+    vec![Partition { name: name.to_string(), personality: personality.to_string(), bytes: None }]
+}
+
+/// The smallest disk a `bootable` layout fits on: the EFI system partition, the data
+/// partition's `data_minimum` and the room the GPT and alignment take around both.
+pub fn bootable_bytes(data_minimum: u64) -> u64 {
+    ESP_BYTES + data_minimum + 2 * OVERHEAD_PER_PARTITION
+}
+
+/// A disk size that fits every partition plus the partition map.
+pub fn total_size(partitions: &[Partition]) -> String {
+    let bytes: u64 = partitions.iter().map(|p| p.bytes.unwrap_or(0) + OVERHEAD_PER_PARTITION).sum();
+    format!("{}M", bytes.div_ceil(1 << 20))
+}
+
+/// Arguments for `diskutil partitionDisk`, which creates the map and formats every partition.
+pub fn diskutil_args(device: &str, scheme: Scheme, partitions: &[Partition]) -> Vec<String> {
+    let mut args = vec!["partitionDisk".to_string(), device.to_string(), scheme.to_string()];
+    for partition in partitions {
+        let size = partition.bytes.map_or_else(|| "R".to_string(), |bytes| format!("{}B", bytes));
+        args.extend([partition.personality.clone(), partition.name.clone(), size]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let partitions = parse("p1:apfs:1G, p2:fat32:512M").unwrap();
+        assert_eq!(
+            partitions[1],
+            Partition { name: "p2".to_string(), personality: "MS-DOS FAT32".to_string(), bytes: Some(512 << 20) }
+        );
+        assert_eq!(total_size(&partitions), "1540M");
+        assert_eq!(
+            diskutil_args("/dev/disk5", Scheme::Gpt, &partitions),
+            ["partitionDisk", "/dev/disk5", "GPT", "APFS", "p1", "1073741824B", "MS-DOS FAT32", "p2", "536870912B"]
+        );
+
+        assert!(parse("p1:apfs").is_err());
+        assert!(parse("p1:apfs:1G,p1:hfs+:1G").is_err());
+        assert!(parse("p1:floppy:1G").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn test_bootable() {
+        let partitions = bootable("Install", "APFS");
+        // diskutil adds the EFI system partition itself
+        assert_eq!(
+            diskutil_args("/dev/disk5", Scheme::Gpt, &partitions),
+            ["partitionDisk", "/dev/disk5", "GPT", "APFS", "Install", "R"]
+        );
+        assert_eq!(bootable_bytes(32 << 20), (200 + 32 + 4) << 20);
+    }
+
+    #[test]
+    fn test_scheme() {
+        assert_eq!(Scheme::parse("MBR").unwrap(), Scheme::Mbr);
+        assert_eq!(Scheme::parse("none").unwrap(), Scheme::Bare);
+        assert!(Scheme::parse("apm").is_err());
+
+        let whole = [Partition { name: "Scratch".to_string(), personality: "APFS".to_string(), bytes: None }];
+        assert_eq!(
+            diskutil_args("/dev/disk5", Scheme::Mbr, &whole),
+            ["partitionDisk", "/dev/disk5", "MBR", "APFS", "Scratch", "R"]
+        );
+    }
+}
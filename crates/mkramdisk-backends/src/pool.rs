@@ -0,0 +1,305 @@
+//! A cap on how many hdiutil and diskutil commands run at once, across every mkramdisk
+//! process, and a lock that runs the ones changing disks one at a time: DiskArbitration
+//! gets flaky when erases and attaches overlap, as they do when a script creates disks
+//! in parallel. `runner` takes a permit before running either program. The slots and
+//! the lock are files in the lock directory held with flock, so a process that dies
+//! lets go of them. Within an `Operation`, such as creating a disk, the lock is kept
+//! from its first command changing disks to its end, so another process's commands
+//! can't land between its steps.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Commands allowed to run at once unless configured otherwise.
+pub const DEFAULT_LIMIT: usize = 4;
+
+/// How long a command waits for a slot and the lock before giving up.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The programs whose commands go through the pool.
+pub const PROGRAMS: &[&str] = &["hdiutil", "diskutil"];
+
+// Subcommands that only read, which may overlap anything
+const READ_ONLY: &[&str] = &["info", "imageinfo", "list", "listFilesystems"];
+
+static LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_LIMIT);
+
+thread_local! {
+    // The operations under way on this thread, and the lock once one of them took it
+    static OPERATION: RefCell<(usize, Option<File>)> = const { RefCell::new((0, None)) };
+}
+
+/// Cap commands at `limit` (`max_commands` in config.toml); `$MKRAMDISK_MAX_COMMANDS`
+/// still takes precedence.
+pub fn set_limit(limit: usize) {
+    LIMIT.store(limit.max(1), Ordering::Relaxed);
+}
+
+/// The cap in force. Processes sharing the lock directory should agree on it: each
+/// only looks at as many slots as its own cap.
+pub fn limit() -> usize {
+    env::var("MKRAMDISK_MAX_COMMANDS")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or_else(|| LIMIT.load(Ordering::Relaxed))
+}
+
+/// How long to wait for the pool: `$MKRAMDISK_LOCK_TIMEOUT` seconds, else
+/// `DEFAULT_TIMEOUT`.
+pub fn timeout() -> Duration {
+    env::var("MKRAMDISK_LOCK_TIMEOUT")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .map_or(DEFAULT_TIMEOUT, Duration::from_secs)
+}
+
+/// `$MKRAMDISK_LOCK_DIR`, else a directory of the user's own under the temp dir.
+pub fn lock_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("MKRAMDISK_LOCK_DIR") {
+        return PathBuf::from(dir);
+    }
+    #[cfg(unix)]
+    let name = format!("mkramdisk-locks-{}", unsafe { libc::getuid() });
+    #[cfg(not(unix))]
+    let name = "mkramdisk-locks".to_string();
+    env::temp_dir().join(name)
+}
+
+/// Whether a command has to go through the pool and, if so, whether it changes disks
+/// and so must not overlap another that does.
+pub fn classify(command: &Command) -> Option<bool> {
+    let program = command.get_program().to_string_lossy();
+    if !PROGRAMS.contains(&program.as_ref()) {
+        return None;
+    }
+    let subcommand = command.get_args().map(|arg| arg.to_string_lossy()).find(|arg| !arg.starts_with('-'));
+    Some(!subcommand.is_some_and(|subcommand| READ_ONLY.contains(&subcommand.as_ref())))
+}
+
+/// Held while a command runs; dropping it frees its slot and the lock.
+pub struct Permit {
+    _slot: File,
+    _exclusive: Option<File>,
+}
+
+/// Held while a sequence of commands runs as one: its commands that change disks
+/// keep the lock from the first of them until this is dropped. Operations nest.
+pub struct Operation {
+    _private: (),
+}
+
+/// Start an operation on this thread.
+pub fn operation() -> Operation {
+    OPERATION.with(|operation| operation.borrow_mut().0 += 1);
+    Operation { _private: () }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        OPERATION.with(|operation| {
+            let mut operation = operation.borrow_mut();
+            operation.0 -= 1;
+            if operation.0 == 0 {
+                operation.1 = None;
+            }
+        });
+    }
+}
+
+/// A permit to run `commands` (a pipeline counts once), or None if none of them go
+/// through the pool or the lock directory is unusable, when they run unchecked.
+/// Waiting longer than `timeout()` is an error rather than a reason to run anyway.
+pub fn acquire(commands: &[&Command]) -> io::Result<Option<Permit>> {
+    let classes: Vec<bool> = commands.iter().filter_map(|command| classify(command)).collect();
+    if classes.is_empty() {
+        return Ok(None);
+    }
+    let pool = Pool { dir: lock_dir(), limit: limit(), timeout: timeout() };
+    let started = Instant::now();
+    match pool.acquire(classes.contains(&true)) {
+        Ok(permit) => {
+            let waited = started.elapsed();
+            if waited >= Duration::from_millis(100) {
+                crate::session::decision(&format!("Waited {:?} for a free hdiutil/diskutil slot", waited));
+            }
+            Ok(Some(permit))
+        }
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(e),
+        Err(e) => {
+            crate::session::decision(&format!("Running without the command pool: {}: {}", pool.dir.display(), e));
+            Ok(None)
+        }
+    }
+}
+
+/// Slots and the lock in one directory.
+pub struct Pool {
+    pub dir: PathBuf,
+    pub limit: usize,
+    pub timeout: Duration,
+}
+
+impl Pool {
+    /// Wait for the lock if `exclusive`, unless this thread's operation holds it
+    /// already, then for a free slot.
+    pub fn acquire(&self, exclusive: bool) -> io::Result<Permit> {
+        self.create_dir()?;
+        let deadline = Instant::now() + self.timeout;
+        // Taken first, so a command waiting its turn doesn't sit on a slot
+        let exclusive = match exclusive {
+            true => OPERATION.with(|operation| match &mut *operation.borrow_mut() {
+                (0, _) => self.wait("exclusive", deadline).map(Some),
+                (_, Some(_)) => Ok(None),
+                (_, held) => {
+                    *held = Some(self.wait("exclusive", deadline)?);
+                    Ok(None)
+                }
+            })?,
+            false => None,
+        };
+        let slots: Vec<String> = (0..self.limit).map(|n| format!("slot-{}", n)).collect();
+        let slot = self.wait_any(&slots, deadline)?;
+        Ok(Permit { _slot: slot, _exclusive: exclusive })
+    }
+
+    fn wait(&self, name: &str, deadline: Instant) -> io::Result<File> {
+        self.wait_any(&[name.to_string()], deadline)
+    }
+
+    // The first of the files `names` to come free, locked, polling until `deadline`
+    fn wait_any(&self, names: &[String], deadline: Instant) -> io::Result<File> {
+        let mut delay = Duration::from_millis(10);
+        loop {
+            for name in names {
+                let file = self.open(name)?;
+                match file.try_lock() {
+                    Ok(()) => return Ok(file),
+                    Err(TryLockError::WouldBlock) => {}
+                    Err(TryLockError::Error(e)) => return Err(e),
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("gave up after {:?} waiting for other hdiutil/diskutil commands ({})", self.timeout, self.dir.display()),
+                ));
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(Duration::from_millis(200));
+        }
+    }
+
+    // The directory is made private to the user, and one anybody else could have
+    // made or could write to is refused: its locks would be theirs to hold forever
+    #[cfg(unix)]
+    fn create_dir(&self) -> io::Result<()> {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        let meta = fs::symlink_metadata(&self.dir)?;
+        if !meta.is_dir() || meta.uid() != unsafe { libc::getuid() } || meta.mode() & 0o022 != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "not a directory of this user's that only they can write to"));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn create_dir(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)
+    }
+
+    fn open(&self, name: &str) -> io::Result<File> {
+        OpenOptions::new().create(true).truncate(false).write(true).open(self.dir.join(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(Command::new("diskutil").args(["info", "-plist", "/dev/disk4"])), Some(false));
+        assert_eq!(classify(Command::new("hdiutil").args(["info", "-plist"])), Some(false));
+        assert_eq!(classify(Command::new("diskutil").args(["erasevolume", "APFS", "Scratch", "/dev/disk4"])), Some(true));
+        assert_eq!(classify(Command::new("hdiutil").args(["attach", "-nomount", "ram://2048"])), Some(true));
+        assert_eq!(classify(Command::new("lsof").arg("/Volumes/Scratch")), None);
+    }
+
+    // Whether acquiring from `pool` in another thread gets through within a moment
+    fn acquired_meanwhile(pool: &Pool, exclusive: bool) -> (bool, mpsc::Receiver<Permit>) {
+        let (sender, receiver) = mpsc::channel();
+        let pool = Pool { dir: pool.dir.clone(), limit: pool.limit, timeout: pool.timeout };
+        thread::spawn(move || sender.send(pool.acquire(exclusive).unwrap()));
+        (receiver.recv_timeout(Duration::from_millis(200)).is_ok(), receiver)
+    }
+
+    #[test]
+    fn test_pool() {
+        let dir = env::temp_dir().join(format!("mkramdisk-pool-{}", std::process::id()));
+        let pool = Pool { dir: dir.clone(), limit: 2, timeout: DEFAULT_TIMEOUT };
+
+        // Two slots: a third command waits for one to come free
+        let first = pool.acquire(false).unwrap();
+        let _second = pool.acquire(false).unwrap();
+        let (acquired, waiting) = acquired_meanwhile(&pool, false);
+        assert!(!acquired);
+        drop(first);
+        let third = waiting.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(third);
+
+        // Commands changing disks go one at a time, even with slots free
+        let pool = Pool { dir: dir.clone(), limit: 4, timeout: DEFAULT_TIMEOUT };
+        let erase = pool.acquire(true).unwrap();
+        assert!(acquired_meanwhile(&pool, false).0);
+        let (acquired, waiting) = acquired_meanwhile(&pool, true);
+        assert!(!acquired);
+        drop(erase);
+        assert!(waiting.recv_timeout(Duration::from_secs(5)).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_operation() {
+        let dir = env::temp_dir().join(format!("mkramdisk-pool-operation-{}", std::process::id()));
+        let pool = Pool { dir: dir.clone(), limit: 4, timeout: DEFAULT_TIMEOUT };
+
+        // The lock outlives the operation's commands, and only goes with the operation
+        let operation = operation();
+        drop(pool.acquire(true).unwrap());
+        drop(pool.acquire(true).unwrap());
+        let (acquired, waiting) = acquired_meanwhile(&pool, true);
+        assert!(!acquired);
+        drop(operation);
+        assert!(waiting.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // Nor is it waited on for ever
+        let held = pool.acquire(true).unwrap();
+        let impatient = Pool { dir: dir.clone(), limit: 4, timeout: Duration::from_millis(50) };
+        assert_eq!(impatient.acquire(true).err().unwrap().kind(), io::ErrorKind::TimedOut);
+        drop(held);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_dir_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("mkramdisk-pool-dir-{}", std::process::id()));
+        let pool = Pool { dir: dir.clone(), limit: 1, timeout: DEFAULT_TIMEOUT };
+        drop(pool.acquire(false).unwrap());
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(pool.acquire(false).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
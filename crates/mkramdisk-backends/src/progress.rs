@@ -0,0 +1,261 @@
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static OUTPUTS: Mutex<Vec<Output>> = Mutex::new(Vec::new());
+
+// Where the local syslog daemon listens, by platform
+#[cfg(unix)]
+const SYSLOG_SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+// How often a running operation redraws its progress line and emits an event
+const INTERVAL: Duration = Duration::from_millis(250);
+
+const MB: f64 = (1 << 20) as f64;
+
+/// Somewhere events are written as newline-delimited JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    /// `--events ndjson`
+    Stderr,
+    /// `--json-lines`, in place of the usual output on stdout
+    Stdout,
+    /// `--events-to file:PATH`, appended to
+    File(PathBuf),
+    /// `--events-to syslog`, one message per event
+    Syslog,
+    /// `--events-to unix:PATH`, a stream socket something else is listening on
+    Socket(PathBuf),
+}
+
+impl Sink {
+    /// Parse an `--events-to` value: `stderr`, `stdout`, `syslog`, `file:PATH` or `unix:PATH`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.split_once(':') {
+            _ if value == "stderr" => Ok(Sink::Stderr),
+            _ if value == "stdout" => Ok(Sink::Stdout),
+            _ if value == "syslog" => Ok(Sink::Syslog),
+            Some(("file", path)) if !path.is_empty() => Ok(Sink::File(PathBuf::from(path))),
+            Some(("unix", path)) if !path.is_empty() => Ok(Sink::Socket(PathBuf::from(path))),
+            _ => Err(format!("Unknown event sink: {} (expected stderr, stdout, syslog, file:PATH or unix:PATH)", value)),
+        }
+    }
+}
+
+// A sink, opened
+enum Output {
+    Stderr,
+    Stdout,
+    File(File),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Socket(UnixStream),
+}
+
+impl Output {
+    fn open(sink: &Sink) -> Result<Self, String> {
+        match sink {
+            Sink::Stderr => Ok(Output::Stderr),
+            Sink::Stdout => Ok(Output::Stdout),
+            Sink::File(path) => fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map(Output::File)
+                .map_err(|e| format!("Failed to open {} for events: {}", path.display(), e)),
+            #[cfg(unix)]
+            Sink::Syslog => {
+                let socket = UnixDatagram::unbound().map_err(|e| format!("Failed to open a syslog socket: {}", e))?;
+                SYSLOG_SOCKETS
+                    .iter()
+                    .find(|path| socket.connect(path).is_ok())
+                    .map(|_| Output::Syslog(socket))
+                    .ok_or_else(|| format!("No syslog daemon is listening (tried {})", SYSLOG_SOCKETS.join(", ")))
+            }
+            #[cfg(unix)]
+            Sink::Socket(path) => UnixStream::connect(path)
+                .map(Output::Socket)
+                .map_err(|e| format!("Failed to connect to {} for events: {}", path.display(), e)),
+            #[cfg(not(unix))]
+            Sink::Syslog | Sink::Socket(_) => Err("Events can only go to stderr or a file on this system".to_string()),
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stderr => writeln!(io::stderr(), "{}", line),
+            // Flushed each time, so a reader on a pipe sees every event as it happens
+            Output::Stdout => {
+                let mut stdout = io::stdout().lock();
+                writeln!(stdout, "{}", line).and_then(|_| stdout.flush())
+            }
+            Output::File(file) => writeln!(file, "{}", line),
+            #[cfg(unix)]
+            Output::Syslog(socket) => socket.send(syslog_message(line).as_bytes()).map(|_| ()),
+            #[cfg(unix)]
+            Output::Socket(stream) => writeln!(stream, "{}", line),
+        }
+    }
+}
+
+#[cfg(unix)]
+// RFC 3164 with the user facility at informational severity; the daemon adds the time
+fn syslog_message(line: &str) -> String {
+    format!("<14>mkramdisk[{}]: {}", std::process::id(), line)
+}
+
+/// Write events to `sinks` from now on; none turns them off. Fails if a sink can't be
+/// opened, so a typo doesn't go unnoticed until the events are missed.
+pub fn set_events(sinks: &[Sink]) -> Result<(), String> {
+    let outputs = sinks.iter().map(Output::open).collect::<Result<Vec<_>, _>>()?;
+    *OUTPUTS.lock().unwrap_or_else(|e| e.into_inner()) = outputs;
+    Ok(())
+}
+
+/// Emit one event to every sink. A sink that goes away (a closed socket, a full disk)
+/// is skipped rather than failing what is being reported on.
+pub fn emit(event: serde_json::Value) {
+    let event = crate::api::stamp(event);
+    crate::session::event(&event);
+    let line = event.to_string();
+    for output in OUTPUTS.lock().unwrap_or_else(|e| e.into_inner()).iter_mut() {
+        let _ = output.write(&line);
+    }
+}
+
+/// Progress of a long copy: a line redrawn on a terminal plus `progress` events, so a
+/// multi-GB seed doesn't look like a hang.
+pub struct Progress {
+    operation: &'static str,
+    total_files: u64,
+    total_bytes: u64,
+    started: Instant,
+    last: Option<Instant>,
+    draw: bool,
+    hidden: bool,
+}
+
+/// A snapshot of a copy's progress, with rates and ETA derived from the elapsed time.
+#[derive(Debug, PartialEq)]
+struct Rates {
+    files_per_sec: f64,
+    bytes_per_sec: f64,
+    eta: Option<Duration>,
+}
+
+impl Progress {
+    /// Track copying `total_files` files of `total_bytes` bytes.
+    pub fn new(operation: &'static str, total_files: u64, total_bytes: u64) -> Self {
+        Self {
+            operation,
+            total_files,
+            total_bytes,
+            started: Instant::now(),
+            last: None,
+            draw: io::stderr().is_terminal(),
+            hidden: false,
+        }
+    }
+
+    /// Progress that is never drawn or emitted, for copies internal to another operation.
+    pub fn hidden() -> Self {
+        Self { draw: false, hidden: true, ..Self::new("copy", 0, 0) }
+    }
+
+    /// Record `files` files and `bytes` bytes copied so far.
+    pub fn update(&mut self, files: u64, bytes: u64) {
+        let now = Instant::now();
+        if self.last.is_some_and(|last| now - last < INTERVAL) {
+            return;
+        }
+        self.last = Some(now);
+        self.report("progress", files, bytes);
+    }
+
+    /// Report the final counts and end the progress line.
+    pub fn finish(&mut self, files: u64, bytes: u64) {
+        self.report("progress_done", files, bytes);
+        if self.draw {
+            eprintln!();
+        }
+    }
+
+    fn report(&self, event: &str, files: u64, bytes: u64) {
+        if self.hidden {
+            return;
+        }
+        let rates = rates(self.started.elapsed(), files, bytes, self.total_bytes);
+        if self.draw {
+            let eta = rates.eta.map(|eta| format!(", ETA {}s", eta.as_secs())).unwrap_or_default();
+            eprint!(
+                "\r\x1b[K{}: {}/{} files, {:.1}/{:.1} MB, {:.0} files/s, {:.1} MB/s{}",
+                self.operation,
+                files,
+                self.total_files,
+                bytes as f64 / MB,
+                self.total_bytes as f64 / MB,
+                rates.files_per_sec,
+                rates.bytes_per_sec / MB,
+                eta
+            );
+            let _ = io::stderr().flush();
+        }
+        emit(serde_json::json!({
+            "event": event,
+            "operation": self.operation,
+            "files": files,
+            "files_total": self.total_files,
+            "bytes": bytes,
+            "bytes_total": self.total_bytes,
+            "files_per_sec": rates.files_per_sec,
+            "bytes_per_sec": rates.bytes_per_sec,
+            "eta_secs": rates.eta.map(|eta| eta.as_secs()),
+        }));
+    }
+}
+
+fn rates(elapsed: Duration, files: u64, bytes: u64, total_bytes: u64) -> Rates {
+    let seconds = elapsed.as_secs_f64();
+    if seconds == 0.0 {
+        return Rates { files_per_sec: 0.0, bytes_per_sec: 0.0, eta: None };
+    }
+    let bytes_per_sec = bytes as f64 / seconds;
+    Rates {
+        files_per_sec: files as f64 / seconds,
+        bytes_per_sec,
+        eta: (bytes_per_sec > 0.0)
+            .then(|| Duration::from_secs_f64(total_bytes.saturating_sub(bytes) as f64 / bytes_per_sec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sink() {
+        assert_eq!(Sink::parse("stderr").unwrap(), Sink::Stderr);
+        assert_eq!(Sink::parse("stdout").unwrap(), Sink::Stdout);
+        assert_eq!(Sink::parse("syslog").unwrap(), Sink::Syslog);
+        assert_eq!(Sink::parse("file:/tmp/events.ndjson").unwrap(), Sink::File(PathBuf::from("/tmp/events.ndjson")));
+        assert_eq!(Sink::parse("unix:/run/collector.sock").unwrap(), Sink::Socket(PathBuf::from("/run/collector.sock")));
+        assert!(Sink::parse("file:").is_err());
+        assert!(Sink::parse("udp:localhost:514").is_err());
+        #[cfg(unix)]
+        assert!(syslog_message("{}").starts_with("<14>mkramdisk["));
+    }
+
+    #[test]
+    fn test_rates() {
+        let rates = rates(Duration::from_secs(2), 10, 200 << 20, 1000 << 20);
+        assert_eq!(rates.files_per_sec, 5.0);
+        assert_eq!(rates.bytes_per_sec, (100 << 20) as f64);
+        assert_eq!(rates.eta, Some(Duration::from_secs(8)));
+        assert_eq!(super::rates(Duration::ZERO, 0, 0, 100).eta, None);
+    }
+}
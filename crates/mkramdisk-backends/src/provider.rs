@@ -0,0 +1,2258 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::partitions::{self, Partition, Scheme};
+use crate::progress::Progress;
+use crate::{copier, remote, runner, FAT_LABEL_MAX};
+
+/// Ways of mounting a formatted device when formatting didn't leave it mounted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MountStrategy {
+    DiskutilMount,
+    HdiutilMountvol,
+}
+
+impl std::fmt::Display for MountStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MountStrategy::DiskutilMount => write!(f, "diskutil mount"),
+            MountStrategy::HdiutilMountvol => write!(f, "hdiutil mountvol"),
+        }
+    }
+}
+
+/// A command the user can run later, e.g. to unmount or eject the disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Action {
+    /// Stable identifier for scripts and GUIs, e.g. "unmount" or "eject".
+    pub id: &'static str,
+    pub label: &'static str,
+    pub argv: Vec<String>,
+}
+
+impl Action {
+    fn new(id: &'static str, label: &'static str, argv: &[&str]) -> Self {
+        Self { id, label, argv: argv.iter().map(|arg| arg.to_string()).collect() }
+    }
+
+    /// The command line, quoted for pasting into a shell.
+    pub fn command(&self) -> String {
+        self.argv.iter().map(|arg| remote::shell_quote(arg)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// Names accepted by `--backend`.
+pub const BACKENDS: &[&str] = &["ram", "file", "dir", "tmpfs", "zram", "md", "imdisk", "mock"];
+
+/// The backends that format a block device of the size asked for, which has to be big
+/// enough for its filesystem; the others have no filesystem of their own to fit.
+pub const BLOCK_BACKENDS: &[&str] = &["ram", "file", "zram", "md", "imdisk"];
+
+/// The backends that can expose a device read-only at a second node (`export_readonly`).
+pub const READONLY_EXPORT_BACKENDS: &[&str] = &["zram", "mock"];
+
+/// The backend to use when none is given: hdiutil RAM disks on macOS, tmpfs on Linux,
+/// md on FreeBSD, ImDisk on Windows.
+pub fn default_backend() -> &'static str {
+    match env::consts::OS {
+        "linux" => "tmpfs",
+        "freebsd" => "md",
+        "windows" => "imdisk",
+        _ => "ram",
+    }
+}
+
+/// Something that can hand out a blank block device (or a stand-in for one),
+/// put a filesystem on it and tear it down again.
+pub trait DeviceProvider {
+    /// Attach a new blank device of `sectors` 512-byte sectors and return its path.
+    fn attach(&self, sectors: u64) -> Result<String, String>;
+
+    /// Create a filesystem named `name` on the device and mount it.
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String>;
+
+    /// Create an encrypted APFS volume named `name` on the device, unlocked with
+    /// `passphrase`, and mount it.
+    fn format_encrypted(&self, _device: &str, _diskutil_format: &str, _name: &str, _passphrase: &str, _verbose: bool) -> Result<(), String> {
+        Err("Encrypted volumes are not supported by this backend".to_string())
+    }
+
+    /// Lay out a partition map on the device, then format and mount each partition.
+    fn partition(&self, _device: &str, _scheme: Scheme, _partitions: &[Partition], _verbose: bool) -> Result<(), String> {
+        Err("Partitioning is not supported by this backend".to_string())
+    }
+
+    /// Size in bytes of a disk image's contents, for sizing a device to hold it.
+    fn image_bytes(&self, image: &Path) -> Result<u64, String> {
+        hdiutil_image_bytes(image)
+    }
+
+    /// Copy a disk image or volume onto the device block for block and mount it. Its
+    /// volume keeps the name it had in the source until renamed.
+    fn restore(&self, _device: &str, _source: &Path, _verbose: bool) -> Result<(), String> {
+        Err("Restoring a disk image is not supported by this backend".to_string())
+    }
+
+    /// Mount the EFI system partition diskutil put on the GPT disk `device`, returning
+    /// where.
+    fn mount_esp(&self, _device: &str) -> Result<PathBuf, String> {
+        Err("EFI system partitions are not supported by this backend".to_string())
+    }
+
+    /// Rename the volume on `device` mounted at `mount_point`, returning where it is mounted now.
+    fn rename(&self, _device: &str, _mount_point: &Path, _name: &str) -> Result<PathBuf, String> {
+        Err("Renaming volumes is not supported by this backend".to_string())
+    }
+
+    /// The diskutil personality of the volume mounted at `mount_point`.
+    fn personality(&self, _mount_point: &Path) -> Option<String> {
+        None
+    }
+
+    /// Details of the disk mounted at `mount_point`, for `mkramdisk info`.
+    fn info(&self, _mount_point: &Path) -> Result<DiskInfo, String> {
+        Err("Disk details are not available from this backend".to_string())
+    }
+
+    /// The device holding a volume named `name`, whether or not it is mounted.
+    fn find_device(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// The device holding the volume mounted at `mount_point`, as `attach` returned it.
+    fn device_at(&self, mount_point: &Path) -> Option<String> {
+        let disks = self.list().ok()?;
+        disks.into_iter().find(|disk| disk.mount_point.as_deref() == Some(mount_point)).map(|disk| disk.device)
+    }
+
+    /// Something telling the disk attached as `device` apart from any later one given
+    /// the same device once it is gone, such as its volume's UUID; None if the backend
+    /// has nothing of the sort for it.
+    fn identity(&self, _device: &str) -> Option<String> {
+        None
+    }
+
+    /// The disks of this backend that are attached now, for `mkramdisk list`.
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        Err("Listing disks is not supported by this backend".to_string())
+    }
+
+    /// Where a volume named `name` ends up mounted.
+    fn mount_point(&self, name: &str) -> PathBuf;
+
+    /// Detach the device and release its memory or backing storage.
+    fn detach(&self, device: &str) -> Result<(), String>;
+
+    /// Tear down the disk mounted at `mount_point` (or the device itself): unmount it and
+    /// release its device. `force` unmounts even with files open.
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String>;
+
+    /// Commands the user can run later to tear the disk down, built from its current state.
+    /// `mount_point` is None for a raw device with no filesystem.
+    fn actions(&self, device: &str, mount_point: Option<&Path>) -> Vec<Action>;
+
+    /// Find where the volume on `device` actually got mounted, if not where expected.
+    fn locate_mount_point(&self, _device: &str, _name: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Fallback strategies to try, in order, if the volume doesn't show up after formatting.
+    fn mount_strategies(&self) -> &'static [MountStrategy] {
+        &[]
+    }
+
+    fn remount(&self, _device: &str, strategy: MountStrategy) -> Result<(), String> {
+        Err(format!("{} is not supported by this backend", strategy))
+    }
+
+    /// Apply extra mount options (e.g. noatime) to the volume mounted at `mount_point`.
+    fn set_mount_options(&self, _mount_point: &Path, _options: &[String]) -> Result<(), String> {
+        Err("This backend cannot change mount options".to_string())
+    }
+
+    /// Expose `device` read-only at a second device node, returning the node. It is
+    /// removed along with the disk.
+    fn export_readonly(&self, _device: &str) -> Result<String, String> {
+        Err("Read-only exports are not supported by this backend (the zram backend has them)".to_string())
+    }
+
+    /// Attach the disk image `source` copy-on-write and mount it, its changes going to the
+    /// `shadow` file so the image itself is never written. Returns the device.
+    fn attach_overlay(&self, _source: &Path, _shadow: &Path) -> Result<String, String> {
+        Err("Copy-on-write overlays are not supported by this backend (the ram backend has them)".to_string())
+    }
+
+    /// Human-readable details about the device's current state, for diagnosing failures.
+    fn describe(&self, _device: &str) -> Option<String> {
+        None
+    }
+}
+
+/// What is known about a mounted disk; fields a backend can't tell are None or empty.
+#[derive(Debug, Default, PartialEq)]
+pub struct DiskInfo {
+    pub device: String,
+    pub sectors: Option<u64>,
+    pub filesystem: Option<String>,
+    pub mount_options: Vec<String>,
+    pub uuid: Option<String>,
+    pub total_bytes: Option<u64>,
+    pub free_bytes: Option<u64>,
+}
+
+/// An attached disk, as `list` finds it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedDisk {
+    pub device: String,
+    /// None for a device attached with nothing mounted.
+    pub mount_point: Option<PathBuf>,
+}
+
+impl ListedDisk {
+    /// The volume's name, which is what its mount point is named after.
+    pub fn name(&self) -> Option<String> {
+        self.mount_point.as_deref()?.file_name().map(|name| name.to_string_lossy().into_owned())
+    }
+}
+
+pub fn select_provider(backend: &str, name: &str) -> Result<Box<dyn DeviceProvider>, String> {
+    match backend {
+        "ram" => Ok(Box::new(RamProvider)),
+        "file" => Ok(Box::new(FileProvider { image: file_image(name) })),
+        "dir" => Ok(Box::new(DirProvider { root: dir_backend_root() })),
+        "tmpfs" => Ok(Box::new(TmpfsProvider { name: name.to_string() })),
+        "zram" => Ok(Box::new(ZramProvider)),
+        "md" => Ok(Box::new(MdProvider)),
+        "imdisk" => Ok(Box::new(ImDiskProvider { name: name.to_string() })),
+        "mock" => Ok(Box::new(MockProvider::from_env())),
+        _ => Err(format!(
+            "Unsupported backend: {}\nSupported backends: {}",
+            backend,
+            BACKENDS.join(", ")
+        )),
+    }
+}
+
+fn hdiutil_attach(args: &[&str]) -> Result<String, String> {
+    let output = runner::output(Command::new("hdiutil")
+        .arg("attach")
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped()))
+        .map_err(|e| format!("Failed to execute hdiutil: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to create RAM disk: {}", stderr.trim()));
+    }
+
+    let device = str::from_utf8(&output.stdout)
+        .map_err(|_| "Invalid UTF-8 in hdiutil output")?
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_string();
+
+    if device.is_empty() {
+        return Err("No device returned by hdiutil".to_string());
+    }
+    Ok(device)
+}
+
+// hdiutil detach also accepts a mount point, ejecting the device behind it
+fn hdiutil_detach_mount_point(mount_point: &Path, force: bool) -> Result<(), String> {
+    let output = runner::output(Command::new("hdiutil").arg("detach").args(force.then_some("-force")).arg(mount_point))
+        .map_err(|e| format!("Failed to execute hdiutil: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to detach {}: {}", mount_point.display(), stderr.trim()));
+    }
+    Ok(())
+}
+
+fn hdiutil_detach(device: &str) -> Result<(), String> {
+    let status = runner::status(Command::new("hdiutil")
+        .args(["detach", device])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null()))
+        .map_err(|e| format!("Failed to execute hdiutil: {}", e))?;
+
+    if !status.success() {
+        return Err(format!("hdiutil detach {} failed", device));
+    }
+    Ok(())
+}
+
+// Format using diskutil erasevolume (the proper macOS way); it formats AND mounts.
+fn diskutil_erasevolume(device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String> {
+    diskutil_format_command(&["erasevolume", diskutil_format, name, device], verbose)
+}
+
+// erasevolume has no way to encrypt, so make the container and add an encrypted volume
+// to it. The passphrase goes on stdin rather than the command line, where ps could see it.
+fn diskutil_encrypted_volume(device: &str, diskutil_format: &str, name: &str, passphrase: &str, verbose: bool) -> Result<(), String> {
+    let container = runner::output(Command::new("diskutil").args(["apfs", "createContainer", device]))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    let stdout = String::from_utf8_lossy(&container.stdout);
+    if verbose {
+        eprint!("{}", stdout);
+    }
+    if !container.status.success() {
+        return Err(format!("Failed to create an APFS container on {}: {}", device, String::from_utf8_lossy(&container.stderr).trim()));
+    }
+    let container = apfs_operation_disk(&stdout).ok_or_else(|| format!("diskutil did not say which container it created on {}", device))?;
+
+    let added = runner::output_with_input(
+        Command::new("diskutil").args(["apfs", "addVolume", container, diskutil_format, name, "-stdinpassphrase"]),
+        format!("{}\n", passphrase).as_bytes(),
+    )
+    .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    if verbose {
+        eprint!("{}", String::from_utf8_lossy(&added.stdout));
+    }
+    if !added.status.success() {
+        return Err(format!("Failed to add an encrypted volume: {}", String::from_utf8_lossy(&added.stderr).trim()));
+    }
+    Ok(())
+}
+
+// "Disk from APFS operation: disk5", the last line of a successful createContainer
+fn apfs_operation_disk(output: &str) -> Option<&str> {
+    output.lines().find_map(|line| line.trim().strip_prefix("Disk from APFS operation:")).map(str::trim)
+}
+
+fn diskutil_partition_disk(device: &str, scheme: Scheme, partitions: &[Partition], verbose: bool) -> Result<(), String> {
+    let args = partitions::diskutil_args(device, scheme, partitions);
+    diskutil_format_command(&args.iter().map(String::as_str).collect::<Vec<_>>(), verbose)
+}
+
+#[derive(Debug, Deserialize)]
+struct DiskList {
+    #[serde(rename = "AllDisksAndPartitions")]
+    disks: Vec<ListedWholeDisk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListedWholeDisk {
+    #[serde(default, rename = "Partitions")]
+    partitions: Vec<ListedPartition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListedPartition {
+    #[serde(rename = "Content")]
+    content: Option<String>,
+    #[serde(rename = "DeviceIdentifier")]
+    device: String,
+}
+
+// The partition of type EFI in `diskutil list -plist <device>`, e.g. "disk5s1"
+fn parse_esp(plist: &[u8]) -> Result<Option<String>, String> {
+    let list: DiskList = plist::from_bytes(plist).map_err(|e| format!("Unexpected diskutil list output: {}", e))?;
+    Ok(list
+        .disks
+        .into_iter()
+        .flat_map(|disk| disk.partitions)
+        .find(|partition| partition.content.as_deref() == Some("EFI"))
+        .map(|partition| partition.device))
+}
+
+// diskutil leaves the EFI system partition unmounted, as the system does
+fn diskutil_mount_esp(device: &str) -> Result<PathBuf, String> {
+    let output = runner::output(Command::new("diskutil").args(["list", "-plist", device]))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to list the partitions of {}: {}", device, stderr.trim()));
+    }
+    let esp = parse_esp(&output.stdout)?
+        .ok_or_else(|| format!("diskutil put no EFI system partition on {}; is the disk too small for one?", device))?;
+    let esp = format!("/dev/{}", esp);
+    hdiutil_remount(&esp, MountStrategy::DiskutilMount)?;
+    diskutil_mount_point(&esp).ok_or_else(|| format!("The EFI system partition {} did not mount", esp))
+}
+
+fn diskutil_format_command(args: &[&str], verbose: bool) -> Result<(), String> {
+    let format_output = runner::output(Command::new("diskutil")
+        .args(args)
+        .stdout(if verbose { Stdio::inherit() } else { Stdio::null() })
+        .stderr(if verbose { Stdio::inherit() } else { Stdio::piped() }))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+
+    if !format_output.status.success() {
+        let stderr = if verbose {
+            "Check verbose output above for details".to_string()
+        } else {
+            str::from_utf8(&format_output.stderr)
+                .unwrap_or("Unknown error")
+                .trim()
+                .to_string()
+        };
+        return Err(format!("Failed to format RAM disk: {}", stderr));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageInfo {
+    #[serde(rename = "Size Information")]
+    size: ImageSize,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageSize {
+    #[serde(rename = "Total Bytes")]
+    total_bytes: u64,
+}
+
+fn hdiutil_image_bytes(image: &Path) -> Result<u64, String> {
+    let output = runner::output(Command::new("hdiutil").args(["imageinfo", "-plist"]).arg(image))
+        .map_err(|e| format!("Failed to execute hdiutil: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to read image {}: {}", image.display(), stderr.trim()));
+    }
+    parse_image_info(&output.stdout)
+}
+
+fn parse_image_info(plist: &[u8]) -> Result<u64, String> {
+    let info: ImageInfo = plist::from_bytes(plist)
+        .map_err(|e| format!("Unexpected hdiutil imageinfo output: {}", e))?;
+    Ok(info.size.total_bytes)
+}
+
+// asr is the fast path, but it refuses images that weren't scanned for restore
+// (asr imagescan), so fall back to copying the attached image's raw device.
+fn restore_image(device: &str, source: &Path, verbose: bool) -> Result<(), String> {
+    if let Err(e) = asr_restore(device, source, verbose) {
+        if source.is_dir() {
+            return Err(e);
+        }
+        block_copy(device, source).map_err(|copy_error| format!("{}\nBlock copy also failed: {}", e, copy_error))?;
+    }
+    let output = runner::output(Command::new("diskutil").args(["mountDisk", device]))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to mount {}: {}", device, stderr.trim()));
+    }
+    Ok(())
+}
+
+fn asr_restore(device: &str, source: &Path, verbose: bool) -> Result<(), String> {
+    let output = runner::output(Command::new("asr")
+        .args(["restore", "--source"])
+        .arg(source)
+        .args(["--target", device, "--erase", "--noprompt"])
+        .stdout(if verbose { Stdio::inherit() } else { Stdio::null() })
+        .stderr(Stdio::piped()))
+        .map_err(|e| format!("Failed to execute asr: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("asr restore failed: {}", stderr.trim()));
+    }
+    Ok(())
+}
+
+fn block_copy(device: &str, image: &Path) -> Result<(), String> {
+    let source = hdiutil_attach(&["-nomount", "-readonly", &image.to_string_lossy()])
+        .map_err(|e| format!("Failed to attach image {}: {}", image.display(), e))?;
+    let output = runner::output(Command::new("dd")
+        .arg(format!("if={}", raw_device(&source)))
+        .arg(format!("of={}", raw_device(device)))
+        .arg("bs=1m"));
+    let _ = hdiutil_detach(&source);
+
+    let output = output.map_err(|e| format!("Failed to execute dd: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to copy image {}: {}", image.display(), stderr.trim()));
+    }
+    Ok(())
+}
+
+fn diskutil_rename(mount_point: &Path, name: &str) -> Result<PathBuf, String> {
+    let output = runner::output(Command::new("diskutil").arg("rename").arg(mount_point).arg(name))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to rename {}: {}", mount_point.display(), stderr.trim()));
+    }
+    Ok(volumes_mount_point(name))
+}
+
+// The character device (/dev/rdiskN) skips the buffer cache, which is much faster for dd
+fn raw_device(device: &str) -> String {
+    device.replacen("/dev/disk", "/dev/rdisk", 1)
+}
+
+fn diskutil_info(device: &str) -> Option<String> {
+    let output = runner::output(Command::new("diskutil").args(["info", device])).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    str::from_utf8(&output.stdout).ok().map(|s| s.to_string())
+}
+
+fn diskutil_info_field(target: &str, field: &str) -> Option<String> {
+    diskutil_info(target)?
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(field)?.strip_prefix(':'))
+        .map(|value| value.trim().to_string())
+        .find(|value| !value.is_empty())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VolumeInfo {
+    device_node: String,
+    parent_whole_disk: Option<String>,
+    total_size: Option<u64>,
+    free_space: Option<u64>,
+    #[serde(rename = "APFSContainerFree")]
+    apfs_container_free: Option<u64>,
+    filesystem_user_visible_name: Option<String>,
+    volume_name: Option<String>,
+    #[serde(rename = "VolumeUUID")]
+    volume_uuid: Option<String>,
+    #[serde(default, rename = "APFSPhysicalStores")]
+    apfs_physical_stores: Vec<PhysicalStore>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PhysicalStore {
+    #[serde(rename = "APFSPhysicalStore")]
+    device: String,
+}
+
+fn diskutil_info_plist(target: &str) -> Result<VolumeInfo, String> {
+    let output = runner::output(Command::new("diskutil").args(["info", "-plist", target]))
+        .map_err(|e| format!("Failed to execute diskutil: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to get info for {}: {}", target, stderr.trim()));
+    }
+    parse_volume_info(&output.stdout)
+}
+
+fn parse_volume_info(plist: &[u8]) -> Result<VolumeInfo, String> {
+    plist::from_bytes(plist).map_err(|e| format!("Unexpected diskutil info output: {}", e))
+}
+
+// The volume's own sizes describe the filesystem; the sector count is the whole
+// device's, which for APFS is the container's physical store.
+fn diskutil_disk_info(mount_point: &Path) -> Result<DiskInfo, String> {
+    let volume = diskutil_info_plist(&mount_point.to_string_lossy())?;
+    let sectors = volume
+        .parent_whole_disk
+        .as_ref()
+        .and_then(|disk| diskutil_info_plist(&format!("/dev/{}", disk)).ok())
+        .and_then(|disk| disk.total_size)
+        .or(volume.total_size)
+        .map(|bytes| bytes / 512);
+    Ok(DiskInfo {
+        device: volume.device_node,
+        sectors,
+        filesystem: volume.filesystem_user_visible_name,
+        mount_options: mount_options(mount_point),
+        uuid: volume.volume_uuid,
+        total_bytes: volume.total_size,
+        free_bytes: volume.free_space.or(volume.apfs_container_free),
+    })
+}
+
+// An APFS volume's whole disk is the container synthesized from the device hdiutil
+// attached, so go through the container's physical store to get back to it.
+fn diskutil_whole_disk(target: &str) -> Option<String> {
+    let volume = diskutil_info_plist(target).ok()?;
+    let whole_disk = match volume.apfs_physical_stores.first() {
+        Some(store) => diskutil_info_plist(&format!("/dev/{}", store.device)).ok()?.parent_whole_disk,
+        None => volume.parent_whole_disk,
+    };
+    whole_disk.map(|disk| format!("/dev/{}", disk))
+}
+
+#[derive(Debug, Deserialize)]
+struct HdiutilInfo {
+    images: Vec<AttachedImage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AttachedImage {
+    image_path: String,
+    system_entities: Vec<SystemEntity>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SystemEntity {
+    dev_entry: String,
+    mount_point: Option<String>,
+}
+
+fn hdiutil_images() -> Vec<AttachedImage> {
+    runner::output(Command::new("hdiutil").args(["info", "-plist"]))
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| parse_hdiutil_info(&output.stdout).ok())
+        .unwrap_or_default()
+}
+
+fn parse_hdiutil_info(plist: &[u8]) -> Result<Vec<AttachedImage>, String> {
+    let info: HdiutilInfo = plist::from_bytes(plist).map_err(|e| format!("Unexpected hdiutil info output: {}", e))?;
+    Ok(info.images)
+}
+
+// hdiutil lists the devices it attached, mounted or not, but an APFS volume lives on
+// the container synthesized from one, so follow that as slice_mount_point does.
+fn hdiutil_find_device(name: &str, attached_from: impl Fn(&str) -> bool) -> Option<String> {
+    let names_volume = |target: &str| {
+        diskutil_info_plist(target).ok().and_then(|info| info.volume_name).as_deref() == Some(name)
+    };
+    hdiutil_images()
+        .into_iter()
+        .filter(|image| attached_from(&image.image_path))
+        .find_map(|image| {
+            let holds_volume = image.system_entities.iter().any(|entity| {
+                names_volume(&entity.dev_entry)
+                    || diskutil_info_field(&entity.dev_entry, "APFS Container")
+                        .is_some_and(|container| names_volume(&format!("/dev/{}s1", container)))
+            });
+            holds_volume.then(|| image.system_entities.first().map(|entity| entity.dev_entry.clone()))?
+        })
+}
+
+/// The images hdiutil has attached from `attached_from`, one per mounted volume (a
+/// partitioned disk has several), or one for the device if nothing is mounted.
+fn hdiutil_list(attached_from: impl Fn(&str) -> bool) -> Vec<ListedDisk> {
+    hdiutil_images()
+        .into_iter()
+        .filter(|image| attached_from(&image.image_path))
+        .filter_map(|image| {
+            let device = image.system_entities.first()?.dev_entry.clone();
+            let mounted: Vec<ListedDisk> = image
+                .system_entities
+                .iter()
+                .filter_map(|entity| entity.mount_point.as_ref())
+                .map(|mount_point| ListedDisk { device: device.clone(), mount_point: Some(PathBuf::from(mount_point)) })
+                .collect();
+            Some(if mounted.is_empty() { vec![ListedDisk { device, mount_point: None }] } else { mounted })
+        })
+        .flatten()
+        .collect()
+}
+
+/// The subdirectories of `root`, each the "device" and mount point of a disk.
+fn list_dirs(root: &Path) -> Vec<ListedDisk> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut disks: Vec<ListedDisk> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .map(|entry| ListedDisk { device: entry.path().to_string_lossy().into_owned(), mount_point: Some(entry.path()) })
+        .collect();
+    disks.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+    disks
+}
+
+/// What the mount table has mounted from devices `is_ours` picks.
+fn list_mounted(is_ours: impl Fn(&MountEntry) -> bool) -> Vec<ListedDisk> {
+    mount_table()
+        .into_iter()
+        .filter(|entry| is_ours(entry))
+        .map(|entry| ListedDisk { device: entry.device, mount_point: Some(entry.mount_point) })
+        .collect()
+}
+
+// diskutil doesn't report mount flags, so take them from mount(8)'s listing
+fn mount_options(mount_point: &Path) -> Vec<String> {
+    match runner::output(&mut Command::new("mount")) {
+        Ok(output) => parse_mount_options(&String::from_utf8_lossy(&output.stdout), mount_point),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Each line reads "/dev/disk5s1 on /Volumes/Scratch (apfs, local, nodev, nosuid)"
+fn parse_mount_options(output: &str, mount_point: &Path) -> Vec<String> {
+    let marker = format!(" on {} (", mount_point.display());
+    output
+        .lines()
+        .find_map(|line| line.split_once(&marker)?.1.strip_suffix(')'))
+        .map(|options| options.split(", ").map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn diskutil_mount_point(device: &str) -> Option<PathBuf> {
+    diskutil_info_field(device, "Mount Point").map(PathBuf::from)
+}
+
+// diskutil info reports nothing for the physical store of an APFS container, and
+// DiskArbitration appends " 1", " 2"... when /Volumes/<name> is taken, so look there too.
+fn locate_volumes_mount_point(device: &str, name: &str) -> Option<PathBuf> {
+    diskutil_mount_point(device)
+        .or_else(|| numbered_mount_point(Path::new("/Volumes"), device, name, |path| diskutil_whole_disk(&path.to_string_lossy())))
+        .or_else(|| slice_mount_point(device))
+}
+
+// "<name> 1" and so on in `volumes`, but only one whose volume is on `device`: the
+// others are volumes of the same name on other disks, such as the one that took
+// /Volumes/<name> first
+fn numbered_mount_point(volumes: &Path, device: &str, name: &str, whole_disk: impl Fn(&Path) -> Option<String>) -> Option<PathBuf> {
+    if name.is_empty() {
+        return None;
+    }
+    (1..10)
+        .map(|n| volumes.join(format!("{} {}", name, n)))
+        .filter(|path| path.is_dir())
+        .find(|path| whole_disk(path).as_deref() == Some(device))
+}
+
+// A restored image brings its own partition map and volume names, so look at the
+// device's slices, following an APFS physical store to its container's first volume.
+fn slice_mount_point(device: &str) -> Option<PathBuf> {
+    (1..=4).map(|n| format!("{}s{}", device, n)).find_map(|slice| {
+        diskutil_mount_point(&slice).or_else(|| {
+            let container = diskutil_info_field(&slice, "APFS Container")?;
+            diskutil_mount_point(&format!("/dev/{}s1", container))
+        })
+    })
+}
+
+const HDIUTIL_MOUNT_STRATEGIES: &[MountStrategy] = &[MountStrategy::DiskutilMount, MountStrategy::HdiutilMountvol];
+
+fn hdiutil_remount(device: &str, strategy: MountStrategy) -> Result<(), String> {
+    let (program, args) = match strategy {
+        MountStrategy::DiskutilMount => ("diskutil", ["mount", device]),
+        MountStrategy::HdiutilMountvol => ("hdiutil", ["mountvol", device]),
+    };
+    let output = runner::output(Command::new(program).args(args))
+        .map_err(|e| format!("Failed to execute {}: {}", program, e))?;
+
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("{} failed: {}", strategy, stderr.trim()));
+    }
+    Ok(())
+}
+
+/// A file a process has open, as lsof reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenFile {
+    pub command: String,
+    pub pid: u32,
+    pub path: String,
+}
+
+impl std::fmt::Display for OpenFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (pid {}): {}", self.command, self.pid, self.path)
+    }
+}
+
+/// The files other processes have open under `mount_point`, from lsof. lsof exits 1
+/// when it finds none, so only its not running at all is an error.
+pub fn open_files(mount_point: &Path) -> Result<Vec<OpenFile>, String> {
+    let output = runner::output(Command::new("lsof").args(["-F", "pcn", "--"]).arg(mount_point))
+        .map_err(|e| format!("Failed to execute lsof: {}", e))?;
+    let mut files = parse_lsof(&String::from_utf8_lossy(&output.stdout));
+    files.retain(|file| file.pid != std::process::id());
+    Ok(files)
+}
+
+/// The processes holding `files` open, each once, as "command (pid N)", and their pids.
+pub fn holders(files: &[OpenFile]) -> (Vec<String>, Vec<u32>) {
+    let mut pids: Vec<u32> = Vec::new();
+    let mut names = Vec::new();
+    for file in files {
+        if !pids.contains(&file.pid) {
+            pids.push(file.pid);
+            names.push(format!("{} (pid {})", file.command, file.pid));
+        }
+    }
+    (names, pids)
+}
+
+// lsof -F output has one field per line, tagged by its first character: p (pid) and
+// c (command) start a process, then each n (name) is one of its open files.
+fn parse_lsof(output: &str) -> Vec<OpenFile> {
+    let (mut pid, mut command) = (0, "");
+    let mut files = Vec::new();
+    for line in output.lines() {
+        let (tag, value) = line.split_at(line.len().min(1));
+        match tag {
+            "p" => pid = value.parse().unwrap_or_default(),
+            "c" => command = value,
+            "n" => files.push(OpenFile { command: command.to_string(), pid, path: value.to_string() }),
+            _ => {}
+        }
+    }
+    files
+}
+
+// The UUID of the first volume mounted from the image attached as `device`; a disk
+// with no volume mounted has none
+fn hdiutil_identity(device: &str) -> Option<String> {
+    hdiutil_list(|_| true)
+        .into_iter()
+        .filter(|disk| disk.device == device)
+        .find_map(|disk| diskutil_info_field(&disk.mount_point?.to_string_lossy(), "Volume UUID"))
+}
+
+fn volumes_mount_point(name: &str) -> PathBuf {
+    Path::new("/Volumes").join(name)
+}
+
+// Refer to the volume by UUID where possible: device numbers are reused as soon as
+// the disk goes away, and the mount point changes if the volume is renamed.
+fn hdiutil_actions(device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+    let Some(mount_point) = mount_point.map(Path::to_string_lossy) else {
+        return vec![Action::new("eject", "To eject", &["hdiutil", "detach", device])];
+    };
+    let volume = diskutil_info_field(&mount_point, "Volume UUID").unwrap_or_else(|| mount_point.to_string());
+    let mut actions = vec![Action::new("unmount", "To unmount", &["diskutil", "unmount", &volume])];
+    if volume == mount_point {
+        actions.push(Action::new("eject", "To eject", &["hdiutil", "detach", device]));
+    } else {
+        actions.push(Action::new("eject", "To eject", &["diskutil", "eject", &volume]));
+    }
+    actions
+}
+
+/// Memory-backed device from `hdiutil attach ram://<sectors>`.
+pub struct RamProvider;
+
+impl DeviceProvider for RamProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        hdiutil_attach(&["-nomount", &format!("ram://{}", sectors)])
+    }
+
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String> {
+        diskutil_erasevolume(device, diskutil_format, name, verbose)
+    }
+
+    fn format_encrypted(&self, device: &str, diskutil_format: &str, name: &str, passphrase: &str, verbose: bool) -> Result<(), String> {
+        diskutil_encrypted_volume(device, diskutil_format, name, passphrase, verbose)
+    }
+
+    fn partition(&self, device: &str, scheme: Scheme, partitions: &[Partition], verbose: bool) -> Result<(), String> {
+        diskutil_partition_disk(device, scheme, partitions, verbose)
+    }
+
+    fn mount_esp(&self, device: &str) -> Result<PathBuf, String> {
+        diskutil_mount_esp(device)
+    }
+
+    fn restore(&self, device: &str, source: &Path, verbose: bool) -> Result<(), String> {
+        restore_image(device, source, verbose)
+    }
+
+    fn rename(&self, _device: &str, mount_point: &Path, name: &str) -> Result<PathBuf, String> {
+        diskutil_rename(mount_point, name)
+    }
+
+    fn personality(&self, mount_point: &Path) -> Option<String> {
+        diskutil_info_field(&mount_point.to_string_lossy(), "File System Personality")
+    }
+
+    fn info(&self, mount_point: &Path) -> Result<DiskInfo, String> {
+        diskutil_disk_info(mount_point)
+    }
+
+    fn find_device(&self, name: &str) -> Option<String> {
+        hdiutil_find_device(name, |image| image.starts_with("ram://"))
+    }
+
+    fn device_at(&self, mount_point: &Path) -> Option<String> {
+        diskutil_whole_disk(&mount_point.to_string_lossy())
+    }
+
+    fn identity(&self, device: &str) -> Option<String> {
+        hdiutil_identity(device)
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        Ok(hdiutil_list(|image| image.starts_with("ram://")))
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        volumes_mount_point(name)
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        hdiutil_detach(device)
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        hdiutil_detach_mount_point(mount_point, force)
+    }
+
+    fn actions(&self, device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        hdiutil_actions(device, mount_point)
+    }
+
+    fn locate_mount_point(&self, device: &str, name: &str) -> Option<PathBuf> {
+        locate_volumes_mount_point(device, name)
+    }
+
+    fn mount_strategies(&self) -> &'static [MountStrategy] {
+        HDIUTIL_MOUNT_STRATEGIES
+    }
+
+    fn remount(&self, device: &str, strategy: MountStrategy) -> Result<(), String> {
+        hdiutil_remount(device, strategy)
+    }
+
+    fn describe(&self, device: &str) -> Option<String> {
+        diskutil_info(device)
+    }
+    fn set_mount_options(&self, mount_point: &Path, options: &[String]) -> Result<(), String> {
+        update_mount(mount_point, options)
+    }
+
+    fn attach_overlay(&self, source: &Path, shadow: &Path) -> Result<String, String> {
+        hdiutil_attach(&["-shadow", &shadow.to_string_lossy(), &source.to_string_lossy()])
+            .map_err(|e| e.replacen("Failed to create RAM disk", &format!("Failed to attach {}", source.display()), 1))
+    }
+}
+
+fn file_image(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("mkramdisk-{}.img", name))
+}
+
+/// Sparse raw image file attached as a loop-style device. Behaves like a RAM disk
+/// for testing, but is backed by storage rather than wired memory.
+pub struct FileProvider {
+    image: PathBuf,
+}
+
+impl DeviceProvider for FileProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        let file = fs::File::create(&self.image)
+            .map_err(|e| format!("Failed to create image {}: {}", self.image.display(), e))?;
+        file.set_len(sectors * 512)
+            .map_err(|e| format!("Failed to size image {}: {}", self.image.display(), e))?;
+
+        let image = self.image.to_string_lossy();
+        hdiutil_attach(&["-nomount", "-imagekey", "diskimage-class=CRawDiskImage", &image]).inspect_err(|_| {
+            let _ = fs::remove_file(&self.image);
+        })
+    }
+
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String> {
+        diskutil_erasevolume(device, diskutil_format, name, verbose)
+    }
+
+    fn format_encrypted(&self, device: &str, diskutil_format: &str, name: &str, passphrase: &str, verbose: bool) -> Result<(), String> {
+        diskutil_encrypted_volume(device, diskutil_format, name, passphrase, verbose)
+    }
+
+    fn partition(&self, device: &str, scheme: Scheme, partitions: &[Partition], verbose: bool) -> Result<(), String> {
+        diskutil_partition_disk(device, scheme, partitions, verbose)
+    }
+
+    fn mount_esp(&self, device: &str) -> Result<PathBuf, String> {
+        diskutil_mount_esp(device)
+    }
+
+    fn restore(&self, device: &str, source: &Path, verbose: bool) -> Result<(), String> {
+        restore_image(device, source, verbose)
+    }
+
+    // The image is named after the disk too, so it's found when the disk is torn down
+    fn rename(&self, _device: &str, mount_point: &Path, name: &str) -> Result<PathBuf, String> {
+        let renamed = diskutil_rename(mount_point, name)?;
+        fs::rename(&self.image, file_image(name))
+            .map_err(|e| format!("Failed to rename image {}: {}", self.image.display(), e))?;
+        Ok(renamed)
+    }
+
+    fn personality(&self, mount_point: &Path) -> Option<String> {
+        diskutil_info_field(&mount_point.to_string_lossy(), "File System Personality")
+    }
+
+    fn info(&self, mount_point: &Path) -> Result<DiskInfo, String> {
+        diskutil_disk_info(mount_point)
+    }
+
+    fn find_device(&self, name: &str) -> Option<String> {
+        hdiutil_find_device(name, |image| Path::new(image) == self.image)
+    }
+
+    fn device_at(&self, mount_point: &Path) -> Option<String> {
+        diskutil_whole_disk(&mount_point.to_string_lossy())
+    }
+
+    fn identity(&self, device: &str) -> Option<String> {
+        hdiutil_identity(device)
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        // Any image named as file_image names them, whichever disk this provider is for
+        Ok(hdiutil_list(|image| {
+            let image = Path::new(image);
+            image.parent() == Some(env::temp_dir().as_path())
+                && image.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("mkramdisk-") && name.ends_with(".img"))
+        }))
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        volumes_mount_point(name)
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        hdiutil_detach(device)?;
+        fs::remove_file(&self.image)
+            .map_err(|e| format!("Failed to remove image {}: {}", self.image.display(), e))
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        hdiutil_detach_mount_point(mount_point, force)?;
+        fs::remove_file(&self.image)
+            .map_err(|e| format!("Failed to remove image {}: {}", self.image.display(), e))
+    }
+
+    fn actions(&self, device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        let mut actions = hdiutil_actions(device, mount_point);
+        actions.push(Action::new("remove", "Then remove", &["rm", &self.image.to_string_lossy()]));
+        actions
+    }
+
+    fn locate_mount_point(&self, device: &str, name: &str) -> Option<PathBuf> {
+        locate_volumes_mount_point(device, name)
+    }
+
+    fn mount_strategies(&self) -> &'static [MountStrategy] {
+        HDIUTIL_MOUNT_STRATEGIES
+    }
+
+    fn remount(&self, device: &str, strategy: MountStrategy) -> Result<(), String> {
+        hdiutil_remount(device, strategy)
+    }
+
+    fn describe(&self, device: &str) -> Option<String> {
+        diskutil_info(device)
+    }
+    fn set_mount_options(&self, mount_point: &Path, options: &[String]) -> Result<(), String> {
+        update_mount(mount_point, options)
+    }
+}
+
+/// A plain directory standing in for a disk, on tmpfs where available (/dev/shm).
+/// Needs no hdiutil, so the whole pipeline can run on systems without it.
+pub struct DirProvider {
+    root: PathBuf,
+}
+
+fn dir_backend_root() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        shm.join("mkramdisk")
+    } else {
+        env::temp_dir().join("mkramdisk")
+    }
+}
+
+impl DeviceProvider for DirProvider {
+    fn attach(&self, _sectors: u64) -> Result<String, String> {
+        fs::create_dir_all(&self.root)
+            .map_err(|e| format!("Failed to create {}: {}", self.root.display(), e))?;
+        // The "device" is the shared root; the disk itself appears when formatted with a name
+        Ok(self.root.to_string_lossy().into_owned())
+    }
+
+    fn format(&self, device: &str, _diskutil_format: &str, name: &str, _verbose: bool) -> Result<(), String> {
+        let path = Path::new(device).join(name);
+        fs::create_dir(&path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        Ok(list_dirs(&self.root))
+    }
+
+    fn detach(&self, _device: &str) -> Result<(), String> {
+        // Nothing was attached; the named directory is removed with the volume
+        Ok(())
+    }
+
+    fn destroy(&self, mount_point: &Path, _force: bool) -> Result<(), String> {
+        fs::remove_dir_all(mount_point)
+            .map_err(|e| format!("Failed to remove {}: {}", mount_point.display(), e))
+    }
+
+    fn actions(&self, _device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        mount_point
+            .map(|mount_point| Action::new("remove", "To remove", &["rm", "-rf", &mount_point.to_string_lossy()]))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// A Linux tmpfs mounted at /mnt/<name>, sized with its size= option. There is no
+/// device: the "device" is the mount itself, mounted as soon as it is attached, and
+/// there is nothing to format. Mounting needs root, so run it with sudo; the disk then
+/// belongs to the user who ran sudo.
+pub struct TmpfsProvider {
+    name: String,
+}
+
+// Where the Linux backends (tmpfs, zram) mount their disks
+fn mnt_mount_point(name: &str) -> PathBuf {
+    Path::new("/mnt").join(name)
+}
+
+/// The `-o` options for a tmpfs of `bytes`, owned by the user behind sudo if there is one.
+fn tmpfs_options(bytes: u64, sudo_ids: Option<(String, String)>) -> String {
+    let mut options = format!("size={},mode=0755", bytes);
+    if let Some((uid, gid)) = sudo_ids {
+        options.push_str(&format!(",uid={},gid={}", uid, gid));
+    }
+    options
+}
+
+fn sudo_ids() -> Option<(String, String)> {
+    env::var("SUDO_UID").ok().zip(env::var("SUDO_GID").ok())
+}
+
+fn umount(mount_point: &Path, force: bool) -> Result<(), String> {
+    // A lazy unmount detaches it now and frees it once the last open file is closed
+    let output = runner::output(Command::new("umount").args(force.then_some("-l")).arg(mount_point))
+        .map_err(|e| format!("Failed to execute umount: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to unmount {}: {}", mount_point.display(), stderr.trim()));
+    }
+    fs::remove_dir(mount_point).map_err(|e| format!("Failed to remove {}: {}", mount_point.display(), e))
+}
+
+// Linux takes the options on a remount; macOS and FreeBSD update the mount in place
+fn update_mount(mount_point: &Path, options: &[String]) -> Result<(), String> {
+    let options = options.join(",");
+    let mut mount = Command::new("mount");
+    if cfg!(target_os = "linux") {
+        mount.args(["-o", &format!("remount,{}", options)]);
+    } else {
+        mount.args(["-u", "-o", &options]);
+    }
+    let output = runner::output(mount.arg(mount_point)).map_err(|e| format!("Failed to execute mount: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("Failed to apply mount options {} to {}: {}", options, mount_point.display(), stderr.trim()));
+    }
+    Ok(())
+}
+
+/// One line of the system's mount table.
+#[derive(Debug, Clone, PartialEq)]
+struct MountEntry {
+    device: String,
+    mount_point: PathBuf,
+    filesystem: String,
+    options: Vec<String>,
+}
+
+/// Parse a mount table in fstab format, as /proc/mounts on Linux and `mount -p` on
+/// FreeBSD give it, where spaces and tabs in paths are written as \040 and \011.
+fn parse_mount_table(table: &str) -> Vec<MountEntry> {
+    let unescape = |field: &str| field.replace("\\040", " ").replace("\\011", "\t").replace("\\134", "\\");
+    table
+        .lines()
+        .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [device, mount_point, filesystem, options, ..] => Some(MountEntry {
+                device: unescape(device),
+                mount_point: PathBuf::from(unescape(mount_point)),
+                filesystem: filesystem.to_string(),
+                options: options.split(',').map(str::to_string).collect(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn mount_table() -> Vec<MountEntry> {
+    if let Ok(table) = fs::read_to_string("/proc/mounts") {
+        return parse_mount_table(&table);
+    }
+    runner::output(Command::new("mount").arg("-p"))
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| parse_mount_table(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or_default()
+}
+
+// The last mount wins, as it hides any earlier one at the same place
+fn mounted_at(mount_point: &Path) -> Option<MountEntry> {
+    mount_table().into_iter().rev().find(|entry| entry.mount_point == mount_point)
+}
+
+fn mounted_from(device: &str) -> Option<MountEntry> {
+    mount_table().into_iter().find(|entry| entry.device == device)
+}
+
+/// Details of a disk mounted from a device under /dev, from the mount table.
+fn mount_table_info(mount_point: &Path) -> Result<DiskInfo, String> {
+    let entry = mounted_at(mount_point).ok_or_else(|| format!("Nothing is mounted at {}", mount_point.display()))?;
+    Ok(DiskInfo {
+        device: entry.device,
+        filesystem: Some(entry.filesystem),
+        mount_options: entry.options,
+        ..DiskInfo::default()
+    })
+}
+
+/// Unmount whatever of `device` is still mounted, as rolling back after a failed
+/// format may find it.
+fn unmount_device(device: &str) -> Result<(), String> {
+    match mounted_from(device) {
+        Some(entry) => umount(&entry.mount_point, false),
+        None => Ok(()),
+    }
+}
+
+impl DeviceProvider for TmpfsProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        let mount_point = mnt_mount_point(&self.name);
+        fs::create_dir_all(&mount_point)
+            .map_err(|e| format!("Failed to create {}: {}", mount_point.display(), e))?;
+        let mounted = runner::output(Command::new("mount")
+            .args(["-t", "tmpfs", "-o", &tmpfs_options(sectors * 512, sudo_ids()), &self.name])
+            .arg(&mount_point))
+            .map_err(|e| format!("Failed to execute mount: {}", e))
+            .and_then(|output| {
+                if output.status.success() {
+                    return Ok(());
+                }
+                let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+                Err(format!("Failed to mount tmpfs: {}", stderr.trim()))
+            });
+        if let Err(e) = mounted {
+            let _ = fs::remove_dir(&mount_point);
+            return Err(e);
+        }
+        Ok(mount_point.to_string_lossy().into_owned())
+    }
+
+    fn format(&self, _device: &str, _diskutil_format: &str, name: &str, _verbose: bool) -> Result<(), String> {
+        // tmpfs has no filesystem to choose; it was mounted under its name when attached
+        if name != self.name {
+            return Err(format!("A tmpfs is mounted as '{}' and cannot be renamed to '{}'", self.name, name));
+        }
+        Ok(())
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        mnt_mount_point(name)
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        // The mount itself is the device, as attach returns it
+        let mut disks = list_mounted(|entry| entry.filesystem == "tmpfs" && entry.mount_point.parent() == Some(Path::new("/mnt")));
+        for disk in &mut disks {
+            disk.device = disk.mount_point.as_deref().unwrap_or(Path::new("")).to_string_lossy().into_owned();
+        }
+        Ok(disks)
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        umount(Path::new(device), false)
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        umount(mount_point, force)
+    }
+
+    fn actions(&self, _device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        mount_point
+            .map(|mount_point| Action::new("unmount", "To unmount", &["sudo", "umount", &mount_point.to_string_lossy()]))
+            .into_iter()
+            .collect()
+    }
+    fn set_mount_options(&self, mount_point: &Path, options: &[String]) -> Result<(), String> {
+        update_mount(mount_point, options)
+    }
+}
+
+/// A Linux zram device: compressed RAM, so a disk takes less memory than its size for
+/// anything that compresses. Devices are added and sized through sysfs (loading the
+/// zram module if needed), formatted ext4 or btrfs and mounted at /mnt/<name>. Needs
+/// root like tmpfs; the volume's root then belongs to the user who ran sudo.
+pub struct ZramProvider;
+
+const ZRAM_CONTROL: &str = "/sys/class/zram-control";
+
+/// The number of a zram device node ("/dev/zram3" is 3).
+fn zram_id(device: &str) -> Result<u32, String> {
+    device
+        .strip_prefix("/dev/zram")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| format!("Not a zram device: {}", device))
+}
+
+fn write_sysfs(path: &str, value: &str) -> Result<(), String> {
+    fs::write(path, value).map_err(|e| format!("Failed to write {} to {}: {}", value, path, e))
+}
+
+/// The mkfs command line for `filesystem` labelled `name`. ext4 keeps no blocks
+/// back for root: nothing on a scratch disk needs them.
+fn mkfs_args(filesystem: &str, device: &str, name: &str, verbose: bool) -> Vec<String> {
+    let mut args = vec![format!("mkfs.{}", filesystem)];
+    if !verbose {
+        args.push("-q".to_string());
+    }
+    if filesystem == "ext4" {
+        args.extend(["-m".to_string(), "0".to_string()]);
+    }
+    args.extend(["-L".to_string(), name.to_string(), device.to_string()]);
+    args
+}
+
+impl ZramProvider {
+    fn remove(&self, id: u32) -> Result<(), String> {
+        // Read-only exports hold the device open, so they go first
+        let device = format!("/dev/zram{}", id);
+        let exports = runner::output(Command::new("losetup").args(["--noheadings", "--output", "NAME", "--associated", &device]));
+        if let Ok(output) = exports
+            && output.status.success()
+        {
+            for export in String::from_utf8_lossy(&output.stdout).split_whitespace() {
+                let output = runner::output(Command::new("losetup").args(["--detach", export]))
+                    .map_err(|e| format!("Failed to execute losetup: {}", e))?;
+                if !output.status.success() {
+                    let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+                    return Err(format!("Failed to remove the read-only export {} of {}: {}", export, device, stderr.trim()));
+                }
+            }
+        }
+        // Resetting frees the memory; a device must be reset before it can be removed
+        write_sysfs(&format!("/sys/block/zram{}/reset", id), "1")?;
+        write_sysfs(&format!("{}/hot_remove", ZRAM_CONTROL), &id.to_string())
+    }
+}
+
+impl DeviceProvider for ZramProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        if !Path::new(ZRAM_CONTROL).exists() {
+            // num_devices=0 so the module adds no device of its own to clean up
+            let output = runner::output(Command::new("modprobe").args(["zram", "num_devices=0"]))
+                .map_err(|e| format!("Failed to execute modprobe: {}", e))?;
+            if !output.status.success() {
+                let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+                return Err(format!("Failed to load the zram module: {}", stderr.trim()));
+            }
+        }
+        let id: u32 = fs::read_to_string(format!("{}/hot_add", ZRAM_CONTROL))
+            .map_err(|e| format!("Failed to add a zram device: {}", e))?
+            .trim()
+            .parse()
+            .map_err(|_| "Failed to add a zram device: unexpected reply from hot_add".to_string())?;
+        if let Err(e) = write_sysfs(&format!("/sys/block/zram{}/disksize", id), &(sectors * 512).to_string()) {
+            let _ = write_sysfs(&format!("{}/hot_remove", ZRAM_CONTROL), &id.to_string());
+            return Err(e);
+        }
+        Ok(format!("/dev/zram{}", id))
+    }
+
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String> {
+        let args = mkfs_args(diskutil_format, device, name, verbose);
+        let output = runner::output(Command::new(&args[0]).args(&args[1..]))
+            .map_err(|e| format!("Failed to execute {}: {}", args[0], e))?;
+        if !output.status.success() {
+            let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+            return Err(format!("Failed to format {} as {}: {}", device, diskutil_format, stderr.trim()));
+        }
+        let mount_point = mnt_mount_point(name);
+        fs::create_dir_all(&mount_point)
+            .map_err(|e| format!("Failed to create {}: {}", mount_point.display(), e))?;
+        let output = runner::output(Command::new("mount").arg(device).arg(&mount_point))
+            .map_err(|e| format!("Failed to execute mount: {}", e))?;
+        if !output.status.success() {
+            let _ = fs::remove_dir(&mount_point);
+            let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+            return Err(format!("Failed to mount {}: {}", device, stderr.trim()));
+        }
+        #[cfg(unix)]
+        if let Some((uid, gid)) = sudo_ids() {
+            std::os::unix::fs::chown(&mount_point, uid.parse().ok(), gid.parse().ok())
+                .map_err(|e| format!("Failed to hand {} to the sudo user: {}", mount_point.display(), e))?;
+        }
+        Ok(())
+    }
+
+    fn info(&self, mount_point: &Path) -> Result<DiskInfo, String> {
+        let mut info = mount_table_info(mount_point)?;
+        // sysfs gives the size in 512-byte sectors whatever the device's block size
+        info.sectors = fs::read_to_string(format!("/sys/block/{}/size", info.device.trim_start_matches("/dev/")))
+            .ok()
+            .and_then(|size| size.trim().parse().ok());
+        Ok(info)
+    }
+
+    fn find_device(&self, name: &str) -> Option<String> {
+        mounted_at(&mnt_mount_point(name)).map(|entry| entry.device).filter(|device| zram_id(device).is_ok())
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        Ok(list_mounted(|entry| zram_id(&entry.device).is_ok()))
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        mnt_mount_point(name)
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        unmount_device(device)?;
+        self.remove(zram_id(device)?)
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        let target = mount_point.to_string_lossy();
+        if zram_id(&target).is_ok() {
+            return self.detach(&target);
+        }
+        let entry = mounted_at(mount_point).ok_or_else(|| format!("Nothing is mounted at {}", mount_point.display()))?;
+        let id = zram_id(&entry.device)?;
+        umount(mount_point, force)?;
+        self.remove(id)
+    }
+
+    fn actions(&self, device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if let Some(mount_point) = mount_point {
+            actions.push(Action::new("unmount", "To unmount", &["sudo", "umount", &mount_point.to_string_lossy()]));
+        }
+        if let Ok(id) = zram_id(device) {
+            let remove = format!("echo 1 > /sys/block/zram{0}/reset && echo {0} > {1}/hot_remove", id, ZRAM_CONTROL);
+            let label = if mount_point.is_some() { "Then release its memory" } else { "To release its memory" };
+            actions.push(Action::new("eject", label, &["sudo", "sh", "-c", &remove]));
+        }
+        actions
+    }
+
+    // A read-only loop device over the zram device
+    fn export_readonly(&self, device: &str) -> Result<String, String> {
+        zram_id(device)?;
+        let output = runner::output(Command::new("losetup").args(["--read-only", "--find", "--show", device]))
+            .map_err(|e| format!("Failed to execute losetup: {}", e))?;
+        if !output.status.success() {
+            let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+            return Err(format!("Failed to expose {} read-only: {}", device, stderr.trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+    fn set_mount_options(&self, mount_point: &Path, options: &[String]) -> Result<(), String> {
+        update_mount(mount_point, options)
+    }
+}
+
+/// A FreeBSD md(4) memory disk backed by swap, so its pages can be swapped out under
+/// memory pressure rather than pinning RAM. It is formatted UFS with newfs and mounted
+/// at /mnt/<name>; creating one needs root, and the volume's root then belongs to the
+/// user who ran sudo.
+pub struct MdProvider;
+
+/// The unit of an md device node ("/dev/md3" is "md3"), as mdconfig -u takes it.
+fn md_unit(device: &str) -> Result<&str, String> {
+    device
+        .strip_prefix("/dev/")
+        .filter(|unit| unit.strip_prefix("md").is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())))
+        .ok_or_else(|| format!("Not an md device: {}", device))
+}
+
+/// A UFS volume label for `name`: newfs takes letters, digits, dashes and underscores only.
+fn ufs_label(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+fn mdconfig(args: &[&str]) -> Result<String, String> {
+    let output = runner::output(Command::new("mdconfig").args(args))
+        .map_err(|e| format!("Failed to execute mdconfig: {}", e))?;
+    if !output.status.success() {
+        let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+        return Err(format!("mdconfig failed: {}", stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl DeviceProvider for MdProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        // -s is a count of 512-byte sectors when it has no suffix
+        let unit = mdconfig(&["-a", "-t", "swap", "-s", &sectors.to_string()])?;
+        Ok(format!("/dev/{}", unit))
+    }
+
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String> {
+        if diskutil_format != "ufs" {
+            return Err(format!("The md backend formats UFS only, not {}", diskutil_format));
+        }
+        // Soft updates, and no space kept back for root on a scratch disk
+        let label = ufs_label(name);
+        let mut newfs = Command::new("newfs");
+        newfs.args(["-U", "-m", "0", "-L", &label, device]);
+        if !verbose {
+            newfs.stdout(Stdio::null());
+        }
+        let output = runner::output(&mut newfs).map_err(|e| format!("Failed to execute newfs: {}", e))?;
+        if !output.status.success() {
+            let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+            return Err(format!("Failed to format {}: {}", device, stderr.trim()));
+        }
+        let mount_point = mnt_mount_point(name);
+        fs::create_dir_all(&mount_point)
+            .map_err(|e| format!("Failed to create {}: {}", mount_point.display(), e))?;
+        let output = runner::output(Command::new("mount").arg(device).arg(&mount_point))
+            .map_err(|e| format!("Failed to execute mount: {}", e))?;
+        if !output.status.success() {
+            let _ = fs::remove_dir(&mount_point);
+            let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+            return Err(format!("Failed to mount {}: {}", device, stderr.trim()));
+        }
+        #[cfg(unix)]
+        if let Some((uid, gid)) = sudo_ids() {
+            std::os::unix::fs::chown(&mount_point, uid.parse().ok(), gid.parse().ok())
+                .map_err(|e| format!("Failed to hand {} to the sudo user: {}", mount_point.display(), e))?;
+        }
+        Ok(())
+    }
+
+    fn info(&self, mount_point: &Path) -> Result<DiskInfo, String> {
+        let mut info = mount_table_info(mount_point)?;
+        // `mdconfig -lv` gives "md0  swap  64M", the size as mdconfig -s takes it
+        info.sectors = md_unit(&info.device)
+            .ok()
+            .and_then(|unit| mdconfig(&["-l", "-v", "-u", unit]).ok())
+            .and_then(|line| line.split_whitespace().nth(2).and_then(|size| crate::parse_size(size).ok()))
+            .map(|bytes| bytes / 512);
+        Ok(info)
+    }
+
+    fn find_device(&self, name: &str) -> Option<String> {
+        mounted_at(&mnt_mount_point(name)).map(|entry| entry.device).filter(|device| md_unit(device).is_ok())
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        Ok(list_mounted(|entry| md_unit(&entry.device).is_ok()))
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        mnt_mount_point(name)
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        unmount_device(device)?;
+        mdconfig(&["-d", "-u", md_unit(device)?]).map(|_| ())
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        let target = mount_point.to_string_lossy();
+        if md_unit(&target).is_ok() {
+            return self.detach(&target);
+        }
+        let entry = mounted_at(mount_point).ok_or_else(|| format!("Nothing is mounted at {}", mount_point.display()))?;
+        let unit = md_unit(&entry.device)?;
+        umount(mount_point, force)?;
+        mdconfig(&["-d", "-u", unit]).map(|_| ())
+    }
+
+    fn actions(&self, device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if let Some(mount_point) = mount_point {
+            actions.push(Action::new("unmount", "To unmount", &["sudo", "umount", &mount_point.to_string_lossy()]));
+        }
+        if let Ok(unit) = md_unit(device) {
+            let label = if mount_point.is_some() { "Then release its memory" } else { "To release its memory" };
+            actions.push(Action::new("eject", label, &["sudo", "mdconfig", "-d", "-u", unit]));
+        }
+        actions
+    }
+    fn set_mount_options(&self, mount_point: &Path, options: &[String]) -> Result<(), String> {
+        update_mount(mount_point, options)
+    }
+}
+
+/// A Windows RAM disk made with ImDisk, mounted on an empty directory under
+/// %SystemDrive%\RAMDisks rather than a drive letter, so a name always leads to the same
+/// place. As with tmpfs the "device" is that mount point. It is formatted with
+/// format.com; creating one needs an elevated prompt.
+pub struct ImDiskProvider {
+    name: String,
+}
+
+fn imdisk_root() -> PathBuf {
+    let drive = env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+    PathBuf::from(format!("{}\\", drive)).join("RAMDisks")
+}
+
+fn imdisk_mount_point(name: &str) -> PathBuf {
+    imdisk_root().join(name)
+}
+
+/// The `/FS:` name format.com takes and the longest label it allows for `filesystem`.
+fn windows_filesystem(filesystem: &str) -> Result<(&'static str, usize), String> {
+    match filesystem {
+        "ntfs" => Ok(("NTFS", 32)),
+        "exfat" => Ok(("exFAT", 15)),
+        "fat32" => Ok(("FAT32", FAT_LABEL_MAX)),
+        _ => Err(format!("The imdisk backend cannot format {}", filesystem)),
+    }
+}
+
+fn imdisk(args: &[&str]) -> Result<(), String> {
+    let output = runner::output(Command::new("imdisk").args(args))
+        .map_err(|e| format!("Failed to execute imdisk (is ImDisk installed?): {}", e))?;
+    if !output.status.success() {
+        // imdisk reports its errors on stdout
+        let message = String::from_utf8_lossy(if output.stderr.is_empty() { &output.stdout } else { &output.stderr }).into_owned();
+        return Err(format!("imdisk failed: {}", message.trim()));
+    }
+    Ok(())
+}
+
+impl DeviceProvider for ImDiskProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        let mount_point = imdisk_mount_point(&self.name);
+        fs::create_dir_all(&mount_point)
+            .map_err(|e| format!("Failed to create {}: {}", mount_point.display(), e))?;
+        let target = mount_point.to_string_lossy().into_owned();
+        // -t vm: backed by virtual memory; the size takes a b suffix for bytes
+        if let Err(e) = imdisk(&["-a", "-t", "vm", "-s", &format!("{}b", sectors * 512), "-m", &target]) {
+            let _ = fs::remove_dir(&mount_point);
+            return Err(e);
+        }
+        Ok(target)
+    }
+
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, verbose: bool) -> Result<(), String> {
+        if name != self.name {
+            return Err(format!("An ImDisk disk is mounted as '{}' and cannot be renamed to '{}'", self.name, name));
+        }
+        let (filesystem, label_max) = windows_filesystem(diskutil_format)?;
+        let label: String = name.chars().take(label_max).collect();
+        let mut format = Command::new("format.com");
+        format.args([device, &format!("/FS:{}", filesystem), "/Q", "/Y", &format!("/V:{}", label.trim_end())]);
+        if !verbose {
+            format.stdout(Stdio::null());
+        }
+        let output = runner::output(&mut format).map_err(|e| format!("Failed to execute format: {}", e))?;
+        if !output.status.success() {
+            let stderr = str::from_utf8(&output.stderr).unwrap_or("Unknown error");
+            return Err(format!("Failed to format {} as {}: {}", device, filesystem, stderr.trim()));
+        }
+        Ok(())
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        imdisk_mount_point(name)
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        // Each disk's directory exists only while it is attached
+        Ok(list_dirs(&imdisk_root()))
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        imdisk(&["-D", "-m", device])?;
+        fs::remove_dir(device).map_err(|e| format!("Failed to remove {}: {}", device, e))
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        // -d refuses while files are open; -D removes the disk regardless
+        let target = mount_point.to_string_lossy();
+        imdisk(&[if force { "-D" } else { "-d" }, "-m", &target])?;
+        fs::remove_dir(mount_point).map_err(|e| format!("Failed to remove {}: {}", mount_point.display(), e))
+    }
+
+    fn actions(&self, device: &str, _mount_point: Option<&Path>) -> Vec<Action> {
+        vec![Action::new("eject", "To remove", &["imdisk", "-d", "-m", device])]
+    }
+}
+
+/// Simulated devices for testing mkramdisk itself on any OS. Everything lives under
+/// a root directory ($MKRAMDISK_MOCK_ROOT, or a temp dir): fake device nodes in
+/// `dev/`, "mounted" volumes in `Volumes/` and their mount options in `options/`. A formatted device file holds its sectors,
+/// format (the scheme, e.g. "GPT", if partitioned) and volume names, one per line. Setting
+/// $MKRAMDISK_MOCK_FAIL to `attach` or `format` makes that step fail so error paths
+/// can be tested (`format:ExFAT` fails only that format); `mount` leaves the volume
+/// unmounted after formatting and `remount` also fails the fallback. Any file can be
+/// restored as a "disk image", and any directory as a volume: the volume takes the
+/// source's name, standing in for the name a real one was created with, and a
+/// directory's contents are copied onto it. `restore` makes restoring fail, and `busy`
+/// makes tearing a disk down fail as if files were open unless forced. An encrypted
+/// volume's format is "APFS (Encrypted)", and `encrypt` makes creating one fail. A GPT
+/// disk's EFI system partition is the volume "EFI", which `esp` makes fail to mount. Each
+/// device gets an identity in `ids/`, unique to that attach, as a volume UUID would be.
+pub struct MockProvider {
+    root: PathBuf,
+    fail: Option<String>,
+}
+
+impl MockProvider {
+    pub fn new(root: PathBuf, fail: Option<String>) -> Self {
+        Self { root, fail }
+    }
+
+    fn from_env() -> Self {
+        let root = env::var_os("MKRAMDISK_MOCK_ROOT")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| env::temp_dir().join("mkramdisk-mock"));
+        Self::new(root, env::var("MKRAMDISK_MOCK_FAIL").ok())
+    }
+
+    fn fails_at(&self, step: &str) -> bool {
+        self.fail.as_deref() == Some(step)
+    }
+
+    /// The device file of the volume named `name`, with its contents.
+    fn device_for(&self, name: &str) -> Option<(PathBuf, String)> {
+        fs::read_dir(self.root.join("dev")).ok()?.flatten().find_map(|entry| {
+            let contents = fs::read_to_string(entry.path()).ok()?;
+            (contents.lines().nth(2) == Some(name)).then(|| (entry.path(), contents))
+        })
+    }
+}
+
+impl DeviceProvider for MockProvider {
+    fn attach(&self, sectors: u64) -> Result<String, String> {
+        if self.fails_at("attach") {
+            return Err("Failed to create RAM disk: simulated attach failure".to_string());
+        }
+        let dev = self.root.join("dev");
+        fs::create_dir_all(&dev).map_err(|e| format!("Failed to create {}: {}", dev.display(), e))?;
+
+        let mut n = 0;
+        while dev.join(format!("disk{}", n)).exists() {
+            n += 1;
+        }
+        let device = dev.join(format!("disk{}", n));
+        fs::write(&device, format!("{}\n", sectors))
+            .map_err(|e| format!("Failed to create {}: {}", device.display(), e))?;
+        let ids = self.root.join("ids");
+        let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        fs::create_dir_all(&ids)
+            .and_then(|_| fs::write(ids.join(format!("disk{}", n)), format!("{}-{}", process::id(), since.as_nanos())))
+            .map_err(|e| format!("Failed to create {}: {}", ids.display(), e))?;
+        Ok(device.to_string_lossy().into_owned())
+    }
+
+    fn format(&self, device: &str, diskutil_format: &str, name: &str, _verbose: bool) -> Result<(), String> {
+        if self.fails_at("format") || self.fails_at(&format!("format:{}", diskutil_format)) {
+            return Err("Failed to format RAM disk: simulated format failure".to_string());
+        }
+        let sectors = fs::read_to_string(device)
+            .map_err(|e| format!("Failed to read {}: {}", device, e))?;
+        fs::write(device, format!("{}{}\n{}\n", sectors, diskutil_format, name))
+            .map_err(|e| format!("Failed to write {}: {}", device, e))?;
+
+        if self.fails_at("mount") || self.fails_at("remount") {
+            return Ok(());
+        }
+        let volume = self.mount_point(name);
+        fs::create_dir_all(&volume).map_err(|e| format!("Failed to create {}: {}", volume.display(), e))
+    }
+
+    fn format_encrypted(&self, device: &str, _diskutil_format: &str, name: &str, passphrase: &str, verbose: bool) -> Result<(), String> {
+        if self.fails_at("encrypt") || passphrase.is_empty() {
+            return Err("Failed to add an encrypted volume: simulated encryption failure".to_string());
+        }
+        self.format(device, "APFS (Encrypted)", name, verbose)
+    }
+
+    fn partition(&self, device: &str, scheme: Scheme, partitions: &[Partition], _verbose: bool) -> Result<(), String> {
+        if self.fails_at("format") {
+            return Err("Failed to partition RAM disk: simulated format failure".to_string());
+        }
+        let sectors = fs::read_to_string(device)
+            .map_err(|e| format!("Failed to read {}: {}", device, e))?;
+        let names: String = partitions.iter().map(|p| format!("{}\n", p.name)).collect();
+        fs::write(device, format!("{}{}\n{}", sectors, scheme, names))
+            .map_err(|e| format!("Failed to write {}: {}", device, e))?;
+
+        for partition in partitions {
+            let volume = self.mount_point(&partition.name);
+            fs::create_dir_all(&volume).map_err(|e| format!("Failed to create {}: {}", volume.display(), e))?;
+        }
+        Ok(())
+    }
+
+    fn mount_esp(&self, device: &str) -> Result<PathBuf, String> {
+        let contents = fs::read_to_string(device).map_err(|e| format!("Failed to read {}: {}", device, e))?;
+        if self.fails_at("esp") || contents.lines().nth(1) != Some("GPT") {
+            return Err(format!("diskutil put no EFI system partition on {}; is the disk too small for one?", device));
+        }
+        fs::write(device, format!("{}{}\n", contents, partitions::ESP_NAME))
+            .map_err(|e| format!("Failed to write {}: {}", device, e))?;
+        let volume = self.mount_point(partitions::ESP_NAME);
+        fs::create_dir_all(&volume).map_err(|e| format!("Failed to create {}: {}", volume.display(), e))?;
+        Ok(volume)
+    }
+
+    fn image_bytes(&self, image: &Path) -> Result<u64, String> {
+        fs::metadata(image)
+            .map(|meta| meta.len())
+            .map_err(|e| format!("Failed to read image {}: {}", image.display(), e))
+    }
+
+    fn restore(&self, device: &str, source: &Path, _verbose: bool) -> Result<(), String> {
+        if self.fails_at("restore") {
+            return Err("asr restore failed: simulated restore failure".to_string());
+        }
+        let name = source.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let sectors = fs::read_to_string(device)
+            .map_err(|e| format!("Failed to read {}: {}", device, e))?;
+        fs::write(device, format!("{}image\n{}\n", sectors, name))
+            .map_err(|e| format!("Failed to write {}: {}", device, e))?;
+
+        let volume = self.mount_point(&name);
+        fs::create_dir_all(&volume).map_err(|e| format!("Failed to create {}: {}", volume.display(), e))?;
+        if source.is_dir() {
+            copier::copy_tree(source, &volume, &copier::CopyOptions::default(), &mut Progress::hidden())?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, device: &str, mount_point: &Path, name: &str) -> Result<PathBuf, String> {
+        let old = mount_point.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let contents = fs::read_to_string(device)
+            .map_err(|e| format!("Failed to read {}: {}", device, e))?;
+        let renamed: String = contents
+            .lines()
+            .enumerate()
+            .map(|(i, line)| if i >= 2 && line == old { format!("{}\n", name) } else { format!("{}\n", line) })
+            .collect();
+        fs::write(device, renamed).map_err(|e| format!("Failed to write {}: {}", device, e))?;
+
+        let volume = self.mount_point(name);
+        fs::rename(mount_point, &volume)
+            .map_err(|e| format!("Failed to rename {}: {}", mount_point.display(), e))?;
+        Ok(volume)
+    }
+
+    fn mount_point(&self, name: &str) -> PathBuf {
+        self.root.join("Volumes").join(name)
+    }
+
+    fn detach(&self, device: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(device)
+            .map_err(|_| format!("hdiutil detach {} failed", device))?;
+        // Lines 3 onwards hold the volume names once formatted (several if partitioned)
+        for name in contents.lines().skip(2) {
+            let _ = fs::remove_dir_all(self.mount_point(name));
+            let _ = fs::remove_file(self.root.join("options").join(name));
+        }
+        if let Some(file_name) = Path::new(device).file_name() {
+            let _ = fs::remove_file(self.root.join("exports").join(file_name));
+            let _ = fs::remove_file(self.root.join("ids").join(file_name));
+        }
+        fs::remove_file(device).map_err(|e| format!("Failed to remove {}: {}", device, e))
+    }
+
+    fn destroy(&self, mount_point: &Path, force: bool) -> Result<(), String> {
+        if self.fails_at("busy") && !force {
+            return Err(format!("Failed to detach {}: hdiutil: couldn't unmount - Resource busy", mount_point.display()));
+        }
+        if mount_point.starts_with(self.root.join("dev")) {
+            return self.detach(&mount_point.to_string_lossy());
+        }
+        let name = mount_point.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        match self.device_for(&name) {
+            Some((device, _)) => self.detach(&device.to_string_lossy()),
+            None => Err(format!("No mock device is mounted at {}", mount_point.display())),
+        }
+    }
+
+    fn personality(&self, mount_point: &Path) -> Option<String> {
+        let (_, contents) = self.device_for(&mount_point.file_name()?.to_string_lossy())?;
+        contents.lines().nth(1).map(str::to_string)
+    }
+
+    fn info(&self, mount_point: &Path) -> Result<DiskInfo, String> {
+        let name = mount_point.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let (device, contents) = self
+            .device_for(&name)
+            .ok_or_else(|| format!("No mock device is mounted at {}", mount_point.display()))?;
+        let sectors = contents.lines().next().and_then(|line| line.parse::<u64>().ok());
+        let total_bytes = sectors.map(|sectors| sectors * 512);
+        let used = copier::scan(mount_point, copier::LinkPolicy::Preserve)?.bytes;
+        Ok(DiskInfo {
+            device: device.to_string_lossy().into_owned(),
+            sectors,
+            filesystem: contents.lines().nth(1).map(str::to_string),
+            total_bytes,
+            free_bytes: total_bytes.map(|total| total.saturating_sub(used)),
+            mount_options: fs::read_to_string(self.root.join("options").join(&name))
+                .map(|options| options.lines().map(str::to_string).collect())
+                .unwrap_or_default(),
+            ..DiskInfo::default()
+        })
+    }
+
+    fn find_device(&self, name: &str) -> Option<String> {
+        self.device_for(name).map(|(device, _)| device.to_string_lossy().into_owned())
+    }
+
+    fn identity(&self, device: &str) -> Option<String> {
+        fs::read_to_string(self.root.join("ids").join(Path::new(device).file_name()?)).ok()
+    }
+
+    fn list(&self) -> Result<Vec<ListedDisk>, String> {
+        let Ok(entries) = fs::read_dir(self.root.join("dev")) else {
+            return Ok(Vec::new());
+        };
+        let mut devices: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        devices.sort();
+        let mut disks = Vec::new();
+        for device in devices {
+            let contents = fs::read_to_string(&device).unwrap_or_default();
+            let before = disks.len();
+            for name in contents.lines().skip(2) {
+                let mount_point = self.mount_point(name);
+                if mount_point.exists() {
+                    disks.push(ListedDisk { device: device.to_string_lossy().into_owned(), mount_point: Some(mount_point) });
+                }
+            }
+            if disks.len() == before {
+                disks.push(ListedDisk { device: device.to_string_lossy().into_owned(), mount_point: None });
+            }
+        }
+        Ok(disks)
+    }
+
+    fn actions(&self, device: &str, mount_point: Option<&Path>) -> Vec<Action> {
+        match mount_point {
+            Some(mount_point) => vec![Action::new("remove", "To remove", &["rm", "-rf", &mount_point.to_string_lossy(), device])],
+            None => vec![Action::new("remove", "To remove", &["rm", device])],
+        }
+    }
+
+    // A read-only copy of the device file under exports/, named after it
+    fn export_readonly(&self, device: &str) -> Result<String, String> {
+        if self.fails_at("export") {
+            return Err(format!("Failed to expose {} read-only: simulated export failure", device));
+        }
+        let exports = self.root.join("exports");
+        fs::create_dir_all(&exports).map_err(|e| format!("Failed to create {}: {}", exports.display(), e))?;
+        let node = exports.join(Path::new(device).file_name().unwrap_or_default());
+        fs::copy(device, &node).map_err(|e| format!("Failed to expose {} read-only: {}", device, e))?;
+        let mut permissions = fs::metadata(&node).map_err(|e| format!("Failed to read {}: {}", node.display(), e))?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&node, permissions).map_err(|e| format!("Failed to make {} read-only: {}", node.display(), e))?;
+        Ok(node.to_string_lossy().into_owned())
+    }
+
+    // A device formatted "overlay" holding the image's volume, copied if a directory
+    fn attach_overlay(&self, source: &Path, shadow: &Path) -> Result<String, String> {
+        if self.fails_at("overlay") {
+            return Err(format!("Failed to attach {}: simulated overlay failure", source.display()));
+        }
+        let name = source.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let sectors = self.image_bytes(source)?.div_ceil(512);
+        fs::write(shadow, "").map_err(|e| format!("Failed to create {}: {}", shadow.display(), e))?;
+        let device = self.attach(sectors)?;
+        fs::write(&device, format!("{}\noverlay\n{}\n", sectors, name)).map_err(|e| format!("Failed to write {}: {}", device, e))?;
+        let volume = self.mount_point(&name);
+        fs::create_dir_all(&volume).map_err(|e| format!("Failed to create {}: {}", volume.display(), e))?;
+        if source.is_dir() {
+            copier::copy_tree(source, &volume, &copier::CopyOptions::default(), &mut Progress::hidden())?;
+        }
+        Ok(device)
+    }
+
+    fn locate_mount_point(&self, device: &str, _name: &str) -> Option<PathBuf> {
+        let contents = fs::read_to_string(device).ok()?;
+        let volume = self.mount_point(contents.lines().nth(2)?);
+        volume.is_dir().then_some(volume)
+    }
+
+    fn mount_strategies(&self) -> &'static [MountStrategy] {
+        &[MountStrategy::DiskutilMount]
+    }
+
+    fn remount(&self, device: &str, strategy: MountStrategy) -> Result<(), String> {
+        if self.fails_at("remount") {
+            return Err(format!("{} failed: simulated remount failure", strategy));
+        }
+        let contents = fs::read_to_string(device)
+            .map_err(|e| format!("Failed to read {}: {}", device, e))?;
+        let name = contents.lines().nth(2).ok_or(format!("{} is not formatted", device))?;
+        let volume = self.mount_point(name);
+        fs::create_dir_all(&volume).map_err(|e| format!("Failed to create {}: {}", volume.display(), e))
+    }
+
+    fn set_mount_options(&self, mount_point: &Path, options: &[String]) -> Result<(), String> {
+        let name = mount_point.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let dir = self.root.join("options");
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let contents: String = options.iter().map(|option| format!("{}\n", option)).collect();
+        fs::write(dir.join(&name), contents).map_err(|e| format!("Failed to write {}: {}", dir.join(&name).display(), e))
+    }
+
+    fn describe(&self, device: &str) -> Option<String> {
+        fs::read_to_string(device).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_provider() {
+        assert!(select_provider("ram", "Test").is_ok());
+        assert!(select_provider("file", "Test").is_ok());
+        assert!(select_provider("dir", "Test").is_ok());
+        assert!(select_provider("mock", "Test").is_ok());
+        assert!(select_provider("floppy", "Test").is_err());
+    }
+
+    #[test]
+    fn test_tmpfs_options() {
+        assert_eq!(tmpfs_options(1 << 30, None), "size=1073741824,mode=0755");
+        assert_eq!(
+            tmpfs_options(64 << 20, Some(("501".to_string(), "20".to_string()))),
+            "size=67108864,mode=0755,uid=501,gid=20"
+        );
+        assert_eq!(select_provider("tmpfs", "Test").unwrap().mount_point("Test"), PathBuf::from("/mnt/Test"));
+    }
+
+    #[test]
+    fn test_zram() {
+        assert_eq!(zram_id("/dev/zram12"), Ok(12));
+        assert!(zram_id("/dev/sda1").is_err());
+        assert_eq!(mkfs_args("ext4", "/dev/zram0", "Scratch", false), ["mkfs.ext4", "-q", "-m", "0", "-L", "Scratch", "/dev/zram0"]);
+        assert_eq!(mkfs_args("btrfs", "/dev/zram1", "Scratch", true), ["mkfs.btrfs", "-L", "Scratch", "/dev/zram1"]);
+
+        let actions = ZramProvider.actions("/dev/zram0", Some(Path::new("/mnt/Build")));
+        assert_eq!(actions[1].command(), "sudo sh -c 'echo 1 > /sys/block/zram0/reset && echo 0 > /sys/class/zram-control/hot_remove'");
+    }
+
+    #[test]
+    fn test_md() {
+        assert_eq!(md_unit("/dev/md12"), Ok("md12"));
+        assert!(md_unit("/dev/mdctl").is_err());
+        assert!(md_unit("/dev/ada0").is_err());
+        assert_eq!(ufs_label("Build Cache.v2"), "Build_Cache_v2");
+        let actions = MdProvider.actions("/dev/md0", None);
+        assert_eq!(actions[0].command(), "sudo mdconfig -d -u md0");
+    }
+
+    #[test]
+    fn test_imdisk() {
+        assert_eq!(windows_filesystem("exfat"), Ok(("exFAT", 15)));
+        assert!(windows_filesystem("apfs").is_err());
+        assert!(imdisk_mount_point("Scratch").ends_with(Path::new("RAMDisks").join("Scratch")));
+    }
+
+    #[test]
+    fn test_parse_mount_table() {
+        // /proc/mounts on Linux
+        let table = parse_mount_table("proc /proc proc rw,nosuid 0 0\n/dev/zram0 /mnt/Build\\040Cache ext4 rw,relatime 0 0\n");
+        assert_eq!(table[1], MountEntry {
+            device: "/dev/zram0".to_string(),
+            mount_point: PathBuf::from("/mnt/Build Cache"),
+            filesystem: "ext4".to_string(),
+            options: vec!["rw".to_string(), "relatime".to_string()],
+        });
+        // `mount -p` on FreeBSD
+        let table = parse_mount_table("/dev/ada0p2\t\t/\t\tufs\trw\t1 1\n/dev/md0\t\t/mnt/Scratch\t\tufs\trw,soft-updates\t2 2\n");
+        assert_eq!(table[1].device, "/dev/md0");
+        assert_eq!(table[1].mount_point, PathBuf::from("/mnt/Scratch"));
+        assert_eq!(table[1].options, ["rw", "soft-updates"]);
+    }
+
+    #[test]
+    fn test_mount_points() {
+        assert_eq!(RamProvider.mount_point("Test"), PathBuf::from("/Volumes/Test"));
+        let dir = DirProvider { root: PathBuf::from("/dev/shm/mkramdisk") };
+        assert_eq!(dir.mount_point("Test"), PathBuf::from("/dev/shm/mkramdisk/Test"));
+    }
+
+    #[test]
+    fn test_numbered_mount_point() {
+        let volumes = env::temp_dir().join(format!("mkramdisk-volumes-{}", std::process::id()));
+        fs::create_dir_all(volumes.join("Scratch 1")).unwrap();
+        fs::create_dir_all(volumes.join("Scratch 2")).unwrap();
+        let whole_disk = |path: &Path| match path.file_name()?.to_str()? {
+            "Scratch 1" => Some("/dev/disk4".to_string()),
+            "Scratch 2" => Some("/dev/disk6".to_string()),
+            _ => None,
+        };
+        // "Scratch 1" belongs to another disk
+        assert_eq!(numbered_mount_point(&volumes, "/dev/disk6", "Scratch", whole_disk), Some(volumes.join("Scratch 2")));
+        assert_eq!(numbered_mount_point(&volumes, "/dev/disk8", "Scratch", whole_disk), None);
+        assert_eq!(numbered_mount_point(&volumes, "/dev/disk4", "", whole_disk), None);
+        let _ = fs::remove_dir_all(&volumes);
+    }
+
+    #[test]
+    fn test_action_command() {
+        let action = Action::new("unmount", "To unmount", &["diskutil", "unmount", "/Volumes/My Disk"]);
+        assert_eq!(action.command(), "diskutil unmount '/Volumes/My Disk'");
+    }
+
+    #[test]
+    fn test_parse_image_info() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>Format</key><string>UDZO</string>
+    <key>Size Information</key>
+    <dict>
+        <key>Compressed Bytes</key><integer>1843200</integer>
+        <key>Total Bytes</key><integer>104857600</integer>
+    </dict>
+</dict>
+</plist>"#;
+        assert_eq!(parse_image_info(plist).unwrap(), 104857600);
+        assert!(parse_image_info(b"<plist><dict/></plist>").is_err());
+        assert_eq!(raw_device("/dev/disk5"), "/dev/rdisk5");
+    }
+
+    #[test]
+    fn test_parse_esp() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>AllDisksAndPartitions</key>
+    <array>
+        <dict>
+            <key>Content</key><string>GUID_partition_scheme</string>
+            <key>DeviceIdentifier</key><string>disk5</string>
+            <key>Partitions</key>
+            <array>
+                <dict>
+                    <key>Content</key><string>EFI</string>
+                    <key>DeviceIdentifier</key><string>disk5s1</string>
+                </dict>
+                <dict>
+                    <key>Content</key><string>Apple_APFS</string>
+                    <key>DeviceIdentifier</key><string>disk5s2</string>
+                </dict>
+            </array>
+        </dict>
+    </array>
+</dict>
+</plist>"#;
+        assert_eq!(parse_esp(plist).unwrap().as_deref(), Some("disk5s1"));
+        let without = br#"<plist version="1.0"><dict><key>AllDisksAndPartitions</key><array><dict>
+            <key>DeviceIdentifier</key><string>disk5</string></dict></array></dict></plist>"#;
+        assert_eq!(parse_esp(without).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_volume_info() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>APFSContainerFree</key><integer>1000000000</integer>
+    <key>APFSPhysicalStores</key>
+    <array>
+        <dict><key>APFSPhysicalStore</key><string>disk5s1</string></dict>
+    </array>
+    <key>DeviceNode</key><string>/dev/disk6s1</string>
+    <key>FilesystemUserVisibleName</key><string>APFS</string>
+    <key>ParentWholeDisk</key><string>disk6</string>
+    <key>TotalSize</key><integer>1073741824</integer>
+    <key>VolumeUUID</key><string>0C3F6C1E-5A43-4F2B-9B0E-2D1C6E8E7A10</string>
+</dict>
+</plist>"#;
+        let info = parse_volume_info(plist).unwrap();
+        assert_eq!(info.device_node, "/dev/disk6s1");
+        assert_eq!(info.parent_whole_disk.as_deref(), Some("disk6"));
+        assert_eq!(info.apfs_physical_stores[0].device, "disk5s1");
+        assert_eq!(info.free_space.or(info.apfs_container_free), Some(1000000000));
+        assert!(parse_volume_info(b"<plist><dict/></plist>").is_err());
+
+        let mount = "/dev/disk3s1s1 on / (apfs, sealed, local, read-only, journaled)\n\
+                     /dev/disk6s1 on /Volumes/My Disk (apfs, local, nodev, nosuid, journaled, noowners)\n";
+        assert_eq!(
+            parse_mount_options(mount, Path::new("/Volumes/My Disk")),
+            ["apfs", "local", "nodev", "nosuid", "journaled", "noowners"]
+        );
+        assert!(parse_mount_options(mount, Path::new("/Volumes/Other")).is_empty());
+    }
+
+    #[test]
+    fn test_parse_hdiutil_info() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>framework</key><string>671</string>
+    <key>images</key>
+    <array>
+        <dict>
+            <key>image-path</key><string>ram://2097152</string>
+            <key>system-entities</key>
+            <array>
+                <dict><key>dev-entry</key><string>/dev/disk5</string></dict>
+                <dict>
+                    <key>dev-entry</key><string>/dev/disk5s1</string>
+                    <key>mount-point</key><string>/Volumes/Scratch</string>
+                </dict>
+            </array>
+        </dict>
+    </array>
+</dict>
+</plist>"#;
+        let images = parse_hdiutil_info(plist).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].image_path, "ram://2097152");
+        assert_eq!(images[0].system_entities[0].dev_entry, "/dev/disk5");
+        assert_eq!(images[0].system_entities[1].mount_point.as_deref(), Some("/Volumes/Scratch"));
+    }
+
+    #[test]
+    fn test_parse_lsof() {
+        let output = "p412\ncvim\nf4\nn/Volumes/Scratch/notes.txt\nf5\nn/Volumes/Scratch/.notes.txt.swp\np977\ncbash\nfcwd\nn/Volumes/Scratch\n";
+        let files = parse_lsof(output);
+        let shown: Vec<String> = files.iter().map(OpenFile::to_string).collect();
+        assert_eq!(
+            shown,
+            ["vim (pid 412): /Volumes/Scratch/notes.txt", "vim (pid 412): /Volumes/Scratch/.notes.txt.swp", "bash (pid 977): /Volumes/Scratch"]
+        );
+        assert_eq!(holders(&files), (vec!["vim (pid 412)".to_string(), "bash (pid 977)".to_string()], vec![412, 977]));
+    }
+
+    #[test]
+    fn test_apfs_operation_disk() {
+        let output = "Started APFS operation on disk4\nCreating a new empty APFS Container\nDisk from APFS operation: disk5\nFinished APFS operation on disk4\n";
+        assert_eq!(apfs_operation_disk(output), Some("disk5"));
+        assert_eq!(apfs_operation_disk("Error: -69888\n"), None);
+    }
+
+    #[test]
+    fn test_mock_provider_lifecycle() {
+        let root = env::temp_dir().join(format!("mkramdisk-mock-unit-{}", std::process::id()));
+        let mock = MockProvider::new(root.clone(), None);
+
+        let first = mock.attach(2048).unwrap();
+        let second = mock.attach(2048).unwrap();
+        assert_ne!(first, second);
+
+        mock.format(&first, "APFS", "Test", false).unwrap();
+        assert!(mock.mount_point("Test").is_dir());
+        fs::remove_dir(mock.mount_point("Test")).unwrap();
+        mock.remount(&first, MountStrategy::DiskutilMount).unwrap();
+        assert!(mock.mount_point("Test").is_dir());
+
+        let actions = mock.actions(&first, Some(&mock.mount_point("Test")));
+        assert_eq!(actions[0].argv[..3], ["rm", "-rf", &*mock.mount_point("Test").to_string_lossy()]);
+
+        mock.destroy(&mock.mount_point("Test"), false).unwrap();
+        mock.detach(&second).unwrap();
+        assert!(!mock.mount_point("Test").exists());
+        assert!(!Path::new(&first).exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
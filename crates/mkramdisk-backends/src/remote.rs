@@ -0,0 +1,88 @@
+use std::process::{Command, Stdio};
+
+use crate::runner;
+
+/// Quote a single argument for a POSIX shell on the remote side of ssh.
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=+:,".contains(c)) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Check whether mkramdisk is installed and on PATH on the remote host.
+pub fn has_remote_mkramdisk(host: &str) -> bool {
+    runner::status(Command::new("ssh")
+        .args(["--", host, "command -v mkramdisk"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null()))
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Run a shell command line on the remote host, streaming its output through. The host
+/// follows `--`, so one starting with "-" is never taken for an ssh option.
+pub fn run_ssh(host: &str, command_line: &str) -> Result<(), String> {
+    let status = runner::status(Command::new("ssh").args(["--", host, command_line]))
+        .map_err(|e| format!("Failed to execute ssh: {}", e))?;
+
+    if !status.success() {
+        return Err(format!("Remote command on {} failed ({})", host, status));
+    }
+    Ok(())
+}
+
+/// Run a shell command line on the remote host and collect what it prints, for when
+/// several hosts run at once and their output would interleave. A failure carries the
+/// last line the command wrote to stderr.
+pub fn output_ssh(host: &str, command_line: &str) -> Result<String, String> {
+    let output = runner::output(Command::new("ssh").args(["--", host, command_line]))
+        .map_err(|e| format!("Failed to execute ssh: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim().lines().last() {
+            Some(line) => format!("Remote command on {} failed ({}): {}", host, output.status, line),
+            None => format!("Remote command on {} failed ({})", host, output.status),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Build a script that creates a RAM disk with raw hdiutil/diskutil, for hosts
+/// that don't have mkramdisk installed.
+pub fn fallback_create_script(sectors: u64, diskutil_format: &str, name: &str) -> String {
+    format!(
+        r#"dev=$(hdiutil attach -nomount ram://{sectors} | tr -d '[:space:]') || exit 1
+if ! diskutil erasevolume {format} {name} "$dev"; then
+    hdiutil detach "$dev" >/dev/null 2>&1
+    exit 1
+fi
+echo "RAM disk created: $dev mounted at "{mount_point}"#,
+        sectors = sectors,
+        format = shell_quote(diskutil_format),
+        name = shell_quote(name),
+        mount_point = shell_quote(&format!("/Volumes/{}", name)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("2G"), "2G");
+        assert_eq!(shell_quote("My Disk"), "'My Disk'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_fallback_create_script() {
+        let script = fallback_create_script(2048, "MS-DOS FAT32", "Build Disk");
+        assert!(script.contains("ram://2048"));
+        assert!(script.contains("erasevolume 'MS-DOS FAT32' 'Build Disk'"));
+        assert!(script.contains("'/Volumes/Build Disk'"));
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// One external command that was run, as kept in the transcript.
+#[derive(Debug, Clone)]
+pub struct CommandRecord {
+    pub argv: Vec<String>,
+    pub duration: Duration,
+    pub result: Result<Option<i32>, String>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+static TRANSCRIPT: Mutex<Vec<CommandRecord>> = Mutex::new(Vec::new());
+static ECHO: AtomicBool = AtomicBool::new(false);
+
+// Output lines shown per stream when echoing; the transcript keeps everything
+const ECHO_MAX_LINES: usize = 10;
+
+/// Echo every command to stderr as it completes (`-vv`).
+pub fn set_echo(echo: bool) {
+    ECHO.store(echo, Ordering::Relaxed);
+}
+
+fn argv(command: &Command) -> Vec<String> {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn record(command: &Command, started: Instant, result: &io::Result<ExitStatus>, stdout: &[u8], stderr: &[u8]) {
+    let record = CommandRecord {
+        argv: argv(command),
+        duration: started.elapsed(),
+        result: result.as_ref().map(|status| status.code()).map_err(|e| e.to_string()),
+        stdout: String::from_utf8_lossy(stdout).trim().to_string(),
+        stderr: String::from_utf8_lossy(stderr).trim().to_string(),
+    };
+    if ECHO.load(Ordering::Relaxed) {
+        eprint!("[CMD] {}", record.display(Some(ECHO_MAX_LINES)));
+    }
+    crate::trace::command(&record.argv, record.duration, &record.result);
+    crate::session::command(&record);
+    TRANSCRIPT.lock().unwrap_or_else(|e| e.into_inner()).push(record);
+}
+
+/// Run a command to completion, collecting its output, and add it to the transcript.
+/// hdiutil and diskutil wait their turn in the `pool` first, as in every function here.
+pub fn output(command: &mut Command) -> io::Result<Output> {
+    let _permit = crate::pool::acquire(&[command])?;
+    let started = Instant::now();
+    let result = command.output();
+    match &result {
+        Ok(output) => record(command, started, &Ok(output.status), &output.stdout, &output.stderr),
+        Err(e) => record(command, started, &Err(io::Error::new(e.kind(), e.to_string())), b"", b""),
+    }
+    result
+}
+
+/// Like `output`, but feed `input` to the command's stdin.
+pub fn output_with_input(command: &mut Command, input: &[u8]) -> io::Result<Output> {
+    let _permit = crate::pool::acquire(&[command])?;
+    let started = Instant::now();
+    let result = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|child| wait_feeding(child, input));
+    match &result {
+        Ok(output) => record(command, started, &Ok(output.status), &output.stdout, &output.stderr),
+        Err(e) => record(command, started, &Err(io::Error::new(e.kind(), e.to_string())), b"", b""),
+    }
+    result
+}
+
+/// Run `first | second`: both to completion, with `input` fed to `first` and its stdout
+/// to `second`, adding both to the transcript. `first`'s output comes back without its
+/// stdout.
+pub fn pipe(first: &mut Command, input: &[u8], second: &mut Command) -> io::Result<(Output, Output)> {
+    let _permit = crate::pool::acquire(&[first, second])?;
+    let started = Instant::now();
+    let mut producer = first.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let consumer = match producer.stdout.take() {
+        Some(stdout) => second.stdin(stdout).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn(),
+        None => Err(io::Error::other("no stdout to pipe")),
+    };
+    let (first_result, second_result) = match consumer {
+        // Fed and waited on aside, so neither blocks on a full pipe while the other runs
+        Ok(consumer) => std::thread::scope(|scope| {
+            let producer = scope.spawn(|| wait_feeding(producer, input));
+            let second_result = consumer.wait_with_output();
+            (producer.join().unwrap_or_else(|_| Err(io::Error::other("panicked waiting for the command"))), second_result)
+        }),
+        // Nothing would ever read what `first` writes
+        Err(e) => {
+            let _ = producer.kill();
+            (producer.wait_with_output(), Err(e))
+        }
+    };
+    for (command, result) in [(&*first, &first_result), (&*second, &second_result)] {
+        match result {
+            Ok(output) => record(command, started, &Ok(output.status), &output.stdout, &output.stderr),
+            Err(e) => record(command, started, &Err(io::Error::new(e.kind(), e.to_string())), b"", b""),
+        }
+    }
+    Ok((first_result?, second_result?))
+}
+
+// Writes `input` to the child's stdin on another thread while collecting its output, so a
+// child that fills its stdout before reading all its input can't stall both. Its stdin
+// closes once written (or the write fails), so the child always ends and is waited on;
+// one that stops reading early says why in its exit status.
+fn wait_feeding(mut child: Child, input: &[u8]) -> io::Result<Output> {
+    let stdin = child.stdin.take();
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.map_or(Ok(()), |mut stdin| stdin.write_all(input)));
+        let output = child.wait_with_output();
+        match writer.join().unwrap_or_else(|_| Err(io::Error::other("panicked writing the command's input"))) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => output.and(Err(e)),
+            _ => output,
+        }
+    })
+}
+
+/// Run a command with its configured stdio and add it to the transcript.
+pub fn status(command: &mut Command) -> io::Result<ExitStatus> {
+    let _permit = crate::pool::acquire(&[command])?;
+    let started = Instant::now();
+    let result = command.status();
+    record(command, started, &result, b"", b"");
+    result
+}
+
+/// Every command run so far, in order.
+pub fn transcript() -> Vec<CommandRecord> {
+    TRANSCRIPT.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+impl CommandRecord {
+    /// Render the record, showing at most `max_lines` lines of each output stream.
+    pub fn display(&self, max_lines: Option<usize>) -> RecordDisplay<'_> {
+        RecordDisplay { record: self, max_lines }
+    }
+}
+
+pub struct RecordDisplay<'a> {
+    record: &'a CommandRecord,
+    max_lines: Option<usize>,
+}
+
+impl fmt::Display for RecordDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let record = self.record;
+        writeln!(f, "$ {}", record.argv.join(" "))?;
+        match &record.result {
+            Ok(Some(code)) => writeln!(f, "  exit {} after {:?}", code, record.duration)?,
+            Ok(None) => writeln!(f, "  killed by signal after {:?}", record.duration)?,
+            Err(e) => writeln!(f, "  failed to start: {}", e)?,
+        }
+        for (label, text) in [("stdout", &record.stdout), ("stderr", &record.stderr)] {
+            if text.is_empty() {
+                continue;
+            }
+            writeln!(f, "  {}:", label)?;
+            let total = text.lines().count();
+            let shown = self.max_lines.unwrap_or(total);
+            for line in text.lines().take(shown) {
+                writeln!(f, "    {}", line)?;
+            }
+            if total > shown {
+                writeln!(f, "    ... ({} more lines)", total - shown)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CommandRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_are_recorded() {
+        let result = output(Command::new("mkramdisk-no-such-program").arg("--flag"));
+        assert!(result.is_err());
+
+        let record = transcript()
+            .into_iter()
+            .find(|r| r.argv[0] == "mkramdisk-no-such-program")
+            .unwrap();
+        assert_eq!(record.argv, ["mkramdisk-no-such-program", "--flag"]);
+        assert!(record.result.is_err());
+        assert!(record.to_string().contains("failed to start"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_with_input() {
+        let output = output_with_input(&mut Command::new("cat"), b"/Volumes/Scratch").unwrap();
+        assert_eq!(output.stdout, b"/Volumes/Scratch");
+        // More than a pipe holds, which cat writes back before it has read it all
+        let input = vec![b'x'; 4 << 20];
+        assert_eq!(output_with_input(&mut Command::new("cat"), &input).unwrap().stdout.len(), input.len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pipe() {
+        let input = vec![b'x'; 4 << 20];
+        let (first, second) = pipe(&mut Command::new("cat"), &input, Command::new("wc").arg("-c")).unwrap();
+        assert!(first.status.success());
+        assert_eq!(String::from_utf8_lossy(&second.stdout).trim(), (4 << 20).to_string());
+        // A consumer that can't start leaves no producer behind
+        assert!(pipe(&mut Command::new("cat"), &input, &mut Command::new("mkramdisk-no-such-program")).is_err());
+    }
+
+    #[test]
+    fn test_display_truncates_output() {
+        let record = CommandRecord {
+            argv: vec!["diskutil".to_string(), "list".to_string()],
+            duration: Duration::from_millis(5),
+            result: Ok(Some(0)),
+            stdout: "a\nb\nc".to_string(),
+            stderr: String::new(),
+        };
+        let text = record.display(Some(2)).to_string();
+        assert!(text.starts_with("$ diskutil list\n  exit 0"));
+        assert!(text.contains("    b\n    ... (1 more lines)"));
+        assert!(!text.contains("stderr"));
+        assert!(record.to_string().contains("    c\n"));
+    }
+}
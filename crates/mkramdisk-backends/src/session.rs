@@ -0,0 +1,146 @@
+//! Session recordings (`--record FILE`): every external command a run of mkramdisk made,
+//! with its output, interleaved with the decisions it logged and the events it emitted,
+//! written as JSON when the run ends. `replay` prints one back, or runs it again.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::runner::CommandRecord;
+
+/// A recorded run of mkramdisk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// The mkramdisk version that recorded it.
+    pub version: String,
+    /// The command line, without `--record` and its file.
+    pub args: Vec<String>,
+    /// Seconds since the epoch.
+    pub started: u64,
+    pub exit_code: i32,
+    pub steps: Vec<Step>,
+}
+
+/// One thing that happened during the run, in order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Step {
+    Command {
+        argv: Vec<String>,
+        /// None if it was killed by a signal.
+        exit: Option<i32>,
+        /// Why it couldn't be started, if it wasn't.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        duration_ms: u64,
+        stdout: String,
+        stderr: String,
+    },
+    Decision {
+        message: String,
+    },
+    Event {
+        event: serde_json::Value,
+    },
+}
+
+// The steps so far while recording; None when not
+static STEPS: Mutex<Option<Vec<Step>>> = Mutex::new(None);
+
+/// Start recording the steps of this run.
+pub fn start() {
+    *STEPS.lock().unwrap_or_else(|e| e.into_inner()) = Some(Vec::new());
+}
+
+fn push(step: Step) {
+    if let Some(steps) = STEPS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        steps.push(step);
+    }
+}
+
+pub fn command(record: &CommandRecord) {
+    push(Step::Command {
+        argv: record.argv.clone(),
+        exit: record.result.as_ref().ok().copied().flatten(),
+        error: record.result.as_ref().err().cloned(),
+        duration_ms: record.duration.as_millis() as u64,
+        stdout: record.stdout.clone(),
+        stderr: record.stderr.clone(),
+    });
+}
+
+pub fn decision(message: &str) {
+    push(Step::Decision { message: message.to_string() });
+}
+
+pub fn event(event: &serde_json::Value) {
+    push(Step::Event { event: event.clone() });
+}
+
+/// Stop recording and write the session for the run of `args` that began at `started`.
+pub fn finish(path: &Path, args: &[String], started: SystemTime, exit_code: i32) -> Result<(), String> {
+    let steps = STEPS.lock().unwrap_or_else(|e| e.into_inner()).take().unwrap_or_default();
+    let session = Session {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        args: args.to_vec(),
+        started: started.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+        exit_code,
+        steps,
+    };
+    let json = serde_json::to_string_pretty(&session).map_err(|e| e.to_string())?;
+    fs::write(path, json + "\n").map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn load(path: &Path) -> Result<Session, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Invalid session {}: {}", path.display(), e))
+}
+
+impl Step {
+    /// The step as `replay --dry-run` prints it: a command as the transcript shows one,
+    /// anything else as a comment.
+    pub fn describe(&self) -> String {
+        match self {
+            Step::Command { argv, exit, error, duration_ms, stdout, stderr } => CommandRecord {
+                argv: argv.clone(),
+                duration: Duration::from_millis(*duration_ms),
+                result: error.clone().map_or(Ok(*exit), Err),
+                stdout: stdout.clone(),
+                stderr: stderr.clone(),
+            }
+            .to_string(),
+            Step::Decision { message } => format!("# {}\n", message),
+            Step::Event { event } => format!("# event: {}\n", event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps_round_trip() {
+        let steps = vec![
+            Step::Decision { message: "Size: 1G = 2097152 sectors".to_string() },
+            Step::Command {
+                argv: vec!["hdiutil".to_string(), "attach".to_string(), "-nomount".to_string(), "ram://2097152".to_string()],
+                exit: Some(0),
+                error: None,
+                duration_ms: 12,
+                stdout: "/dev/disk5".to_string(),
+                stderr: String::new(),
+            },
+            Step::Event { event: serde_json::json!({ "event": "device_acquired" }) },
+        ];
+        let json = serde_json::to_string(&steps).unwrap();
+        assert!(json.starts_with(r#"[{"kind":"decision","#), "{}", json);
+        assert_eq!(serde_json::from_str::<Vec<Step>>(&json).unwrap(), steps);
+
+        assert_eq!(steps[0].describe(), "# Size: 1G = 2097152 sectors\n");
+        assert!(steps[1].describe().starts_with("$ hdiutil attach -nomount ram://2097152\n  exit 0 after 12ms\n"));
+    }
+}
@@ -0,0 +1,309 @@
+//! Tracing spans for creating and ejecting disks, their pipeline stages and the external
+//! commands those run, for finding where provisioning is slow across a fleet. Built with
+//! `--features otel`, spans are exported as OTLP/HTTP JSON (with curl) to
+//! $OTEL_EXPORTER_OTLP_TRACES_ENDPOINT, or $OTEL_EXPORTER_OTLP_ENDPOINT/v1/traces, each
+//! time a top-level span ends. Without the feature, or with neither set, every call here
+//! does nothing.
+
+use std::time::Duration;
+
+/// An open span, ended by `end` or, successfully, when dropped.
+pub struct Span {
+    #[cfg(feature = "otel")]
+    open: Option<otel::Open>,
+}
+
+/// Start a span, under the one currently open if any.
+pub fn start(name: &str) -> Span {
+    #[cfg(feature = "otel")]
+    return Span { open: otel::start(name, false) };
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = name;
+        Span {}
+    }
+}
+
+impl Span {
+    pub fn attribute(&mut self, key: &'static str, value: &str) {
+        #[cfg(feature = "otel")]
+        if let Some(open) = &mut self.open {
+            open.attributes.push((key, value.to_string()));
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (key, value);
+    }
+
+    /// End the span, marking it failed with `error`, if any.
+    pub fn end(mut self, error: Option<&str>) {
+        #[cfg(feature = "otel")]
+        if let Some(open) = self.open.take() {
+            otel::end(open, error.map(str::to_string));
+        }
+        #[cfg(not(feature = "otel"))]
+        let _ = (&mut self, error);
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(open) = self.open.take() {
+            otel::end(open, None);
+        }
+    }
+}
+
+/// Run `f` in a span named `name`, failed if `f` fails.
+pub fn in_span<T>(name: &str, f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let span = start(name);
+    let result = f();
+    span.end(result.as_ref().err().map(String::as_str));
+    result
+}
+
+/// Record an external command that just finished as a span under the one open, if any;
+/// commands run outside a traced operation are left out.
+pub fn command(argv: &[String], elapsed: Duration, result: &Result<Option<i32>, String>) {
+    #[cfg(feature = "otel")]
+    otel::command(argv, elapsed, result);
+    #[cfg(not(feature = "otel"))]
+    let _ = (argv, elapsed, result);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use std::env;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde_json::{Value, json};
+    use sha2::{Digest, Sha256};
+
+    static STATE: Mutex<State> = Mutex::new(State { trace_id: None, open: Vec::new(), finished: Vec::new() });
+    static IDS: AtomicU64 = AtomicU64::new(0);
+
+    // How long an export may hold up the command being traced
+    const EXPORT_TIMEOUT_SECS: &str = "5";
+
+    struct State {
+        trace_id: Option<String>,
+        /// IDs of the spans open now, innermost last.
+        open: Vec<String>,
+        finished: Vec<Record>,
+    }
+
+    pub struct Open {
+        span_id: String,
+        name: String,
+        started: SystemTime,
+        pub attributes: Vec<(&'static str, String)>,
+    }
+
+    /// A finished span, ready to export.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Record {
+        pub trace_id: String,
+        pub span_id: String,
+        pub parent_id: Option<String>,
+        pub name: String,
+        pub started: SystemTime,
+        pub ended: SystemTime,
+        pub attributes: Vec<(&'static str, String)>,
+        pub error: Option<String>,
+    }
+
+    fn endpoint() -> Option<String> {
+        if let Some(endpoint) = env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").ok().filter(|e| !e.is_empty()) {
+            return Some(endpoint);
+        }
+        let base = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|e| !e.is_empty())?;
+        Some(format!("{}/v1/traces", base.trim_end_matches('/')))
+    }
+
+    // Unique enough for IDs: the process, the time and a counter, hashed
+    fn random_hex(bytes: usize) -> String {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+        let seed = format!("{}:{}:{}", std::process::id(), nanos, IDS.fetch_add(1, Ordering::Relaxed));
+        Sha256::digest(seed.as_bytes())[..bytes].iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn lock() -> std::sync::MutexGuard<'static, State> {
+        STATE.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn start(name: &str, child_only: bool) -> Option<Open> {
+        endpoint()?;
+        let mut state = lock();
+        if child_only && state.open.is_empty() {
+            return None;
+        }
+        let span_id = random_hex(8);
+        state.open.push(span_id.clone());
+        Some(Open { span_id, name: name.to_string(), started: SystemTime::now(), attributes: Vec::new() })
+    }
+
+    pub fn end(open: Open, error: Option<String>) {
+        let finished = {
+            let mut state = lock();
+            state.open.retain(|id| *id != open.span_id);
+            let parent_id = state.open.last().cloned();
+            let trace_id = state.trace_id.get_or_insert_with(|| random_hex(16)).clone();
+            state.finished.push(Record {
+                trace_id,
+                span_id: open.span_id,
+                parent_id,
+                name: open.name,
+                started: open.started,
+                ended: SystemTime::now(),
+                attributes: open.attributes,
+                error,
+            });
+            if !state.open.is_empty() {
+                return;
+            }
+            // The top-level span is done: send the trace and start a new one next time
+            state.trace_id = None;
+            std::mem::take(&mut state.finished)
+        };
+        if let Some(endpoint) = endpoint()
+            && let Err(e) = export(&endpoint, &finished)
+        {
+            eprintln!("Warning: failed to export traces to {}: {}", endpoint, e);
+        }
+    }
+
+    pub fn command(argv: &[String], elapsed: Duration, result: &Result<Option<i32>, String>) {
+        let program = argv.first().map(String::as_str).unwrap_or("command");
+        let Some(mut open) = start(program, true) else {
+            return;
+        };
+        open.started = SystemTime::now() - elapsed;
+        open.attributes.push(("process.command_args", argv.join(" ")));
+        let error = match result {
+            Ok(Some(code)) => {
+                open.attributes.push(("process.exit.code", code.to_string()));
+                (*code != 0).then(|| format!("exited with status {}", code))
+            }
+            Ok(None) => Some("killed by a signal".to_string()),
+            Err(e) => Some(e.clone()),
+        };
+        end(open, error);
+    }
+
+    fn unix_nanos(time: SystemTime) -> String {
+        time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default().to_string()
+    }
+
+    fn attribute(key: &str, value: &str) -> Value {
+        json!({ "key": key, "value": { "stringValue": value } })
+    }
+
+    /// The OTLP/HTTP JSON request body for `spans`.
+    pub fn request(spans: &[Record], service: &str) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|span| {
+                let mut value = json!({
+                    "traceId": span.trace_id,
+                    "spanId": span.span_id,
+                    "name": span.name,
+                    // SPAN_KIND_INTERNAL
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(span.started),
+                    "endTimeUnixNano": unix_nanos(span.ended),
+                    "attributes": span.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+                    // STATUS_CODE_OK or STATUS_CODE_ERROR
+                    "status": match &span.error {
+                        Some(error) => json!({ "code": 2, "message": error }),
+                        None => json!({ "code": 1 }),
+                    },
+                });
+                if let Some(parent_id) = &span.parent_id {
+                    value["parentSpanId"] = json!(parent_id);
+                }
+                value
+            })
+            .collect();
+        json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [attribute("service.name", service)] },
+                "scopeSpans": [{
+                    "scope": { "name": "mkramdisk", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    // Not through the runner, which would trace the export itself
+    fn export(endpoint: &str, spans: &[Record]) -> Result<(), String> {
+        let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "mkramdisk".to_string());
+        let body = request(spans, &service).to_string();
+        let mut curl = Command::new("curl");
+        curl.args(["-sS", "-f", "--max-time", EXPORT_TIMEOUT_SECS, "-X", "POST", "-H", "Content-Type: application/json"]);
+        // "key1=value1,key2=value2", as the other OpenTelemetry exporters take it
+        for header in env::var("OTEL_EXPORTER_OTLP_HEADERS").unwrap_or_default().split(',') {
+            if let Some((key, value)) = header.split_once('=') {
+                curl.args(["-H", &format!("{}: {}", key.trim(), value.trim())]);
+            }
+        }
+        let mut child = curl
+            .args(["--data-binary", "@-", endpoint])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run curl: {}", e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(body.as_bytes()).map_err(|e| format!("Failed to send spans to curl: {}", e))?;
+        }
+        let output = child.wait_with_output().map_err(|e| format!("Failed to run curl: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_request() {
+            let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let root = Record {
+                trace_id: "ab".repeat(16),
+                span_id: "01".repeat(8),
+                parent_id: None,
+                name: "create".to_string(),
+                started,
+                ended: started + Duration::from_millis(1500),
+                attributes: vec![("mkramdisk.size", "2G".to_string())],
+                error: None,
+            };
+            let command = Record {
+                span_id: "02".repeat(8),
+                parent_id: Some(root.span_id.clone()),
+                name: "hdiutil".to_string(),
+                error: Some("exited with status 1".to_string()),
+                ..root.clone()
+            };
+            let request = request(&[command, root], "build-mac");
+
+            let resource = &request["resourceSpans"][0];
+            assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "build-mac");
+            let spans = &resource["scopeSpans"][0]["spans"];
+            assert_eq!(spans[0]["parentSpanId"], "01".repeat(8));
+            assert_eq!(spans[0]["status"]["code"], 2);
+            assert_eq!(spans[1]["startTimeUnixNano"], "1700000000000000000");
+            assert_eq!(spans[1]["endTimeUnixNano"], "1700000001500000000");
+            assert!(spans[1].get("parentSpanId").is_none());
+            assert_eq!(spans[1]["attributes"][0]["key"], "mkramdisk.size");
+            assert_eq!(spans[1]["status"]["code"], 1);
+        }
+    }
+}
@@ -0,0 +1,2603 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use mkramdisk_core::{
+    accelerate, api, attributes, backup, copier, deprecations, diagnostics, disks, features, fleet, formats, keychain, memory, migrate, output, partitions, passphrase, paths, pool, progress, project, provider, registry, remote, runner, selftest, session,
+    user_config,
+};
+use mkramdisk_core::{
+    check_volume_name, create_ramdisk, format_timestamp, disk_minimum_bytes, disk_sectors, diskutil_format, eject, fat_label, filesystem_minimum_bytes,
+    get_diskutil_format, log_verbose, parse_size, sanitize_volume_name, say, size_to_sectors, size_unit,
+    path_bytes, path_from_bytes, run_hook, tagged, utf8_arg, validate_filesystem, validate_tag, validate_volume_name, warn, Config, FAT_LABEL_MAX,
+};
+
+fn main() {
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    let mut args = &args[..];
+    
+    // --record goes before everything else, e.g. `mkramdisk --record session.json create 2G`
+    let mut record = None;
+    if args.first().is_some_and(|arg| arg == "--record") {
+        let Some(value) = args.get(1) else {
+            eprintln!("Error: --record requires a file");
+            std::process::exit(1);
+        };
+        record = Some(PathBuf::from(value));
+        args = &args[2..];
+    }
+    let Some(path) = record else {
+        std::process::exit(run(args));
+    };
+    session::start();
+    let started = SystemTime::now();
+    let code = run(args);
+    // A session is JSON, so a path that isn't UTF-8 is recorded as near as it can be
+    let recorded: Vec<String> = args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    if let Err(e) = session::finish(&path, &recorded, started, code) {
+        eprintln!("Error: {}", e);
+        std::process::exit(code.max(1));
+    }
+    std::process::exit(code);
+}
+
+/// Run the command line after `--record`, returning the exit code.
+fn run(args: &[OsString]) -> i32 {
+    // --enable-experimental goes anywhere, as it is for whichever command runs
+    if args.iter().any(|arg| arg == "--enable-experimental") {
+        features::enable_all();
+    }
+    let mut args: Vec<OsString> = args.iter().filter(|arg| *arg != "--enable-experimental").cloned().collect();
+    // So does --api-version, failing before anything is done if it can't be had
+    if let Some(at) = args.iter().position(|arg| arg == "--api-version") {
+        let negotiated = args.get(at + 1).ok_or_else(|| "--api-version requires a version".to_string()).and_then(|version| api::negotiate(utf8_arg(version)?));
+        if let Err(e) = negotiated {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        args.drain(at..at + 2);
+    }
+    // And --state-dir, before anything reads the state file
+    let state_dir = args.iter().position(|arg| arg == "--state-dir");
+    if let Some(at) = state_dir {
+        let Some(dir) = args.get(at + 1) else {
+            eprintln!("Error: --state-dir requires a directory");
+            return 1;
+        };
+        let dir = PathBuf::from(dir.clone());
+        // config.toml is the user's settings rather than state, so it stays put
+        let config_file = paths::get().map_or_else(|| dir.join("config.toml"), |paths| paths.config_file);
+        paths::set(paths::StatePaths { config_file, ..paths::StatePaths::in_dir(&dir) });
+        args.drain(at..at + 2);
+    }
+    // Replaced forms still work, with a warning saying what they are now
+    let (args, deprecated) = deprecations::rewrite(&args, deprecations::DEPRECATIONS);
+    deprecations::warn(&deprecated);
+    let mut args = &args[..];
+    
+    // --host must come before the command, e.g. `mkramdisk --host mac-mini-1 create 2G`
+    let mut host = None;
+    if args.first().is_some_and(|arg| arg == "--host") {
+        let Some(value) = args.get(1) else {
+            eprintln!("Error: Host option requires a value");
+            return 1;
+        };
+        match utf8_arg(value) {
+            Ok(value) => host = Some(value.to_string()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+        args = &args[2..];
+    }
+    if host.is_some() && state_dir.is_some() {
+        eprintln!("Error: --state-dir is for this machine and cannot be combined with --host");
+        return 1;
+    }
+    
+    let (command, rest) = match args.first().and_then(|arg| arg.to_str()) {
+        Some(command @ ("plan" | "create" | "up" | "down" | "formats" | "selftest" | "list" | "info" | "changes" | "backups" | "status" | "exists" | "eject" | "destroy" | "resize" | "overlay" | "accelerate" | "decelerate" | "adopt" | "gc" | "replay" | "features" | "migrate-state" | "registry" | "sync" | "fleet" | "daemon")) => (command, args[1..].to_vec()),
+        // `from-dmg IMAGE` is shorthand for `create --from-dmg IMAGE`
+        Some("from-dmg") => ("create", std::iter::once(OsString::from("--from-dmg")).chain(args[1..].iter().cloned()).collect()),
+        _ => ("create", args.to_vec()),
+    };
+    let rest = &rest[..];
+    
+    // Answered before config.toml is read, so a broken one can't stand in the way of them
+    match help_or_version(rest) {
+        Some("help") => {
+            print_usage();
+            return 0;
+        }
+        Some(_) => {
+            print_version();
+            return 0;
+        }
+        None => {}
+    }
+    
+    // Files an older mkramdisk wrote are upgraded before anything reads them
+    if host.is_none() && command != "migrate-state" {
+        migrate_automatically();
+    }
+    if let Some(limit) = user_config::load().ok().and_then(|defaults| defaults.max_commands) {
+        pool::set_limit(limit);
+    }
+    
+    if command == "formats" {
+        if let Err(e) = utf8_args(rest).and_then(|rest| list_formats(host.as_deref(), &rest)) {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if command == "selftest" {
+        let passed = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest).map(|_| true),
+            None => run_selftest(rest),
+        };
+        match passed {
+            Ok(true) => return 0,
+            Ok(false) => return 1,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+    }
+    if command == "changes" {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            None => parse_changes_command(rest).and_then(|(config, name, since)| changes(&config, &name, since)),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if matches!(command, "backups" | "sync") {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            // `sync` is short for `backups sync`
+            None if command == "sync" => backups(&[vec![OsString::from("sync")], rest.to_vec()].concat()),
+            None => backups(rest),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if command == "replay" {
+        let result = match &host {
+            Some(_) => Err("'replay' runs a local session file and cannot run with --host".to_string()),
+            None => replay(rest),
+        };
+        return match result {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                1
+            }
+        };
+    }
+    if command == "migrate-state" {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            None => migrate_state(rest),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if command == "registry" {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            None => registry_command(rest),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if command == "gc" {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            None => gc(rest),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if matches!(command, "accelerate" | "decelerate") {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            None => accelerate_command(command, rest),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if matches!(command, "status" | "exists") {
+        let code = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest).map(|_| 0),
+            None => parse_disk_command(rest).and_then(|(config, args)| match args.as_slice() {
+                [name] => status(&config, utf8_arg(name)?),
+                [] => Err(format!("{} needs the name of a RAM disk", command)),
+                _ => Err("Too many arguments".to_string()),
+            }),
+        };
+        match code {
+            Ok(code) => return code,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+    }
+    if matches!(command, "list" | "info" | "eject" | "destroy" | "resize" | "overlay" | "adopt" | "features") {
+        let result = match &host {
+            Some(host) => run_remote_disk_command(host, command, rest),
+            None => {
+                // Only eject has --all, for every disk in the state file
+                let all = matches!(command, "eject" | "destroy") && rest.iter().any(|arg| arg == "--all");
+                let rest: Vec<OsString> = rest.iter().filter(|arg| !all || *arg != "--all").cloned().collect();
+                parse_disk_command(&rest).and_then(|(config, args)| {
+                    runner::set_echo(config.echo_commands);
+                    progress::set_events(&config.events)?;
+                    // The image overlaid is a path; everything else here is a name or a size
+                    if command == "overlay" {
+                        return match args.as_slice() {
+                            [source] => overlay(&config, Path::new(source), disks::DEFAULT_SHADOW_SIZE),
+                            [source, size] => overlay(&config, Path::new(source), utf8_arg(size)?),
+                            [] => Err("overlay needs a disk image to attach".to_string()),
+                            _ => Err("Too many arguments".to_string()),
+                        };
+                    }
+                    let args = utf8_args(&args)?;
+                    match (command, args.as_slice()) {
+                        ("eject" | "destroy", []) if all || !config.tags.is_empty() => disks::eject_all(&config).map(|_| ()),
+                        ("eject" | "destroy", _) if all => Err("--all takes no name or device".to_string()),
+                        ("eject" | "destroy", _) if !config.tags.is_empty() => Err("--tag takes no name or device".to_string()),
+                        ("resize", [name, size]) => disks::resize(&config, name, size).map(|_| ()),
+                        ("resize", _) => Err("resize needs the name of a RAM disk and its new size".to_string()),
+                        ("features", []) => list_features(&config),
+                        ("features", _) => Err("features takes no arguments".to_string()),
+                        ("adopt", [device]) => adopt(&config, device),
+                        ("adopt", []) => Err("adopt needs the device of a RAM disk".to_string()),
+                        ("list", []) => list(&config),
+                        ("list", _) => Err("list takes no arguments".to_string()),
+                        ("info", [name]) => info(&config, name),
+                        ("info", []) => Err("info needs the name of a RAM disk".to_string()),
+                        (_, [target]) => eject(&config, target),
+                        (_, []) => Err(format!("{} needs the name or device of a RAM disk", command)),
+                        _ => Err("Too many arguments".to_string()),
+                    }
+                })
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    
+    if command == "daemon" {
+        let result = match &host {
+            Some(_) => Err("'daemon' talks to the mkramdiskd on this machine, and cannot run with --host".to_string()),
+            None => daemon_command(rest),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    if command == "fleet" {
+        let result = match &host {
+            Some(_) => Err("'fleet' runs from this machine against the hosts it is given, and cannot run with --host".to_string()),
+            None => fleet(rest),
+        };
+        match result {
+            Ok(()) => return 0,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+    }
+    if matches!(command, "up" | "down") {
+        let result = match &host {
+            Some(_) => Err(format!("'{}' uses the local project file and cannot run with --host", command)),
+            None => find_projects().and_then(|projects| run_projects(command, &projects, rest)),
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        return 0;
+    }
+    
+    let parsed = user_config::load().and_then(|defaults| parse_args(rest, &defaults));
+    
+    match parsed {
+        Ok(config) => {
+            runner::set_echo(config.echo_commands);
+            // With --host the remote mkramdisk opens the sinks on its side
+            if host.is_none()
+                && let Err(e) = progress::set_events(&config.events)
+            {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+            let result = match (command, &host) {
+                (_, Some(host)) => run_remote(host, command, rest, &config),
+                ("plan", None) => plan_ramdisk(&config),
+                _ => create_ramdisk(&config).and_then(|created| {
+                    if let Some(hook) = &config.after_create {
+                        run_hook(&config, "after_create", hook, &config.name, Some(&created.device), created.mount_point.as_deref())
+                            .map_err(|e| format!("{} (the disk was created as {})", e, created.device))?;
+                    }
+                    if let Some(format) = &config.output {
+                        let sectors = disk_sectors(&config)?;
+                        print!("{}", output::render_one(format, &vec![
+                            ("result", json!("ok")),
+                            ("device", json!(created.device)),
+                            ("mount_point", json!(created.mount_point)),
+                            ("name", json!(config.name)),
+                            ("size", json!(config.size)),
+                            ("size_bytes", json!(sectors * 512)),
+                            ("sectors", json!(sectors)),
+                            ("filesystem", json!(created.filesystem)),
+                            ("backend", json!(config.backend)),
+                            ("partitions", json!(created.partitions)),
+                            ("readonly_device", json!(created.readonly_device)),
+                            ("encrypted", json!(config.encrypt.is_some())),
+                        ])?);
+                    } else if config.summary && !config.actions_json {
+                        let mount_point = created.mount_point.as_deref().map(Path::to_string_lossy).unwrap_or_default();
+                        println!("{}", summary_line(&[
+                            ("RESULT", "ok"),
+                            ("device", &created.device),
+                            ("mount_point", &mount_point),
+                            ("name", &config.name),
+                            ("size", &config.size),
+                            ("filesystem", &created.filesystem),
+                            ("backend", &config.backend),
+                        ]));
+                    }
+                    Ok(())
+                }),
+            };
+            // The last event of a create, whichever way it went
+            if command == "create" && host.is_none() {
+                progress::emit(match &result {
+                    Ok(()) => json!({ "event": "done", "result": "ok" }),
+                    Err(e) => json!({ "event": "done", "result": "error", "error": e }),
+                });
+            }
+            if let Err(e) = result {
+                // A template names fields of the created disk, which there is none of
+                if command == "create" && host.is_none() && let Some(format) = &config.output {
+                    if let Ok(rendered) = output::render_one(format, &vec![("result", json!("error")), ("error", json!(e))]) {
+                        print!("{}", rendered);
+                    }
+                } else if command == "create" && host.is_none() && config.summary {
+                    println!("{}", summary_line(&[("RESULT", "error"), ("error", &e)]));
+                }
+                eprintln!("Error: {}", e);
+                if config.diagnostics {
+                    match diagnostics::write_bundle(&config, &e) {
+                        Ok(dir) => eprintln!("Diagnostics written to {}", dir.display()),
+                        Err(e) => eprintln!("Failed to write diagnostics: {}", e),
+                    }
+                }
+                return 1;
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            print_usage();
+            return 1;
+        }
+    }
+    0
+}
+
+fn print_usage() {
+    println!(r#"
+Usage: mkramdisk [--host HOST] [create] [OPTIONS] <size> [name]
+       mkramdisk [--host HOST] [create] [OPTIONS] --size <size> [--name <name>]
+       mkramdisk [--host HOST] plan [OPTIONS] <size> [name]
+       mkramdisk [--host HOST] from-dmg <image> [OPTIONS] [size] [name]
+       mkramdisk [--host HOST] selftest [OPTIONS]
+       mkramdisk [--host HOST] list [-b BACKEND] [--tag TAG]... [--json|--output FORMAT|--format TEMPLATE]
+       mkramdisk [--host HOST] info [--json|--output FORMAT|--format TEMPLATE] <name>
+       mkramdisk [--host HOST] changes [--since TIME] <name>
+       mkramdisk [--host HOST] backups ls|save|sync|restore|rm|keygen [--store DIR] [name] [snapshot]
+       mkramdisk [--host HOST] sync [--tag TAG]... [--store DIR]
+       mkramdisk [--host HOST] status [--quiet] [--json|--output FORMAT|--format TEMPLATE] <name>
+       mkramdisk [--host HOST] eject [--force] [--unmanaged] [--profile NAME] <name|mount-point|device>
+       mkramdisk [--host HOST] eject --all [--force]
+       mkramdisk [--host HOST] eject --tag TAG... [--force]
+       mkramdisk [--host HOST] resize <name> <size>
+       mkramdisk [--host HOST] overlay [--json|--output FORMAT|--format TEMPLATE] <image> [shadow-size]
+       mkramdisk [--host HOST] adopt [--json|--output FORMAT|--format TEMPLATE] <device>
+       mkramdisk [--host HOST] gc [--dry-run] [--yes]
+       mkramdisk [--host HOST] migrate-state [--dry-run]
+       mkramdisk [--host HOST] registry rebuild [--dry-run] [-b BACKEND]
+       mkramdisk [--host HOST] accelerate <dir> [size]
+       mkramdisk [--host HOST] decelerate [--discard] <dir>
+       mkramdisk up|down [OPTIONS]
+       mkramdisk fleet apply <manifest.toml> --hosts <hosts.txt>
+       mkramdisk daemon reload|stop [--socket PATH]
+       mkramdisk features [--json|--output FORMAT|--format TEMPLATE]
+       mkramdisk --record FILE <any of the above>
+       mkramdisk replay [--dry-run] <session.json>
+
+Create a RAM disk on macOS (or Linux, FreeBSD or Windows) with specified size and optional name.
+
+Commands:
+    create  Create the RAM disk (the default when no command is given)
+    plan    Report the projected memory impact of creating the disk,
+            alongside the RAM the disks mkramdisk created already hold,
+            without creating anything
+    up      Create the disks declared in the nearest .mkramdisk.toml
+            (searching up from the current directory) that are not
+            already up, seed them, create their symlinks and print
+            their env exports; the file describes one disk, or several
+            as [[disk]] tables, each with a name and any depends_on
+            names of disks to bring up before it
+    down    Remove the project's symlinks and tear down its disks
+    fleet   fleet apply creates the disks a manifest (a .mkramdisk.toml
+            without seeds or links) declares on every host in a hosts
+            file, one per line, over ssh and all at once; disks already
+            up are left alone. Prints a JSON result per host and exits
+            1 if any host failed
+    from-dmg
+            Create a RAM disk holding a writable copy of a disk image
+            (sized to fit it unless a size is given); the same as
+            create --from-dmg <image>
+    selftest
+            Create the smallest disk the options allow, write a test
+            pattern, read it back, verify it and eject the disk, printing
+            PASS or FAIL for each step; exits 1 if any step fails
+    list    List the RAM disks the backend has attached: name, device
+            and mount point, one per line, with their tags; --tag TAG
+            (repeatable) lists only those created with every TAG
+    info    Show a RAM disk's device, size, filesystem, mount options,
+            UUID, creation time and space used
+    changes List what changed on a RAM disk since a time (--since 10m,
+            2h, 1d or 2024-05-01 12:00 UTC; default: since it was
+            created), one "A|M|R|D path" per line, from the volume's
+            FSEvents history and what changed on disk since. Useful to
+            decide whether anything needs saving before eject
+    status  Exit 0 if a RAM disk is attached and mounted, 2 if it is
+            attached but not mounted and 1 if there is none, saying
+            which on stdout unless --quiet. Also: exists
+    eject   Unmount a RAM disk and release its memory in one step, given
+            its name, mount point or device (e.g. Scratch, /Volumes/Scratch,
+            /dev/disk5 or disk5), finding the device with diskutil;
+            if it is busy, lsof lists the processes with files open on it,
+            and --force ejects it anyway. Only the backend's RAM disks
+            are ejected, and one mkramdisk didn't create needs
+            --unmanaged. Also: destroy.
+            eject --all tears down every disk mkramdisk created (as the
+            state file has them), newest first, e.g. at the end of a CI
+            job; an accelerated directory is decelerated, its changes
+            written back, and disks already gone are forgotten.
+            eject --tag TAG (repeatable) does the same for just the
+            disks created with every TAG
+    backups Keep copies of RAM disks' files in a backup store, one
+            directory per disk with a metadata.json and its snapshots
+            under snapshots/<time>/:
+              ls [name]                list disks with backups, or the
+                                       snapshots of one
+              save <name>              copy the disk's files into a new
+                                       snapshot
+              sync                     save every mounted disk mkramdisk
+                                       created, or with --tag TAG those
+                                       created with every TAG
+              restore <name> [snapshot]
+                                       copy the latest (or given)
+                                       snapshot back onto the disk,
+                                       only the files that differ from
+                                       the snapshot's checksums when
+                                       the disk still has the rest
+              rm <name> [snapshot]     remove a snapshot, or with --force
+                                       every backup of the disk
+              keygen                   make a key to encrypt backups with
+            The store is --store DIR, else backup_dir in config.toml,
+            else backups in the state directory on macOS (or --state-dir)
+            and $XDG_DATA_HOME/mkramdisk/backups elsewhere. With --encrypt-to
+            RECIPIENT (an age or SSH public key; repeatable, or
+            backup_recipients in config.toml) save keeps the files only
+            as a tar encrypted with age, and restore decrypts it with
+            --identity FILE (or backup_identities); needs age installed.
+            keygen --keychain-item NAME makes a new age key and keeps it
+            only in the macOS Keychain, printing its public key; save
+            and restore with --keychain-item NAME (or
+            backup_keychain_item) then encrypt to it and decrypt with it
+    sync    The same as backups sync, e.g. sync --tag build
+    resize  Move a RAM disk's contents to a new disk of another size,
+            which then takes its name and mount point
+    overlay Attach a disk image copy-on-write, keeping every change in a
+            shadow file on a new RAM disk (of shadow-size, default 1G)
+            so the image is never written; ejecting the image's volume
+            also ejects the shadow disk and drops the changes
+            (experimental: needs the overlay feature)
+    adopt   Record a RAM disk created some other way (e.g. with hdiutil
+            attach ram://...) as one of mkramdisk's, given its device
+            (/dev/disk5 or disk5), so list, info and eject treat it as
+            their own
+    gc      Detach the backend's RAM disks that are attached with nothing
+            mounted, such as those a failed or interrupted create left
+            behind, after listing them on stdout and asking (--yes
+            skips asking, --dry-run only lists them). Raw devices
+            mkramdisk created on purpose are left alone
+    migrate-state
+            Upgrade the state file and config.toml when an older
+            mkramdisk wrote them, so the disks it created stay known,
+            keeping each old file beside it as FILE.bak (--dry-run
+            only says what would change). Every command does this
+            on its own first, saying so on stderr
+    registry rebuild
+            Make the state file match the backend's attached disks, for
+            when it was lost or corrupted: disks that are gone are
+            forgotten, and those whose volume carries the
+            .mkramdisk-disk.json marker every disk mkramdisk creates
+            gets are recorded again (--dry-run only says what would
+            change). A state file that can't be read is kept as
+            state.json.corrupt
+    daemon reload|stop
+            Have mkramdiskd read config.toml again, or stop (syncing or
+            ejecting the disks first if its [daemon] table says so),
+            sending $MKRAMDISKD_TOKEN with the request if it is set
+    accelerate
+            Serve a directory from RAM in place: copy it onto a new RAM
+            disk named after it (twice its size unless a size is given),
+            move it aside to .<dir>.mkramdisk-original and leave a
+            symlink to the disk where it was (experimental: needs the
+            accelerate feature)
+    decelerate
+            Put an accelerated directory back, with the disk's contents
+            written back over the original (or with --discard, the
+            original as it was), and eject the disk
+    features
+            List the experimental features, whether each is on and what
+            turned it on. They are off unless named in features in
+            config.toml or in MKRAMDISK_FEATURES (comma separated), or
+            all turned on with --enable-experimental (anywhere on the
+            command line) or "all" in either list
+    replay  Print a session recorded with --record FILE (every external
+            command run, with its output, and every decision and event,
+            in order) with --dry-run, or run its command line again
+    formats List the filesystems this system can create, usable with -f
+            (formats --experimental also lists experimental ones)
+
+Arguments:
+    size    Size of RAM disk (e.g., 1G, 512M, 2048K)
+            Supports suffixes: K/KB, M/MB, G/GB, T/TB
+            When omitted, taken from the size in
+            ~/.config/mkramdisk/config.toml, or prompted for
+            on a terminal
+    name    Optional name for the RAM disk (default: RAMDisk, or the
+            name in config.toml)
+            May come before the size (mkramdisk Build 2G)
+
+Options:
+        --size SIZE     Size of the RAM disk, instead of the positional argument
+        --name NAME     Name of the RAM disk, instead of the positional argument
+        --host HOST     Run on a remote Mac over ssh (must come first).
+                        Uses mkramdisk on HOST if installed, otherwise
+                        falls back to raw hdiutil/diskutil for create
+    -f, --format FS     Filesystem format (default: apfs)
+                        Supported: apfs, hfs+, fat32, exfat, or a diskutil
+                        personality from `mkramdisk formats`
+        --personality P Format with this diskutil personality exactly as given,
+                        skipping mkramdisk's checks (e.g. a localized name)
+        --partitions LAYOUT
+                        Partition the disk (GPT), e.g. 'p1:apfs:1G,p2:fat32:512M'
+                        (name:filesystem:size); each partition mounts at
+                        its own name and the size defaults to fit them
+        --scheme S      Partition scheme: gpt, mbr, or none (default: none,
+                        the volume fills the bare device; gpt for --partitions)
+        --bootable      Lay out the structure of a bootable disk: a GPT map
+                        with diskutil's EFI system partition (mounted,
+                        with EFI/BOOT created on it) and a data partition;
+                        nothing is installed
+        --protected     Mark the disk protected: eject, destroy and down then
+                        need Touch ID or the account password on macOS, or
+                        its name typed at a terminal elsewhere (delete
+                        .mkramdisk-protected on it to lift this)
+        --readonly-export
+                        Also expose the device read-only at a second node
+                        (a loop device; the zram backend only) for forensics
+                        tools to read its blocks while the volume stays
+                        mounted read-write; removed with the disk
+                        (experimental: needs the readonly-export feature)
+        --encrypt       Create the volume as APFS (Encrypted), locked with a
+                        passphrase asked for twice at the terminal, or read
+                        from where --passphrase-from says
+        --passphrase-from SOURCE
+                        Where --encrypt reads the passphrase: prompt (the
+                        default), stdin (its first line), env (the
+                        MKRAMDISK_PASSPHRASE variable) or env:VAR
+        --keychain      With --encrypt, use the passphrase the login Keychain
+                        keeps for a disk of this name, or keep the one read
+                        now there for next time, so the same disk can be
+                        made again without typing it (macOS only)
+        --tag TAG       Put the disk in the group TAG (repeatable), for list,
+                        eject and sync to act on with --tag TAG
+        --from-dmg IMAGE
+                        Copy a disk image onto the disk instead of formatting
+                        it (asr restore, or a block copy if asr refuses the
+                        image); its volume is renamed to the disk's name,
+                        which defaults to the image's file name
+        --experimental  Allow experimental filesystems for filesystem
+                        development: MS-DOS FAT12, UFS, and "Free Space",
+                        which leaves the device raw with nothing mounted
+        --fallback-format FS
+                        Filesystem to use instead if formatting with the
+                        requested one fails (e.g. hfs+)
+    -b, --backend NAME  Device provider (default: ram on macOS, tmpfs on Linux,
+                        md on FreeBSD, imdisk on Windows)
+                        ram:   hdiutil ram:// device
+                        file:  sparse image file attached via hdiutil
+                        dir:   plain directory on tmpfs, no hdiutil needed
+                        tmpfs: Linux tmpfs mounted at /mnt/<name> (needs
+                               root; -f is ignored)
+                        zram:  Linux compressed RAM device formatted ext4
+                               (default) or btrfs with -f, mounted at
+                               /mnt/<name> (needs root)
+                        md:    FreeBSD swap-backed md(4) memory disk
+                               formatted UFS, mounted at /mnt/<name>
+                               (needs root)
+                        imdisk: Windows ImDisk RAM disk formatted NTFS
+                               (default), exFAT or FAT32 with -f, mounted
+                               at %SystemDrive%\RAMDisks\<name> (needs
+                               an elevated prompt)
+                        mock:  simulated devices for testing mkramdisk
+        --profile NAME  Take defaults from [profile.NAME] in config.toml
+                        instead of its top level (eject runs the profile's
+                        before_eject hook)
+        --mount-options LIST
+                        Remount the volume with these options once it is
+                        mounted, comma-separated (e.g. noatime,nosuid)
+        --mount-timeout T
+                        How long to wait for the volume to mount
+                        (default: 5s; accepts e.g. 30, 30s, 500ms)
+        --keep-on-failure
+                        Leave the device attached if creation fails,
+                        for debugging
+        --auto-min      Raise a size below the filesystem's minimum to that
+                        minimum (with a warning) instead of failing
+        --strict        Treat warnings as errors (for CI)
+        --no-summary    Don't print the RESULT=... line that create writes to
+                        stdout for scripts (other output goes to stderr)
+        --json          Print the result on stdout as one JSON object instead
+                        of the RESULT=... line: for create the device, size
+                        in bytes, sectors, filesystem, mount point and name;
+                        list, info and status take it too
+        --output FORMAT Like --json, in json, yaml, csv or tsv (csv and tsv
+                        print a header row, then a row per result)
+        --api-version N Produce JSON and YAML results and events as API
+                        version N has them (anywhere on the command line),
+                        or fail if this mkramdisk can't. Each carries its
+                        "api_version"; within a version fields are only
+                        added, and a version stays available for two minor
+                        releases after the next replaces it
+        --state-dir DIR Keep the state file, logs and default backup store in
+                        DIR (anywhere on the command line; config.toml stays
+                        where it is)
+        --print-path    Print nothing on stdout but the mount point, for
+                        cd "$(mkramdisk --print-path 1G)"; the same as
+                        --format '{{mount_point}}'
+        --format TEMPLATE
+                        Print just the fields named in TEMPLATE, a line per
+                        result, e.g. --format '{{device}} {{mount_point}}' (any
+                        --format value with a brace is a template; {{{{ and }}}}
+                        are literal braces). The fields are those of --json
+        --print-actions F
+                        How to print the follow-up commands after create:
+                        text (default) or json (on stdout, for GUIs)
+        --events ndjson Deprecated: the same as --events-to stderr
+        --events-to SINK
+                        Also write events, one JSON object per line, to
+                        SINK: stderr, file:PATH (appended to), syslog,
+                        unix:PATH (a listening stream socket) or stdout;
+                        may be given more than once. Events report progress
+                        of long operations (such as seeding a project disk)
+                        and each disk created or ejected
+        --json-lines    Write events on stdout, one JSON object per line, in
+                        place of the summary line: attach_started,
+                        device_acquired, format_started, mounted, created
+                        and, last, done with its result (ok or error)
+        --preserve LIST File metadata kept when copying files onto a disk
+                        (seeding and resize): any of xattr (including
+                        resource forks and quarantine), acl, flags and
+                        times, comma-separated, or all (default) or none
+        --strip         Copy contents and permissions only, for reproducible
+                        test data; the same as --preserve none
+        --links POLICY  What seeding and resize do with symlinks: preserve
+                        them as links (default), follow them and copy what
+                        they point to, or skip them
+        --copy-path     Copy the new mount point to the clipboard (pbcopy)
+        --legacy-output Print progress and results on stdout, without the
+                        RESULT=... line, as older versions did
+        --diagnostics   On failure, write a diagnostics bundle (command
+                        transcript, hdiutil/diskutil state, memory stats)
+                        to a temp directory for bug reports
+        --name-from-git Name the disk after the current git repository and
+                        branch, e.g. "myapp-feature-login"
+    -v, --verbose       Show detailed output; repeat (-vv) to also echo every
+                        external command with its exit status and output
+        --force         Create the disk even under heavy memory pressure
+        --allow-system-name
+                        Allow a name macOS uses for its own volumes
+                        (Macintosh HD, Data, Preboot, Recovery, VM, Update)
+    -V, --version       Show version, build and feature information
+    -h, --help         Show this help message
+
+Configuration:
+    ~/.config/mkramdisk/config.toml (or $MKRAMDISK_CONFIG) sets defaults,
+    which options on the command line override:
+        size = "2G"
+        filesystem = "hfs+"
+        name = "Scratch"
+        verbose = true
+        mount_options = ["noatime"]
+        after_create = "mkdir $MKRAMDISK_MOUNT_POINT/tmp"
+    and [profile.NAME] tables hold the same keys for --profile NAME:
+        [profile.xcode]
+        size = "8G"
+        fs = "apfs"
+        name = "DerivedData"
+        protected = true
+    Hooks (after_create, and before_eject run by eject) go through sh -c
+    and get MKRAMDISK_NAME, MKRAMDISK_DEVICE and
+    MKRAMDISK_MOUNT_POINT in their environment.
+    Disks mkramdisk creates are recorded in state.json in the state
+    directory, ~/Library/Application Support/mkramdisk on macOS and
+    $XDG_STATE_HOME/mkramdisk (~/.local/state/mkramdisk) elsewhere, or
+    --state-dir, or in $MKRAMDISK_STATE, until it ejects them; list and info show which disks those are.
+    Options that have been replaced keep working for at least two minor
+    releases, with a warning naming the replacement the first time each
+    is used; silence them with MKRAMDISK_NO_DEPRECATION_WARNINGS=1 or
+    deprecation_warnings = false.
+    At most max_commands hdiutil and diskutil commands (default 4, or
+    $MKRAMDISK_MAX_COMMANDS) run at once across every mkramdisk process,
+    and those that change disks run one at a time, a whole create or eject
+    at once, so scripts creating disks in parallel don't trip up
+    DiskArbitration. A command waiting longer than 300 seconds (or
+    $MKRAMDISK_LOCK_TIMEOUT) for its turn fails.
+
+Examples:
+    mkramdisk 1G                    # Create 1GB APFS RAM disk named "RAMDisk"
+    mkramdisk 512M MyRAM            # Create 512MB APFS RAM disk named "MyRAM"
+    mkramdisk -f hfs+ 2G TempDisk   # Create 2GB HFS+ RAM disk named "TempDisk"
+    mkramdisk --format fat32 256M   # Create 256MB FAT32 RAM disk
+    mkramdisk plan 8G               # Check whether an 8GB RAM disk fits in memory
+    mkramdisk --format '{{mount_point}}' 1G   # Print only where the disk is mounted
+    mkramdisk --profile xcode       # Create the disk [profile.xcode] describes
+    mkramdisk --host mac-mini-1 create 2G   # Create a 2GB RAM disk over ssh
+    mkramdisk fleet apply ci.toml --hosts hosts.txt   # The same disks on every CI Mac
+"#);
+}
+
+fn print_version() {
+    println!(
+        "mkramdisk {} ({} {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("MKRAMDISK_GIT_COMMIT"),
+        env!("MKRAMDISK_BUILD_DATE")
+    );
+    println!("backends: {}", provider::BACKENDS.join(", "));
+    let versions: Vec<String> = api::SUPPORTED.iter().map(u32::to_string).collect();
+    println!("api versions: {} (default {})", versions.join(", "), api::CURRENT);
+    if cfg!(feature = "otel") {
+        println!("features: otel");
+    }
+}
+
+// Options that take a value; anything else starting with '-' is a flag
+/// The fields of `create`'s result, for `--json`, `--output` and `--format` templates.
+const CREATE_FIELDS: &[&str] = &["result", "device", "mount_point", "name", "size", "size_bytes", "sectors", "filesystem", "backend", "partitions", "readonly_device", "encrypted"];
+
+const VALUE_OPTIONS: &[&str] = &["-f", "--format", "-b", "--backend", "--mount-timeout", "--mount-options", "--profile", "--print-actions", "--fallback-format", "--personality", "--partitions", "--scheme", "--from-dmg", "--events", "--events-to", "--output", "--preserve", "--links", "--size", "--name", "--passphrase-from", "--tag"];
+
+/// Split `--option=value` and expand combined short flags (`-vf apfs` becomes
+/// `-v -f apfs`, `-fapfs` becomes `-f apfs`), so parsing sees one option per argument.
+fn normalize_args(args: &[OsString]) -> Result<Vec<OsString>, String> {
+    let mut normalized = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            normalized.push(arg.clone());
+            normalized.extend(iter.cloned());
+            break;
+        }
+        // Only a path can be other than UTF-8, as the value of an option or on its own
+        let Some(arg) = arg.to_str() else {
+            match split_option(arg) {
+                Some((option, value)) if VALUE_OPTIONS.contains(&option.as_str()) => normalized.extend([OsString::from(option), value]),
+                Some((option, _)) if option.starts_with("--") => return Err(format!("Option {} does not take a value", option)),
+                _ => normalized.push(arg.clone()),
+            }
+            continue;
+        };
+        if arg.starts_with("--") {
+            match arg.split_once('=') {
+                Some((option, value)) if VALUE_OPTIONS.contains(&option) => {
+                    normalized.push(option.into());
+                    normalized.push(value.into());
+                }
+                Some((option, _)) => return Err(format!("Option {} does not take a value", option)),
+                None => normalized.push(arg.into()),
+            }
+        } else if arg.len() > 2 && arg.starts_with('-') && !is_negative_number(arg) {
+            for (pos, c) in arg.char_indices().skip(1) {
+                let option = format!("-{}", c);
+                let takes_value = VALUE_OPTIONS.contains(&option.as_str());
+                normalized.push(option.into());
+                let rest = &arg[pos + c.len_utf8()..];
+                if takes_value {
+                    if !rest.is_empty() {
+                        normalized.push(rest.into());
+                    }
+                    break;
+                }
+            }
+        } else {
+            normalized.push(arg.into());
+        }
+    }
+    Ok(normalized)
+}
+
+// `--option=value` as the option and its value, the value as it was given
+fn split_option(arg: &OsStr) -> Option<(String, OsString)> {
+    let bytes = path_bytes(Path::new(arg));
+    let at = bytes.iter().position(|&b| b == b'=')?;
+    let option = String::from_utf8(bytes[..at].to_vec()).ok()?;
+    Some((option, path_from_bytes(&bytes[at + 1..]).into_os_string()))
+}
+
+// "-1G" is a (bad) size, not a cluster of short flags
+fn is_negative_number(arg: &str) -> bool {
+    arg.strip_prefix('-').is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+// "help" or "version" if `args` ask for either among their options, skipping the
+// values of options that take one
+fn help_or_version(args: &[OsString]) -> Option<&'static str> {
+    let args = normalize_args(args).ok()?;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].to_str() {
+            Some("--") => return None,
+            Some("-h" | "--help") => return Some("help"),
+            Some("-V" | "--version") => return Some("version"),
+            Some(option) if VALUE_OPTIONS.contains(&option) => i += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn option_value(args: &[OsString], i: usize) -> Result<&str, String> {
+    utf8_arg(option_path(args, i)?)
+}
+
+// The value of an option that names a file or directory, which needn't be UTF-8
+fn option_path(args: &[OsString], i: usize) -> Result<&OsStr, String> {
+    args.get(i + 1).map(OsString::as_os_str).ok_or_else(|| format!("{} requires a value", args[i].to_string_lossy()))
+}
+
+// Arguments that have to be text, such as names and sizes
+fn utf8_args(args: &[OsString]) -> Result<Vec<String>, String> {
+    args.iter().map(|arg| utf8_arg(arg).map(str::to_string)).collect()
+}
+
+fn parse_args(args: &[OsString], defaults: &user_config::UserConfig) -> Result<Config, String> {
+    let args = normalize_args(args)?;
+    let mut config = Config::default();
+    let mut size = None;
+    let mut name = None;
+    let mut positional = Vec::new();
+    let mut name_from_git = false;
+    let mut filesystem_given = false;
+    let mut mount_options = None;
+    let mut profile = None;
+    let mut encrypt = false;
+    let mut passphrase_from = None;
+    let mut keychain = false;
+    let mut options_done = false;
+    let mut i = 0;
+    
+    while i < args.len() {
+        let arg = utf8_arg(&args[i])?;
+        if options_done || !arg.starts_with('-') || arg == "-" || is_negative_number(arg) {
+            positional.push(arg.to_string());
+            i += 1;
+            continue;
+        }
+        
+        match arg {
+            "--" => options_done = true,
+            "-h" | "--help" => {
+                print_usage();
+                std::process::exit(0);
+            }
+            "-V" | "--version" => {
+                print_version();
+                std::process::exit(0);
+            }
+            "-v" | "--verbose" => {
+                config.echo_commands = config.verbose;
+                config.verbose = true;
+            }
+            "--force" => config.force = true,
+            "--allow-system-name" => config.allow_system_name = true,
+            "--keep-on-failure" => config.keep_on_failure = true,
+            "--diagnostics" => config.diagnostics = true,
+            "--strict" => config.strict = true,
+            "--no-summary" => config.summary = false,
+            "--json" => config.output = Some(output::Format::Json),
+            "--output" => {
+                config.output = Some(output::Format::parse(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--copy-path" => config.copy_path = true,
+            // cd "$(mkramdisk --print-path 1G)"
+            "--print-path" => config.output = Some(output::Format::Template("{mount_point}".to_string())),
+            "--auto-min" => config.auto_min = true,
+            "--experimental" => config.experimental = true,
+            "--bootable" => config.bootable = true,
+            "--protected" => config.protected = true,
+            "--encrypt" => encrypt = true,
+            "--keychain" => keychain = true,
+            "--passphrase-from" => {
+                passphrase_from = Some(passphrase::Source::parse(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--readonly-export" => {
+                features::require("readonly-export")?;
+                config.readonly_export = true;
+            }
+            "--legacy-output" => {
+                config.legacy_output = true;
+                config.summary = false;
+            }
+            "--name-from-git" => name_from_git = true,
+            "--size" => {
+                if size.replace(option_value(&args, i)?.to_string()).is_some() {
+                    return Err("Size given more than once".to_string());
+                }
+                i += 1;
+            }
+            "--name" => {
+                if name.replace(option_value(&args, i)?.to_string()).is_some() {
+                    return Err("Name given more than once".to_string());
+                }
+                i += 1;
+            }
+            "-b" | "--backend" => {
+                config.backend = option_value(&args, i)?.to_string();
+                i += 1;
+            }
+            "--print-actions" => {
+                config.actions_json = match option_value(&args, i)? {
+                    "text" => false,
+                    "json" => true,
+                    other => return Err(format!("Unknown --print-actions format: {} (expected text or json)", other)),
+                };
+                i += 1;
+            }
+            "--json-lines" => {
+                config.events.push(progress::Sink::Stdout);
+                config.summary = false;
+            }
+            "--events" => {
+                config.events.push(parse_events(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--events-to" => {
+                config.events.push(progress::Sink::parse(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--preserve" => {
+                config.copy.preserve = attributes::Preserve::parse(option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--strip" => config.copy.preserve = attributes::Preserve::none(),
+            "--links" => {
+                config.copy.links = copier::LinkPolicy::parse(option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--profile" => {
+                profile = Some(option_value(&args, i)?.to_string());
+                i += 1;
+            }
+            "--mount-options" => {
+                mount_options = Some(option_value(&args, i)?.split(',').map(str::to_string).collect());
+                i += 1;
+            }
+            "--tag" => {
+                add_tag(&mut config.tags, option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--mount-timeout" => {
+                config.mount_timeout = parse_duration(option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--fallback-format" => {
+                config.fallback_format = Some(option_value(&args, i)?.to_string());
+                i += 1;
+            }
+            "--partitions" => {
+                config.partitions = partitions::parse(option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--scheme" => {
+                config.scheme = Some(partitions::Scheme::parse(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--from-dmg" => {
+                config.source_image = Some(PathBuf::from(option_path(&args, i)?));
+                i += 1;
+            }
+            "--personality" => {
+                config.personality = Some(option_value(&args, i)?.to_string());
+                i += 1;
+            }
+            // No filesystem has a brace in its name, so one makes the value an output template
+            "--format" if option_value(&args, i)?.contains('{') => {
+                let template = option_value(&args, i)?;
+                output::check_template(template, CREATE_FIELDS)?;
+                config.output = Some(output::Format::Template(template.to_string()));
+                i += 1;
+            }
+            "-f" | "--format" => {
+                config.filesystem = option_value(&args, i)?.to_string();
+                filesystem_given = true;
+                i += 1;
+            }
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+        i += 1;
+    }
+
+    if passphrase_from.is_some() && !encrypt {
+        return Err("--passphrase-from only applies with --encrypt".to_string());
+    }
+    if keychain && !encrypt {
+        return Err("--keychain only applies with --encrypt".to_string());
+    }
+    config.encrypt = encrypt.then(|| passphrase_from.unwrap_or(passphrase::Source::Prompt));
+
+    if config.events.contains(&progress::Sink::Stdout) && (config.output.is_some() || config.actions_json || config.legacy_output) {
+        return Err("Events on stdout (--json-lines) cannot be combined with --json, --output, --format templates, --print-path, --print-actions json or --legacy-output".to_string());
+    }
+    if config.output.is_some() && (config.actions_json || config.legacy_output) {
+        return Err("--json, --output and --format templates cannot be combined with --print-actions json or --legacy-output, which also write to stdout".to_string());
+    }
+    
+    // config.toml fills in what the command line left out
+    let defaults = match &profile {
+        Some(profile) => &defaults.with_profile(profile)?,
+        None => defaults,
+    };
+    config.after_create = defaults.after_create.clone();
+    config.before_eject = defaults.before_eject.clone();
+    config.verbose |= defaults.verbose.unwrap_or(false);
+    config.protected |= defaults.protected.unwrap_or(false);
+    config.mount_options = match mount_options.or_else(|| defaults.mount_options.clone()) {
+        Some(options) => validate_mount_options(options)?,
+        None => Vec::new(),
+    };
+    if let Some(filesystem) = &defaults.filesystem
+        && !filesystem_given
+        && config.source_image.is_none()
+        && config.partitions.is_empty()
+        && config.personality.is_none()
+        && formats::backend_filesystems(&config.backend).is_none_or(|_| formats::backend_filesystem(&config.backend, filesystem).is_ok())
+    {
+        config.filesystem = filesystem.clone();
+    }
+    
+    // Positionals fill in whatever --size and --name left open. With both open, the
+    // size is whichever argument parses as one, so `mkramdisk Build 2G` works too.
+    if size.is_none() && name.is_none() && positional.len() == 2
+        && parse_size(&positional[0]).is_err() && parse_size(&positional[1]).is_ok()
+    {
+        positional.swap(0, 1);
+    }
+    // A disk image sizes the disk itself, so a lone positional only counts as a size if it is one
+    let sized_by_image = config.source_image.is_some()
+        && positional.first().is_none_or(|arg| parse_size(arg).is_err());
+    let mut positional = positional.into_iter().peekable();
+    if size.is_none() && !sized_by_image {
+        size = positional.next();
+    } else if name.is_none() && positional.peek().is_some_and(|arg| parse_size(arg).is_ok()) {
+        return Err("Size given more than once".to_string());
+    }
+    if name.is_none() {
+        name = positional.next();
+    }
+    if positional.next().is_some() {
+        return Err("Too many arguments".to_string());
+    }
+    
+    // A partitioned disk is named after its first partition and sized to fit them all
+    if !config.partitions.is_empty() {
+        if name.is_some() || name_from_git {
+            return Err("--partitions names each partition, so the disk cannot be named too".to_string());
+        }
+        name = Some(config.partitions[0].name.clone());
+        size = size.or_else(|| Some(partitions::total_size(&config.partitions)));
+    }
+    
+    if let Some(image) = &config.source_image {
+        if config.bootable || !config.partitions.is_empty() || config.scheme.is_some() || config.personality.is_some()
+            || config.filesystem != Config::default().filesystem
+        {
+            return Err("--from-dmg copies the image's own layout and filesystems, so it cannot be combined with options that choose them".to_string());
+        }
+        if matches!(config.backend.as_str(), "dir" | "tmpfs") {
+            return Err(format!("The {} backend has no device to restore an image onto", config.backend));
+        }
+        let image_bytes = provider::select_provider(&config.backend, "")?.image_bytes(image)?;
+        if let Some(size) = &size
+            && parse_size(size).is_ok_and(|bytes| bytes < image_bytes)
+        {
+            return Err(format!("{} is too small to hold {} ({} bytes)", size, image.display(), image_bytes));
+        }
+        size = size.or_else(|| Some(image_bytes.to_string()));
+        name = name.or_else(|| image.file_stem().map(|stem| stem.to_string_lossy().into_owned()));
+        config.filesystem = "image".to_string();
+    }
+    
+    config.size = match size.or_else(|| defaults.size.clone()) {
+        Some(size) => size,
+        None if io::stdin().is_terminal() => prompt_for_size()?,
+        None => return Err("Size argument is required".to_string()),
+    };
+    // `mkramdisk 512 M` splits the size in two and would name the disk "M"
+    if let Some(name) = &name
+        && config.size.chars().all(|c| c.is_ascii_digit())
+        && let Some(suffix) = size_unit(&name.to_uppercase()).filter(|suffix| !suffix.is_empty())
+    {
+        return Err(format!(
+            "Size '{} {}' contains a space\nDid you mean {}{}?",
+            config.size, name, config.size, suffix
+        ));
+    }
+    if let Some(name) = name.or_else(|| defaults.name.clone().filter(|_| !name_from_git)) {
+        config.name = name;
+    }
+    
+    if name_from_git {
+        if config.name != Config::default().name {
+            return Err("--name-from-git cannot be combined with a name".to_string());
+        }
+        config.name = git_volume_name()?;
+    }
+    
+    if config.bootable {
+        if !config.partitions.is_empty() {
+            return Err("--bootable lays out its own partitions and cannot be combined with --partitions".to_string());
+        }
+        if config.scheme.is_some_and(|scheme| scheme != partitions::Scheme::Gpt) {
+            return Err("--bootable needs a GPT partition map".to_string());
+        }
+        let data = match &config.personality {
+            Some(personality) => personality.clone(),
+            None => get_diskutil_format(&config.filesystem)?,
+        };
+        if config.name == partitions::ESP_NAME {
+            return Err(format!("'{}' is the EFI system partition's name; pick another disk name", config.name));
+        }
+        config.partitions = partitions::bootable(&config.name, &data);
+        let needed = partitions::bootable_bytes(filesystem_minimum_bytes(&data));
+        if parse_size(&config.size).is_ok_and(|bytes| bytes < needed) {
+            return Err(format!(
+                "A bootable {} disk needs at least {} (EFI system partition plus data)",
+                config.filesystem,
+                memory::format_size(needed)
+            ));
+        }
+    }
+    
+    // zram and md disks are formatted with mkfs or newfs, which take their own filesystems
+    if let Some(filesystems) = formats::backend_filesystems(&config.backend)
+        && config.source_image.is_none()
+        && config.partitions.is_empty()
+        && config.personality.is_none()
+    {
+        let filesystem = if config.filesystem == Config::default().filesystem {
+            filesystems[0]
+        } else {
+            formats::backend_filesystem(&config.backend, &config.filesystem)?
+        };
+        config.filesystem = filesystem.to_string();
+        config.personality = Some(filesystem.to_string());
+    }
+    
+    // Validate filesystem format early; an explicit personality is passed to diskutil as is
+    if config.source_image.is_some() {
+        // Whatever the image holds
+    } else if !config.partitions.is_empty() {
+        for partition in &config.partitions {
+            let minimum = filesystem_minimum_bytes(&partition.personality);
+            if partition.bytes.is_some_and(|bytes| bytes < minimum) {
+                return Err(format!(
+                    "Partition '{}' is too small for {}; it needs at least {}",
+                    partition.name, partition.personality, memory::format_size(minimum)
+                ));
+            }
+        }
+        match (config.scheme, config.partitions.len()) {
+            (Some(partitions::Scheme::Bare), _) => {
+                return Err("--partitions needs a partition scheme (gpt or mbr)".to_string());
+            }
+            (Some(partitions::Scheme::Mbr), count) if count > 4 => {
+                return Err("MBR holds at most 4 partitions; use --scheme gpt".to_string());
+            }
+            _ => {}
+        }
+        config.filesystem = config.scheme.unwrap_or(partitions::Scheme::Gpt).to_string();
+        let needed: u64 = config.partitions.iter().filter_map(|p| p.bytes).sum();
+        if parse_size(&config.size).is_ok_and(|bytes| bytes < needed) {
+            return Err(format!("A {} disk cannot hold partitions totalling {} bytes", config.size, needed));
+        }
+    } else if let Some(personality) = &config.personality {
+        config.filesystem = personality.clone();
+    } else if let Some(personality) = formats::experimental_personality(&config.filesystem) {
+        if !config.experimental {
+            return Err(format!("{} is experimental; pass --experimental to use it", personality));
+        }
+        config.personality = Some(personality.to_string());
+        config.filesystem = personality.to_string();
+    } else {
+        validate_filesystem(&config.filesystem)?;
+    }
+    if let Some(fallback) = &config.fallback_format {
+        validate_filesystem(fallback)?;
+    }
+    if config.encrypt.is_some() {
+        if config.source_image.is_some() || !config.partitions.is_empty() || config.fallback_format.is_some() {
+            return Err("--encrypt formats a single APFS volume and cannot be combined with --from-dmg, --partitions, --bootable or --fallback-format".to_string());
+        }
+        if config.scheme.is_some() {
+            return Err("--encrypt puts its APFS container on the bare device and cannot be combined with --scheme".to_string());
+        }
+        let personality = diskutil_format(&config)?;
+        if !personality.ends_with("APFS") {
+            return Err(format!("--encrypt needs an APFS filesystem, not {}", personality));
+        }
+    }
+    
+    if !provider::BACKENDS.contains(&config.backend.as_str()) {
+        return Err(format!(
+            "Unsupported backend: {}\nSupported backends: {}",
+            config.backend,
+            provider::BACKENDS.join(", ")
+        ));
+    }
+    if matches!(config.backend.as_str(), "dir" | "tmpfs") && config.personality.as_deref() == Some(formats::RAW) {
+        return Err(format!("The {} backend has no device to leave raw", config.backend));
+    }
+    // Refused before a disk is made only for the export to fail
+    if config.readonly_export && !provider::READONLY_EXPORT_BACKENDS.contains(&config.backend.as_str()) {
+        return Err(format!("--readonly-export needs the zram backend; the {} backend cannot expose a device read-only", config.backend));
+    }
+    // --print-path is the {mount_point} template, which a raw device would leave empty
+    if config.output == Some(output::Format::Template("{mount_point}".to_string())) && diskutil_format(&config)? == formats::RAW {
+        return Err("--print-path has nothing to print for a raw device, which is never mounted; use --output '{device}'".to_string());
+    }
+    
+    let (minimum, filesystem) = disk_minimum_bytes(&config);
+    if config.auto_min && parse_size(&config.size).is_ok_and(|bytes| bytes < minimum) {
+        let bumped = memory::format_size(minimum);
+        warn(&config, &format!(
+            "{} is below the {} minimum; creating a {} disk instead",
+            config.size, filesystem, bumped
+        ))?;
+        config.size = bumped;
+    }
+    
+    // Sanitize volume name
+    let sanitized = sanitize_volume_name(&config.name);
+    if sanitized.is_empty() {
+        return Err(format!(
+            "Volume name '{}' has nothing left once unsupported characters are removed; use letters, digits, spaces, '-' or '_'",
+            config.name
+        ));
+    }
+    if sanitized != config.name {
+        warn(&config, &format!("Volume name '{}' was sanitized to '{}'", config.name, sanitized))?;
+    }
+    config.name = sanitized;
+    
+    if diskutil_format(&config)?.starts_with("MS-DOS")
+        && config.name.chars().count() > FAT_LABEL_MAX
+    {
+        let truncated: String = config.name.chars().take(FAT_LABEL_MAX).collect();
+        warn(&config, &format!(
+            "FAT volume labels are limited to {} characters; '{}' was truncated to '{}'",
+            FAT_LABEL_MAX, config.name, truncated
+        ))?;
+        config.name = truncated.trim_end().to_string();
+    }
+    for name in std::iter::once(&config.name).chain(config.partitions.iter().map(|p| &p.name)) {
+        validate_volume_name(name)?;
+        check_volume_name(name, config.allow_system_name)?;
+    }
+    if let Some(fallback) = &config.fallback_format
+        && get_diskutil_format(fallback)?.starts_with("MS-DOS")
+        && fat_label(&config.name) != config.name
+    {
+        let label = fat_label(&config.name);
+        validate_volume_name(&label)?;
+        warn(&config, &format!(
+            "Should formatting fall back to {}, the volume is labelled '{}': FAT keeps labels to {} characters, in upper case",
+            fallback, label, FAT_LABEL_MAX
+        ))?;
+    }
+    
+    // Looked up by the name the disk ends up with
+    if keychain && let Some(source) = config.encrypt.take() {
+        config.encrypt = Some(passphrase::Source::Keychain { item: passphrase::keychain_item(&config.name), otherwise: Box::new(source) });
+    }
+    
+    Ok(config)
+}
+
+// A --tag given twice counts once
+fn add_tag(tags: &mut Vec<String>, tag: &str) -> Result<(), String> {
+    validate_tag(tag)?;
+    if !tags.iter().any(|known| known == tag) {
+        tags.push(tag.to_string());
+    }
+    Ok(())
+}
+
+fn validate_mount_options(options: Vec<String>) -> Result<Vec<String>, String> {
+    if let Some(option) = options.iter().find(|option| option.is_empty() || option.contains(|c: char| c.is_whitespace() || c == ',')) {
+        return Err(format!("Invalid mount option '{}': options are comma-separated words like noatime", option));
+    }
+    Ok(options)
+}
+
+fn parse_events(format: &str) -> Result<progress::Sink, String> {
+    match format {
+        "ndjson" => Ok(progress::Sink::Stderr),
+        other => Err(format!("Unknown --events format: {} (expected ndjson)", other)),
+    }
+}
+
+fn git_output(args: &[&str]) -> Result<String, String> {
+    let output = runner::output(std::process::Command::new("git").args(args))
+        .map_err(|e| format!("Failed to execute git: {}", e))?;
+    if !output.status.success() {
+        return Err("--name-from-git must be run inside a git repository".to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Volume name for the current git repository and branch, e.g. "myapp-feature-login".
+fn git_volume_name() -> Result<String, String> {
+    let toplevel = git_output(&["rev-parse", "--show-toplevel"])?;
+    let mut branch = git_output(&["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if branch == "HEAD" {
+        // Detached HEAD: use the commit instead
+        branch = git_output(&["rev-parse", "--short", "HEAD"])?;
+    }
+    let repo = Path::new(&toplevel)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok(git_name(&repo, &branch))
+}
+
+fn git_name(repo: &str, branch: &str) -> String {
+    // Branch separators would otherwise be dropped by sanitizing, gluing words together
+    sanitize_volume_name(&format!("{}-{}", repo, branch.replace(['/', '.'], "-")))
+}
+
+/// Ask for a size on the terminal, offering a tenth of physical memory as the default.
+fn prompt_for_size() -> Result<String, String> {
+    let default = memory::physical_memory()
+        .map(|bytes| memory::format_size(bytes / 10))
+        .unwrap_or_else(|| "1G".to_string());
+    loop {
+        eprint!("Size of RAM disk [{}]: ", default);
+        io::stderr().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("Size argument is required".to_string());
+        }
+        let size = match line.trim() {
+            "" => default.clone(),
+            size => size.to_string(),
+        };
+        match size_to_sectors(&size) {
+            Ok(_) => return Ok(size),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(pos) => (&value[..pos], &value[pos..]),
+        None => (value, "s"),
+    };
+    let number: f64 = number.parse()
+        .map_err(|_| format!("Invalid duration: {}", value))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(format!("Unknown duration unit in {} (use ms, s or m)", value)),
+    };
+    if !seconds.is_finite() {
+        return Err(format!("Invalid duration: {}", value));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Duration {} is too long", value))
+}
+
+fn list_formats(host: Option<&str>, args: &[String]) -> Result<(), String> {
+    if host.is_some() {
+        return Err("'formats' lists local filesystems and cannot run with --host".to_string());
+    }
+    let experimental = match args {
+        [] => false,
+        [flag] if flag == "--experimental" => true,
+        [arg, ..] => return Err(format!("Unexpected argument to formats: {}", arg)),
+    };
+    for personality in formats::available(formats::SAFE_PERSONALITIES)? {
+        let number = formats::SAFE_PERSONALITIES.iter().position(|p| *p == personality).unwrap_or(0) + 1;
+        let names: Vec<String> = std::iter::once(number.to_string())
+            .chain(formats::ALIASES.iter().filter(|(_, p)| *p == personality).map(|(alias, _)| alias.to_string()))
+            .collect();
+        println!("{:<30}-f {}", personality, names.join(", -f "));
+    }
+    if experimental {
+        for personality in formats::available(formats::EXPERIMENTAL_PERSONALITIES)? {
+            println!("{:<30}--experimental -f '{}'", personality, personality);
+        }
+    }
+    Ok(())
+}
+
+/// One `key=value` line for scripts, quoted so it can also be `eval`ed by a shell. Line
+/// breaks in a value, as in a multi-line error, are written as `\n` so the line stays
+/// one line.
+fn summary_line(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, remote::shell_quote(&value.replace('\r', "").replace('\n', "\\n"))))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn plan_ramdisk(config: &Config) -> Result<(), String> {
+    let plan = memory::plan(config)?;
+    let status = &plan.status;
+    
+    println!("Plan for {} {} RAM disk '{}' ({} sectors)", config.size, config.filesystem, config.name, plan.sectors);
+    println!("  Available now:     {}", memory::format_size(status.available));
+    println!("  Available after:   {}", memory::format_size(status.available.saturating_sub(plan.bytes)));
+    println!("  Managed disks:     {} holding {}", plan.managed, memory::format_size(plan.committed));
+    println!("  Managed after:     {}", memory::format_size(plan.committed + plan.bytes));
+    println!("  Suggested maximum: {}", status.suggested_max().as_deref().unwrap_or("none"));
+    println!("  Swap used:         {} of {}", memory::format_size(status.swap_used), memory::format_size(status.swap_total));
+    match status.compression_ratio() {
+        Some(ratio) => println!("  Compressor ratio:  {:.1}x", ratio),
+        None => println!("  Compressor ratio:  idle"),
+    }
+    println!();
+    println!("RAM disk pages are wired: they are never compressed or swapped, so other");
+    println!("processes absorb the pressure through compression and swap instead.");
+    println!();
+    
+    if status.is_under_heavy_pressure() {
+        println!("\x1b[1;31mVerdict:\x1b[0m system is already under heavy memory pressure; creation requires --force");
+    } else if plan.bytes > status.available {
+        println!("\x1b[1;31mVerdict:\x1b[0m exceeds available memory");
+    } else if plan.bytes > status.safe_max_bytes() {
+        println!("\x1b[1;33mVerdict:\x1b[0m exceeds the suggested maximum of {}", status.suggested_max().as_deref().unwrap_or("none"));
+    } else {
+        println!("\x1b[1;32mVerdict:\x1b[0m fits within the suggested maximum");
+    }
+    
+    Ok(())
+}
+
+fn run_remote(host: &str, command: &str, args: &[OsString], config: &Config) -> Result<(), String> {
+    if remote::has_remote_mkramdisk(host) {
+        log_verbose(config, &format!("Running mkramdisk {} on {}", command, host));
+        let args = match command {
+            "create" | "plan" => remote_create_args(args, config)?,
+            _ => utf8_args(args)?,
+        };
+        let mut command_line = format!("mkramdisk{} {}", remote_api_version(), command);
+        for arg in &args {
+            command_line.push(' ');
+            command_line.push_str(&remote::shell_quote(arg));
+        }
+        return remote::run_ssh(host, &command_line);
+    }
+    
+    if command != "create" {
+        return Err(format!("mkramdisk is not installed on {}; '{}' requires it", host, command));
+    }
+    
+    if config.source_image.is_some() {
+        return Err(format!("mkramdisk is not installed on {}; --from-dmg requires it", host));
+    }
+    log_verbose(config, &format!("mkramdisk not found on {}, falling back to hdiutil/diskutil", host));
+    let sectors = disk_sectors(config)?;
+    let diskutil_format = diskutil_format(config)?;
+    remote::run_ssh(host, &remote::fallback_create_script(sectors, &diskutil_format, &config.name))
+}
+
+// The arguments for the remote mkramdisk to create the disk `config` describes. What
+// was resolved here (a size asked for at the terminal, config.toml and profile
+// defaults, a name from git) is passed explicitly, in place of whatever it came from;
+// every other option goes as it was given.
+fn remote_create_args(args: &[OsString], config: &Config) -> Result<Vec<String>, String> {
+    // The remote command line is text, so a path given here has to be UTF-8 too
+    let args = utf8_args(&normalize_args(args)?)?;
+    let mut forwarded = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "--" {
+            break;
+        }
+        let takes_value = VALUE_OPTIONS.contains(&arg);
+        let value = args.get(i + 1).filter(|_| takes_value);
+        let replaced = match arg {
+            "--size" | "--name" | "--profile" | "--mount-options" | "--name-from-git" | "--protected" => true,
+            // A --format with braces is an output template, which stays
+            "-f" | "--format" => value.is_none_or(|value| !value.contains('{')),
+            _ => !arg.starts_with('-') || arg == "-" || is_negative_number(arg),
+        };
+        if !replaced {
+            forwarded.push(arg.to_string());
+            forwarded.extend(value.cloned());
+        }
+        i += if takes_value { 2 } else { 1 };
+    }
+    forwarded.extend(["--size".to_string(), config.size.clone(), "--name".to_string(), config.name.clone()]);
+    if config.personality.is_none() && config.source_image.is_none() {
+        forwarded.extend(["--format".to_string(), config.filesystem.clone()]);
+    }
+    if config.protected {
+        forwarded.push("--protected".to_string());
+    }
+    if !config.mount_options.is_empty() {
+        forwarded.extend(["--mount-options".to_string(), config.mount_options.join(",")]);
+    }
+    Ok(forwarded)
+}
+
+fn find_projects() -> Result<Vec<project::Project>, String> {
+    let cwd = env::current_dir().map_err(|e| format!("Failed to get current directory: {}", e))?;
+    let path = project::discover(&cwd)
+        .ok_or_else(|| format!("No {} found in {} or its parents", project::PROJECT_FILE, cwd.display()))?;
+    project::load(&path)
+}
+
+/// Bring each of a project's disks up after those it depends on, or down in reverse, with
+/// the command line's options applied to every disk. Disks already up stay up if a later
+/// one fails.
+fn run_projects(command: &str, projects: &[project::Project], args: &[OsString]) -> Result<(), String> {
+    let defaults = user_config::load()?;
+    let ordered: Vec<&project::Project> = match command {
+        "down" => projects.iter().rev().collect(),
+        _ => projects.iter().collect(),
+    };
+    for project in ordered {
+        let config = parse_args(&[project.to_args().into_iter().map(OsString::from).collect(), args.to_vec()].concat(), &defaults)?;
+        runner::set_echo(config.echo_commands);
+        progress::set_events(&config.events)?;
+        let result = match command {
+            "up" => project::up(&config, project).map(|mount_point| {
+                for export in project.env_exports(&mount_point) {
+                    println!("{}", export);
+                }
+            }),
+            _ => project::down(&config, project),
+        };
+        if let Err(e) = result {
+            if config.diagnostics {
+                match diagnostics::write_bundle(&config, &e) {
+                    Ok(dir) => eprintln!("Diagnostics written to {}", dir.display()),
+                    Err(e) => eprintln!("Failed to write diagnostics: {}", e),
+                }
+            }
+            return Err(format!("{}: {}", config.name, e));
+        }
+    }
+    Ok(())
+}
+
+/// `daemon reload|stop [--socket PATH]`: ask mkramdiskd to read config.toml again, or to stop.
+#[cfg(unix)]
+fn daemon_command(args: &[OsString]) -> Result<(), String> {
+    use mkramdisk_daemon::{client, protocol::Request, socket_path};
+    let mut socket = None;
+    let mut request = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].to_str() {
+            Some("--socket") => {
+                socket = Some(PathBuf::from(option_path(args, i)?));
+                i += 1;
+            }
+            Some("reload") if request.is_none() => request = Some(Request::Reload),
+            Some("stop") if request.is_none() => request = Some(Request::Stop),
+            _ => return Err(format!("daemon takes one command, reload or stop, not {}", args[i].to_string_lossy())),
+        }
+        i += 1;
+    }
+    let request = request.ok_or("daemon takes one command: reload or stop")?;
+    let socket = socket.or_else(socket_path).ok_or("No socket: pass --socket or set MKRAMDISK_SOCKET")?;
+    let token = env::var("MKRAMDISKD_TOKEN").ok().filter(|token| !token.is_empty());
+    client::request_with_token(&socket, &request, token.as_deref())?;
+    match request {
+        Request::Reload => eprintln!("mkramdiskd reloaded config.toml"),
+        _ => eprintln!("mkramdiskd is stopping"),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemon_command(_args: &[OsString]) -> Result<(), String> {
+    Err("mkramdiskd listens on a Unix socket, which this system does not have".to_string())
+}
+
+/// `fleet apply MANIFEST --hosts FILE`: the manifest's disks on every host, printed as a
+/// JSON result per host. Fails, after printing them, if any host did.
+fn fleet(args: &[OsString]) -> Result<(), String> {
+    let mut manifest = None;
+    let mut hosts = None;
+    let mut i = 0;
+    match args.first().and_then(|arg| arg.to_str()) {
+        Some("apply") => i += 1,
+        _ => return Err("fleet takes one command: apply".to_string()),
+    }
+    while i < args.len() {
+        match args[i].to_str() {
+            Some("--hosts") => {
+                hosts = Some(PathBuf::from(option_path(args, i)?));
+                i += 1;
+            }
+            Some(arg) if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if manifest.is_none() => manifest = Some(PathBuf::from(&args[i])),
+            _ => return Err("Too many arguments".to_string()),
+        }
+        i += 1;
+    }
+    let manifest = manifest.ok_or("fleet apply needs a manifest")?;
+    let hosts = hosts.ok_or("fleet apply needs --hosts, a file listing the hosts")?;
+    let projects = project::load(&manifest)?;
+    fleet::check(&projects)?;
+    let contents = fs::read_to_string(&hosts).map_err(|e| format!("Failed to read {}: {}", hosts.display(), e))?;
+    let hosts = fleet::parse_hosts(&contents).map_err(|e| format!("Invalid {}: {}", hosts.display(), e))?;
+    
+    let results = fleet::apply(&hosts, &projects, &format!("mkramdisk{}", remote_api_version()));
+    let records: Vec<output::Record> = results
+        .iter()
+        .map(|result| vec![
+            ("host", json!(result.host)),
+            ("ok", json!(result.error.is_none())),
+            ("disks", result.disks.iter().map(|(name, result)| json!({ "name": name, "result": result })).collect()),
+            ("error", json!(result.error)),
+        ])
+        .collect();
+    print!("{}", output::render_many(&output::Format::Json, &records)?);
+    let failed: Vec<&str> = results.iter().filter(|result| result.error.is_some()).map(|result| result.host.as_str()).collect();
+    if !failed.is_empty() {
+        return Err(format!("{} of {} hosts failed: {}", failed.len(), results.len(), failed.join(", ")));
+    }
+    Ok(())
+}
+
+/// Options for the commands that act on an existing disk (eject, resize), which take
+/// positional arguments but none of create's disk options.
+fn parse_disk_command(args: &[OsString]) -> Result<(Config, Vec<OsString>), String> {
+    let args = normalize_args(args)?;
+    let mut config = Config::default();
+    let mut positional = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        // A positional argument may be a path, which needn't be UTF-8
+        let Some(arg) = args[i].to_str() else {
+            positional.push(args[i].clone());
+            i += 1;
+            continue;
+        };
+        match arg {
+            "-b" | "--backend" => {
+                config.backend = option_value(&args, i)?.to_string();
+                i += 1;
+            }
+            "--events" => {
+                config.events.push(parse_events(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--events-to" => {
+                config.events.push(progress::Sink::parse(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--preserve" => {
+                config.copy.preserve = attributes::Preserve::parse(option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--strip" => config.copy.preserve = attributes::Preserve::none(),
+            "--tag" => {
+                add_tag(&mut config.tags, option_value(&args, i)?)?;
+                i += 1;
+            }
+            "--force" => config.force = true,
+            "--unmanaged" => config.unmanaged = true,
+            "-q" | "--quiet" => config.quiet = true,
+            "--strict" => config.strict = true,
+            "-v" | "--verbose" => {
+                config.echo_commands = config.verbose;
+                config.verbose = true;
+            }
+            "--legacy-output" => config.legacy_output = true,
+            "--json" => config.output = Some(output::Format::Json),
+            "--output" => {
+                config.output = Some(output::Format::parse(option_value(&args, i)?)?);
+                i += 1;
+            }
+            "--format" => {
+                let template = option_value(&args, i)?;
+                if !template.contains('{') {
+                    return Err(format!("--format takes a template here, such as '{{name}} {{device}}', not {}", template));
+                }
+                config.output = Some(output::Format::Template(template.to_string()));
+                i += 1;
+            }
+            "--profile" => {
+                config.before_eject = user_config::load()?.with_profile(option_value(&args, i)?)?.before_eject;
+                i += 1;
+            }
+            arg if arg.starts_with('-') && arg != "-" => return Err(format!("Unknown option: {}", arg)),
+            arg => positional.push(arg.into()),
+        }
+        i += 1;
+    }
+    Ok((config, positional))
+}
+
+fn run_remote_disk_command(host: &str, command: &str, args: &[OsString]) -> Result<(), String> {
+    if !remote::has_remote_mkramdisk(host) {
+        return Err(format!("mkramdisk is not installed on {}; '{}' requires it", host, command));
+    }
+    let quoted: Vec<String> = utf8_args(args)?.iter().map(|arg| remote::shell_quote(arg)).collect();
+    remote::run_ssh(host, &format!("mkramdisk{} {} {}", remote_api_version(), command, quoted.join(" ")))
+}
+
+// The remote mkramdisk must produce the API version asked for here, or fail
+fn remote_api_version() -> String {
+    api::requested().map(|version| format!(" --api-version {}", version)).unwrap_or_default()
+}
+
+/// Whether a RAM disk is mounted, as an exit code: 0 if it is, 2 if its device is
+/// attached but the volume isn't mounted, 1 if there is no such disk.
+fn status(config: &Config, name: &str) -> Result<i32, String> {
+    let status = disks::status(config, name)?;
+    let code = status.code();
+    if let Some(format) = &config.output {
+        print!("{}", output::render_one(format, &vec![
+            ("name", json!(name)),
+            ("mounted", json!(status.mounted)),
+            ("attached", json!(status.device.is_some() || status.mounted)),
+            ("device", json!(status.device)),
+            ("mount_point", json!(status.mounted.then_some(&status.mount_point))),
+        ])?);
+        return Ok(code);
+    }
+    let mount_point = status.mount_point.display();
+    let message = match (status.mounted, status.device) {
+        (true, Some(device)) => format!("{} is mounted at {} ({})", name, mount_point, device),
+        (true, None) => format!("{} is mounted at {}", name, mount_point),
+        (false, Some(device)) => format!("{} is attached as {} but not mounted", name, device),
+        (false, None) => format!("No RAM disk named '{}'", name),
+    };
+    if !config.quiet {
+        println!("{}", message);
+    }
+    Ok(code)
+}
+
+/// Run the self-test with create's options, printing one line per step on stdout.
+fn run_selftest(args: &[OsString]) -> Result<bool, String> {
+    let name = format!("mkramdisk-selftest-{}", std::process::id());
+    let args = [vec![OsString::from("1M"), OsString::from(name)], args.to_vec()].concat();
+    let mut config = parse_args(&args, &user_config::load()?)?;
+    // The smallest disk the filesystem takes
+    config.size = memory::format_size(filesystem_minimum_bytes(&config.filesystem));
+    
+    let checks = selftest::run(&config);
+    for check in &checks {
+        let (label, detail) = match &check.outcome {
+            selftest::Outcome::Pass(detail) => ("PASS", detail.as_str()),
+            selftest::Outcome::Fail(error) => ("FAIL", error.as_str()),
+            selftest::Outcome::Skip => ("SKIP", ""),
+        };
+        println!("{:<5} {:<8} {}", label, check.step, detail);
+    }
+    let passed = checks.iter().filter(|check| matches!(check.outcome, selftest::Outcome::Pass(_))).count();
+    if selftest::passed(&checks) {
+        println!("Self-test passed ({} of {} steps)", passed, checks.len());
+    } else {
+        println!("Self-test failed ({} of {} steps passed)", passed, checks.len());
+    }
+    Ok(selftest::passed(&checks))
+}
+
+/// Print the attached RAM disks on stdout, one "name device mount-point" per line.
+fn list(config: &Config) -> Result<(), String> {
+    let disks = disks::list(config)?;
+    if let Some(format) = &config.output {
+        let disks: Vec<output::Record> = disks
+            .iter()
+            .map(|listed| vec![
+                ("name", json!(listed.disk.name())),
+                ("device", json!(listed.disk.device)),
+                ("mount_point", json!(listed.disk.mount_point)),
+                ("managed", json!(listed.entry.is_some())),
+                ("tags", json!(listed.entry.as_ref().map(|entry| &entry.tags).unwrap_or(&Vec::new()))),
+            ])
+            .collect();
+        print!("{}", output::render_many(format, &disks)?);
+        return Ok(());
+    }
+    if disks.is_empty() {
+        say(config, &format!("No RAM disks{} attached", tagged(&config.tags)));
+    }
+    for disks::Listed { disk, entry } in &disks {
+        let mount_point = disk.mount_point.as_ref().map_or_else(|| "not mounted".to_string(), |mp| mp.display().to_string());
+        let origin = match entry {
+            Some(entry) if !entry.tags.is_empty() => format!("  [{}]", entry.tags.join(", ")),
+            Some(_) => String::new(),
+            None => "  (not created by mkramdisk)".to_string(),
+        };
+        println!("{:<20} {:<16} {}{}", disk.name().as_deref().unwrap_or("-"), disk.device, mount_point, origin);
+    }
+    Ok(())
+}
+
+/// Print the details of one RAM disk on stdout, one "Field: value" per line.
+fn info(config: &Config, name: &str) -> Result<(), String> {
+    let disks::Info { mount_point, details: info, created, entry, .. } = disks::info(config, name)?;
+    let unknown = || "unknown".to_string();
+    if let Some(format) = &config.output {
+        print!("{}", output::render_one(format, &vec![
+            ("name", json!(name)),
+            ("device", json!(info.device)),
+            ("mount_point", json!(mount_point)),
+            ("sectors", json!(info.sectors)),
+            ("size_bytes", json!(info.sectors.map(|sectors| sectors * 512))),
+            ("filesystem", json!(info.filesystem)),
+            ("mount_options", json!(info.mount_options)),
+            ("uuid", json!(info.uuid)),
+            ("created", json!(created)),
+            ("managed", json!(entry.is_some())),
+            ("flags", json!(entry.as_ref().map(|entry| &entry.flags))),
+            ("total_bytes", json!(info.total_bytes)),
+            ("free_bytes", json!(info.free_bytes)),
+        ])?);
+        return Ok(());
+    }
+    
+    println!("Name:          {}", name);
+    println!("Device:        {}", info.device);
+    println!("Mount point:   {}", mount_point.display());
+    println!("Sectors:       {}", info.sectors.map_or_else(unknown, |sectors| {
+        format!("{} ({})", sectors, memory::format_size(sectors * 512))
+    }));
+    println!("Filesystem:    {}", info.filesystem.unwrap_or_else(unknown));
+    if info.mount_options.is_empty() {
+        println!("Mount options: {}", unknown());
+    } else {
+        println!("Mount options: {}", info.mount_options.join(", "));
+    }
+    println!("UUID:          {}", info.uuid.unwrap_or_else(unknown));
+    println!("Created:       {}", created.map_or_else(unknown, format_timestamp));
+    match entry {
+        Some(entry) if entry.flags.is_empty() => println!("Managed:       yes"),
+        Some(entry) => println!("Managed:       yes ({})", entry.flags.join(", ")),
+        None => println!("Managed:       no (not created by mkramdisk)"),
+    }
+    match (info.total_bytes, info.free_bytes) {
+        (Some(total), Some(free)) => {
+            let used = total.saturating_sub(free);
+            println!(
+                "Used:          {} bytes of {} ({}%), {} bytes free",
+                used,
+                total,
+                (used * 100).checked_div(total).unwrap_or(0),
+                free
+            );
+        }
+        _ => println!("Used:          {}", unknown()),
+    }
+    Ok(())
+}
+
+/// Options for `changes`: those of the other disk commands plus `--since`.
+fn parse_changes_command(args: &[OsString]) -> Result<(Config, String, Option<SystemTime>), String> {
+    let mut since = None;
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = match arg.to_str().and_then(|arg| arg.strip_prefix("--since")) {
+            Some("") => utf8_arg(iter.next().ok_or("--since requires a value")?)?,
+            Some(value) if value.starts_with('=') => &value[1..],
+            _ => {
+                rest.push(arg.clone());
+                continue;
+            }
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        since = Some(UNIX_EPOCH + Duration::from_secs(parse_since(value, now)?));
+    }
+    let (config, positional) = parse_disk_command(&rest)?;
+    match utf8_args(&positional)?.as_slice() {
+        [name] => Ok((config, name.clone(), since)),
+        [] => Err("changes needs the name of a RAM disk".to_string()),
+        _ => Err("Too many arguments".to_string()),
+    }
+}
+
+/// `backups ls|save|restore|rm|keygen`: the backup store's commands. They take the
+/// options of the other disk commands plus `--store`, `--encrypt-to`, `--identity` and
+/// `--keychain-item`.
+fn backups(args: &[OsString]) -> Result<(), String> {
+    let mut store = None;
+    let mut recipients = Vec::new();
+    let mut identities = Vec::new();
+    let mut keychain_item = None;
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let (option, inline) = match split_option(arg) {
+            Some((option, value)) => (option, Some(value)),
+            None => (arg.to_string_lossy().into_owned(), None),
+        };
+        if !["--store", "--encrypt-to", "--identity", "--keychain-item"].contains(&option.as_str()) {
+            rest.push(arg.clone());
+            continue;
+        }
+        let value = match inline {
+            Some(value) => value,
+            None => iter.next().ok_or_else(|| format!("{} requires a value", option))?.clone(),
+        };
+        // The store and identity are paths; a recipient and a Keychain item are text
+        match option.as_str() {
+            "--store" => store = Some(PathBuf::from(value)),
+            "--encrypt-to" => recipients.push(utf8_arg(&value)?.to_string()),
+            "--identity" => identities.push(backup::Identity::File(PathBuf::from(value))),
+            _ => keychain_item = Some(utf8_arg(&value)?.to_string()),
+        }
+    }
+    let (config, positional) = parse_disk_command(&rest)?;
+    let defaults = user_config::load()?;
+    if recipients.is_empty() {
+        recipients = defaults.backup_recipients.unwrap_or_default();
+    }
+    if identities.is_empty() {
+        identities = defaults.backup_identities.unwrap_or_default().into_iter().map(backup::Identity::File).collect();
+    }
+    let keychain_item = keychain_item.or(defaults.backup_keychain_item);
+    let root = match store {
+        Some(store) => store,
+        None => defaults.backup_dir.or_else(backup::default_root).ok_or("No backup store: set backup_dir in config.toml or pass --store")?,
+    };
+    let store = backup::Store::new(root);
+    let positional = utf8_args(&positional)?;
+    let args: Vec<&str> = positional.iter().map(String::as_str).collect();
+    if let Some(name) = args.get(1) {
+        validate_volume_name(name)?;
+    }
+    match args.as_slice() {
+        ["ls"] => {
+            let disks = store.disks()?;
+            if disks.is_empty() {
+                say(&config, &format!("No backups in {}", store.root().display()));
+            }
+            for disk in &disks {
+                let latest = disk.snapshots.last().map_or_else(|| "-".to_string(), |snapshot| snapshot.id.clone());
+                println!("{:<20} {:>3} snapshots  latest {}", disk.name, disk.snapshots.len(), latest);
+            }
+            Ok(())
+        }
+        ["ls", name] => {
+            let metadata = store.metadata(name)?.ok_or_else(|| format!("No backups of '{}' in {}", name, store.root().display()))?;
+            for snapshot in &metadata.snapshots {
+                println!(
+                    "{:<20} {}  {} files  {}{}",
+                    snapshot.id,
+                    format_timestamp(snapshot.created),
+                    snapshot.files,
+                    memory::format_size(snapshot.bytes),
+                    if snapshot.encrypted { "  encrypted" } else { "" }
+                );
+            }
+            Ok(())
+        }
+        ["save", name] => {
+            if let Some(item) = &keychain_item {
+                recipients.push(backup::recipient(&keychain::find(item)?)?);
+            }
+            let snapshot = backup::save_disk(&config, &store, name, &recipients)?;
+            println!("{}", snapshot.id);
+            Ok(())
+        }
+        ["sync"] => {
+            if let Some(item) = &keychain_item {
+                recipients.push(backup::recipient(&keychain::find(item)?)?);
+            }
+            let mut errors = Vec::new();
+            for backup::Synced { name, saved } in backup::sync(&config, &store, &recipients)? {
+                match saved {
+                    Ok(snapshot) => println!("{} {}", name, snapshot.id),
+                    Err(e) => errors.push(format!("{}: {}", name, e)),
+                }
+            }
+            if errors.is_empty() { Ok(()) } else { Err(errors.join("\n")) }
+        }
+        ["restore", name, id @ ..] if id.len() <= 1 => {
+            let snapshot = store.find(name, id.first().copied())?;
+            if let Some(item) = keychain_item.as_deref().filter(|_| snapshot.encrypted) {
+                identities.push(backup::Identity::Secret(keychain::find(item)?));
+            }
+            backup::restore_disk(&config, &store, name, &snapshot, &identities)?;
+            Ok(())
+        }
+        ["rm", name] if !config.force => Err(format!("Removing every backup of '{}' needs --force", name)),
+        ["rm", name, id @ ..] if id.len() <= 1 => {
+            store.remove(name, id.first().copied())?;
+            match id.first() {
+                Some(id) => say(&config, &format!("Removed snapshot {} of {}", id, name)),
+                None => say(&config, &format!("Removed every backup of {}", name)),
+            }
+            Ok(())
+        }
+        ["keygen"] => {
+            let item = keychain_item.ok_or("keygen needs --keychain-item NAME to keep the new key in")?;
+            // Replacing a key loses every backup encrypted to it
+            if !config.force && keychain::find(&item).is_ok() {
+                return Err(format!("Keychain item '{}' already holds a key; replacing it needs --force", item));
+            }
+            let identity = backup::generate_identity()?;
+            let recipient = backup::recipient(&identity)?;
+            keychain::store(&item, &identity)?;
+            println!("{}", recipient);
+            say(&config, &format!("Stored a new age key as Keychain item '{}'; save with --keychain-item {} to encrypt to it", item, item));
+            Ok(())
+        }
+        [] => Err("backups needs an action: ls, save, sync, restore, rm or keygen".to_string()),
+        ["ls" | "save" | "sync" | "restore" | "rm" | "keygen", ..] => Err(format!("Usage: mkramdisk backups {}", match args[0] {
+            "ls" => "ls [name]",
+            "save" => "save [--encrypt-to RECIPIENT] [--keychain-item NAME] <name>",
+            "sync" => "sync [--tag TAG]... [--encrypt-to RECIPIENT] [--keychain-item NAME]",
+            "restore" => "restore [--identity FILE] [--keychain-item NAME] <name> [snapshot]",
+            "keygen" => "keygen --keychain-item NAME",
+            _ => "rm <name> [snapshot]",
+        })),
+        [action, ..] => Err(format!("Unknown backups action: {} (expected ls, save, sync, restore, rm or keygen)", action)),
+    }
+}
+
+/// A `--since` time as seconds since the epoch: a duration ago ("90s", "10m", "2h",
+/// "1d") or a UTC date, optionally with a time ("2024-05-01", "2024-05-01 12:00",
+/// "2024-05-01T12:00:30").
+fn parse_since(value: &str, now: u64) -> Result<u64, String> {
+    let invalid = || format!("Invalid time: {} (use a duration such as 10m, 2h or 1d, or a date such as 2024-05-01 12:00)", value);
+    if let Some(unit) = value.chars().last().filter(|_| value.len() > 1 && value[..value.len() - 1].bytes().all(|b| b.is_ascii_digit())) {
+        let number: u64 = value[..value.len() - 1].parse().map_err(|_| invalid())?;
+        let seconds = match unit {
+            's' => number,
+            'm' => number * 60,
+            'h' => number * 3600,
+            'd' => number * 86400,
+            _ => return Err(invalid()),
+        };
+        return Ok(now.saturating_sub(seconds));
+    }
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00"));
+    let numbers = |text: &str, count: std::ops::RangeInclusive<usize>, sep: char| -> Result<Vec<i64>, String> {
+        let parts: Vec<i64> = text.split(sep).map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+        if count.contains(&parts.len()) { Ok(parts) } else { Err(invalid()) }
+    };
+    let date = numbers(date, 3..=3, '-')?;
+    let mut time = numbers(time.trim_end_matches(" UTC"), 2..=3, ':')?;
+    time.resize(3, 0);
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 59 {
+        return Err(invalid());
+    }
+    // days_from_civil, the inverse of format_timestamp's civil_from_days
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2]).map_err(|_| invalid())
+}
+
+/// Print what changed on a RAM disk since `since` (or since it was created), one
+/// "A|M|R|D path" per line on stdout.
+fn changes(config: &Config, name: &str, since: Option<SystemTime>) -> Result<(), String> {
+    let (changes, since) = disks::changes(config, name, since)?;
+    let mut stdout = io::stdout().lock();
+    for (change, path) in &changes {
+        let line = [format!("{} ", change.letter()).into_bytes(), path_bytes(path), b"\n".to_vec()].concat();
+        stdout.write_all(&line).map_err(|e| format!("Failed to write to stdout: {}", e))?;
+    }
+    let when = since.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    say(config, &format!("{} changes on {} since {}", changes.len(), name, format_timestamp(when)));
+    Ok(())
+}
+
+/// `accelerate <dir> [size]` and `decelerate [--discard] <dir>`, which take the options
+/// of the other disk commands.
+fn accelerate_command(command: &str, args: &[OsString]) -> Result<(), String> {
+    let discard = args.iter().any(|arg| arg == "--discard");
+    if discard && command == "accelerate" {
+        return Err("--discard only applies to decelerate".to_string());
+    }
+    let args: Vec<OsString> = args.iter().filter(|arg| *arg != "--discard").cloned().collect();
+    let (config, args) = parse_disk_command(&args)?;
+    runner::set_echo(config.echo_commands);
+    progress::set_events(&config.events)?;
+    match (command, args.as_slice()) {
+        ("accelerate", [dir]) => accelerate::accelerate(&config, Path::new(dir), None),
+        ("accelerate", [dir, size]) => accelerate::accelerate(&config, Path::new(dir), Some(utf8_arg(size)?)),
+        ("decelerate", [dir]) => accelerate::decelerate(&config, Path::new(dir), discard),
+        (_, []) => Err(format!("{} needs a directory", command)),
+        _ => Err("Too many arguments".to_string()),
+    }
+}
+
+/// Print the experimental features on stdout, one "name on|off description" per line.
+fn list_features(config: &Config) -> Result<(), String> {
+    let mut records = Vec::new();
+    for feature in features::FEATURES {
+        let source = features::source(feature.name)?;
+        records.push(vec![
+            ("name", json!(feature.name)),
+            ("enabled", json!(source.is_some())),
+            ("source", json!(source.map(|source| source.to_string()))),
+            ("description", json!(feature.description)),
+        ]);
+        if config.output.is_none() {
+            let state = source.map_or_else(|| "off".to_string(), |source| format!("on ({})", source));
+            println!("{:<16} {:<28} {}", feature.name, state, feature.description);
+        }
+    }
+    if let Some(format) = &config.output {
+        print!("{}", output::render_many(format, &records)?);
+    }
+    Ok(())
+}
+
+/// `replay [--dry-run] <session.json>`: print a recorded session, or run its command line
+/// again with this mkramdisk, returning its exit code.
+fn replay(args: &[OsString]) -> Result<i32, String> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let files: Vec<&OsString> = args.iter().filter(|arg| *arg != "--dry-run").collect();
+    let [file] = files.as_slice() else {
+        return Err("replay needs one session file".to_string());
+    };
+    let file = Path::new(file);
+    if path_bytes(file).starts_with(b"-") {
+        return Err(format!("Unknown option: {}", file.display()));
+    }
+    let session = session::load(file)?;
+    if dry_run {
+        println!("# mkramdisk {} (recorded by {}, exit {})", session.args.join(" "), session.version, session.exit_code);
+        for step in &session.steps {
+            print!("{}", step.describe());
+        }
+        return Ok(0);
+    }
+    let exe = env::current_exe().map_err(|e| format!("Cannot find this mkramdisk to run: {}", e))?;
+    let status = std::process::Command::new(exe)
+        .args(&session.args)
+        .status()
+        .map_err(|e| format!("Failed to replay {}: {}", file.display(), e))?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// `migrate-state [--dry-run]`: upgrade the files an older mkramdisk wrote, keeping
+/// a copy of each as it was.
+fn migrate_state(args: &[OsString]) -> Result<(), String> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<OsString> = args.iter().filter(|arg| *arg != "--dry-run").cloned().collect();
+    let (config, args) = parse_disk_command(&args)?;
+    if !args.is_empty() {
+        return Err("migrate-state takes no arguments".to_string());
+    }
+    let migrations = migrate::pending()?;
+    if migrations.is_empty() {
+        say(&config, "Nothing to upgrade");
+        return Ok(());
+    }
+    for migration in &migrations {
+        if dry_run {
+            say(&config, &format!("Would upgrade {}: {}", migration.path.display(), migration.changes.join(", ")));
+            continue;
+        }
+        let backup = migration.apply()?;
+        say(&config, &format!(
+            "Upgraded {}: {} (the old file is {})",
+            migration.path.display(),
+            migration.changes.join(", "),
+            backup.display()
+        ));
+    }
+    Ok(())
+}
+
+/// `registry rebuild [--dry-run]`: make the state file match the disks the backend has
+/// attached, recovering the entries of those whose volume carries a marker.
+fn registry_command(args: &[OsString]) -> Result<(), String> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<OsString> = args.iter().filter(|arg| *arg != "--dry-run").cloned().collect();
+    let (config, args) = parse_disk_command(&args)?;
+    if args != ["rebuild"] {
+        return Err("registry takes one command: rebuild".to_string());
+    }
+    runner::set_echo(config.echo_commands);
+    let changes = registry::rebuild(&config.backend, &registry::attached(&config.backend)?, dry_run)?;
+    if changes.is_empty() {
+        say(&config, "The state file already matches the attached disks");
+    }
+    for change in &changes {
+        let (done, doing, entry) = match change {
+            registry::Change::Forgotten(entry) => ("Forgot", "forget", entry),
+            registry::Change::Recovered(entry) => ("Recovered", "recover", entry),
+        };
+        match dry_run {
+            true => say(&config, &format!("Would {} '{}' ({})", doing, entry.name, entry.device)),
+            false => say(&config, &format!("{} '{}' ({})", done, entry.name, entry.device)),
+        }
+    }
+    Ok(())
+}
+
+// What `migrate-state` does, run ahead of every other command. A failure is only
+// reported: the command itself says more if it cannot read the file
+fn migrate_automatically() {
+    let migrations = match migrate::pending() {
+        Ok(migrations) => migrations,
+        Err(e) => {
+            eprintln!("Warning: cannot upgrade mkramdisk's files: {}", e);
+            return;
+        }
+    };
+    for migration in &migrations {
+        match migration.apply() {
+            Ok(backup) => eprintln!(
+                "Note: upgraded {} from an older mkramdisk: {} (the old file is {})",
+                migration.path.display(),
+                migration.changes.join(", "),
+                backup.display()
+            ),
+            Err(e) => eprintln!("Warning: cannot upgrade {}: {}", migration.path.display(), e),
+        }
+    }
+}
+
+/// `gc [--dry-run] [--yes]`: detach the backend's devices that are attached with nothing
+/// mounted, after listing them on stdout and asking.
+fn gc(args: &[OsString]) -> Result<(), String> {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let yes = args.iter().any(|arg| arg == "--yes" || arg == "-y");
+    let args: Vec<OsString> = args.iter().filter(|arg| !matches!(arg.to_str(), Some("--dry-run" | "--yes" | "-y"))).cloned().collect();
+    let (config, args) = parse_disk_command(&args)?;
+    if !args.is_empty() {
+        return Err("gc takes no arguments".to_string());
+    }
+    runner::set_echo(config.echo_commands);
+    progress::set_events(&config.events)?;
+    
+    let orphans = disks::orphans(&config)?;
+    if orphans.is_empty() {
+        say(&config, "No orphaned RAM disks");
+        return Ok(());
+    }
+    for device in &orphans {
+        println!("{}", device);
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if !yes {
+        confirm_gc(orphans.len())?;
+    }
+    disks::detach(&config, &orphans)
+}
+
+fn confirm_gc(count: usize) -> Result<(), String> {
+    if !io::stdin().is_terminal() {
+        return Err("Not detaching anything without confirming at a terminal; use --yes to detach them anyway".to_string());
+    }
+    eprint!("Detach {} orphaned RAM disk{}? [y/N] ", count, if count == 1 { "" } else { "s" });
+    io::stderr().flush().map_err(|e| e.to_string())?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).map_err(|e| e.to_string())?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err("Nothing detached".to_string()),
+    }
+}
+
+/// Record an attached disk of the backend that mkramdisk didn't create in the state
+/// file, as if it had.
+fn adopt(config: &Config, device: &str) -> Result<(), String> {
+    let entry = disks::adopt(config, device)?;
+    if let Some(format) = &config.output {
+        print!("{}", output::render_one(format, &vec![
+            ("name", json!(entry.name)),
+            ("device", json!(entry.device)),
+            ("mount_point", json!(entry.mount_point)),
+            ("size_bytes", json!(entry.size_bytes)),
+            ("filesystem", json!(entry.filesystem)),
+        ])?);
+    }
+    Ok(())
+}
+
+/// Attach `source` copy-on-write with its shadow file on a new RAM disk.
+fn overlay(config: &Config, source: &Path, size: &str) -> Result<(), String> {
+    let overlay = disks::overlay(config, source, size)?;
+    if let Some(format) = &config.output {
+        print!("{}", output::render_one(format, &vec![
+            ("name", json!(overlay.name)),
+            ("device", json!(overlay.device)),
+            ("mount_point", json!(overlay.mount_point)),
+            ("source", json!(source)),
+            ("shadow", json!(overlay.shadow)),
+            ("shadow_device", json!(overlay.shadow_device)),
+        ])?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use user_config::UserConfig;
+    
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+        
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("99999999999999999999999").unwrap_err().contains("too long"));
+    }
+    
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+    
+    #[test]
+    fn test_normalize_args() {
+        assert_eq!(normalize_args(&args(&["-vf", "hfs+", "1G"])).unwrap(), ["-v", "-f", "hfs+", "1G"]);
+        assert_eq!(normalize_args(&args(&["-fhfs+"])).unwrap(), ["-f", "hfs+"]);
+        assert_eq!(normalize_args(&args(&["--name=My Disk"])).unwrap(), ["--name", "My Disk"]);
+        assert_eq!(normalize_args(&args(&["--", "-vf"])).unwrap(), ["--", "-vf"]);
+        assert!(normalize_args(&args(&["--force=yes"])).is_err());
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_normalize_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let image = OsStr::from_bytes(b"caf\xe9.dmg");
+        let mut inline = OsString::from("--from-dmg=");
+        inline.push(image);
+        assert_eq!(normalize_args(&[inline]).unwrap(), [OsStr::new("--from-dmg"), image]);
+        // The image is looked for under the name given, where a name would be refused
+        let error = parse_args(&[OsString::from("--from-dmg"), image.to_os_string(), OsString::from("-b"), OsString::from("mock")], &UserConfig::default()).unwrap_err();
+        assert!(error.contains("No such file"), "{}", error);
+        assert!(parse_args(&[OsString::from("64M"), image.to_os_string()], &UserConfig::default()).unwrap_err().contains("not valid UTF-8"));
+    }
+    
+    #[test]
+    fn test_parse_args() {
+        let config = parse_args(&args(&["--size", "1G", "--name=Build", "-vvf", "hfs+"]), &UserConfig::default()).unwrap();
+        assert_eq!((config.size.as_str(), config.name.as_str()), ("1G", "Build"));
+        assert_eq!(config.filesystem, "hfs+");
+        assert!(config.verbose && config.echo_commands);
+        
+        let config = parse_args(&args(&["1G", "-b", "zram"]), &UserConfig::default()).unwrap();
+        assert_eq!(diskutil_format(&config).unwrap(), "ext4");
+        let config = parse_args(&args(&["1G", "-b", "zram", "-f", "btrfs"]), &UserConfig::default()).unwrap();
+        assert_eq!(diskutil_format(&config).unwrap(), "btrfs");
+        assert!(parse_args(&args(&["1G", "-b", "zram", "-f", "hfs+"]), &UserConfig::default()).is_err());
+        assert!(parse_args(&args(&["1G", "/"]), &UserConfig::default()).unwrap_err().contains("nothing left"));
+        assert!(parse_args(&args(&["1G", "   "]), &UserConfig::default()).is_err());
+        assert!(parse_args(&args(&["1G", &"x".repeat(300)]), &UserConfig::default()).unwrap_err().contains("at most 255"));
+        assert!(parse_args(&args(&["1G", "Preboot"]), &UserConfig::default()).is_err());
+        assert!(parse_args(&args(&["1G", "Preboot", "--allow-system-name"]), &UserConfig::default()).is_ok());
+        let config = parse_args(&args(&["1G", "-b", "md"]), &UserConfig::default()).unwrap();
+        assert_eq!(diskutil_format(&config).unwrap(), "ufs");
+        let config = parse_args(&args(&["1G", "-b", "imdisk", "-f", "exfat"]), &UserConfig::default()).unwrap();
+        assert_eq!(diskutil_format(&config).unwrap(), "exfat");
+        
+        let defaults = UserConfig {
+            filesystem: Some("hfs+".to_string()),
+            name: Some("Scratch".to_string()),
+            verbose: Some(true),
+            mount_options: Some(vec!["noatime".to_string()]),
+            ..UserConfig::default()
+        };
+        let config = parse_args(&args(&["1G"]), &defaults).unwrap();
+        assert_eq!((config.filesystem.as_str(), config.name.as_str()), ("hfs+", "Scratch"));
+        assert!(config.verbose && !config.echo_commands);
+        assert_eq!(config.mount_options, ["noatime"]);
+        let config = parse_args(&args(&["1G", "Build", "-f", "apfs", "--mount-options", "nosuid,nodev"]), &defaults).unwrap();
+        assert_eq!((config.filesystem.as_str(), config.name.as_str()), ("apfs", "Build"));
+        assert_eq!(config.mount_options, ["nosuid", "nodev"]);
+        // Not a filesystem zram can make, so zram keeps its own default
+        assert_eq!(diskutil_format(&parse_args(&args(&["1G", "-b", "zram"]), &defaults).unwrap()).unwrap(), "ext4");
+        assert!(parse_args(&args(&["1G", "--mount-options", "noatime,"]), &UserConfig::default()).is_err());
+        let defaults: UserConfig = toml::from_str("name = \"Scratch\"\n[profile.xcode]\nsize = \"8G\"\nname = \"DerivedData\"\nbefore_eject = \"true\"").unwrap();
+        let config = parse_args(&args(&["--profile", "xcode"]), &defaults).unwrap();
+        assert_eq!((config.size.as_str(), config.name.as_str()), ("8G", "DerivedData"));
+        assert_eq!(config.before_eject.as_deref(), Some("true"));
+        assert_eq!(parse_args(&args(&["1G", "--profile", "xcode"]), &defaults).unwrap().size, "1G");
+        assert_eq!(parse_args(&args(&["1G"]), &defaults).unwrap().name, "Scratch");
+        assert!(parse_args(&args(&["1G", "--profile", "rust"]), &defaults).is_err());
+        
+        let config = parse_args(&args(&["--size", "1G", "Build"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.name, "Build");
+        
+        let config = parse_args(&args(&["512M", "--", "-Dash"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.name, "-Dash");
+        
+        assert!(parse_args(&args(&["1G", "--size", "2G"]), &UserConfig::default()).is_err());
+        assert!(parse_args(&args(&["1G", "A", "B"]), &UserConfig::default()).is_err());
+        assert!(parse_args(&args(&["--format"]), &UserConfig::default()).is_err());
+        assert!(parse_args(&args(&["-x", "1G"]), &UserConfig::default()).is_err());
+        
+        let config = parse_args(&args(&["Build", "2G"]), &UserConfig::default()).unwrap();
+        assert_eq!((config.size.as_str(), config.name.as_str()), ("2G", "Build"));
+        let config = parse_args(&args(&["Build", "--size", "2G"]), &UserConfig::default()).unwrap();
+        assert_eq!((config.size.as_str(), config.name.as_str()), ("2G", "Build"));
+        let config = parse_args(&args(&["1G", "2G"]), &UserConfig::default()).unwrap();
+        assert_eq!((config.size.as_str(), config.name.as_str()), ("1G", "2G"));
+        assert!(parse_args(&args(&["--name", "Build", "1G", "2G"]), &UserConfig::default()).is_err());
+        
+        assert_eq!(parse_args(&args(&["-1G"]), &UserConfig::default()).unwrap().size, "-1G");
+        
+        assert_eq!(parse_args(&args(&["1G", "Build!"]), &UserConfig::default()).unwrap().name, "Build");
+        assert!(parse_args(&args(&["1G", "Build!", "--strict"]), &UserConfig::default()).is_err());
+        let config = parse_args(&args(&["1G", "--personality", "Journaled HFS+ (Custom)"]), &UserConfig::default()).unwrap();
+        assert_eq!(diskutil_format(&config).unwrap(), "Journaled HFS+ (Custom)");
+        
+        assert!(parse_args(&args(&["1G", "-f", "free space"]), &UserConfig::default()).is_err());
+        let config = parse_args(&args(&["1G", "-f", "free space", "--experimental", "-b", "ram"]), &UserConfig::default()).unwrap();
+        assert_eq!(diskutil_format(&config).unwrap(), "Free Space");
+        
+        let config = parse_args(&args(&["4M", "--auto-min", "-b", "ram"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.size, "32M");
+        assert!(parse_args(&args(&["4M", "--auto-min", "--strict", "-b", "ram"]), &UserConfig::default()).is_err());
+        // Only a block device is formatted at the size given
+        assert_eq!(parse_args(&args(&["4M", "--auto-min", "--strict", "-b", "dir"]), &UserConfig::default()).unwrap().size, "4M");
+        let config = parse_args(&args(&["1G", "Build Cache Disk", "-f", "fat32"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.name, "Build Cache");
+        // The fallback filesystem has to fit too
+        let config = parse_args(&args(&["10M", "-f", "hfs+", "--fallback-format", "fat32", "--auto-min", "-b", "ram"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.size, "34M");
+        assert!(parse_args(&args(&["1G", "Build Cache Disk", "--fallback-format", "fat32", "--strict"]), &UserConfig::default()).unwrap_err().contains("'BUILD CACHE'"));
+        assert!(parse_args(&args(&["512", "MB"]), &UserConfig::default()).unwrap_err().ends_with("Did you mean 512M?"));
+        
+        let config = parse_args(&args(&["1G", "--preserve=xattr,times"]), &UserConfig::default()).unwrap();
+        assert!(config.copy.preserve.xattrs && !config.copy.preserve.acls);
+        let config = parse_args(&args(&["1G", "--strip"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.copy.preserve, attributes::Preserve::none());
+        let config = parse_args(&args(&["1G", "--links", "follow"]), &UserConfig::default()).unwrap();
+        assert_eq!(config.copy.links, copier::LinkPolicy::Follow);
+        assert!(parse_args(&args(&["1G", "--links", "copy"]), &UserConfig::default()).is_err());
+    }
+    
+    #[test]
+    fn test_remote_create_args() {
+        let defaults = UserConfig {
+            filesystem: Some("hfs+".to_string()),
+            mount_options: Some(vec!["noatime".to_string()]),
+            ..UserConfig::default()
+        };
+        let given = args(&["2G", "Build", "--strict", "--format={name}:{device}", "-b", "ram", "--mount-timeout", "5"]);
+        let config = parse_args(&given, &defaults).unwrap();
+        assert_eq!(
+            remote_create_args(&given, &config).unwrap(),
+            [
+                "--strict", "--format", "{name}:{device}", "-b", "ram", "--mount-timeout", "5",
+                "--size", "2G", "--name", "Build", "--format", "hfs+", "--mount-options", "noatime",
+            ]
+        );
+
+        // What came from elsewhere is replaced by what it resolved to
+        let given = args(&["--size", "1G", "--name", "Scratch", "-f", "apfs", "--protected"]);
+        let config = Config { size: "4G".to_string(), ..parse_args(&given, &UserConfig::default()).unwrap() };
+        assert_eq!(
+            remote_create_args(&given, &config).unwrap(),
+            ["--size", "4G", "--name", "Scratch", "--format", "apfs", "--protected"]
+        );
+    }
+
+    #[test]
+    fn test_summary_line() {
+        assert_eq!(
+            summary_line(&[("RESULT", "ok"), ("device", "/dev/disk5"), ("mount_point", "/Volumes/My Disk")]),
+            "RESULT=ok device=/dev/disk5 mount_point='/Volumes/My Disk'"
+        );
+        assert_eq!(
+            summary_line(&[("RESULT", "error"), ("error", "Failed to format\r\nScratch was rolled back")]),
+            r"RESULT=error error='Failed to format\nScratch was rolled back'"
+        );
+    }
+    
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1709210096), "2024-02-29 12:34:56 UTC");
+    }
+    
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("10m", 1_000_000), Ok(1_000_000 - 600));
+        assert_eq!(parse_since("2d", 100), Ok(0));
+        assert_eq!(parse_since("2024-02-29 12:34:56", 0), Ok(1709210096));
+        assert_eq!(parse_since("2024-02-29T12:34", 0), Ok(1709210040));
+        assert_eq!(parse_since("1970-01-02", 0), Ok(86400));
+        assert!(parse_since("10y", 0).is_err());
+        assert!(parse_since("2024-13-01", 0).is_err());
+        assert!(parse_since("yesterday", 0).is_err());
+    }
+    
+    #[test]
+    fn test_git_name() {
+        assert_eq!(git_name("myapp", "main"), "myapp-main");
+        assert_eq!(git_name("myapp", "feature/login"), "myapp-feature-login");
+        assert_eq!(git_name("my.app", "release/1.2"), "myapp-release-1-2");
+    }
+    
+}
\ No newline at end of file
@@ -0,0 +1,1547 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A scratch root for the mock backend, removed when the test finishes.
+struct MockRoot(PathBuf);
+
+impl MockRoot {
+    fn new(test: &str) -> Self {
+        let root = env::temp_dir().join(format!("mkramdisk-cli-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        Self(root)
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_with(args, &[])
+    }
+
+    fn run_with(&self, args: &[&str], envs: &[(&str, &str)]) -> Output {
+        self.command(args).envs(envs.iter().copied()).output().expect("failed to run mkramdisk")
+    }
+
+    // Subcommands must come first, so options go after them
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mkramdisk"));
+        let (subcommand, rest) = match args.first() {
+            Some(&subcommand @ ("up" | "down" | "selftest" | "list" | "info" | "changes" | "backups" | "status" | "exists" | "eject" | "destroy" | "resize" | "overlay" | "accelerate" | "decelerate" | "adopt" | "gc" | "features" | "migrate-state" | "registry" | "sync")) => (Some(subcommand), &args[1..]),
+            _ => (None, args),
+        };
+        command
+            .args(subcommand)
+            .args(["--backend", "mock"])
+            .args(rest)
+            .env("MKRAMDISK_MOCK_ROOT", &self.0)
+            .env("MKRAMDISK_CONFIG", self.0.join("config.toml"))
+            .env("MKRAMDISK_STATE", self.0.join("state.json"));
+        command
+    }
+
+    fn devices(&self) -> usize {
+        fs::read_dir(self.0.join("dev")).map(|d| d.count()).unwrap_or(0)
+    }
+}
+
+impl Drop for MockRoot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_create_mock_disk() {
+    let root = MockRoot::new("create");
+    let output = root.run(&["64M", "Scratch"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("RAM disk created successfully"));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(stdout.starts_with("RESULT=ok device="));
+    assert!(stdout.contains(&format!("mount_point={}", root.0.join("Volumes/Scratch").display())));
+    assert!(root.0.join("Volumes/Scratch").is_dir());
+    assert_eq!(root.devices(), 1);
+}
+
+#[test]
+fn test_json_output() {
+    let root = MockRoot::new("json");
+    let output = root.run(&["64M", "Scratch", "--json"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let created: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(created["result"], "ok");
+    assert_eq!(created["name"], "Scratch");
+    assert_eq!(created["size_bytes"], 64 << 20);
+    assert_eq!(created["sectors"], 131072);
+    assert_eq!(created["filesystem"], "apfs");
+    assert_eq!(created["mount_point"], root.0.join("Volumes/Scratch").to_string_lossy().as_ref());
+    assert!(created["device"].as_str().unwrap().ends_with("dev/disk0"));
+
+    let output = root.run(&["64M", "Scratch", "--json"]);
+    assert!(!output.status.success());
+    let failed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(failed["result"], "error");
+    assert!(failed["error"].as_str().unwrap().contains("already exists"));
+
+    let listed: serde_json::Value = serde_json::from_slice(&root.run(&["list", "--json"]).stdout).unwrap();
+    assert_eq!(listed[0]["name"], "Scratch");
+    let info: serde_json::Value = serde_json::from_slice(&root.run(&["info", "Scratch", "--json"]).stdout).unwrap();
+    assert_eq!((info["sectors"].as_u64(), info["filesystem"].as_str()), (Some(131072), Some("APFS")));
+    let output = root.run(&["status", "Missing", "--json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status["mounted"], false);
+
+    assert!(!root.run(&["64M", "Other", "--json", "--print-actions", "json"]).status.success());
+}
+
+#[test]
+fn test_output_formats() {
+    let root = MockRoot::new("output-formats");
+    let output = root.run(&["64M", "Scratch", "--output", "yaml"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let created = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(created.starts_with("result: \"ok\"\n"), "{}", created);
+    assert!(created.contains("\nsectors: 131072\n"), "{}", created);
+
+    let output = root.run(&["list", "--output", "csv"]);
+    let listed = String::from_utf8_lossy(&output.stdout).to_string();
+    let lines: Vec<&str> = listed.lines().collect();
+    assert_eq!(lines[0], "name,device,mount_point,managed,tags");
+    assert!(lines[1].starts_with("Scratch,") && lines[1].ends_with(",true,"), "{}", listed);
+
+    let output = root.run(&["info", "Scratch", "--output", "tsv"]);
+    let info = String::from_utf8_lossy(&output.stdout).to_string();
+    let (header, row) = info.split_once('\n').unwrap();
+    let column = header.split('\t').position(|key| key == "filesystem").unwrap();
+    assert_eq!(row.trim_end().split('\t').nth(column), Some("APFS"));
+
+    assert!(!root.run(&["list", "--output", "xml"]).status.success());
+}
+
+#[test]
+fn test_format_template() {
+    let root = MockRoot::new("format-template");
+    let output = root.run(&["64M", "Scratch", "--format", "{name} {sectors} {mount_point}"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let mount_point = root.0.join("Volumes/Scratch");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("Scratch 131072 {}\n", mount_point.display()));
+
+    let output = root.run(&["list", "--format", "{name}"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Scratch\n");
+    let output = root.run(&["status", "Scratch", "--format", "{mounted}"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "true\n");
+
+    // An unknown field fails before any disk is created
+    let output = root.run(&["64M", "Other", "--format", "{uuid}"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown field in template: {uuid}"));
+    assert_eq!(root.devices(), 1);
+    // Without a brace --format is still the filesystem
+    assert!(root.run(&["64M", "Other", "--format", "hfs+"]).status.success());
+}
+
+#[test]
+fn test_json_lines() {
+    let root = MockRoot::new("json-lines");
+    let events = |output: &Output| -> Vec<serde_json::Value> {
+        String::from_utf8_lossy(&output.stdout).lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    };
+    let output = root.run(&["64M", "Scratch", "--json-lines"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let events = events(&output);
+    let names: Vec<&str> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+    assert_eq!(names, ["attach_started", "device_acquired", "format_started", "mounted", "created", "done"]);
+    assert_eq!(events[1]["device"], events[3]["device"]);
+    assert_eq!(events[5]["result"], "ok");
+
+    let output = root.run(&["64M", "Scratch", "--json-lines"]);
+    let last: serde_json::Value = serde_json::from_str(String::from_utf8_lossy(&output.stdout).lines().last().unwrap()).unwrap();
+    assert_eq!((last["event"].as_str(), last["result"].as_str()), (Some("done"), Some("error")));
+    assert!(!root.run(&["64M", "Other", "--json-lines", "--json"]).status.success());
+}
+
+#[test]
+fn test_readonly_export() {
+    let root = MockRoot::new("readonly-export");
+    let output = root.run(&["64M", "Scratch", "--readonly-export", "--json", "--enable-experimental"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let created: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let node = PathBuf::from(created["readonly_device"].as_str().unwrap());
+    assert_ne!(created["device"], created["readonly_device"]);
+    assert!(fs::metadata(&node).unwrap().permissions().readonly());
+    assert!(root.run(&["eject", "Scratch"]).status.success());
+    assert!(!node.exists());
+
+    // A failed export takes the new disk with it
+    let output = root.run_with(&["64M", "Scratch", "--readonly-export"], &[("MKRAMDISK_MOCK_FAIL", "export"), ("MKRAMDISK_FEATURES", "readonly-export")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("simulated export failure"));
+    assert_eq!(root.devices(), 0);
+
+    // Backends without exports are refused up front
+    for backend in ["ram", "file", "tmpfs"] {
+        let output = root.run(&["64M", "Scratch", "--readonly-export", "--enable-experimental", "--backend", backend]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--readonly-export needs the zram backend"), "{}", backend);
+    }
+}
+
+#[test]
+fn test_print_path() {
+    let root = MockRoot::new("print-path");
+    let output = root.run(&["64M", "Scratch", "--print-path", "-v"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\n", root.0.join("Volumes/Scratch").display()));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("RAM disk created successfully"));
+    assert!(!root.run(&["64M", "Other", "--print-path", "--legacy-output"]).status.success());
+
+    // A raw device has no mount point to print
+    let output = root.run(&["64M", "Raw", "--print-path", "--experimental", "-f", "Free Space"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--output '{device}'"));
+}
+
+#[test]
+fn test_existing_volume_is_rejected() {
+    let root = MockRoot::new("existing");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+
+    let output = root.run(&["64M", "Scratch"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+    assert_eq!(root.devices(), 1);
+}
+
+#[test]
+fn test_format_failure_detaches_device() {
+    let root = MockRoot::new("format-failure");
+    let output = root.run_with(&["64M", "Scratch"], &[("MKRAMDISK_MOCK_FAIL", "format")]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("simulated format failure"));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("RESULT=error error="));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_unmounted_volume_falls_back_to_remount() {
+    let root = MockRoot::new("remount");
+    let output = root.run_with(
+        &["--mount-timeout", "100ms", "64M", "Scratch"],
+        &[("MKRAMDISK_MOCK_FAIL", "mount")],
+    );
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("mounted using diskutil mount"));
+    assert!(root.0.join("Volumes/Scratch").is_dir());
+}
+
+#[test]
+fn test_mount_failure_detaches_device() {
+    let root = MockRoot::new("remount-failure");
+    let output = root.run_with(
+        &["--mount-timeout", "100ms", "64M", "Scratch"],
+        &[("MKRAMDISK_MOCK_FAIL", "remount")],
+    );
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("failed to mount"));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_project_disks() {
+    let root = MockRoot::new("project-disks");
+    let project = root.0.join("myapp");
+    fs::create_dir_all(project.join("fixtures")).unwrap();
+    fs::write(project.join("fixtures/data.txt"), "seeded").unwrap();
+    fs::write(
+        project.join(".mkramdisk.toml"),
+        concat!(
+            "[[disk]]\nname = \"Fixtures\"\nsize = \"32M\"\nseed = \"fixtures\"\ndepends_on = [\"Build\"]\n\n",
+            "[[disk]]\nname = \"Build\"\nsize = \"64M\"\n[disk.env]\nOUT = \"{mount_point}/out\"\n",
+        ),
+    )
+    .unwrap();
+
+    let up = root.command(&["up"]).current_dir(&project).output().unwrap();
+    assert!(up.status.success(), "{}", String::from_utf8_lossy(&up.stderr));
+    assert_eq!(root.devices(), 2);
+    assert_eq!(fs::read_to_string(root.0.join("Volumes/Fixtures/data.txt")).unwrap(), "seeded");
+    assert!(!root.0.join("Volumes/Build/data.txt").exists());
+    let stdout = String::from_utf8_lossy(&up.stdout);
+    assert_eq!(stdout.trim_end(), format!("export OUT='{}'", root.0.join("Volumes/Build/out").display()));
+
+    let again = root.command(&["up"]).current_dir(&project).output().unwrap();
+    let stderr = String::from_utf8_lossy(&again.stderr);
+    assert!(stderr.contains("'Build' is already up") && stderr.contains("'Fixtures' is already up"), "{}", stderr);
+    // Fixtures is declared first, but depends on Build
+    assert!(stderr.find("'Build'") < stderr.find("'Fixtures'"), "{}", stderr);
+
+    let down = root.command(&["down"]).current_dir(&project).output().unwrap();
+    assert!(down.status.success(), "{}", String::from_utf8_lossy(&down.stderr));
+    assert_eq!(root.devices(), 0);
+
+    // A volume called Build that mkramdisk didn't make is neither used nor torn down
+    let elsewhere = root.0.join("elsewhere.json");
+    assert!(root.run_with(&["64M", "Build"], &[("MKRAMDISK_STATE", &elsewhere.to_string_lossy())]).status.success());
+    fs::create_dir_all(root.0.join("Volumes/Fixtures")).unwrap();
+    for command in ["up", "down"] {
+        let output = root.command(&[command]).current_dir(&project).output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("is not a RAM disk mkramdisk created"), "{}", command);
+    }
+    assert!(root.0.join("Volumes/Build").is_dir() && root.0.join("Volumes/Fixtures").is_dir());
+    assert_eq!(root.devices(), 1);
+
+    fs::write(project.join(".mkramdisk.toml"), "[[disk]]\nsize = \"64M\"\n[[disk]]\nsize = \"32M\"\n").unwrap();
+    let up = root.command(&["up"]).current_dir(&project).output().unwrap();
+    assert!(!up.status.success());
+    assert!(String::from_utf8_lossy(&up.stderr).contains("needs a name"));
+}
+
+// Stands in for ssh: runs the command line here, with each host's disks in a mock root
+// of its own, and can't reach a host called "offline"
+#[cfg(unix)]
+const FAKE_SSH: &str = r#"#!/bin/sh
+[ "$1" = "--" ] && shift
+if [ "$1" = offline ]; then
+    echo "ssh: connect to host offline port 22: Connection refused" >&2
+    exit 255
+fi
+export MKRAMDISK_MOCK_ROOT="$MKRAMDISK_MOCK_ROOT/$1" MKRAMDISK_STATE="$MKRAMDISK_MOCK_ROOT/$1/state.json"
+exec sh -c "$2"
+"#;
+
+#[cfg(unix)]
+#[test]
+fn test_fleet_apply() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = MockRoot::new("fleet");
+    fs::create_dir_all(root.0.join("bin")).unwrap();
+    fs::write(root.0.join("bin/ssh"), FAKE_SSH).unwrap();
+    fs::set_permissions(root.0.join("bin/ssh"), fs::Permissions::from_mode(0o755)).unwrap();
+    let mkramdisk = Path::new(env!("CARGO_BIN_EXE_mkramdisk")).parent().unwrap();
+    let path = format!("{}:{}:{}", root.0.join("bin").display(), mkramdisk.display(), env::var("PATH").unwrap_or_default());
+    fs::write(
+        root.0.join("ci.toml"),
+        concat!(
+            "[[disk]]\nname = \"Cache\"\nsize = \"32M\"\nbackend = \"mock\"\ndepends_on = [\"Build\"]\n\n",
+            "[[disk]]\nname = \"Build\"\nsize = \"64M\"\nbackend = \"mock\"\n",
+        ),
+    )
+    .unwrap();
+    fs::write(root.0.join("hosts.txt"), "mac-mini-1\nmac-mini-2  # spare\n").unwrap();
+    let apply = |hosts: &str| {
+        // The backend is the manifest's, so this isn't root.command
+        let output = Command::new(env!("CARGO_BIN_EXE_mkramdisk"))
+            .args(["fleet", "apply"])
+            .arg(root.0.join("ci.toml"))
+            .arg("--hosts")
+            .arg(root.0.join(hosts))
+            .env("PATH", &path)
+            .env("MKRAMDISK_MOCK_ROOT", &root.0)
+            .env("MKRAMDISK_CONFIG", root.0.join("config.toml"))
+            .env("MKRAMDISK_STATE", root.0.join("state.json"))
+            .output()
+            .unwrap();
+        let results: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        (output, results)
+    };
+
+    let (output, results) = apply("hosts.txt");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(results.as_array().unwrap().len(), 2);
+    for (result, host) in results.as_array().unwrap().iter().zip(["mac-mini-1", "mac-mini-2"]) {
+        assert_eq!((&result["host"], &result["ok"]), (&serde_json::json!(host), &serde_json::json!(true)));
+        assert_eq!(result["disks"], serde_json::json!([{ "name": "Build", "result": "created" }, { "name": "Cache", "result": "created" }]));
+        assert!(root.0.join(host).join("Volumes/Build").is_dir() && root.0.join(host).join("Volumes/Cache").is_dir());
+    }
+
+    // Applying again changes nothing, and a host that can't be reached fails on its own
+    fs::write(root.0.join("more-hosts.txt"), "mac-mini-1\noffline\n").unwrap();
+    let (output, results) = apply("more-hosts.txt");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 of 2 hosts failed: offline"));
+    assert_eq!(results[0]["disks"], serde_json::json!([{ "name": "Build", "result": "unchanged" }, { "name": "Cache", "result": "unchanged" }]));
+    assert_eq!(results[1]["ok"], false);
+    assert!(results[1]["error"].as_str().unwrap().contains("offline cannot be reached"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_event_sinks() {
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    let root = MockRoot::new("event-sinks");
+    fs::create_dir_all(&root.0).unwrap();
+    let log = root.0.join("events.ndjson");
+    let socket = root.0.join("events.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let collector = std::thread::spawn(move || {
+        let mut received = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut received).unwrap();
+        received
+    });
+
+    let file_sink = format!("file:{}", log.display());
+    let socket_sink = format!("unix:{}", socket.display());
+    let output = root.run(&["64M", "Scratch", "--events-to", &file_sink, "--events-to", &socket_sink]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // Only --events-to stderr writes them to stderr
+    assert!(!String::from_utf8_lossy(&output.stderr).contains(r#""event""#));
+    assert!(root.run(&["eject", "Scratch", "--events-to", &file_sink]).status.success());
+
+    // The created and ejected events, among the create's stage events
+    let events: Vec<serde_json::Value> = fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .filter(|event| matches!(event["event"].as_str(), Some("created" | "ejected")))
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!((events[0]["event"].as_str(), events[0]["name"].as_str()), (Some("created"), Some("Scratch")));
+    assert_eq!((events[1]["event"].as_str(), events[1]["target"].as_str()), (Some("ejected"), Some("Scratch")));
+    let received = collector.join().unwrap();
+    assert!(received.starts_with(r#"{"#) && received.contains(r#""event":"created""#), "{}", received);
+
+    let output = root.run(&["64M", "Other", "--events-to", &format!("unix:{}", root.0.join("missing.sock").display())]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to connect"));
+    assert!(root.run(&["64M", "Other", "--events-to", "udp:collector:514"]).status.code() == Some(1));
+}
+
+#[test]
+fn test_project_up_and_down() {
+    let root = MockRoot::new("project");
+    let project = root.0.join("myapp");
+    fs::create_dir_all(project.join("src")).unwrap();
+    fs::create_dir_all(project.join("fixtures")).unwrap();
+    fs::write(project.join("fixtures/data.txt"), "seeded").unwrap();
+    fs::write(
+        project.join(".mkramdisk.toml"),
+        "size = \"64M\"\nseed = \"fixtures\"\n[links]\nbuild = \"build\"\n[env]\nOUT = \"{mount_point}/build\"\n",
+    )
+    .unwrap();
+
+    let up = root.command(&["up", "--events", "ndjson"]).current_dir(project.join("src")).output().unwrap();
+    assert!(up.status.success(), "{}", String::from_utf8_lossy(&up.stderr));
+    let done = String::from_utf8_lossy(&up.stderr)
+        .lines()
+        .find(|line| line.contains(r#""event":"progress_done""#))
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .expect("no progress_done event");
+    assert_eq!((done["files"].as_u64(), done["bytes_total"].as_u64()), (Some(1), Some(6)));
+    let volume = root.0.join("Volumes/myapp");
+    assert_eq!(fs::read_to_string(volume.join("data.txt")).unwrap(), "seeded");
+    assert!(fs::read_to_string(volume.join(".mkramdisk-seed.sha256")).unwrap().ends_with("  data.txt\n"));
+    assert_eq!(fs::read_link(project.join("build")).unwrap(), volume.join("build"));
+    let stdout = String::from_utf8_lossy(&up.stdout);
+    assert_eq!(stdout.trim_end(), format!("export OUT='{}'", volume.join("build").display()));
+
+    let again = root.command(&["up"]).current_dir(&project).output().unwrap();
+    assert!(String::from_utf8_lossy(&again.stderr).contains("already up"));
+    assert_eq!(root.devices(), 1);
+
+    let down = root.command(&["down"]).current_dir(&project).output().unwrap();
+    assert!(down.status.success(), "{}", String::from_utf8_lossy(&down.stderr));
+    assert!(!volume.exists());
+    assert!(fs::symlink_metadata(project.join("build")).is_err());
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_project_seed_image_is_restored() {
+    let root = MockRoot::new("project-restore");
+    let project = root.0.join("myapp");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(project.join("seed.dmg"), "").unwrap();
+    fs::write(project.join(".mkramdisk.toml"), "size = \"64M\"\nseed = \"seed.dmg\"\n").unwrap();
+
+    let up = root.command(&["up"]).current_dir(&project).output().unwrap();
+    assert!(up.status.success(), "{}", String::from_utf8_lossy(&up.stderr));
+    assert!(String::from_utf8_lossy(&up.stderr).contains("by block restore"));
+    // Restored as "seed", then renamed so the project finds it again
+    assert!(root.0.join("Volumes/myapp").is_dir());
+    assert!(!root.0.join("Volumes/seed").exists());
+
+    let down = root.command(&["down"]).current_dir(&project).output().unwrap();
+    assert!(down.status.success(), "{}", String::from_utf8_lossy(&down.stderr));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_eject() {
+    let root = MockRoot::new("eject");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+
+    let busy = root.run_with(&["eject", "Scratch"], &[("MKRAMDISK_MOCK_FAIL", "busy")]);
+    assert!(!busy.status.success());
+    assert!(String::from_utf8_lossy(&busy.stderr).contains("use --force"));
+    assert_eq!(root.devices(), 1);
+
+    // Something with the volume as its working directory holds it, which lsof finds
+    let mut holder = Command::new("sleep").arg("30").current_dir(root.0.join("Volumes/Scratch")).spawn().unwrap();
+    let busy = root.run_with(&["eject", "Scratch"], &[("MKRAMDISK_MOCK_FAIL", "busy")]);
+    let stderr = String::from_utf8_lossy(&busy.stderr);
+    if Command::new("lsof").arg("-v").output().is_ok() {
+        assert!(stderr.contains(&format!("Files still open on Scratch:\n  sleep (pid {}): ", holder.id())), "{}", stderr);
+        assert!(stderr.contains(&format!("Quit sleep (pid {0}) (or run `kill {0}`), or use --force", holder.id())), "{}", stderr);
+    }
+
+    let forced = root.run_with(&["eject", "--force", "Scratch"], &[("MKRAMDISK_MOCK_FAIL", "busy")]);
+    let stderr = String::from_utf8_lossy(&forced.stderr);
+    assert!(forced.status.success(), "{}", stderr);
+    if Command::new("lsof").arg("-v").output().is_ok() {
+        assert!(stderr.contains(&format!("Forcing Scratch out from under sleep (pid {})", holder.id())), "{}", stderr);
+    }
+    holder.kill().unwrap();
+    holder.wait().unwrap();
+    assert!(!root.0.join("Volumes/Scratch").exists());
+    assert_eq!(root.devices(), 0);
+
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let device = root.0.join("dev/disk0");
+    assert!(root.run(&["eject", &device.to_string_lossy()]).status.success());
+    assert_eq!(root.devices(), 0);
+
+    // By mount point, which finds the device and so the disk in the state file
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let output = root.run(&["eject", "--verbose", &root.0.join("Volumes/Scratch").to_string_lossy()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("is on {}", device.display())), "{}", stderr);
+    assert!(!stderr.contains("not created by mkramdisk"), "{}", stderr);
+    assert_eq!(root.devices(), 0);
+
+    let missing = root.run(&["eject", "Scratch"]);
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("No RAM disk named 'Scratch'"));
+
+    // Volumes/.. exists, but is no RAM disk
+    let parent = root.run(&["eject", ".."]);
+    assert!(String::from_utf8_lossy(&parent.stderr).contains("cannot be a volume name"));
+    assert!(root.0.join("Volumes").is_dir());
+
+    // Every way of naming a disk someone else made needs --unmanaged
+    assert!(root.run(&["64M", "Other"]).status.success());
+    fs::remove_file(root.0.join("state.json")).unwrap();
+    let volume = root.0.join("Volumes/Other");
+    for target in [volume.to_string_lossy(), device.to_string_lossy()] {
+        let output = root.run(&["eject", &target]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("pass --unmanaged"), "{}", target);
+    }
+    assert_eq!(root.devices(), 1);
+    assert!(root.run(&["eject", "--unmanaged", &volume.to_string_lossy()]).status.success());
+    assert_eq!(root.devices(), 0);
+
+    // And a mount point, device or disk that isn't the backend's is never ejected
+    fs::create_dir_all(root.0.join("Volumes/MyUSB")).unwrap();
+    let usb = root.0.join("Volumes/MyUSB");
+    for target in [usb.to_string_lossy(), root.0.join("dev/disk9").to_string_lossy(), "disk9".into()] {
+        let output = root.run(&["eject", "--unmanaged", "--force", &target]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("is not a RAM disk of the mock backend"), "{}", target);
+    }
+    assert!(usb.is_dir());
+}
+
+#[test]
+fn test_resize() {
+    let root = MockRoot::new("resize");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let volume = root.0.join("Volumes/Scratch");
+    fs::write(volume.join("data.txt"), "kept").unwrap();
+
+    let output = root.run(&["resize", "Scratch", "128M"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(volume.join("data.txt")).unwrap(), "kept");
+    assert!(!root.0.join("Volumes/Scratch-resizing").exists());
+    assert_eq!(root.devices(), 1);
+    let device = fs::read_dir(root.0.join("dev")).unwrap().next().unwrap().unwrap().path();
+    assert_eq!(fs::read_to_string(device).unwrap(), "262144\nAPFS\nScratch\n");
+    let output = root.run(&["list"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("not created by mkramdisk"));
+
+    fs::write(volume.join("big.bin"), vec![0; 2 << 20]).unwrap();
+    let output = root.run(&["resize", "Scratch", "1M"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot hold"));
+    assert_eq!(root.devices(), 1);
+}
+
+#[test]
+fn test_features() {
+    let root = MockRoot::new("features");
+    let image = root.0.join("Golden.dmg");
+    fs::create_dir_all(&image).unwrap();
+    let output = root.run(&["overlay", image.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("overlay is experimental; turn it on with --enable-experimental"));
+    assert_eq!(root.devices(), 0);
+
+    let output = root.run(&["features"]);
+    let listed = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(listed.lines().all(|line| line.contains(" off ")), "{}", listed);
+
+    fs::write(root.0.join("config.toml"), "features = [\"overlay\"]\n").unwrap();
+    let output = root.run_with(&["features", "--json"], &[("MKRAMDISK_FEATURES", "accelerate")]);
+    let listed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(listed[0]["source"], "config.toml");
+    assert_eq!(listed[1]["source"], "MKRAMDISK_FEATURES");
+    assert_eq!(listed[2]["enabled"], false);
+    let output = root.run(&["features", "--enable-experimental"]);
+    assert!(String::from_utf8_lossy(&output.stdout).lines().all(|line| line.contains("on (--enable-experimental)")));
+    assert!(root.run(&["overlay", image.to_str().unwrap()]).status.success());
+
+    let output = root.run_with(&["features"], &[("MKRAMDISK_FEATURES", "raid")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown feature 'raid' in MKRAMDISK_FEATURES"));
+}
+
+#[test]
+fn test_overlay() {
+    let root = MockRoot::new("overlay");
+    let image = root.0.join("images/Golden.dmg");
+    fs::create_dir_all(&image).unwrap();
+    fs::write(image.join("installer.txt"), "v1").unwrap();
+    let image = image.to_str().unwrap();
+
+    let output = root.run(&["overlay", image, "64M", "--json", "--enable-experimental"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let attached: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(attached["name"], "Golden");
+    assert_eq!(attached["shadow"], root.0.join("Volumes/Golden-shadow/Golden.shadow").to_string_lossy().as_ref());
+    assert!(root.0.join("Volumes/Golden-shadow/Golden.shadow").is_file());
+    assert_eq!(root.devices(), 2);
+    let volume = root.0.join("Volumes/Golden");
+    fs::write(volume.join("installer.txt"), "v2").unwrap();
+    assert_eq!(fs::read_to_string(root.0.join("images/Golden.dmg/installer.txt")).unwrap(), "v1");
+    let listed = root.run(&["list"]);
+    assert!(!String::from_utf8_lossy(&listed.stdout).contains("not created by mkramdisk"));
+
+    // The image's volume and the disk with its shadow file go together
+    let output = root.run(&["eject", "Golden"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(root.devices(), 0);
+    assert!(!root.0.join("Volumes/Golden-shadow").exists());
+
+    let output = root.run_with(&["overlay", image, "--enable-experimental"], &[("MKRAMDISK_MOCK_FAIL", "overlay")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("simulated overlay failure"));
+    assert_eq!(root.devices(), 0);
+    assert!(!root.run(&["overlay", "missing.dmg", "--enable-experimental"]).status.success());
+}
+
+#[test]
+fn test_accelerate() {
+    let root = MockRoot::new("accelerate");
+    let dir = root.0.join("project/cache");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("kept.txt"), "v1").unwrap();
+    let path = dir.to_str().unwrap();
+
+    let output = root.run(&["accelerate", path, "--enable-experimental"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_link(&dir).unwrap(), root.0.join("Volumes/cache"));
+    assert_eq!(fs::read_to_string(dir.join("kept.txt")).unwrap(), "v1");
+    assert!(!root.run(&["accelerate", path, "--enable-experimental"]).status.success());
+    fs::write(dir.join("kept.txt"), "v2").unwrap();
+    fs::write(dir.join("new.txt"), "new").unwrap();
+
+    let output = root.run(&["decelerate", path]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!fs::symlink_metadata(&dir).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_to_string(dir.join("kept.txt")).unwrap(), "v2");
+    assert!(dir.join("new.txt").is_file());
+    assert_eq!(fs::read_dir(root.0.join("project")).unwrap().count(), 1);
+    assert_eq!(root.devices(), 0);
+
+    assert!(root.run(&["accelerate", path, "64M", "--enable-experimental"]).status.success());
+    fs::write(dir.join("scratch.txt"), "dropped").unwrap();
+    let output = root.run(&["decelerate", "--discard", path]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!dir.join("scratch.txt").exists());
+    assert!(dir.join("new.txt").is_file());
+    assert_eq!(root.devices(), 0);
+    assert!(!root.run(&["decelerate", path]).status.success());
+}
+
+#[test]
+fn test_selftest() {
+    let root = MockRoot::new("selftest");
+    let output = root.run(&["selftest"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for step in ["create", "write", "read", "verify", "destroy"] {
+        assert!(stdout.contains(&format!("PASS  {:<8}", step)), "{}", stdout);
+    }
+    assert!(stdout.ends_with("Self-test passed (5 of 5 steps)\n"));
+    assert_eq!(root.devices(), 0);
+
+    let failed = root.run_with(&["selftest"], &[("MKRAMDISK_MOCK_FAIL", "format")]);
+    assert_eq!(failed.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&failed.stdout);
+    assert!(stdout.starts_with("FAIL  create"), "{}", stdout);
+    assert!(stdout.contains("SKIP  destroy"));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_list() {
+    let root = MockRoot::new("list");
+    assert!(String::from_utf8_lossy(&root.run(&["list"]).stderr).contains("No RAM disks attached"));
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let unmounted = root.run_with(
+        &["--mount-timeout", "100ms", "--keep-on-failure", "64M", "Attached"],
+        &[("MKRAMDISK_MOCK_FAIL", "remount")],
+    );
+    assert!(!unmounted.status.success());
+
+    let output = root.run(&["list"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", stdout);
+    assert!(lines[0].starts_with("Scratch ") && lines[0].ends_with("Volumes/Scratch"), "{}", stdout);
+    // Left attached by a failed create, so it never made it into the state file
+    assert!(lines[1].starts_with("- ") && lines[1].ends_with("dev/disk1 not mounted  (not created by mkramdisk)"), "{}", stdout);
+}
+
+#[test]
+fn test_registry() {
+    let root = MockRoot::new("registry");
+    assert!(root.run(&["64M", "Scratch", "--protected"]).status.success());
+    let state: serde_json::Value = serde_json::from_slice(&fs::read(root.0.join("state.json")).unwrap()).unwrap();
+    let disk = &state["disks"][0];
+    assert_eq!(disk["name"], "Scratch");
+    assert_eq!(disk["backend"], "mock");
+    assert_eq!(disk["size_bytes"], 64 << 20);
+    assert_eq!(disk["flags"], serde_json::json!(["protected"]));
+    assert!(disk["device"].as_str().unwrap().ends_with("dev/disk0"));
+
+    let output = root.run(&["info", "Scratch"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Managed:       yes (protected)"));
+    fs::remove_file(root.0.join("Volumes/Scratch/.mkramdisk-protected")).unwrap();
+    let output = root.run(&["eject", "Scratch"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Warning"));
+    let state: serde_json::Value = serde_json::from_slice(&fs::read(root.0.join("state.json")).unwrap()).unwrap();
+    assert_eq!(state["disks"], serde_json::json!([]));
+
+    // A disk the state file doesn't know about is someone else's
+    assert!(root.run(&["64M", "Other"]).status.success());
+    fs::remove_file(root.0.join("state.json")).unwrap();
+    let output = root.run(&["info", "Other"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Managed:       no"));
+    let output = root.run(&["eject", "Other", "--force"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'Other' was not created by mkramdisk; pass --unmanaged"));
+    assert!(root.0.join("Volumes/Other").exists());
+    assert!(root.run(&["eject", "Other", "--unmanaged"]).status.success());
+    assert!(!root.0.join("Volumes/Other").exists());
+
+    // Nor is a volume of some other disk, even with --unmanaged
+    fs::create_dir_all(root.0.join("Volumes/MyUSB")).unwrap();
+    let output = root.run(&["eject", "MyUSB", "--force", "--unmanaged"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'MyUSB' is not a RAM disk of the mock backend"));
+    assert!(root.0.join("Volumes/MyUSB").exists());
+
+    // A disk detached behind mkramdisk's back leaves its entry, but a disk given the
+    // same device afterwards is still someone else's
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let device = root.0.join("dev/disk0");
+    fs::remove_file(&device).unwrap();
+    fs::remove_dir_all(root.0.join("Volumes/Scratch")).unwrap();
+    let elsewhere = root.0.join("elsewhere.json");
+    assert!(root.run_with(&["64M", "Reused"], &[("MKRAMDISK_STATE", &elsewhere.to_string_lossy())]).status.success());
+    assert!(device.exists());
+    let output = root.run(&["info", "Reused"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Managed:       no"));
+    let output = root.run(&["eject", "Reused"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("pass --unmanaged"));
+}
+
+#[test]
+fn test_strict_warning_after_creation_detaches_the_disk() {
+    let root = MockRoot::new("strict-after");
+    fs::create_dir_all(&root.0).unwrap();
+    // A state file that can't be written, as its directory is a file
+    fs::write(root.0.join("not-a-dir"), "").unwrap();
+    let state = root.0.join("not-a-dir/state.json");
+    let output = root.run_with(&["64M", "Scratch", "--strict"], &[("MKRAMDISK_STATE", &state.to_string_lossy())]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Failed to record Scratch in the state file") && stderr.contains("was detached again"), "{}", stderr);
+    assert_eq!(root.devices(), 0);
+    assert!(!root.0.join("Volumes/Scratch").exists());
+
+    if Command::new("pbcopy").output().is_err() {
+        let output = root.run(&["64M", "Scratch", "--copy-path", "--strict"]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("was detached again"));
+        assert_eq!(root.devices(), 0);
+        let state: serde_json::Value = serde_json::from_slice(&fs::read(root.0.join("state.json")).unwrap()).unwrap();
+        assert_eq!(state["disks"], serde_json::json!([]));
+    }
+}
+
+#[test]
+fn test_eject_all() {
+    let root = MockRoot::new("eject-all");
+    assert!(root.run(&["64M", "Other"]).status.success());
+    // Made without mkramdisk, as far as it knows
+    fs::remove_file(root.0.join("state.json")).unwrap();
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    assert!(root.run(&["64M", "Raw", "--experimental", "-f", "Free Space"]).status.success());
+    assert!(root.run(&["64M", "Gone"]).status.success());
+    assert!(root.run(&["eject", "Gone"]).status.success());
+    assert!(root.run(&["64M", "Build"]).status.success());
+    // Build goes behind mkramdisk's back, so its entry is stale
+    let state: serde_json::Value = serde_json::from_slice(&fs::read(root.0.join("state.json")).unwrap()).unwrap();
+    let build = state["disks"].as_array().unwrap().iter().find(|disk| disk["name"] == "Build").unwrap();
+    fs::remove_file(build["device"].as_str().unwrap()).unwrap();
+    fs::remove_dir_all(root.0.join("Volumes/Build")).unwrap();
+    // and someone else's disk takes both its device and its name
+    let elsewhere = root.0.join("elsewhere.json");
+    assert!(root.run_with(&["64M", "Build"], &[("MKRAMDISK_STATE", &elsewhere.to_string_lossy())]).status.success());
+    assert!(Path::new(build["device"].as_str().unwrap()).exists());
+    let dir = root.0.join("project/cache");
+    fs::create_dir_all(&dir).unwrap();
+    assert!(root.run(&["accelerate", dir.to_str().unwrap(), "--enable-experimental"]).status.success());
+    fs::write(dir.join("kept.txt"), "written back").unwrap();
+    assert_eq!(root.devices(), 5);
+
+    let output = root.run(&["eject", "--all"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(root.devices(), 2);
+    assert!(root.0.join("Volumes/Other").is_dir());
+    assert!(root.0.join("Volumes/Build").is_dir());
+    assert_eq!(fs::read_to_string(dir.join("kept.txt")).unwrap(), "written back");
+    let state: serde_json::Value = serde_json::from_slice(&fs::read(root.0.join("state.json")).unwrap()).unwrap();
+    assert_eq!(state["disks"], serde_json::json!([]));
+
+    let output = root.run(&["eject", "--all"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No RAM disks to eject"));
+    assert!(!root.run(&["eject", "--all", "Other"]).status.success());
+}
+
+#[test]
+fn test_adopt() {
+    let root = MockRoot::new("adopt");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    // As if made with hdiutil directly
+    fs::remove_file(root.0.join("state.json")).unwrap();
+    let device = root.0.join("dev/disk0");
+
+    let output = root.run(&["adopt", device.to_str().unwrap(), "--json"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let adopted: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(adopted["name"], "Scratch");
+    assert_eq!(adopted["size_bytes"], 64 << 20);
+    let output = root.run(&["info", "Scratch"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Managed:       yes (adopted)"));
+    let output = root.run(&["adopt", device.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already managed by mkramdisk as 'Scratch'"));
+    assert!(!root.run(&["adopt", "/dev/disk99"]).status.success());
+
+    let output = root.run(&["eject", "Scratch", "--strict"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_info() {
+    let root = MockRoot::new("info");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    fs::write(root.0.join("Volumes/Scratch/data.txt"), "12345678").unwrap();
+
+    let output = root.run(&["info", "Scratch"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Sectors:       131072 (64M)\n"), "{}", stdout);
+    assert!(stdout.contains("Filesystem:    APFS\n"));
+    assert!(stdout.contains("Used:          8 bytes of 67108864 (0%), 67108856 bytes free\n"));
+
+    let output = root.run(&["info", "Missing"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No RAM disk named 'Missing'"));
+}
+
+#[test]
+fn test_backups() {
+    let root = MockRoot::new("backups");
+    let store = root.0.join("store").display().to_string();
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    fs::create_dir_all(root.0.join("Volumes/Scratch/build")).unwrap();
+    fs::write(root.0.join("Volumes/Scratch/build/out.o"), "object").unwrap();
+
+    let output = root.run(&["backups", "save", "Scratch", "--store", &store]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    assert!(root.0.join("store/Scratch/metadata.json").is_file());
+    assert!(root.0.join("store/Scratch/snapshots").join(&id).join("build/out.o").is_file());
+
+    let output = root.run(&["backups", "ls", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&format!("1 snapshots  latest {}", id)));
+    let output = root.run(&["backups", "ls", "Scratch", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with(&id));
+
+    // Back onto a fresh disk of the same name
+    assert!(root.run(&["eject", "Scratch"]).status.success());
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let output = root.run(&["backups", "restore", "Scratch", "--store", &store]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(root.0.join("Volumes/Scratch/build/out.o")).unwrap(), "object");
+    // Again, onto a disk that still has the files, copies only what differs
+    let output = root.run(&["backups", "restore", "Scratch", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 already there"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!root.run(&["backups", "restore", "Scratch", "20000101T000000Z", "--store", &store]).status.success());
+
+    let output = root.run(&["backups", "rm", "Scratch", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs --force"));
+    assert!(root.run(&["backups", "rm", "Scratch", &id, "--store", &store]).status.success());
+    assert!(!root.0.join("store/Scratch/snapshots").join(&id).exists());
+    assert!(root.run(&["backups", "rm", "Scratch", "--force", "--store", &store]).status.success());
+    assert!(!root.0.join("store/Scratch").exists());
+}
+
+#[test]
+fn test_tags() {
+    let root = MockRoot::new("tags");
+    let store = root.0.join("store").display().to_string();
+    assert!(root.run(&["64M", "Build", "--tag", "ci", "--tag", "build"]).status.success());
+    assert!(root.run(&["64M", "Cache", "--tag=ci"]).status.success());
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let output = root.run(&["64M", "Bad", "--tag", "ci build"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be a tag"));
+
+    let output = root.run(&["list", "--tag", "ci"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().count(), 2, "{}", stdout);
+    assert!(stdout.contains("[ci, build]"));
+    let output = root.run(&["list", "--tag", "ci", "--tag", "build", "--json"]);
+    let listed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["tags"], serde_json::json!(["ci", "build"]));
+
+    fs::write(root.0.join("Volumes/Build/out.o"), "object").unwrap();
+    let output = root.run(&["sync", "--tag", "build", "--store", &store]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Build "));
+    assert!(root.0.join("store/Build/metadata.json").is_file());
+    assert!(!root.0.join("store/Cache").exists());
+    let output = root.run(&["sync", "--tag", "nightly", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No RAM disks tagged nightly to sync"));
+
+    assert!(String::from_utf8_lossy(&root.run(&["eject", "--tag", "ci", "Build"]).stderr).contains("--tag takes no name"));
+    let output = root.run(&["destroy", "--tag", "ci"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(root.devices(), 1);
+    assert!(root.0.join("Volumes/Scratch").is_dir());
+    let output = root.run(&["eject", "--tag", "ci"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No RAM disks tagged ci to eject"));
+}
+
+// Stands in for age: "encrypts" by putting a line in front of the tar, and
+// "decrypts" only when given an identity
+#[cfg(unix)]
+const FAKE_AGE: &str = r#"#!/bin/sh
+out= decrypt= identity=
+while [ $# -gt 0 ]; do
+    case $1 in
+        -d) decrypt=1 ;;
+        -o) out=$2; shift ;;
+        -i) identity=$2; shift ;;
+        -r) shift ;;
+        *) input=$1 ;;
+    esac
+    shift
+done
+if [ -n "$decrypt" ]; then
+    [ -f "$identity" ] || { echo "age: no identity matched" >&2; exit 1; }
+    tail -n +2 "$input"
+else
+    { echo "age-encrypted"; cat; } > "$out"
+fi
+"#;
+
+#[test]
+#[cfg(unix)]
+fn test_encrypted_backups() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = MockRoot::new("encrypted-backups");
+    let store = root.0.join("store").display().to_string();
+    fs::create_dir_all(root.0.join("bin")).unwrap();
+    fs::write(root.0.join("bin/age"), FAKE_AGE).unwrap();
+    fs::set_permissions(root.0.join("bin/age"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(root.0.join("key.txt"), "AGE-SECRET-KEY-1").unwrap();
+    let path = format!("{}:{}", root.0.join("bin").display(), env::var("PATH").unwrap_or_default());
+    let envs = [("PATH", path.as_str())];
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    fs::write(root.0.join("Volumes/Scratch/secret.txt"), "plaintext").unwrap();
+
+    let output = root.run_with(&["backups", "save", "Scratch", "--store", &store, "--encrypt-to", "age1example"], &envs);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let snapshot = root.0.join("store/Scratch/snapshots").join(&id);
+    assert!(!snapshot.join("secret.txt").exists());
+    assert!(fs::read(snapshot.join("files.tar.age")).unwrap().starts_with(b"age-encrypted\n"));
+    let output = root.run(&["backups", "ls", "Scratch", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 files"));
+    assert!(String::from_utf8_lossy(&output.stdout).trim_end().ends_with("encrypted"));
+
+    fs::remove_file(root.0.join("Volumes/Scratch/secret.txt")).unwrap();
+    let output = root.run_with(&["backups", "restore", "Scratch", "--store", &store], &envs);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--identity"));
+    let key = root.0.join("key.txt").display().to_string();
+    let output = root.run_with(&["backups", "restore", "Scratch", "--store", &store, "--identity", &key], &envs);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(root.0.join("Volumes/Scratch/secret.txt")).unwrap(), "plaintext");
+
+    // Recipients from config.toml encrypt without the option
+    fs::write(root.0.join("config.toml"), "backup_recipients = [\"age1example\"]\n").unwrap();
+    let output = root.run_with(&["backups", "save", "Scratch", "--store", &store], &envs);
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    assert!(root.0.join("store/Scratch/snapshots").join(&id).join("files.tar.age").is_file());
+
+    let output = root.run(&["backups", "keygen", "--store", &store]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--keychain-item"));
+    #[cfg(not(target_os = "macos"))]
+    {
+        let output = root.run(&["backups", "restore", "Scratch", "--store", &store, "--keychain-item", "backups"]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("only available on macOS"));
+    }
+}
+
+// On macOS this would ask for Touch ID
+#[test]
+#[cfg(not(target_os = "macos"))]
+fn test_protected_disk() {
+    let root = MockRoot::new("protected");
+    assert!(root.run(&["64M", "Scratch", "--protected"]).status.success());
+    let marker = root.0.join("Volumes/Scratch/.mkramdisk-protected");
+    assert!(marker.is_file());
+
+    // Without a terminal to confirm at, it stays
+    let output = root.run(&["eject", "Scratch"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Not allowed to eject the protected RAM disk 'Scratch'"));
+    assert_eq!(root.devices(), 1);
+
+    fs::remove_file(marker).unwrap();
+    assert!(root.run(&["eject", "Scratch"]).status.success());
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_encrypt() {
+    let root = MockRoot::new("encrypt");
+    let output = root.run_with(&["64M", "Scratch", "--encrypt", "--passphrase-from", "env", "--json"], &[("MKRAMDISK_PASSPHRASE", "hunter2")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let created: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(created["encrypted"], true);
+    let device = fs::read_to_string(created["device"].as_str().unwrap()).unwrap();
+    assert_eq!(device.lines().nth(1), Some("APFS (Encrypted)"));
+    assert!(fs::read_to_string(root.0.join("state.json")).unwrap().contains("\"encrypted\""));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("hunter2"));
+
+    // From stdin
+    let mut child = root.command(&["64M", "Other", "--encrypt", "--passphrase-from=stdin"]).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), b"hunter2\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Encrypted:  yes"));
+    assert_eq!(root.devices(), 2);
+
+    // Nowhere to read it from: nothing is attached
+    let output = root.command(&["64M", "Third", "--encrypt"]).stdin(Stdio::null()).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No terminal to ask for the passphrase at"));
+    let output = root.run(&["64M", "Third", "--encrypt", "--passphrase-from", "env:UNSET_PASSPHRASE_VAR"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("UNSET_PASSPHRASE_VAR is not set"));
+    assert_eq!(root.devices(), 2);
+
+    // A failure part way through takes the device with it
+    let output = root.run_with(&["64M", "Third", "--encrypt", "--passphrase-from", "env"], &[("MKRAMDISK_PASSPHRASE", "hunter2"), ("MKRAMDISK_MOCK_FAIL", "encrypt")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("simulated encryption failure"));
+    assert_eq!(root.devices(), 2);
+
+    // The Keychain is only on macOS; elsewhere --keychain fails before anything is attached
+    if !cfg!(target_os = "macos") {
+        let output = root.run_with(&["64M", "Third", "--encrypt", "--keychain", "--passphrase-from", "env"], &[("MKRAMDISK_PASSPHRASE", "hunter2")]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Keychain item 'volume:Third': the Keychain is only available on macOS"));
+        assert_eq!(root.devices(), 2);
+    }
+
+    for args in [&["64M", "Third", "--encrypt", "-f", "hfs+"][..], &["64M", "Third", "--encrypt", "--partitions", "a:apfs:32M"], &["64M", "Third", "--passphrase-from", "stdin"], &["64M", "Third", "--keychain"]] {
+        assert!(!root.run(args).status.success(), "{:?}", args);
+    }
+}
+
+#[test]
+fn test_changes() {
+    let root = MockRoot::new("changes");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    fs::create_dir_all(root.0.join("Volumes/Scratch/build")).unwrap();
+    fs::write(root.0.join("Volumes/Scratch/build/out.o"), "object").unwrap();
+
+    let output = root.run(&["changes", "Scratch", "--since", "1h"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "M build\nM build/out.o\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("2 changes on Scratch since"));
+
+    let output = root.run(&["changes", "--since=2999-01-01", "Scratch"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(!root.run(&["changes", "Scratch", "--since", "soon"]).status.success());
+}
+
+#[test]
+fn test_status() {
+    let root = MockRoot::new("status");
+    let missing = root.run(&["status", "Scratch"]);
+    assert_eq!(missing.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&missing.stdout), "No RAM disk named 'Scratch'\n");
+
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    let mounted = root.run(&["status", "Scratch"]);
+    assert_eq!(mounted.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&mounted.stdout).starts_with("Scratch is mounted at "));
+
+    let unmounted = root.run_with(
+        &["--mount-timeout", "100ms", "--keep-on-failure", "64M", "Attached"],
+        &[("MKRAMDISK_MOCK_FAIL", "remount")],
+    );
+    assert!(!unmounted.status.success());
+    let quiet = root.run(&["exists", "--quiet", "Attached"]);
+    assert_eq!(quiet.status.code(), Some(2));
+    assert!(quiet.stdout.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_argument() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let output = Command::new(env!("CARGO_BIN_EXE_mkramdisk"))
+        .args([OsStr::new("64M"), OsStr::from_bytes(b"caf\xe9")])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Error: Argument 'caf\u{FFFD}' is not valid UTF-8"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let root = MockRoot::new("non-utf8-path");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    fs::write(root.0.join("Volumes/Scratch/out.o"), "object").unwrap();
+    // A store whose name isn't UTF-8, given both ways
+    let store = root.0.join(OsStr::from_bytes(b"store-caf\xe9"));
+    let output = root.command(&["backups", "save", "Scratch", "--store"]).arg(&store).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(store.join("Scratch/metadata.json").is_file());
+    let mut inline = OsStr::new("--store=").to_os_string();
+    inline.push(&store);
+    let output = root.command(&["backups", "ls"]).arg(&inline).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 snapshots"), "{}", String::from_utf8_lossy(&output.stderr));
+    // A volume name still has to be UTF-8
+    let output = root.command(&["backups", "save", "--store"]).arg(&store).arg(OsStr::from_bytes(b"caf\xe9")).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not valid UTF-8"));
+}
+
+#[test]
+fn test_version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mkramdisk")).arg("--version").output().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with(&format!("mkramdisk {} (", env!("CARGO_PKG_VERSION"))));
+    assert!(stdout.contains("backends: ram"));
+    assert!(stdout.contains("api versions: 1 (default 1)"));
+}
+
+#[test]
+fn test_help_and_version_with_a_broken_config() {
+    let root = MockRoot::new("broken-config");
+    fs::create_dir_all(&root.0).unwrap();
+    fs::write(root.0.join("config.toml"), "size = [").unwrap();
+    for args in [&["--help"][..], &["-V"], &["create", "1G", "--help"], &["-vh"]] {
+        let output = root.run(args);
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        assert!(!output.stdout.is_empty());
+    }
+    // The config still counts for anything else
+    let output = root.run(&["64M", "Scratch"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("config.toml"));
+    // A value that merely looks like --help is not asked for help
+    assert!(!root.run(&["64M", "--name", "-h"]).stdout.starts_with(b"Usage"));
+}
+
+#[test]
+fn test_deprecations() {
+    let root = MockRoot::new("deprecations");
+    let warning = "Warning: --events ndjson is deprecated since 0.1.0; use --events-to stderr instead";
+    let output = root.run(&["64M", "Scratch", "--events", "ndjson"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(stderr.matches(warning).count(), 1, "{}", stderr);
+    assert!(stderr.contains(r#""event":"created""#), "{}", stderr);
+    // Only the first time
+    let output = root.run(&["list", "--events", "ndjson"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("deprecated"));
+    fs::remove_file(root.0.join("deprecations-shown.json")).unwrap();
+
+    let output = root.run_with(&["eject", "--events=ndjson", "Scratch"], &[("MKRAMDISK_NO_DEPRECATION_WARNINGS", "1")]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success() && !stderr.contains("deprecated"), "{}", stderr);
+    assert!(stderr.contains(r#""event":"ejected""#), "{}", stderr);
+
+    fs::write(root.0.join("config.toml"), "deprecation_warnings = false\n").unwrap();
+    let output = root.run(&["list", "--events", "ndjson"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("deprecated"));
+}
+
+#[test]
+fn test_api_version() {
+    let root = MockRoot::new("api-version");
+    let json = |output: &Output| -> serde_json::Value { serde_json::from_slice(&output.stdout).unwrap() };
+    let output = root.run(&["64M", "Scratch", "--json"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(json(&output)["api_version"], 1);
+
+    let output = root.run(&["list", "--api-version", "1", "--json"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(json(&output)[0]["api_version"], 1);
+    let output = root.run(&["64M", "Other", "--json-lines", "--api-version", "1"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().all(|line| line.contains(r#""api_version":1"#)), "{}", stdout);
+
+    // Nothing happens when the version can't be had
+    let output = root.run(&["64M", "Build", "--api-version", "2"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("API version 2 is not supported (this mkramdisk supports 1)"));
+    assert_eq!(root.devices(), 2);
+    assert!(!root.run(&["list", "--api-version"]).status.success());
+}
+
+#[test]
+fn test_missing_size_is_an_error_without_a_terminal() {
+    let root = MockRoot::new("missing-size");
+    let output = root.command(&["--name", "Scratch"]).stdin(Stdio::null()).output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Size argument is required"));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_legacy_output() {
+    let root = MockRoot::new("legacy-output");
+    let output = root.run(&["64M", "Scratch", "--legacy-output"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("RAM disk created successfully"));
+    assert!(!stdout.contains("RESULT="));
+}
+
+#[test]
+fn test_print_actions_json() {
+    let root = MockRoot::new("actions-json");
+    let output = root.run(&["64M", "Scratch", "--print-actions", "json"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let volume = root.0.join("Volumes/Scratch");
+    assert_eq!(json["mount_point"], volume.to_string_lossy().as_ref());
+    assert_eq!(json["actions"][0]["id"], "remove");
+    assert_eq!(json["actions"][0]["argv"][2], volume.to_string_lossy().as_ref());
+}
+
+#[test]
+fn test_default_size_from_user_config() {
+    let root = MockRoot::new("default-size");
+    fs::create_dir_all(&root.0).unwrap();
+    fs::write(root.0.join("config.toml"), "size = \"64M\"\n").unwrap();
+    let output = root.command(&["--name", "Scratch"]).stdin(Stdio::null()).output().unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("size=64M"));
+}
+
+#[test]
+fn test_defaults_from_user_config() {
+    let root = MockRoot::new("defaults");
+    fs::create_dir_all(&root.0).unwrap();
+    fs::write(root.0.join("config.toml"), "filesystem = \"hfs+\"\nname = \"Scratch\"\nmount_options = [\"noatime\"]\n").unwrap();
+    assert!(root.run(&["64M"]).status.success());
+
+    let output = root.run(&["info", "Scratch"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Filesystem:    HFS+\n"), "{}", stdout);
+    assert!(stdout.contains("Mount options: noatime\n"), "{}", stdout);
+
+    // The command line wins
+    assert!(root.run(&["64M", "Other", "-f", "apfs", "--mount-options", "nosuid,nodev"]).status.success());
+    let output = root.run(&["info", "Other"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Filesystem:    APFS\n"), "{}", stdout);
+    assert!(stdout.contains("Mount options: nosuid, nodev\n"), "{}", stdout);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_profile() {
+    let root = MockRoot::new("profile");
+    fs::create_dir_all(&root.0).unwrap();
+    fs::write(root.0.join("config.toml"), concat!(
+        "size = \"1G\"\n",
+        "[profile.xcode]\n",
+        "size = \"64M\"\n",
+        "fs = \"hfs+\"\n",
+        "name = \"DerivedData\"\n",
+        "after_create = \"echo hook says hello; echo $MKRAMDISK_DEVICE > \\\"$MKRAMDISK_MOUNT_POINT/device\\\"\"\n",
+        "before_eject = \"cp \\\"$MKRAMDISK_MOUNT_POINT/device\\\" \\\"$MKRAMDISK_MOUNT_POINT/../ejected\\\"\"\n",
+    )).unwrap();
+
+    let output = root.run(&["--profile", "xcode"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("size=64M") && stdout.contains("name=DerivedData") && stdout.contains("filesystem=hfs+"), "{}", stdout);
+    // Hook output goes to stderr, leaving stdout to the summary
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("hook says hello"));
+    let device = fs::read_to_string(root.0.join("Volumes/DerivedData/device")).unwrap();
+    assert!(device.trim().ends_with("dev/disk0"), "{}", device);
+
+    let output = root.run(&["eject", "DerivedData", "--profile", "xcode"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(root.0.join("Volumes/ejected")).unwrap(), device);
+    assert_eq!(root.devices(), 0);
+
+    let output = root.run(&["--profile", "rust"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Profiles: xcode"));
+}
+
+#[test]
+fn test_experimental_raw_device() {
+    let root = MockRoot::new("raw");
+    let output = root.run(&["64M", "Scratch", "--experimental", "-f", "Free Space"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("mount_point='' "));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("none (raw device)"));
+    assert!(!root.0.join("Volumes/Scratch").exists());
+    assert_eq!(root.devices(), 1);
+}
+
+#[test]
+fn test_migrate_state() {
+    let root = MockRoot::new("migrate-state");
+    let state = root.0.join("state.json");
+    let config = root.0.join("config.toml");
+    fs::create_dir_all(&root.0).unwrap();
+    // As mkramdisk wrote them before the state file had a schema and fs was renamed
+    let old_state = "{\"disks\": []}\n";
+    fs::write(&state, old_state).unwrap();
+    fs::write(&config, "# defaults\nfs = \"hfs+\"\n").unwrap();
+
+    let output = root.command(&["migrate-state", "--dry-run"]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("Would upgrade {}: schema 0 to 1", state.display())), "{}", stderr);
+    assert!(stderr.contains("rename fs to filesystem"));
+    assert_eq!(fs::read_to_string(&state).unwrap(), old_state);
+
+    assert!(root.command(&["migrate-state"]).output().unwrap().status.success());
+    assert!(fs::read_to_string(&state).unwrap().contains("\"schema\": 1"));
+    assert_eq!(fs::read_to_string(&config).unwrap(), "# defaults\nfilesystem = \"hfs+\"\n");
+    assert_eq!(fs::read_to_string(root.0.join("state.json.bak")).unwrap(), old_state);
+    assert!(root.0.join("config.toml.bak").exists());
+    let output = root.command(&["migrate-state"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Nothing to upgrade"));
+
+    // Any other command upgrades them first
+    fs::write(&state, old_state).unwrap();
+    let output = root.run(&["64M", "Scratch"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Note: upgraded"));
+    assert!(root.0.join("state.json.bak.1").exists());
+
+    // A state file from a newer mkramdisk is left alone and refused
+    fs::write(&state, "{\"schema\": 99, \"disks\": []}").unwrap();
+    let output = root.run(&["list"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("newer mkramdisk"));
+    assert!(fs::read_to_string(&state).unwrap().contains("99"));
+}
+
+#[test]
+fn test_registry_rebuild() {
+    let root = MockRoot::new("registry");
+    let state = root.0.join("state.json");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    assert!(root.run(&["64M", "Other"]).status.success());
+    let output = root.run(&["registry", "rebuild"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already matches"));
+
+    // A corrupted state file is set aside, and every disk recovered from its marker
+    fs::write(&state, "{not json").unwrap();
+    let output = root.run(&["registry", "rebuild", "--dry-run"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Would recover 'Scratch'"));
+    assert_eq!(fs::read_to_string(&state).unwrap(), "{not json");
+    let output = root.run(&["registry", "rebuild"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Recovered 'Scratch'") && stderr.contains("Recovered 'Other'"), "{}", stderr);
+    assert_eq!(fs::read_to_string(root.0.join("state.json.corrupt")).unwrap(), "{not json");
+    assert!(root.run(&["eject", "Scratch"]).status.success());
+
+    // A disk detached behind mkramdisk's back is forgotten
+    fs::remove_file(&state).unwrap();
+    assert!(root.run(&["registry", "rebuild"]).status.success());
+    assert!(root.run(&["eject", "--unmanaged", "Other"]).status.success());
+    let output = root.run(&["registry", "rebuild"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already matches"));
+    assert!(root.run(&["64M", "Third"]).status.success());
+    fs::remove_dir_all(root.0.join("Volumes/Third")).unwrap();
+    fs::remove_file(root.0.join("dev/disk0")).unwrap();
+    let output = root.run(&["registry", "rebuild"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Forgot 'Third'"), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_state_dir() {
+    let root = MockRoot::new("state-dir");
+    let dir = root.0.join("state");
+    let run = |args: &[&str]| root.command(args).env_remove("MKRAMDISK_STATE").output().unwrap();
+    let output = run(&["64M", "Scratch", "--state-dir", dir.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::read_to_string(dir.join("state.json")).unwrap().contains("Scratch"));
+    let output = run(&["backups", "save", "Scratch", "--state-dir", dir.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(dir.join("backups/Scratch/metadata.json").is_file());
+    assert!(run(&["eject", "--all", "--state-dir", dir.to_str().unwrap()]).status.success());
+    assert_eq!(root.devices(), 0);
+    let output = Command::new(env!("CARGO_BIN_EXE_mkramdisk")).args(["--host", "mac-mini-1", "list", "--state-dir", "x"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be combined with --host"));
+}
+
+#[test]
+fn test_gc() {
+    let root = MockRoot::new("gc");
+    assert!(root.run(&["64M", "Scratch"]).status.success());
+    assert!(root.run(&["64M", "Raw", "--experimental", "-f", "Free Space"]).status.success());
+    let output = root.run(&["gc"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No orphaned RAM disks"));
+
+    let leaked = root.run_with(&["--mount-timeout", "100ms", "--keep-on-failure", "64M", "Leaked"], &[("MKRAMDISK_MOCK_FAIL", "remount")]);
+    assert!(!leaked.status.success());
+    assert_eq!(root.devices(), 3);
+    let output = root.run(&["gc", "--dry-run"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\n", root.0.join("dev/disk2").display()));
+    assert_eq!(root.devices(), 3);
+    // Without a terminal to ask at, only --yes goes ahead
+    let output = root.run(&["gc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--yes"));
+    let output = root.run(&["gc", "--yes"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(root.devices(), 2);
+    assert!(!root.0.join("dev/disk2").exists());
+}
+
+#[test]
+fn test_record_and_replay() {
+    let root = MockRoot::new("record");
+    fs::create_dir_all(&root.0).unwrap();
+    let session = root.0.join("session.json");
+    // --record and replay come before any subcommand, so these skip MockRoot::command
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_mkramdisk"))
+            .args(args)
+            .env("MKRAMDISK_MOCK_ROOT", &root.0)
+            .env("MKRAMDISK_CONFIG", root.0.join("config.toml"))
+            .env("MKRAMDISK_STATE", root.0.join("state.json"))
+            .output()
+            .unwrap()
+    };
+    let output = run(&["--record", session.to_str().unwrap(), "create", "--backend", "mock", "64M", "Scratch"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let recorded: serde_json::Value = serde_json::from_slice(&fs::read(&session).unwrap()).unwrap();
+    assert_eq!(recorded["args"], serde_json::json!(["create", "--backend", "mock", "64M", "Scratch"]));
+    assert_eq!(recorded["exit_code"], 0);
+    let steps = recorded["steps"].as_array().unwrap();
+    assert!(steps.iter().any(|step| step["kind"] == "decision" && step["message"] == "Size: 64M = 131072 sectors"));
+    assert!(steps.iter().any(|step| step["kind"] == "event" && step["event"]["event"] == "created"));
+
+    let output = run(&["replay", session.to_str().unwrap(), "--dry-run"]);
+    let printed = String::from_utf8_lossy(&output.stdout);
+    assert!(printed.starts_with("# mkramdisk create --backend mock 64M Scratch ("), "{}", printed);
+    assert!(printed.contains("\n# Size: 64M = 131072 sectors\n"), "{}", printed);
+    assert_eq!(root.devices(), 1);
+
+    // Run again, it finds the disk it made last time
+    let output = run(&["replay", session.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+}
+
+#[test]
+fn test_partitions() {
+    let root = MockRoot::new("partitions");
+    let output = root.run(&["--partitions", "src:apfs:64M,data:fat32:64M"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("name=src size=132M filesystem=GPT"), "{}", stdout);
+    assert!(root.0.join("Volumes/src").is_dir());
+    assert!(root.0.join("Volumes/data").is_dir());
+    assert_eq!(root.devices(), 1);
+}
+
+#[test]
+fn test_bootable_layout() {
+    let root = MockRoot::new("bootable");
+    let output = root.run(&["512M", "Install", "--bootable"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(root.0.join("Volumes/EFI/EFI/BOOT").is_dir());
+    assert!(root.0.join("Volumes/Install").is_dir());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("mount_point={}", root.0.join("Volumes/Install").display())));
+
+    let output = root.run(&["64M", "Other", "--bootable"]);
+    assert!(!output.status.success());
+    assert!(root.run(&["eject", "Install"]).status.success());
+    assert!(!root.0.join("Volumes/EFI").exists());
+
+    // A disk whose EFI system partition can't be set up is rolled back
+    let output = root.run_with(&["512M", "Install", "--bootable"], &[("MKRAMDISK_MOCK_FAIL", "esp")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no EFI system partition"));
+    assert_eq!(root.devices(), 0);
+}
+
+#[test]
+fn test_from_dmg() {
+    let root = MockRoot::new("from-dmg");
+    fs::create_dir_all(&root.0).unwrap();
+    let image = root.0.join("Fixture.dmg");
+    fs::File::create(&image).unwrap().set_len(2 << 20).unwrap();
+    let image = image.to_string_lossy();
+
+    let output = root.run(&["--from-dmg", &image]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("size=2097152"));
+    assert!(stdout.contains(&format!("mount_point={}", root.0.join("Volumes/Fixture").display())));
+
+    let output = root.run(&["--from-dmg", &image, "1M", "Small"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("too small to hold"));
+    assert_eq!(root.devices(), 1);
+}
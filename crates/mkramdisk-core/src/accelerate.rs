@@ -0,0 +1,175 @@
+//! Serving a directory from a RAM disk in place. `accelerate` copies the directory onto
+//! a disk, moves it aside and puts a symlink to the disk where it was; `decelerate`
+//! puts it back, with what is on the disk written back unless it is discarded.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::copier::{self, CopyOptions, CopyStats};
+use crate::progress::Progress;
+use crate::project::symlink_dir;
+use crate::{create_ramdisk, eject, features, filesystem_minimum_bytes, log_verbose, memory, registry, sanitize_volume_name, say, validate_volume_name, warn, Config};
+
+/// Copy `dir` onto a new RAM disk named after it, of `size` or else twice what it holds,
+/// and swap the disk in for it.
+pub fn accelerate(config: &Config, dir: &Path, size: Option<&str>) -> Result<(), String> {
+    features::require("accelerate")?;
+    let meta = fs::symlink_metadata(dir).map_err(|e| format!("Cannot accelerate {}: {}", dir.display(), e))?;
+    if meta.file_type().is_symlink() || !meta.is_dir() {
+        return Err(format!("{} is not a directory; only a real directory can be accelerated", dir.display()));
+    }
+    let dir = std::path::absolute(dir).map_err(|e| format!("Cannot accelerate {}: {}", dir.display(), e))?;
+    let name = dir.file_name().map(|name| sanitize_volume_name(&name.to_string_lossy())).ok_or("Cannot accelerate the root directory")?;
+    validate_volume_name(&name)?;
+
+    let used = copier::scan(&dir, config.copy.links)?;
+    // Room to grow into, as the point is to write there
+    let size = size.map_or_else(|| memory::format_size((used.bytes * 2).max(filesystem_minimum_bytes(&config.filesystem))), str::to_string);
+    let disk = Config { size, name: name.clone(), ..config.clone() };
+    let mount_point = create_ramdisk(&disk)?.mount_point.ok_or("The new disk has no mount point")?;
+
+    log_verbose(config, &format!("Copying {} files ({} bytes) to {}...", used.files, used.bytes, mount_point.display()));
+    let swapped = copier::copy_tree(&dir, &mount_point, &config.copy, &mut Progress::new("accelerate", used.files, used.bytes))
+        .and_then(|_| swap_in(&dir, &mount_point));
+    if let Err(e) = swapped {
+        let _ = eject(&Config { force: true, ..disk }, &name);
+        return Err(format!("{}\n{} was left as it was", e, dir.display()));
+    }
+    let recorded = registry::update(|registry| {
+        if let Some(entry) = registry.disks.iter_mut().find(|entry| entry.backend == config.backend && entry.mount_point.as_ref() == Some(&mount_point)) {
+            entry.flags.push("accelerated".to_string());
+            entry.directory = Some(dir.clone());
+        }
+    });
+    if let Err(e) = recorded {
+        warn(config, &format!("Failed to record {} in the state file: {}", name, e))?;
+    }
+    say(config, &format!("Accelerated {}: {} files ({} bytes) now served from {}", dir.display(), used.files, used.bytes, mount_point.display()));
+    say(config, &format!("Run 'mkramdisk decelerate {}' to write the changes back", dir.display()));
+    Ok(())
+}
+
+/// Put an accelerated directory back, writing the disk's contents back unless `discard`,
+/// and eject the disk.
+pub fn decelerate(config: &Config, dir: &Path, discard: bool) -> Result<(), String> {
+    let dir = std::path::absolute(dir).map_err(|e| format!("Cannot decelerate {}: {}", dir.display(), e))?;
+    if !is_accelerated(&dir) {
+        return Err(format!("{} is not accelerated", dir.display()));
+    }
+    let mount_point = fs::read_link(&dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    // The disk's own backend, which need not be the default
+    let backend = registry::load()?.find_directory(&dir).map_or_else(|| config.backend.clone(), |entry| entry.backend.clone());
+
+    let write_back = (!discard).then_some(&config.copy);
+    let total = if discard { CopyStats::default() } else { copier::scan(&mount_point, config.copy.links)? };
+    let stats = swap_out(&dir, write_back, &mut Progress::new("decelerate", total.files, total.bytes))?;
+    match stats {
+        Some(stats) => say(config, &format!("Wrote {} files ({} bytes) back to {}", stats.files, stats.bytes, dir.display())),
+        None => say(config, &format!("Put {} back as it was, discarding the changes", dir.display())),
+    }
+    // By where the link led rather than the volume's name, which another disk may share
+    eject(&Config { backend, ..config.clone() }, &mount_point.to_string_lossy())
+}
+
+/// Where the original directory waits while the disk stands in for it: a hidden sibling,
+/// so moving it there and back is a rename within one filesystem.
+pub fn parked(dir: &Path) -> PathBuf {
+    sibling(dir, "mkramdisk-original")
+}
+
+// Where the disk's contents are copied before they replace the original
+fn staging(dir: &Path) -> PathBuf {
+    sibling(dir, "mkramdisk-writeback")
+}
+
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let name = dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    dir.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+/// Whether `dir` is a symlink standing in for a directory `swap_in` moved aside.
+pub fn is_accelerated(dir: &Path) -> bool {
+    fs::symlink_metadata(dir).is_ok_and(|meta| meta.file_type().is_symlink()) && parked(dir).is_dir()
+}
+
+/// Move `dir` aside and link it to `mount_point`, where a copy of it must already be.
+pub fn swap_in(dir: &Path, mount_point: &Path) -> Result<(), String> {
+    let parked = parked(dir);
+    if fs::symlink_metadata(&parked).is_ok() {
+        return Err(format!("{} already exists; is {} already accelerated?", parked.display(), dir.display()));
+    }
+    fs::rename(dir, &parked).map_err(|e| format!("Failed to move {} aside: {}", dir.display(), e))?;
+    if let Err(e) = symlink_dir(mount_point, dir) {
+        let _ = fs::rename(&parked, dir);
+        return Err(format!("Failed to link {} to {}: {}", dir.display(), mount_point.display(), e));
+    }
+    Ok(())
+}
+
+/// Undo `swap_in`. With `write_back`, the contents of the disk it was linked to replace
+/// the original, which is only removed once they are all copied; otherwise the original
+/// comes back as it was.
+pub fn swap_out(dir: &Path, write_back: Option<&CopyOptions>, progress: &mut Progress) -> Result<Option<CopyStats>, String> {
+    if !is_accelerated(dir) {
+        return Err(format!("{} is not accelerated", dir.display()));
+    }
+    let parked = parked(dir);
+    let stats = match write_back {
+        Some(options) => {
+            let mount_point = fs::read_link(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+            let staging = staging(dir);
+            let _ = fs::remove_dir_all(&staging);
+            match copier::copy_tree(&mount_point, &staging, options, progress) {
+                Ok(stats) => Some(stats),
+                Err(e) => {
+                    let _ = fs::remove_dir_all(&staging);
+                    return Err(format!("{}\n{} is still accelerated", e, dir.display()));
+                }
+            }
+        }
+        None => None,
+    };
+    fs::remove_file(dir).map_err(|e| format!("Failed to remove {}: {}", dir.display(), e))?;
+    let restored = if stats.is_some() { staging(dir) } else { parked.clone() };
+    fs::rename(&restored, dir).map_err(|e| format!("Failed to move {} into place: {}", restored.display(), e))?;
+    if stats.is_some() {
+        fs::remove_dir_all(&parked).map_err(|e| format!("Failed to remove {}: {}", parked.display(), e))?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_swap() {
+        let root = env::temp_dir().join(format!("mkramdisk-accelerate-{}", std::process::id()));
+        let dir = root.join("project/node_modules");
+        let disk = root.join("disk");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&disk).unwrap();
+        fs::write(dir.join("a.js"), "old").unwrap();
+        fs::write(disk.join("a.js"), "new").unwrap();
+
+        swap_in(&dir, &disk).unwrap();
+        assert!(is_accelerated(&dir));
+        assert_eq!(fs::read_to_string(dir.join("a.js")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(root.join("project/.node_modules.mkramdisk-original/a.js")).unwrap(), "old");
+        assert!(swap_in(&dir, &disk).is_err());
+
+        // Discarding brings back the original as it was
+        swap_out(&dir, None, &mut Progress::hidden()).unwrap();
+        assert!(!is_accelerated(&dir));
+        assert_eq!(fs::read_to_string(dir.join("a.js")).unwrap(), "old");
+        assert!(swap_out(&dir, None, &mut Progress::hidden()).is_err());
+
+        swap_in(&dir, &disk).unwrap();
+        let stats = swap_out(&dir, Some(&CopyOptions::default()), &mut Progress::hidden()).unwrap().unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(fs::read_to_string(dir.join("a.js")).unwrap(), "new");
+        assert!(fs::read_dir(root.join("project")).unwrap().count() == 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
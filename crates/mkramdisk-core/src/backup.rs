@@ -0,0 +1,446 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::copier::{self, CopyOptions, MANIFEST_FILE};
+use crate::progress::Progress;
+use crate::{log_verbose, memory, provider, registry, runner, say, tagged, validate_volume_name, Config};
+
+/// Each disk's `metadata.json` in the store.
+pub const METADATA_FILE: &str = "metadata.json";
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+/// The one file in an encrypted snapshot: a tar of the disk's files, encrypted with age.
+pub const ARCHIVE_FILE: &str = "files.tar.age";
+
+/// What the store knows about one disk's backups, kept in its `metadata.json`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub name: String,
+    /// Filesystem of the disk when it was last backed up.
+    pub filesystem: Option<String>,
+    /// Oldest first.
+    pub snapshots: Vec<Snapshot>,
+}
+
+/// One backup: a copy of the disk's files in `snapshots/<id>/`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// When it was taken, as "20240229T123456Z" (with "-2" and on if taken in the same second).
+    pub id: String,
+    /// Seconds since the epoch.
+    pub created: u64,
+    pub files: u64,
+    pub bytes: u64,
+    /// Whether its files are in an age-encrypted `files.tar.age` instead of copied as they are.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// Backups of RAM disks, one directory per disk name:
+///
+/// ```text
+/// <root>/<name>/metadata.json
+/// <root>/<name>/snapshots/<id>/...
+/// <root>/<name>/snapshots/<id>/files.tar.age   (encrypted)
+/// ```
+pub struct Store {
+    root: PathBuf,
+}
+
+/// A key an encrypted snapshot can be decrypted with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Identity {
+    /// An age identity file, as `age -i` takes it.
+    File(PathBuf),
+    /// The contents of one, e.g. from the Keychain, handed to age on stdin.
+    Secret(String),
+}
+
+/// The age recipient (public key) of the identity `secret`, from `age-keygen -y`.
+pub fn recipient(secret: &str) -> Result<String, String> {
+    let output = runner::output_with_input(Command::new("age-keygen").arg("-y"), secret.as_bytes())
+        .map_err(|e| format!("Failed to run age-keygen: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("age-keygen failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let recipient = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match recipient.lines().count() {
+        1 => Ok(recipient),
+        _ => Err("Expected one age identity, to encrypt backups to".to_string()),
+    }
+}
+
+/// A new age identity, from `age-keygen`, kept only in memory.
+pub fn generate_identity() -> Result<String, String> {
+    let output = runner::output(&mut Command::new("age-keygen")).map_err(|e| format!("Failed to run age-keygen: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("age-keygen failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    String::from_utf8(output.stdout).map_err(|_| "age-keygen printed an identity that is not text".to_string())
+}
+
+/// The store when none is named: `paths::get()`'s backup directory.
+pub fn default_root() -> Option<PathBuf> {
+    Some(crate::paths::get()?.backup_dir)
+}
+
+/// A snapshot ID for `seconds` since the epoch: the UTC time without separators.
+pub fn snapshot_id(seconds: u64) -> String {
+    let digits: String = crate::format_timestamp(seconds).chars().filter(char::is_ascii_digit).collect();
+    format!("{}T{}Z", &digits[..8], &digits[8..])
+}
+
+impl Store {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Every path in the store goes through here, so no name or ID can lead out of it
+    fn disk_dir(&self, name: &str) -> Result<PathBuf, String> {
+        validate_volume_name(name)?;
+        Ok(self.root.join(name))
+    }
+
+    /// Where a snapshot's files are.
+    pub fn snapshot_dir(&self, name: &str, id: &str) -> Result<PathBuf, String> {
+        validate_volume_name(id).map_err(|_| format!("'{}' cannot be a snapshot ID", id))?;
+        Ok(self.disk_dir(name)?.join(SNAPSHOTS_DIR).join(id))
+    }
+
+    /// The metadata of the disk `name`, or None if it has never been backed up.
+    pub fn metadata(&self, name: &str) -> Result<Option<Metadata>, String> {
+        let path = self.disk_dir(name)?.join(METADATA_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        serde_json::from_str(&contents).map(Some).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+    }
+
+    fn write_metadata(&self, metadata: &Metadata) -> Result<(), String> {
+        let path = self.disk_dir(&metadata.name)?.join(METADATA_FILE);
+        let json = serde_json::to_string_pretty(metadata).map_err(|e| e.to_string())?;
+        // Written aside and renamed into place, so a crash never leaves half a file
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, json + "\n").map_err(|e| format!("Failed to write {}: {}", partial.display(), e))?;
+        fs::rename(&partial, &path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// Every disk with backups, by name.
+    pub fn disks(&self) -> Result<Vec<Metadata>, String> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to read {}: {}", self.root.display(), e)),
+        };
+        let mut disks = Vec::new();
+        for entry in entries.flatten() {
+            if let Some(metadata) = self.metadata(&entry.file_name().to_string_lossy())? {
+                disks.push(metadata);
+            }
+        }
+        disks.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(disks)
+    }
+
+    /// Copy the files of the disk `name` mounted at `mount_point` into a new snapshot.
+    pub fn save(&self, name: &str, mount_point: &Path, filesystem: Option<String>, options: &CopyOptions, progress: &mut Progress) -> Result<Snapshot, String> {
+        self.add_snapshot(name, filesystem, false, |partial| {
+            let (stats, manifest) = copier::copy_tree_with_manifest(mount_point, partial, options, progress)?;
+            manifest.write(&partial.join(MANIFEST_FILE))?;
+            Ok(stats)
+        })
+    }
+
+    /// Like `save`, but keep the files only as a tar encrypted with age to `recipients`
+    /// (age or SSH public keys), so none of them reach the store in plaintext.
+    pub fn save_encrypted(&self, name: &str, mount_point: &Path, filesystem: Option<String>, recipients: &[String], options: &CopyOptions, progress: &mut Progress) -> Result<Snapshot, String> {
+        if recipients.is_empty() {
+            return Err("Encrypting a backup needs at least one recipient".to_string());
+        }
+        let stats = copier::scan(mount_point, options.links)?;
+        self.add_snapshot(name, filesystem, true, |partial| {
+            fs::create_dir_all(partial).map_err(|e| format!("Failed to create {}: {}", partial.display(), e))?;
+            let mut tar = Command::new("tar");
+            tar.arg("-C").arg(mount_point).args(["-cf", "-", "."]);
+            let mut age = Command::new("age");
+            for recipient in recipients {
+                age.args(["-r", recipient]);
+            }
+            age.arg("-o").arg(partial.join(ARCHIVE_FILE));
+            run_pipe(&mut tar, b"", &mut age)?;
+            progress.finish(stats.files, stats.bytes);
+            Ok(stats)
+        })
+    }
+
+    // Fill a new snapshot's directory with `fill`, then record it in the disk's metadata
+    fn add_snapshot(&self, name: &str, filesystem: Option<String>, encrypted: bool, fill: impl FnOnce(&Path) -> Result<copier::CopyStats, String>) -> Result<Snapshot, String> {
+        let mut metadata = self.metadata(name)?.unwrap_or_else(|| Metadata { name: name.to_string(), ..Metadata::default() });
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        let base = snapshot_id(created);
+        let id = (1..)
+            .map(|n| if n == 1 { base.clone() } else { format!("{}-{}", base, n) })
+            .find(|id| self.snapshot_dir(name, id).is_ok_and(|dir| !dir.exists()))
+            .unwrap_or(base);
+
+        // Filled aside first, so a failed copy never looks like a snapshot
+        let dir = self.snapshot_dir(name, &id)?;
+        let partial = dir.with_extension("partial");
+        let _ = fs::remove_dir_all(&partial);
+        let filled = fill(&partial).and_then(|stats| {
+            fs::rename(&partial, &dir).map_err(|e| format!("Failed to move {} into place: {}", dir.display(), e))?;
+            Ok(stats)
+        });
+        let stats = match filled {
+            Ok(stats) => stats,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&partial);
+                return Err(e);
+            }
+        };
+
+        let snapshot = Snapshot { id, created, files: stats.files, bytes: stats.bytes, encrypted };
+        metadata.filesystem = filesystem.or(metadata.filesystem);
+        metadata.snapshots.push(snapshot.clone());
+        self.write_metadata(&metadata)?;
+        Ok(snapshot)
+    }
+
+    /// The snapshot `id` of the disk `name`, or its latest if no ID is given.
+    pub fn find(&self, name: &str, id: Option<&str>) -> Result<Snapshot, String> {
+        let metadata = self.metadata(name)?.ok_or_else(|| format!("No backups of '{}' in {}", name, self.root.display()))?;
+        let found = match id {
+            Some(id) => metadata.snapshots.iter().find(|snapshot| snapshot.id == id),
+            None => metadata.snapshots.last(),
+        };
+        found.cloned().ok_or_else(|| match id {
+            Some(id) => format!("'{}' has no snapshot {}", name, id),
+            None => format!("'{}' has no snapshots", name),
+        })
+    }
+
+    /// Copy a snapshot's files back onto the disk mounted at `mount_point`, over any
+    /// files of the same names. Files the disk still has as the snapshot's manifest
+    /// records them, e.g. from restoring it before, are left alone, so restoring onto
+    /// a disk that outlived a logout copies only what changed; a disk created afresh
+    /// still has every file copied onto it. An encrypted snapshot is decrypted with the
+    /// age `identities` straight onto the disk.
+    pub fn restore(&self, name: &str, snapshot: &Snapshot, mount_point: &Path, identities: &[Identity], options: &CopyOptions, progress: &mut Progress) -> Result<copier::CopyStats, String> {
+        let dir = self.snapshot_dir(name, &snapshot.id)?;
+        if !dir.is_dir() {
+            return Err(format!("Snapshot {} of '{}' is missing from {}", snapshot.id, name, dir.display()));
+        }
+        if !snapshot.encrypted {
+            return match copier::Manifest::read(&dir.join(MANIFEST_FILE))? {
+                Some(manifest) => copier::copy_tree_over(&dir, mount_point, &manifest, options, progress),
+                None => copier::copy_tree(&dir, mount_point, options, progress),
+            };
+        }
+        if identities.is_empty() {
+            return Err(format!(
+                "Snapshot {} of '{}' is encrypted: pass the key to decrypt it with --identity or --keychain-item",
+                snapshot.id, name
+            ));
+        }
+        let mut age = Command::new("age");
+        age.arg("-d");
+        // At most one identity can come on stdin; age reads the archive from its path
+        let mut input = String::new();
+        for identity in identities {
+            match identity {
+                Identity::File(path) => age.arg("-i").arg(path),
+                Identity::Secret(_) if !input.is_empty() => return Err("Only one identity can come from the Keychain".to_string()),
+                Identity::Secret(secret) => {
+                    input = secret.clone();
+                    age.args(["-i", "-"])
+                }
+            };
+        }
+        age.arg(dir.join(ARCHIVE_FILE));
+        let mut tar = Command::new("tar");
+        tar.arg("-C").arg(mount_point).args(["-xf", "-"]);
+        run_pipe(&mut age, input.as_bytes(), &mut tar)?;
+        progress.finish(snapshot.files, snapshot.bytes);
+        Ok(copier::CopyStats { files: snapshot.files, bytes: snapshot.bytes, ..copier::CopyStats::default() })
+    }
+
+    /// Remove one snapshot of the disk `name`, or all of them and the disk's directory.
+    pub fn remove(&self, name: &str, id: Option<&str>) -> Result<(), String> {
+        let mut metadata = self.metadata(name)?.ok_or_else(|| format!("No backups of '{}' in {}", name, self.root.display()))?;
+        let Some(id) = id else {
+            let dir = self.disk_dir(name)?;
+            return fs::remove_dir_all(&dir).map_err(|e| format!("Failed to remove {}: {}", dir.display(), e));
+        };
+        let before = metadata.snapshots.len();
+        metadata.snapshots.retain(|snapshot| snapshot.id != id);
+        if metadata.snapshots.len() == before {
+            return Err(format!("'{}' has no snapshot {}", name, id));
+        }
+        let dir = self.snapshot_dir(name, id)?;
+        if dir.exists() {
+            fs::remove_dir_all(&dir).map_err(|e| format!("Failed to remove {}: {}", dir.display(), e))?;
+        }
+        self.write_metadata(&metadata)
+    }
+}
+
+/// Snapshot the mounted disk `name` into `store`, encrypted to `recipients` if there
+/// are any.
+pub fn save_disk(config: &Config, store: &Store, name: &str, recipients: &[String]) -> Result<Snapshot, String> {
+    validate_volume_name(name)?;
+    let provider = provider::select_provider(&config.backend, name)?;
+    let mount_point = provider.mount_point(name);
+    if !mount_point.exists() {
+        return Err(format!("No RAM disk named '{}' is mounted at {}", name, mount_point.display()));
+    }
+    save_mounted(config, store, provider.as_ref(), name, &mount_point, recipients)
+}
+
+/// One disk `sync` tried to snapshot: its new snapshot, or why there is none.
+#[derive(Debug)]
+pub struct Synced {
+    pub name: String,
+    pub saved: Result<Snapshot, String>,
+}
+
+/// Snapshot every mounted disk in the state file with all of `config`'s tags, carrying
+/// on past any that fail.
+pub fn sync(config: &Config, store: &Store, recipients: &[String]) -> Result<Vec<Synced>, String> {
+    let mut synced = Vec::new();
+    // Overlays are kept by their image, and a shadow disk by its overlay
+    for entry in registry::load()?.disks.iter().filter(|entry| entry.tagged(&config.tags) && !entry.flags.iter().any(|flag| flag == "shadow" || flag == "overlay")) {
+        let Some(mount_point) = entry.mount_point.as_ref().filter(|mount_point| mount_point.exists()) else {
+            log_verbose(config, &format!("{} ({}) is not mounted; skipping it", entry.name, entry.device));
+            continue;
+        };
+        let saved = provider::select_provider(&entry.backend, &entry.name)
+            .and_then(|provider| save_mounted(config, store, provider.as_ref(), &entry.name, mount_point, recipients));
+        synced.push(Synced { name: entry.name.clone(), saved });
+    }
+    if synced.is_empty() {
+        say(config, &format!("No RAM disks{} to sync", tagged(&config.tags)));
+    }
+    Ok(synced)
+}
+
+// Copy the files of the disk `name` mounted at `mount_point` into a new snapshot
+fn save_mounted(config: &Config, store: &Store, provider: &dyn provider::DeviceProvider, name: &str, mount_point: &Path, recipients: &[String]) -> Result<Snapshot, String> {
+    let total = copier::scan(mount_point, config.copy.links)?;
+    let mut progress = Progress::new("backup", total.files, total.bytes);
+    let filesystem = provider.personality(mount_point);
+    let snapshot = match recipients {
+        [] => store.save(name, mount_point, filesystem, &config.copy, &mut progress)?,
+        _ => store.save_encrypted(name, mount_point, filesystem, recipients, &config.copy, &mut progress)?,
+    };
+    say(config, &format!("Saved snapshot {} of {} ({} files, {})", snapshot.id, name, snapshot.files, memory::format_size(snapshot.bytes)));
+    Ok(snapshot)
+}
+
+/// Restore `snapshot` onto the mounted disk `name`, decrypting it with `identities` if
+/// it is encrypted.
+pub fn restore_disk(config: &Config, store: &Store, name: &str, snapshot: &Snapshot, identities: &[Identity]) -> Result<copier::CopyStats, String> {
+    validate_volume_name(name)?;
+    let provider = provider::select_provider(&config.backend, name)?;
+    let mount_point = provider.mount_point(name);
+    if !mount_point.exists() {
+        return Err(format!("No RAM disk named '{}' is mounted at {}; create it first", name, mount_point.display()));
+    }
+    let mut progress = Progress::new("restore", snapshot.files, snapshot.bytes);
+    let stats = store.restore(name, snapshot, &mount_point, identities, &config.copy, &mut progress)?;
+    match stats.unchanged {
+        0 => say(config, &format!("Restored snapshot {} onto {} ({} files)", snapshot.id, name, stats.files)),
+        unchanged => say(config, &format!("Restored snapshot {} onto {} ({} files copied, {} already there)", snapshot.id, name, stats.files, unchanged)),
+    }
+    Ok(stats)
+}
+
+// `first | second`, failing with the stderr of whichever of them failed
+fn run_pipe(first: &mut Command, input: &[u8], second: &mut Command) -> Result<(), String> {
+    let program = |command: &Command| command.get_program().to_string_lossy().into_owned();
+    let (first_output, second_output) =
+        runner::pipe(first, input, second).map_err(|e| format!("Failed to run {} | {}: {}", program(first), program(second), e))?;
+    for (command, output) in [(&*first, &first_output), (&*second, &second_output)] {
+        if !output.status.success() {
+            return Err(format!("{} failed: {}", program(command), String::from_utf8_lossy(&output.stderr).trim()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_store() {
+        let root = env::temp_dir().join(format!("mkramdisk-backups-{}", std::process::id()));
+        let disk = root.join("Volumes/Scratch");
+        fs::create_dir_all(disk.join("build")).unwrap();
+        fs::write(disk.join("build/out.o"), "object").unwrap();
+        let store = Store::new(root.join("store"));
+        let options = CopyOptions::default();
+
+        assert!(store.disks().unwrap().is_empty());
+        let first = store.save("Scratch", &disk, Some("APFS".to_string()), &options, &mut Progress::hidden()).unwrap();
+        let second = store.save("Scratch", &disk, None, &options, &mut Progress::hidden()).unwrap();
+        assert_eq!((first.files, first.bytes), (1, 6));
+        assert_ne!(first.id, second.id);
+        assert_eq!(fs::read_to_string(store.snapshot_dir("Scratch", &first.id).unwrap().join("build/out.o")).unwrap(), "object");
+        assert!(store.snapshot_dir("Scratch", &first.id).unwrap().join(MANIFEST_FILE).is_file());
+        let metadata = store.metadata("Scratch").unwrap().unwrap();
+        assert_eq!(metadata.filesystem.as_deref(), Some("APFS"));
+        assert_eq!(metadata.snapshots, [first.clone(), second.clone()]);
+        assert_eq!(store.find("Scratch", None).unwrap(), second);
+
+        fs::remove_file(disk.join("build/out.o")).unwrap();
+        store.restore("Scratch", &store.find("Scratch", Some(&first.id)).unwrap(), &disk, &[], &options, &mut Progress::hidden()).unwrap();
+        assert_eq!(fs::read_to_string(disk.join("build/out.o")).unwrap(), "object");
+        // Restoring again lands over the files the last restore left
+        fs::write(disk.join("build/out.o"), "changed").unwrap();
+        let stats = store.restore("Scratch", &store.find("Scratch", Some(&first.id)).unwrap(), &disk, &[], &options, &mut Progress::hidden()).unwrap();
+        assert_eq!(fs::read_to_string(disk.join("build/out.o")).unwrap(), "object");
+        // The snapshot's manifest stays in the store
+        assert_eq!((stats.files, stats.unchanged), (1, 0));
+        assert!(!disk.join(MANIFEST_FILE).exists());
+        // And copies only what changed since
+        let stats = store.restore("Scratch", &store.find("Scratch", Some(&first.id)).unwrap(), &disk, &[], &options, &mut Progress::hidden()).unwrap();
+        assert_eq!((stats.files, stats.unchanged), (0, 1));
+
+        store.remove("Scratch", Some(&first.id)).unwrap();
+        assert!(!store.snapshot_dir("Scratch", &first.id).unwrap().exists());
+        assert!(store.find("Scratch", Some(&first.id)).is_err());
+        store.remove("Scratch", None).unwrap();
+        assert!(store.disks().unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_names_stay_in_the_store() {
+        let root = env::temp_dir().join(format!("mkramdisk-backups-names-{}", std::process::id()));
+        fs::create_dir_all(root.join("outside")).unwrap();
+        let store = Store::new(root.join("store"));
+        for name in ["..", "../outside", "a/b", ""] {
+            assert!(store.remove(name, None).is_err(), "{}", name);
+            assert!(store.metadata(name).is_err(), "{}", name);
+            assert!(store.snapshot_dir(name, "20240229T123456Z").is_err(), "{}", name);
+        }
+        assert!(store.snapshot_dir("Scratch", "../../outside").is_err());
+        assert!(root.join("outside").is_dir());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_id() {
+        assert_eq!(snapshot_id(1709210096), "20240229T123456Z");
+    }
+}
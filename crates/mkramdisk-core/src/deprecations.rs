@@ -0,0 +1,141 @@
+//! Forms of the command line that have been replaced but still work. `rewrite` turns
+//! each into its replacement before the command line is parsed, and `warn` says what
+//! to write instead the first time each is used, unless MKRAMDISK_NO_DEPRECATION_WARNINGS
+//! is set or config.toml has `deprecation_warnings = false`. Those already warned about
+//! are remembered in `SHOWN_FILE` beside the state file. A form stays for at least two minor
+//! releases after the one that deprecated it.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{registry, user_config};
+
+/// The old forms already warned about, as a JSON array, next to the state file.
+pub const SHOWN_FILE: &str = "deprecations-shown.json";
+
+/// A replaced form and its replacement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deprecation {
+    /// The old form: an option and, if it took one, the value it had.
+    pub old: &'static [&'static str],
+    /// What to write instead.
+    pub new: &'static [&'static str],
+    /// The version that deprecated it.
+    pub since: &'static str,
+}
+
+pub const DEPRECATIONS: &[Deprecation] = &[
+    Deprecation { old: &["--events", "ndjson"], new: &["--events-to", "stderr"], since: "0.1.0" },
+];
+
+impl std::fmt::Display for Deprecation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} is deprecated since {}; use {} instead", self.old.join(" "), self.since, self.new.join(" "))
+    }
+}
+
+/// `args` with every deprecated form in `deprecations` replaced, and the deprecations
+/// found, each once. Nothing after `--` is touched.
+pub fn rewrite(args: &[OsString], deprecations: &'static [Deprecation]) -> (Vec<OsString>, Vec<&'static Deprecation>) {
+    let mut rewritten = Vec::new();
+    let mut found: Vec<&Deprecation> = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--" {
+            rewritten.extend_from_slice(&args[i..]);
+            break;
+        }
+        // The old form as separate arguments, or as --option=value
+        let matched = deprecations.iter().find_map(|deprecation| {
+            let old = deprecation.old;
+            if args[i..].starts_with(&old.iter().map(OsString::from).collect::<Vec<_>>()) {
+                Some((deprecation, old.len()))
+            } else if old.len() == 2 && args[i] == *format!("{}={}", old[0], old[1]) {
+                Some((deprecation, 1))
+            } else {
+                None
+            }
+        });
+        match matched {
+            Some((deprecation, taken)) => {
+                rewritten.extend(deprecation.new.iter().map(OsString::from));
+                if !found.contains(&deprecation) {
+                    found.push(deprecation);
+                }
+                i += taken;
+            }
+            None => {
+                rewritten.push(args[i].clone());
+                i += 1;
+            }
+        }
+    }
+    (rewritten, found)
+}
+
+/// Whether deprecation warnings are silenced.
+pub fn silenced() -> bool {
+    env::var_os("MKRAMDISK_NO_DEPRECATION_WARNINGS").is_some_and(|value| !value.is_empty())
+        || user_config::load().is_ok_and(|config| config.deprecation_warnings == Some(false))
+}
+
+/// Say on stderr what each of `found` should be written as now, unless silenced or
+/// already said on an earlier run.
+pub fn warn(found: &[&Deprecation]) {
+    if found.is_empty() || silenced() {
+        return;
+    }
+    let path = shown_path();
+    let mut shown: Vec<String> = path
+        .as_ref()
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default();
+    let unseen: Vec<&&Deprecation> = found.iter().filter(|deprecation| !shown.contains(&deprecation.old.join(" "))).collect();
+    if unseen.is_empty() {
+        return;
+    }
+    for deprecation in unseen {
+        eprintln!("Warning: {}", deprecation);
+        shown.push(deprecation.old.join(" "));
+    }
+    eprintln!("(each deprecation is only warned about once; set MKRAMDISK_NO_DEPRECATION_WARNINGS=1 to silence them all)");
+    // Failing to remember only means warning again next time
+    if let Some(path) = path
+        && path.parent().is_none_or(|dir| fs::create_dir_all(dir).is_ok())
+        && let Ok(contents) = serde_json::to_vec(&shown)
+    {
+        let _ = fs::write(path, contents);
+    }
+}
+
+fn shown_path() -> Option<PathBuf> {
+    Some(registry::path()?.with_file_name(SHOWN_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_rewrite() {
+        const RENAMED: &[Deprecation] = &[
+            Deprecation { old: &["--events", "ndjson"], new: &["--events-to", "stderr"], since: "0.1.0" },
+            Deprecation { old: &["--quiet-eject"], new: &["--quiet"], since: "0.2.0" },
+        ];
+        let (rewritten, found) = rewrite(&args(&["1G", "--events", "ndjson", "--events=ndjson", "--quiet-eject", "--", "--quiet-eject"]), RENAMED);
+        assert_eq!(rewritten, ["1G", "--events-to", "stderr", "--events-to", "stderr", "--quiet", "--", "--quiet-eject"]);
+        assert_eq!(found, [&RENAMED[0], &RENAMED[1]]);
+        assert_eq!(found[0].to_string(), "--events ndjson is deprecated since 0.1.0; use --events-to stderr instead");
+
+        // Another value isn't the deprecated form
+        let (rewritten, found) = rewrite(&args(&["--events", "json"]), RENAMED);
+        assert_eq!((rewritten, found.len()), (args(&["--events", "json"]), 0));
+    }
+}
@@ -0,0 +1,77 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{runner, Config};
+
+// System state worth having in a bug report: (file name, program, args)
+const SNAPSHOTS: &[(&str, &str, &[&str])] = &[
+    ("hdiutil-info.plist", "hdiutil", &["info", "-plist"]),
+    ("diskutil-list.plist", "diskutil", &["list", "-plist"]),
+    ("vm_stat.txt", "vm_stat", &[]),
+    ("sysctl.txt", "sysctl", &["vm.swapusage", "kern.memorystatus_vm_pressure_level", "hw.memsize"]),
+];
+
+fn write_file(dir: &Path, name: &str, contents: &str) -> Result<(), String> {
+    let path = dir.join(name);
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Write a diagnostics bundle for a failed run into a new temp directory and return its path.
+/// Contains the error, the configuration, a transcript of every external command that
+/// ran, and snapshots of attached images, disks and memory statistics.
+pub fn write_bundle(config: &Config, error: &str) -> Result<PathBuf, String> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let dir = env::temp_dir().join(format!("mkramdisk-diagnostics-{}-{}", timestamp, std::process::id()));
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    // Lossy: this is for reading, and must not fail over an argument that isn't UTF-8
+    let argv: Vec<String> = env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    write_file(&dir, "error.txt", &format!(
+        "mkramdisk {}\n\nCommand line: {}\n\nError: {}\n\n{:#?}\n",
+        env!("CARGO_PKG_VERSION"),
+        argv.join(" "),
+        error,
+        config
+    ))?;
+
+    // Written before the snapshots below so it only holds the failed run's commands
+    let transcript: String = runner::transcript().iter().map(|record| record.to_string()).collect();
+    write_file(&dir, "transcript.txt", &transcript)?;
+
+    for (file, program, args) in SNAPSHOTS {
+        let contents = match runner::output(Command::new(program).args(*args)) {
+            Ok(output) => format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => format!("{} unavailable: {}\n", program, e),
+        };
+        write_file(&dir, file, &contents)?;
+    }
+
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_bundle() {
+        let dir = write_bundle(&Config::default(), "simulated failure").unwrap();
+
+        let error = fs::read_to_string(dir.join("error.txt")).unwrap();
+        assert!(error.contains("simulated failure"));
+        for (file, _, _) in SNAPSHOTS {
+            assert!(dir.join(file).exists());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
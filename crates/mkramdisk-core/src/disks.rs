@@ -0,0 +1,406 @@
+//! What can be done with disks that already exist: asking after them, resizing them,
+//! ejecting every one, detaching orphans, adopting those mkramdisk didn't create, and
+//! attaching a disk image copy-on-write. These report through the `Config`'s `say`,
+//! `log_verbose` and `warn`, and return what a caller would print.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::journal::{self, Change};
+use crate::provider::{self, DiskInfo, ListedDisk};
+use crate::registry::{self, Entry};
+use crate::{
+    accelerate, check_memory_headroom, copier, disk_sectors, diskutil_format, eject, features, formats, log_verbose, memory, pipeline,
+    progress, sanitize_volume_name, say, tagged, validate_volume_name, warn, Config,
+};
+
+/// Whether a disk is mounted, or only attached, or neither.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub name: String,
+    pub device: Option<String>,
+    pub mount_point: PathBuf,
+    pub mounted: bool,
+}
+
+impl Status {
+    /// 0 if the disk is mounted, 2 if its device is attached but the volume isn't
+    /// mounted, 1 if there is no such disk.
+    pub fn code(&self) -> i32 {
+        match (self.mounted, &self.device) {
+            (true, _) => 0,
+            (false, Some(_)) => 2,
+            (false, None) => 1,
+        }
+    }
+}
+
+/// Where the disk `name` is, if anywhere.
+pub fn status(config: &Config, name: &str) -> Result<Status, String> {
+    validate_volume_name(name)?;
+    let provider = provider::select_provider(&config.backend, name)?;
+    let mount_point = provider.mount_point(name);
+    Ok(Status {
+        name: name.to_string(),
+        device: provider.find_device(name),
+        mounted: mount_point.exists(),
+        mount_point,
+    })
+}
+
+/// An attached disk with its state file entry, if mkramdisk created it.
+#[derive(Debug, Clone)]
+pub struct Listed {
+    pub disk: ListedDisk,
+    pub entry: Option<Entry>,
+}
+
+/// The backend's attached disks. With tags in `config`, only those mkramdisk created
+/// with all of them.
+pub fn list(config: &Config) -> Result<Vec<Listed>, String> {
+    let provider = provider::select_provider(&config.backend, "")?;
+    let registry = registry::load()?;
+    Ok(provider
+        .list()?
+        .into_iter()
+        .map(|disk| Listed {
+            entry: registry.find(&config.backend, &disk.device, provider.identity(&disk.device).as_deref()).cloned(),
+            disk,
+        })
+        .filter(|listed| config.tags.is_empty() || listed.entry.as_ref().is_some_and(|entry| entry.tagged(&config.tags)))
+        .collect())
+}
+
+/// The details of one mounted disk.
+#[derive(Debug)]
+pub struct Info {
+    pub name: String,
+    pub mount_point: PathBuf,
+    pub details: DiskInfo,
+    /// When it was created, as the state file or else the volume has it.
+    pub created: Option<u64>,
+    pub entry: Option<Entry>,
+}
+
+/// The details of the mounted disk `name`.
+pub fn info(config: &Config, name: &str) -> Result<Info, String> {
+    let (provider, mount_point) = mounted(config, name)?;
+    let details = provider.info(&mount_point)?;
+    let entry = registry::load()?.find(&config.backend, &details.device, provider.identity(&details.device).as_deref()).cloned();
+    let created = entry.as_ref().map(|entry| entry.created).or_else(|| created(&mount_point));
+    Ok(Info { name: name.to_string(), mount_point, details, created, entry })
+}
+
+// When the volume at `mount_point` was made, if it says
+fn created(mount_point: &Path) -> Option<u64> {
+    fs::metadata(mount_point)
+        .and_then(|meta| meta.created())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs())
+}
+
+// The provider of the disk `name` and where it is mounted, which it must be
+fn mounted(config: &Config, name: &str) -> Result<(Box<dyn provider::DeviceProvider>, PathBuf), String> {
+    validate_volume_name(name)?;
+    let provider = provider::select_provider(&config.backend, name)?;
+    let mount_point = provider.mount_point(name);
+    if !mount_point.exists() {
+        return Err(format!("No RAM disk named '{}' is mounted at {}", name, mount_point.display()));
+    }
+    Ok((provider, mount_point))
+}
+
+/// What changed on a disk since `since`, or since it was created, together with the
+/// time they are counted from.
+pub fn changes(config: &Config, name: &str, since: Option<SystemTime>) -> Result<(Vec<(Change, PathBuf)>, SystemTime), String> {
+    let (_, mount_point) = mounted(config, name)?;
+    let since = since.unwrap_or_else(|| fs::metadata(&mount_point).and_then(|meta| meta.created()).unwrap_or(UNIX_EPOCH));
+    Ok((journal::changes(&mount_point, since)?, since))
+}
+
+/// Move a disk's contents onto a new device of `size` and put it in the old one's
+/// place, returning where it is mounted. ram:// devices can't grow, so even APFS is
+/// migrated rather than resized in place; the name is unmounted briefly between
+/// ejecting the old device and renaming the new volume to it.
+pub fn resize(config: &Config, name: &str, size: &str) -> Result<PathBuf, String> {
+    let (provider, mount_point) = mounted(config, name)?;
+    let personality = provider
+        .personality(&mount_point)
+        .ok_or_else(|| format!("Cannot tell which filesystem '{}' has", name))?;
+    if formats::personality(&personality).is_err() {
+        return Err(format!("'{}' holds {}; only a disk with a single volume can be resized", name, personality));
+    }
+
+    let used = copier::scan(&mount_point, config.copy.links)?;
+    let staging = Config {
+        size: size.to_string(),
+        name: format!("{}-resizing", name),
+        filesystem: personality.clone(),
+        ..config.clone()
+    };
+    let sectors = disk_sectors(&staging)?;
+    if sectors * 512 < used.bytes {
+        return Err(format!("{} cannot hold the {} bytes already on '{}'", size, used.bytes, name));
+    }
+    check_memory_headroom(&staging, sectors * 512)?;
+
+    let staging_provider = provider::select_provider(&config.backend, &staging.name)?;
+    if staging_provider.mount_point(&staging.name).exists() {
+        return Err(format!("Volume '{}' already exists; is another resize running?", staging.name));
+    }
+    let created = pipeline::create(&staging, staging_provider.as_ref(), sectors, &personality)?;
+    let staged = created.mount_point.clone().ok_or("The new disk has no mount point")?;
+
+    log_verbose(config, &format!("Copying {} files ({} bytes) to {}...", used.files, used.bytes, staged.display()));
+    let migrated = copier::copy_tree(&mount_point, &staged, &config.copy, &mut progress::Progress::new("resize", used.files, used.bytes))
+        .and_then(|_| provider.destroy(&mount_point, config.force));
+    if let Err(e) = migrated {
+        let _ = staging_provider.destroy(&staged, true);
+        return Err(format!("{}\n'{}' was left as it was", e, name));
+    }
+    let resized = staging_provider.rename(&created.device, &staged, name)?;
+    // The disk lives on in the new device, so move its entry there
+    let identity = staging_provider.identity(&created.device);
+    let mut marker = None;
+    let moved = registry::update(|registry| {
+        let Some(mut entry) = registry.find_named(&config.backend, name).cloned() else {
+            return;
+        };
+        registry.forget(&config.backend, Some(&entry.device), name);
+        entry.device = created.device.clone();
+        entry.identity = identity;
+        entry.size = size.to_string();
+        entry.size_bytes = sectors * 512;
+        entry.mount_point = Some(resized.clone());
+        marker = Some(entry.clone());
+        registry.record(entry);
+    });
+    if let Err(e) = moved {
+        warn(config, &format!("Failed to update {} in the state file: {}", name, e))?;
+    }
+    // The copy left the old volume's marker behind
+    if let Some(Err(e)) = marker.map(|entry| registry::write_marker(&resized, &entry)) {
+        warn(config, &e)?;
+    }
+    say(config, &format!("Resized '{}' to {} at {}", name, size, resized.display()));
+    Ok(resized)
+}
+
+/// Eject every disk in the state file with all of `config`'s tags, newest first,
+/// carrying on past any that fail. Returns how many were ejected.
+pub fn eject_all(config: &Config) -> Result<usize, String> {
+    let registry = registry::load()?;
+    let mut errors = Vec::new();
+    let mut ejected = 0;
+    // A shadow disk goes with its overlay
+    for entry in registry.disks.iter().rev().filter(|entry| entry.tagged(&config.tags) && !entry.flags.iter().any(|flag| flag == "shadow")) {
+        let config = Config { backend: entry.backend.clone(), ..config.clone() };
+        let provider = provider::select_provider(&entry.backend, &entry.name)?;
+        let attached = provider.list().is_ok_and(|disks| disks.iter().any(|disk| disk.device == entry.device));
+        // The device may have gone to a disk mkramdisk never saw since
+        let same_disk = entry.identity.as_deref().is_none_or(|identity| provider.identity(&entry.device).as_deref() == Some(identity));
+        if !attached || !same_disk {
+            let why = if attached { "is another disk's now" } else { "is gone" };
+            log_verbose(&config, &format!("{} ({}) {}; forgetting it", entry.name, entry.device, why));
+            registry::update(|registry| registry.forget(&entry.backend, Some(&entry.device), &entry.name))?;
+            continue;
+        }
+        // By its device, not its name, which some other volume may have taken
+        let result = match &entry.directory {
+            Some(dir) if accelerate::is_accelerated(dir) => accelerate::decelerate(&config, dir, false),
+            _ => eject(&config, &entry.device),
+        };
+        match result {
+            Ok(()) => ejected += 1,
+            Err(e) => errors.push(format!("{}: {}", entry.name, e)),
+        }
+    }
+    if ejected == 0 && errors.is_empty() {
+        say(config, &format!("No RAM disks{} to eject", tagged(&config.tags)));
+    }
+    if errors.is_empty() { Ok(ejected) } else { Err(errors.join("\n")) }
+}
+
+/// The backend's devices that are attached with nothing mounted, which `detach`
+/// frees. A raw device mkramdisk made has nothing to mount and isn't one of them.
+pub fn orphans(config: &Config) -> Result<Vec<String>, String> {
+    let provider = provider::select_provider(&config.backend, "")?;
+    let registry = registry::load()?;
+    Ok(provider
+        .list()?
+        .into_iter()
+        .filter(|disk| disk.mount_point.is_none())
+        .filter(|disk| registry.find(&config.backend, &disk.device, provider.identity(&disk.device).as_deref()).is_none_or(|entry| entry.mount_point.is_some()))
+        .map(|disk| disk.device)
+        .collect())
+}
+
+/// Detach `devices`, as `orphans` found them, and forget them in the state file.
+pub fn detach(config: &Config, devices: &[String]) -> Result<(), String> {
+    let provider = provider::select_provider(&config.backend, "")?;
+    let mut errors = Vec::new();
+    for device in devices {
+        match provider.detach(device) {
+            Ok(()) => say(config, &format!("Detached {}", device)),
+            Err(e) => errors.push(e),
+        }
+    }
+    let forgotten = registry::update(|registry| {
+        for device in devices {
+            registry.forget(&config.backend, Some(device), "");
+        }
+    });
+    if let Err(e) = forgotten {
+        warn(config, &format!("Failed to update the state file: {}", e))?;
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors.join("\n")) }
+}
+
+/// Record an attached disk of the backend that mkramdisk didn't create in the state
+/// file, as if it had, returning its new entry.
+pub fn adopt(config: &Config, device: &str) -> Result<Entry, String> {
+    let provider = provider::select_provider(&config.backend, "")?;
+    let disks = provider.list()?;
+    // "disk5" is short for /dev/disk5, as for eject
+    let qualified = format!("/dev/{}", device);
+    let disk = disks
+        .iter()
+        .find(|disk| disk.device == device || disk.device == qualified)
+        .ok_or_else(|| format!("{} is not a RAM disk of the {} backend", device, config.backend))?;
+    let identity = provider.identity(&disk.device);
+    if let Some(entry) = registry::load()?.find(&config.backend, &disk.device, identity.as_deref()) {
+        return Err(format!("{} is already managed by mkramdisk as '{}'", disk.device, entry.name));
+    }
+    let info = disk.mount_point.as_deref().and_then(|mount_point| provider.info(mount_point).ok()).unwrap_or_default();
+    let size_bytes = info.sectors.map_or(0, |sectors| sectors * 512);
+    let name = disk.name().unwrap_or_else(|| disk.device.rsplit('/').next().unwrap_or_default().to_string());
+    let entry = Entry {
+        device: disk.device.clone(),
+        identity,
+        name: name.clone(),
+        backend: config.backend.clone(),
+        size: memory::format_size(size_bytes),
+        size_bytes,
+        filesystem: info.filesystem.unwrap_or_else(|| "none".to_string()),
+        mount_point: disk.mount_point.clone(),
+        // When it was made, as near as the volume tells, else now
+        created: disk.mount_point.as_deref().and_then(created).unwrap_or_else(now),
+        flags: vec!["adopted".to_string()],
+        shadow: None,
+        directory: None,
+        tags: config.tags.clone(),
+        timers: None,
+    };
+    if let Some(Err(e)) = entry.mount_point.as_deref().map(|mount_point| registry::write_marker(mount_point, &entry)) {
+        warn(config, &e)?;
+    }
+    registry::update(|registry| registry.record(entry.clone()))?;
+    say(config, &format!("Adopted {} as '{}'", disk.device, name));
+    Ok(entry)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// RAM set aside for an overlay's changes when `overlay` isn't given a size.
+pub const DEFAULT_SHADOW_SIZE: &str = "1G";
+
+/// A disk image attached copy-on-write by `overlay`.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub name: String,
+    pub device: String,
+    pub mount_point: Option<PathBuf>,
+    /// The shadow file holding the changes, and the RAM disk it is on.
+    pub shadow: PathBuf,
+    pub shadow_device: String,
+}
+
+/// Attach `source` copy-on-write with its shadow file on a new RAM disk of `size`, so
+/// changes to the image's volume live only in memory. The shadow disk is named after
+/// the image.
+pub fn overlay(config: &Config, source: &Path, size: &str) -> Result<Overlay, String> {
+    features::require("overlay")?;
+    if !source.exists() {
+        return Err(format!("No such disk image: {}", source.display()));
+    }
+    let stem = source.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let shadow_disk = Config {
+        size: size.to_string(),
+        name: sanitize_volume_name(&format!("{}-shadow", stem)),
+        ..config.clone()
+    };
+    validate_volume_name(&shadow_disk.name)?;
+    let provider = provider::select_provider(&config.backend, &shadow_disk.name)?;
+    if provider.mount_point(&shadow_disk.name).exists() {
+        return Err(format!("Volume '{}' already exists; is {} already attached?", shadow_disk.name, source.display()));
+    }
+    let sectors = disk_sectors(&shadow_disk)?;
+    check_memory_headroom(&shadow_disk, sectors * 512)?;
+
+    let created = pipeline::create(&shadow_disk, provider.as_ref(), sectors, &diskutil_format(&shadow_disk)?)?;
+    let shadow = created.mount_point.as_deref().ok_or("The shadow disk has no mount point")?.join(format!("{}.shadow", stem));
+    log_verbose(config, &format!("Attaching {} with its shadow file at {}...", source.display(), shadow.display()));
+    let device = match provider.attach_overlay(source, &shadow) {
+        Ok(device) => device,
+        Err(e) => {
+            let _ = provider.detach(&created.device);
+            return Err(e);
+        }
+    };
+    let mount_point = provider.locate_mount_point(&device, &stem);
+    let name = mount_point.as_deref().and_then(Path::file_name).map_or(stem, |name| name.to_string_lossy().into_owned());
+    let image_bytes = provider.image_bytes(source).unwrap_or(0);
+
+    let now = now();
+    let (shadow_identity, identity) = (provider.identity(&created.device), provider.identity(&device));
+    let recorded = registry::update(|registry| {
+        registry.record(Entry {
+            device: created.device.clone(),
+            identity: shadow_identity,
+            name: shadow_disk.name.clone(),
+            backend: config.backend.clone(),
+            size: size.to_string(),
+            size_bytes: sectors * 512,
+            filesystem: created.filesystem.clone(),
+            mount_point: created.mount_point.clone(),
+            created: now,
+            flags: vec!["shadow".to_string()],
+            shadow: None,
+            directory: None,
+            tags: Vec::new(),
+            timers: None,
+        });
+        registry.record(Entry {
+            device: device.clone(),
+            identity,
+            name: name.clone(),
+            backend: config.backend.clone(),
+            size: memory::format_size(image_bytes),
+            size_bytes: image_bytes,
+            filesystem: mount_point.as_deref().and_then(|mp| provider.personality(mp)).unwrap_or_default(),
+            mount_point: mount_point.clone(),
+            created: now,
+            flags: vec!["overlay".to_string()],
+            shadow: Some(created.device.clone()),
+            directory: None,
+            tags: config.tags.clone(),
+            timers: None,
+        });
+    });
+    if let Err(e) = recorded {
+        warn(config, &format!("Failed to record {} in the state file: {}", name, e))?;
+    }
+
+    say(config, &format!("Attached {} copy-on-write as {}", source.display(), device));
+    match &mount_point {
+        Some(mount_point) => say(config, &format!("  Mount point: {}", mount_point.display())),
+        None => say(config, "  Mount point: none"),
+    }
+    say(config, &format!("  Shadow:     {} on {}", shadow.display(), created.device));
+    say(config, &format!("Eject '{}' to drop the changes and free the memory", name));
+    Ok(Overlay { name, device, mount_point, shadow, shadow_device: created.device })
+}
@@ -0,0 +1,101 @@
+//! Runtime feature flags for experimental subsystems. Each is off unless turned on by
+//! name in `features` in config.toml or in $MKRAMDISK_FEATURES (comma separated), or
+//! along with every other by `--enable-experimental` or "all" in either list.
+
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::user_config;
+
+/// An experimental subsystem that can be turned on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Feature {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+pub const FEATURES: &[Feature] = &[
+    Feature { name: "overlay", description: "Attach disk images copy-on-write with `overlay`" },
+    Feature { name: "accelerate", description: "Serve directories from RAM in place with `accelerate`" },
+    Feature { name: "readonly-export", description: "Expose new disks read-only at a second node with --readonly-export" },
+];
+
+/// Where a feature was turned on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Flag,
+    Environment,
+    Config,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::Flag => write!(f, "--enable-experimental"),
+            Source::Environment => write!(f, "MKRAMDISK_FEATURES"),
+            Source::Config => write!(f, "config.toml"),
+        }
+    }
+}
+
+static ALL: AtomicBool = AtomicBool::new(false);
+
+/// Turn every feature on, as `--enable-experimental` does.
+pub fn enable_all() {
+    ALL.store(true, Ordering::Relaxed);
+}
+
+/// The names in a list of features, checking each is one (or "all").
+pub fn parse_list<'a>(names: impl IntoIterator<Item = &'a str>, origin: &str) -> Result<Vec<String>, String> {
+    let mut parsed = Vec::new();
+    for name in names.into_iter().map(str::trim).filter(|name| !name.is_empty()) {
+        if name != "all" && !FEATURES.iter().any(|feature| feature.name == name) {
+            let known: Vec<&str> = FEATURES.iter().map(|feature| feature.name).collect();
+            return Err(format!("Unknown feature '{}' in {}\nFeatures: {}", name, origin, known.join(", ")));
+        }
+        parsed.push(name.to_string());
+    }
+    Ok(parsed)
+}
+
+/// Where `name` is turned on from, if anywhere.
+pub fn source(name: &str) -> Result<Option<Source>, String> {
+    let listed = |names: &[String]| names.iter().any(|listed| listed == name || listed == "all");
+    if ALL.load(Ordering::Relaxed) {
+        return Ok(Some(Source::Flag));
+    }
+    if let Ok(value) = env::var("MKRAMDISK_FEATURES")
+        && listed(&parse_list(value.split(','), "MKRAMDISK_FEATURES")?)
+    {
+        return Ok(Some(Source::Environment));
+    }
+    let configured = user_config::load()?.features.unwrap_or_default();
+    if listed(&parse_list(configured.iter().map(String::as_str), "config.toml")?) {
+        return Ok(Some(Source::Config));
+    }
+    Ok(None)
+}
+
+/// Fail unless the feature `name` is turned on.
+pub fn require(name: &str) -> Result<(), String> {
+    match source(name)? {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "{} is experimental; turn it on with --enable-experimental, MKRAMDISK_FEATURES={} or features = [\"{}\"] in config.toml",
+            name, name, name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("overlay, accelerate,".split(','), "test").unwrap(), ["overlay", "accelerate"]);
+        assert_eq!(parse_list(["all"], "test").unwrap(), ["all"]);
+        let error = parse_list(["raid"], "MKRAMDISK_FEATURES").unwrap_err();
+        assert!(error.starts_with("Unknown feature 'raid' in MKRAMDISK_FEATURES\nFeatures: overlay,"), "{}", error);
+    }
+}
@@ -0,0 +1,126 @@
+//! `mkramdisk fleet apply`: a project file's disks brought up on many hosts at once, over
+//! ssh, with what came of it on each host.
+
+use std::thread;
+
+use crate::project::Project;
+use crate::remote;
+
+/// What came of applying the disks to one host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostResult {
+    pub host: String,
+    /// Each disk applied before any failure, and whether it was "created" or "unchanged".
+    pub disks: Vec<(String, String)>,
+    /// Why the host stopped short, if it did. The disks after the one that failed are left
+    /// alone, since they may depend on it.
+    pub error: Option<String>,
+}
+
+/// The hosts in a hosts file: one per line, skipping blank lines and `#` comments.
+pub fn parse_hosts(contents: &str) -> Result<Vec<String>, String> {
+    let mut hosts: Vec<String> = Vec::new();
+    for line in contents.lines() {
+        let host = line.split('#').next().unwrap_or_default().trim();
+        if host.is_empty() {
+            continue;
+        }
+        if host.contains(char::is_whitespace) {
+            return Err(format!("'{}' is not a host: put one host on each line", host));
+        }
+        if hosts.iter().any(|listed| listed == host) {
+            return Err(format!("{} is listed twice", host));
+        }
+        hosts.push(host.to_string());
+    }
+    if hosts.is_empty() {
+        return Err("No hosts are listed".to_string());
+    }
+    Ok(hosts)
+}
+
+/// Refuse disks that need this machine's files: a seed or links name paths here, which the
+/// other hosts don't have.
+pub fn check(projects: &[Project]) -> Result<(), String> {
+    for project in projects {
+        if project.config.seed.is_some() || !project.config.links.is_empty() {
+            return Err(format!("'{}' has a seed or links, which are paths on this machine and cannot be applied to other hosts", project.name()));
+        }
+    }
+    Ok(())
+}
+
+/// Create each of `projects`' disks that isn't already up on every host, all hosts at
+/// once, running `mkramdisk` there as `mkramdisk` (which may carry options, e.g.
+/// `--api-version`). The results are in the order of `hosts`.
+pub fn apply(hosts: &[String], projects: &[Project], mkramdisk: &str) -> Vec<HostResult> {
+    thread::scope(|scope| {
+        let applying: Vec<_> = hosts.iter().map(|host| scope.spawn(move || apply_host(host, projects, mkramdisk))).collect();
+        applying
+            .into_iter()
+            .zip(hosts)
+            .map(|(applying, host)| {
+                applying.join().unwrap_or_else(|_| HostResult { host: host.clone(), disks: Vec::new(), error: Some("Applying the disks panicked".to_string()) })
+            })
+            .collect()
+    })
+}
+
+fn apply_host(host: &str, projects: &[Project], mkramdisk: &str) -> HostResult {
+    let mut result = HostResult { host: host.to_string(), disks: Vec::new(), error: None };
+    if !remote::has_remote_mkramdisk(host) {
+        result.error = Some(format!("{} cannot be reached, or mkramdisk is not installed there", host));
+        return result;
+    }
+    for project in projects {
+        match remote::output_ssh(host, &apply_script(project, mkramdisk)) {
+            Ok(output) => result.disks.push((project.name(), output.lines().last().unwrap_or_default().trim().to_string())),
+            Err(e) => {
+                result.error = Some(format!("{}: {}", project.name(), e));
+                break;
+            }
+        }
+    }
+    result
+}
+
+// A shell script creating `project`'s disk unless it is already mounted, printing
+// "created" or "unchanged"
+fn apply_script(project: &Project, mkramdisk: &str) -> String {
+    let backend = project.config.backend.as_deref().map(|backend| format!(" --backend {}", remote::shell_quote(backend))).unwrap_or_default();
+    let create: Vec<String> = project.to_args().iter().map(|arg| remote::shell_quote(arg)).collect();
+    format!(
+        "if {mkramdisk} status --quiet{backend} {name} >/dev/null 2>&1; then echo unchanged; else {mkramdisk} create {create} >/dev/null && echo created; fi",
+        name = remote::shell_quote(&project.name()),
+        create = create.join(" "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::project::ProjectConfig;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_hosts() {
+        assert_eq!(parse_hosts("mac-mini-1\n\n# spares\nmac-mini-2  # the old one\n").unwrap(), ["mac-mini-1", "mac-mini-2"]);
+        assert!(parse_hosts("# none yet\n").is_err());
+        assert!(parse_hosts("mac-mini-1\nmac-mini-1\n").unwrap_err().contains("twice"));
+        assert!(parse_hosts("mac-mini-1 mac-mini-2\n").is_err());
+    }
+
+    #[test]
+    fn test_apply_script() {
+        let config = |toml| toml::from_str::<ProjectConfig>(toml).unwrap();
+        let project = Project { root: PathBuf::from("/src/app"), config: config("size = \"2G\"\nname = \"Build Cache\"\nbackend = \"ram\"") };
+        assert_eq!(
+            apply_script(&project, "mkramdisk"),
+            "if mkramdisk status --quiet --backend ram 'Build Cache' >/dev/null 2>&1; then echo unchanged; \
+             else mkramdisk create 2G 'Build Cache' --backend ram >/dev/null && echo created; fi"
+        );
+        assert!(check(std::slice::from_ref(&project)).is_ok());
+        let seeded = Project { config: config("size = \"2G\"\nseed = \"fixtures\""), ..project };
+        assert!(check(&[seeded]).is_err());
+    }
+}
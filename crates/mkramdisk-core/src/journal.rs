@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use crate::runner;
+
+/// Where macOS keeps a volume's FSEvents history: gzipped logs named by the hex ID of
+/// their last event, written out when the volume is quiet or unmounted.
+pub const JOURNAL_DIR: &str = ".fseventsd";
+
+// FSEventStreamEventFlags, as stored with each event in the logs
+const ITEM_CREATED: u32 = 0x100;
+const ITEM_REMOVED: u32 = 0x200;
+const ITEM_RENAMED: u32 = 0x800;
+
+/// One entry of the FSEvents journal, with its path relative to the volume root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub path: PathBuf,
+    pub id: u64,
+    pub flags: u32,
+}
+
+/// What happened to a path, as `changes` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    Added,
+    Modified,
+    Renamed,
+    Removed,
+}
+
+impl Change {
+    /// The letter `changes` prints before the path, as `git status --short` does.
+    pub fn letter(self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Modified => 'M',
+            Change::Renamed => 'R',
+            Change::Removed => 'D',
+        }
+    }
+}
+
+impl Event {
+    pub fn change(&self) -> Change {
+        // Events are coalesced, so a file made and removed again carries both flags
+        if self.flags & ITEM_REMOVED != 0 {
+            Change::Removed
+        } else if self.flags & ITEM_RENAMED != 0 {
+            Change::Renamed
+        } else if self.flags & ITEM_CREATED != 0 {
+            Change::Added
+        } else {
+            Change::Modified
+        }
+    }
+}
+
+/// Parse one decompressed log: pages starting "1SLD", "2SLD" or "3SLD" with the page
+/// length, each holding records of a NUL-terminated path, the event ID and its flags,
+/// and from version 2 on the file's node ID (version 3 adds four more bytes).
+pub fn parse_log(data: &[u8]) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    let mut page = data;
+    while !page.is_empty() {
+        if page.len() < 12 {
+            return Err("Truncated FSEvents page header".to_string());
+        }
+        let extra = match &page[..4] {
+            b"1SLD" => 0,
+            b"2SLD" => 8,
+            b"3SLD" => 12,
+            magic => return Err(format!("Unknown FSEvents page: {:?}", String::from_utf8_lossy(magic))),
+        };
+        let length = u32::from_le_bytes(page[8..12].try_into().unwrap()) as usize;
+        if length < 12 || length > page.len() {
+            return Err(format!("FSEvents page length {} is out of range", length));
+        }
+        let mut records = &page[12..length];
+        while !records.is_empty() {
+            let end = records.iter().position(|&byte| byte == 0).ok_or("Unterminated path in FSEvents page")?;
+            let rest = &records[end + 1..];
+            if rest.len() < 12 + extra {
+                return Err("Truncated FSEvents record".to_string());
+            }
+            events.push(Event {
+                path: PathBuf::from(String::from_utf8_lossy(&records[..end]).into_owned()),
+                id: u64::from_le_bytes(rest[..8].try_into().unwrap()),
+                flags: u32::from_le_bytes(rest[8..12].try_into().unwrap()),
+            });
+            records = &rest[12 + extra..];
+        }
+        page = &page[length..];
+    }
+    Ok(events)
+}
+
+/// The journal events of the volume at `mount_point` from logs written since `since`,
+/// or None if the volume keeps no journal (tmpfs, or FSEvents disabled). Events still in
+/// memory have not been written out yet, so these can lag a few seconds behind.
+pub fn read(mount_point: &Path, since: SystemTime) -> Result<Option<Vec<Event>>, String> {
+    let dir = mount_point.join(JOURNAL_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+    let mut events = Vec::new();
+    for entry in entries.flatten() {
+        let is_log = entry.file_name().to_str().is_some_and(|name| name.len() == 16 && name.chars().all(|c| c.is_ascii_hexdigit()));
+        let written = entry.metadata().and_then(|meta| meta.modified());
+        if !is_log || written.is_ok_and(|written| written < since) {
+            continue;
+        }
+        let output = runner::output(Command::new("gzip").arg("-dc").arg(entry.path()))
+            .map_err(|e| format!("Failed to run gzip: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("Failed to read {}: {}", entry.path().display(), String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        events.extend(parse_log(&output.stdout)?);
+    }
+    events.sort_by_key(|event| event.id);
+    Ok(Some(events))
+}
+
+/// Files and directories under `root` changed since `since`, by their inode change time
+/// where there is one: a copy that keeps modification times (seeding, resize) would
+/// otherwise look untouched.
+pub fn changed_since(root: &Path, since: SystemTime) -> Result<Vec<PathBuf>, String> {
+    let mut changed = Vec::new();
+    walk(root, Path::new(""), since, &mut changed)?;
+    Ok(changed)
+}
+
+fn walk(root: &Path, relative: &Path, since: SystemTime, changed: &mut Vec<PathBuf>) -> Result<(), String> {
+    let dir = root.join(relative);
+    for entry in fs::read_dir(&dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))? {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        if relative.as_os_str().is_empty() && crate::copier::is_volume_metadata(&entry) {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        let meta = fs::symlink_metadata(entry.path()).map_err(|e| format!("Failed to read {}: {}", entry.path().display(), e))?;
+        if changed_at(&meta).is_some_and(|time| time >= since) {
+            changed.push(path.clone());
+        }
+        if meta.is_dir() {
+            walk(root, &path, since, changed)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn changed_at(meta: &fs::Metadata) -> Option<SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    let since_epoch = std::time::Duration::new(u64::try_from(meta.ctime()).ok()?, meta.ctime_nsec() as u32);
+    Some(SystemTime::UNIX_EPOCH + since_epoch)
+}
+
+#[cfg(not(unix))]
+fn changed_at(meta: &fs::Metadata) -> Option<SystemTime> {
+    meta.modified().ok()
+}
+
+/// What changed on the volume at `mount_point` since `since`, one change per path in
+/// path order: the journal's last word on each path, then anything changed on disk that
+/// the journal hasn't been told about yet as modified.
+pub fn changes(mount_point: &Path, since: SystemTime) -> Result<Vec<(Change, PathBuf)>, String> {
+    let mut changes = BTreeMap::new();
+    for event in read(mount_point, since)?.unwrap_or_default() {
+        if !event.path.starts_with(JOURNAL_DIR) {
+            changes.insert(event.path.clone(), event.change());
+        }
+    }
+    for path in changed_since(mount_point, since)? {
+        changes.entry(path).or_insert(Change::Modified);
+    }
+    Ok(changes.into_iter().map(|(path, change)| (change, path)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str, id: u64, flags: u32, extra: usize) -> Vec<u8> {
+        let mut record = path.as_bytes().to_vec();
+        record.push(0);
+        record.extend(id.to_le_bytes());
+        record.extend(flags.to_le_bytes());
+        record.extend(vec![0; extra]);
+        record
+    }
+
+    fn page(magic: &[u8], records: &[Vec<u8>]) -> Vec<u8> {
+        let body = records.concat();
+        let mut page = magic.to_vec();
+        page.extend(0u32.to_le_bytes());
+        page.extend((12 + body.len() as u32).to_le_bytes());
+        page.extend(body);
+        page
+    }
+
+    #[test]
+    fn test_parse_log() {
+        let mut log = page(b"2SLD", &[record("build/out.o", 7, ITEM_CREATED | 0x10000, 8), record("notes.txt", 9, ITEM_REMOVED, 8)]);
+        log.extend(page(b"3SLD", &[record("src/a.rs", 12, 0x1000, 12)]));
+
+        let events = parse_log(&log).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], Event { path: PathBuf::from("build/out.o"), id: 7, flags: ITEM_CREATED | 0x10000 });
+        let changes: Vec<Change> = events.iter().map(Event::change).collect();
+        assert_eq!(changes, [Change::Added, Change::Removed, Change::Modified]);
+        assert_eq!(events[2].id, 12);
+
+        assert!(parse_log(b"9SLD\0\0\0\0\x0c\0\0\0").is_err());
+        assert!(parse_log(&log[..log.len() - 3]).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Secrets kept in the macOS login Keychain as generic passwords of the service
+//! "mkramdisk", one per item name (`--keychain-item`), so they never pass through the
+//! command line or a file. Other systems have no Keychain and every call fails.
+
+/// The Keychain service every item is stored under.
+pub const SERVICE: &str = "mkramdisk";
+
+/// The secret stored as `item`.
+pub fn find(item: &str) -> Result<String, String> {
+    lookup(item)?.ok_or_else(|| format!("No Keychain item '{}' (service {})", item, SERVICE))
+}
+
+/// The secret stored as `item`, or None if nothing is.
+pub fn lookup(item: &str) -> Result<Option<String>, String> {
+    #[cfg(target_os = "macos")]
+    return security::find(item);
+    #[cfg(not(target_os = "macos"))]
+    Err(unsupported(item))
+}
+
+/// Store `secret` as `item`, replacing the secret it held if any.
+pub fn store(item: &str, secret: &str) -> Result<(), String> {
+    #[cfg(target_os = "macos")]
+    return security::store(item, secret);
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = secret;
+        Err(unsupported(item))
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn unsupported(item: &str) -> String {
+    format!("Keychain item '{}': the Keychain is only available on macOS", item)
+}
+
+#[cfg(target_os = "macos")]
+mod security {
+    use std::ffi::c_void;
+    use std::ptr;
+
+    use super::SERVICE;
+
+    type OSStatus = i32;
+    const ERR_SEC_ITEM_NOT_FOUND: OSStatus = -25300;
+
+    #[link(name = "Security", kind = "framework")]
+    unsafe extern "C" {
+        fn SecKeychainFindGenericPassword(
+            keychain: *const c_void,
+            service_length: u32,
+            service: *const u8,
+            account_length: u32,
+            account: *const u8,
+            password_length: *mut u32,
+            password: *mut *mut c_void,
+            item: *mut *mut c_void,
+        ) -> OSStatus;
+        fn SecKeychainAddGenericPassword(
+            keychain: *const c_void,
+            service_length: u32,
+            service: *const u8,
+            account_length: u32,
+            account: *const u8,
+            password_length: u32,
+            password: *const c_void,
+            item: *mut *mut c_void,
+        ) -> OSStatus;
+        fn SecKeychainItemModifyAttributesAndData(item: *mut c_void, attributes: *const c_void, length: u32, data: *const c_void) -> OSStatus;
+        fn SecKeychainItemFreeContent(attributes: *mut c_void, data: *mut c_void) -> OSStatus;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    unsafe extern "C" {
+        fn CFRelease(object: *const c_void);
+    }
+
+    fn failed(action: &str, item: &str, status: OSStatus) -> String {
+        format!("Failed to {} Keychain item '{}' (OSStatus {})", action, item, status)
+    }
+
+    // An item found in the Keychain: its reference, to be released, and its secret if asked for
+    struct Found {
+        reference: *mut c_void,
+        secret: Option<Vec<u8>>,
+    }
+
+    fn lookup(item: &str, secret: bool) -> Result<Option<Found>, String> {
+        let mut length = 0u32;
+        let mut data = ptr::null_mut();
+        let mut reference = ptr::null_mut();
+        let status = unsafe {
+            SecKeychainFindGenericPassword(
+                ptr::null(),
+                SERVICE.len() as u32,
+                SERVICE.as_ptr(),
+                item.len() as u32,
+                item.as_ptr(),
+                if secret { &mut length } else { ptr::null_mut() },
+                if secret { &mut data } else { ptr::null_mut() },
+                &mut reference,
+            )
+        };
+        match status {
+            0 => {}
+            ERR_SEC_ITEM_NOT_FOUND => return Ok(None),
+            status => return Err(failed("read", item, status)),
+        }
+        let secret = (!data.is_null()).then(|| {
+            let bytes = unsafe { std::slice::from_raw_parts(data.cast::<u8>(), length as usize) }.to_vec();
+            unsafe { SecKeychainItemFreeContent(ptr::null_mut(), data) };
+            bytes
+        });
+        Ok(Some(Found { reference, secret }))
+    }
+
+    pub fn find(item: &str) -> Result<Option<String>, String> {
+        let Some(found) = lookup(item, true)? else {
+            return Ok(None);
+        };
+        unsafe { CFRelease(found.reference) };
+        String::from_utf8(found.secret.unwrap_or_default()).map(Some).map_err(|_| format!("Keychain item '{}' is not text", item))
+    }
+
+    pub fn store(item: &str, secret: &str) -> Result<(), String> {
+        let status = match lookup(item, false)? {
+            Some(found) => {
+                let status = unsafe { SecKeychainItemModifyAttributesAndData(found.reference, ptr::null(), secret.len() as u32, secret.as_ptr().cast()) };
+                unsafe { CFRelease(found.reference) };
+                status
+            }
+            None => unsafe {
+                SecKeychainAddGenericPassword(
+                    ptr::null(),
+                    SERVICE.len() as u32,
+                    SERVICE.as_ptr(),
+                    item.len() as u32,
+                    item.as_ptr(),
+                    secret.len() as u32,
+                    secret.as_ptr().cast(),
+                    ptr::null_mut(),
+                )
+            },
+        };
+        match status {
+            0 => Ok(()),
+            status => Err(failed("write", item, status)),
+        }
+    }
+}
@@ -0,0 +1,803 @@
+//! Create and tear down macOS RAM disks. The `mkramdisk` command line is a thin wrapper
+//! over this library:
+//!
+//! ```no_run
+//! let config = mkramdisk_core::Config { size: "2G".to_string(), name: "Scratch".to_string(), ..Default::default() };
+//! let created = mkramdisk_core::create_ramdisk(&config)?;
+//! mkramdisk_core::eject(&config, "Scratch")?;
+//! # Ok::<(), String>(())
+//! ```
+//!
+//! A sandboxed app keeps the state file in its app group container rather than
+//! `~/Library`, by setting the paths before anything else:
+//!
+//! ```no_run
+//! # let container = std::path::Path::new("/Users/me/Library/Group Containers/TEAM.mkramdisk");
+//! mkramdisk_core::paths::set(mkramdisk_core::paths::StatePaths::app_group(container));
+//! ```
+//!
+//! What can be done with a disk once it exists (`status`, `resize`, `gc`, `adopt`,
+//! `eject --all`, ...) is in `disks`, and `project`, `fleet`, `backup` and `accelerate` hold
+//! the commands of the same names.
+//!
+//! The device providers and the plumbing under them live in `mkramdisk-backends`,
+//! re-exported here.
+
+pub mod accelerate;
+pub mod backup;
+pub mod deprecations;
+pub mod diagnostics;
+pub mod disks;
+pub mod features;
+pub mod fleet;
+pub mod journal;
+pub mod keychain;
+pub mod memory;
+pub mod migrate;
+pub mod output;
+pub mod passphrase;
+pub mod paths;
+pub mod pipeline;
+pub mod presence;
+pub mod project;
+pub mod registry;
+pub mod selftest;
+pub mod user_config;
+
+pub use mkramdisk_backends::{api, attributes, copier, formats, partitions, pool, progress, provider, remote, runner, session, trace};
+pub use mkramdisk_backends::{
+    parse_size, path_bytes, path_from_bytes, sanitize_volume_name, size_to_bytes, size_to_sectors, size_unit, FAT_LABEL_MAX,
+};
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// A RAM disk to create and how to report on it. The default is what `mkramdisk` does
+/// with no options, short of a size.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub size: String,
+    pub name: String,
+    pub filesystem: String,
+    pub backend: String,
+    pub verbose: bool,
+    pub echo_commands: bool,
+    pub force: bool,
+    pub allow_system_name: bool,
+    pub keep_on_failure: bool,
+    pub diagnostics: bool,
+    pub quiet: bool,
+    /// Where progress and lifecycle events go, if anywhere.
+    pub events: Vec<progress::Sink>,
+    pub strict: bool,
+    pub summary: bool,
+    /// Print results for scripts on stdout in this format instead of the summary line
+    /// or text (`--output`, or `--json`).
+    pub output: Option<output::Format>,
+    pub legacy_output: bool,
+    pub actions_json: bool,
+    pub copy_path: bool,
+    pub auto_min: bool,
+    pub fallback_format: Option<String>,
+    pub personality: Option<String>,
+    pub experimental: bool,
+    pub partitions: Vec<partitions::Partition>,
+    pub scheme: Option<partitions::Scheme>,
+    pub bootable: bool,
+    pub source_image: Option<PathBuf>,
+    pub copy: copier::CopyOptions,
+    pub mount_timeout: Duration,
+    /// Extra options the volume is remounted with once mounted, e.g. "noatime".
+    pub mount_options: Vec<String>,
+    /// Shell command `mkramdisk create` runs once the disk is up (see `run_hook`).
+    pub after_create: Option<String>,
+    /// Shell command `eject` runs before tearing the disk down.
+    pub before_eject: Option<String>,
+    /// Mark the new disk so tearing it down needs the user's presence (`--protected`).
+    pub protected: bool,
+    /// Also expose the device read-only at a second node (`--readonly-export`).
+    pub readonly_export: bool,
+    /// Let `eject` tear down a RAM disk of the backend that mkramdisk didn't create
+    /// (`--unmanaged`).
+    pub unmanaged: bool,
+    /// Create the volume as APFS (Encrypted), with the passphrase read from here (`--encrypt`).
+    pub encrypt: Option<passphrase::Source>,
+    /// Groups to put the new disk in, or for list, eject and sync the groups a disk
+    /// must be in all of (`--tag`).
+    pub tags: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            size: String::new(),
+            name: "RAMDisk".to_string(),
+            filesystem: "apfs".to_string(),
+            backend: provider::default_backend().to_string(),
+            verbose: false,
+            echo_commands: false,
+            force: false,
+            allow_system_name: false,
+            keep_on_failure: false,
+            diagnostics: false,
+            quiet: false,
+            events: Vec::new(),
+            strict: false,
+            summary: true,
+            output: None,
+            legacy_output: false,
+            actions_json: false,
+            copy_path: false,
+            auto_min: false,
+            fallback_format: None,
+            personality: None,
+            experimental: false,
+            partitions: Vec::new(),
+            scheme: None,
+            bootable: false,
+            source_image: None,
+            copy: copier::CopyOptions::default(),
+            mount_timeout: Duration::from_secs(5),
+            mount_options: Vec::new(),
+            protected: false,
+            readonly_export: false,
+            unmanaged: false,
+            encrypt: None,
+            tags: Vec::new(),
+            after_create: None,
+            before_eject: None,
+        }
+    }
+}
+
+/// Progress and results meant for people. These go to stderr so stdout only carries
+/// data for scripts, unless `--legacy-output` asks for the old behaviour.
+pub fn say(config: &Config, message: &str) {
+    if config.legacy_output {
+        println!("{}", message);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+pub fn log_verbose(config: &Config, message: &str) {
+    session::decision(message);
+    if config.verbose {
+        eprintln!("[INFO] {}", message);
+    }
+}
+
+/// Report something the user should know about but that does not stop the run.
+/// With `--strict` every warning is an error instead.
+pub fn warn(config: &Config, message: &str) -> Result<(), String> {
+    session::decision(&format!("Warning: {}", message));
+    if config.strict {
+        return Err(format!("{} (warnings are errors with --strict)", message));
+    }
+    eprintln!("Warning: {}", message);
+    Ok(())
+}
+
+/// A command-line argument that has to be text, such as an option, a volume name or a
+/// tag. Paths are taken as they are given, but one of these that isn't UTF-8 is refused
+/// here, naming it, rather than panicking or being mangled later.
+pub fn utf8_arg(arg: &std::ffi::OsStr) -> Result<&str, String> {
+    arg.to_str().ok_or_else(|| format!("Argument '{}' is not valid UTF-8 (only paths may be)", arg.to_string_lossy()))
+}
+
+/// Seconds since the epoch as "YYYY-MM-DD HH:MM:SS UTC", using Howard Hinnant's
+/// civil_from_days as mkramdisk-cli's build.rs does for the build date.
+pub fn format_timestamp(seconds: u64) -> String {
+    let z = (seconds / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, time / 3600, time % 3600 / 60, time % 60
+    )
+}
+
+// Longest name a mount point's directory can have, in bytes
+pub const VOLUME_NAME_MAX: usize = 255;
+
+/// Check that `name` can name a volume and its mount point: not empty, not a path
+/// (".", ".." or anything with a '/') and not too long for a directory name.
+pub fn validate_volume_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("A volume name needs at least one letter, digit, '-' or '_'".to_string());
+    }
+    if name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(format!("'{}' cannot be a volume name: it would mount outside the volumes directory", name));
+    }
+    if name.len() > VOLUME_NAME_MAX {
+        return Err(format!("Volume name is {} bytes long; at most {} are allowed", name.len(), VOLUME_NAME_MAX));
+    }
+    Ok(())
+}
+
+/// Check that `tag` can name a group of disks: letters, digits, '-', '_' and '.', so
+/// it reads the same in the state file, on the command line and in a list.
+pub fn validate_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() || !tag.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(format!("'{}' cannot be a tag: use letters, digits, '-', '_' and '.'", tag));
+    }
+    Ok(())
+}
+
+/// " tagged ci and build", to say which disks a command looked at; nothing for no tags.
+pub fn tagged(tags: &[String]) -> String {
+    match tags {
+        [] => String::new(),
+        tags => format!(" tagged {}", tags.join(" and ")),
+    }
+}
+
+/// Names macOS gives its own volumes. A RAM disk mounted at /Volumes under one of these
+/// can be mistaken for the real volume by backup, update and disk tools.
+pub const SYSTEM_VOLUME_NAMES: &[&str] = &["Macintosh HD", "Macintosh HD - Data", "Data", "Preboot", "Recovery", "VM", "Update"];
+
+/// Refuse a volume name that shadows a system volume, unless `allow` (`--allow-system-name`).
+/// Only the exact name is refused, as tools match it exactly: "data" is fine, "Data" isn't.
+pub fn check_volume_name(name: &str, allow: bool) -> Result<(), String> {
+    if SYSTEM_VOLUME_NAMES.contains(&name) && !allow {
+        return Err(format!(
+            "'{}' is the name of a macOS system volume; other software may mistake the RAM disk for it. \
+             Pick another name, or pass --allow-system-name to use it anyway",
+            name
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_filesystem(filesystem: &str) -> Result<(), String> {
+    formats::personality(filesystem).map(|_| ())
+}
+
+// Smallest disks the formatters reliably accept, so an undersized disk is refused
+// before anything is attached rather than failing inside diskutil.
+pub fn filesystem_minimum_bytes(filesystem: &str) -> u64 {
+    let personality = formats::personality(filesystem).unwrap_or_default();
+    if filesystem == "btrfs" {
+        // mkfs.btrfs refuses anything much under 110M
+        128 << 20
+    } else if personality.ends_with("APFS") {
+        32 << 20
+    } else if personality == "MS-DOS FAT32" {
+        // FAT32 needs at least 65525 clusters
+        34 << 20
+    } else if personality.contains("HFS+") {
+        8 << 20
+    } else {
+        1 << 20
+    }
+}
+
+/// The largest minimum of the filesystems the disk may end up with: the fallback's
+/// too, as the disk has to hold it should the first choice fail. Only a block backend
+/// has a minimum, as only its disks are formatted at the size given.
+pub fn disk_minimum_bytes(config: &Config) -> (u64, &str) {
+    if !provider::BLOCK_BACKENDS.contains(&config.backend.as_str()) {
+        return (0, config.filesystem.as_str());
+    }
+    std::iter::once(&config.filesystem)
+        .chain(&config.fallback_format)
+        .map(|filesystem| (filesystem_minimum_bytes(filesystem), filesystem.as_str()))
+        .fold((0, config.filesystem.as_str()), |largest, next| if next.0 > largest.0 { next } else { largest })
+}
+
+/// The label a volume named `name` gets on FAT, which keeps labels to `FAT_LABEL_MAX`
+/// characters and in upper case.
+pub fn fat_label(name: &str) -> String {
+    name.chars().take(FAT_LABEL_MAX).collect::<String>().trim_end().to_uppercase()
+}
+
+/// The configured size in sectors, warning when it is not a whole number of sectors.
+pub fn disk_sectors(config: &Config) -> Result<u64, String> {
+    let sectors = size_to_sectors(&config.size)?;
+    let (minimum, filesystem) = disk_minimum_bytes(config);
+    if sectors * 512 < minimum {
+        return Err(format!(
+            "A {} RAM disk is too small for {}; it needs at least {} (or use --auto-min)",
+            config.size,
+            filesystem,
+            memory::format_size(minimum)
+        ));
+    }
+    if sectors * 512 != size_to_bytes(&config.size)? {
+        warn(config, &format!(
+            "Size {} is not a multiple of 512 bytes; rounded up to {} bytes",
+            config.size,
+            sectors * 512
+        ))?;
+    }
+    Ok(sectors)
+}
+
+pub fn get_diskutil_format(filesystem: &str) -> Result<String, String> {
+    formats::personality(filesystem).map(str::to_string)
+}
+
+/// The personality to format with: `--personality` verbatim, else resolved from `--format`.
+/// Partitioned disks report their scheme; the partitions carry their own personalities.
+pub fn diskutil_format(config: &Config) -> Result<String, String> {
+    if config.source_image.is_some() {
+        return Ok(config.filesystem.clone());
+    }
+    if !config.partitions.is_empty() {
+        return Ok(config.scheme.unwrap_or(partitions::Scheme::Gpt).to_string());
+    }
+    match &config.personality {
+        Some(personality) => Ok(personality.clone()),
+        None => get_diskutil_format(&config.filesystem),
+    }
+}
+
+pub fn check_memory_headroom(config: &Config, bytes: u64) -> Result<(), String> {
+    let Some(status) = memory::query_memory_status() else {
+        log_verbose(config, "Memory status unavailable, skipping the memory pressure check");
+        return Ok(());
+    };
+    log_verbose(config, &format!(
+        "Memory: {} available, swap {} of {} used, pressure level {}",
+        memory::format_size(status.available),
+        memory::format_size(status.swap_used),
+        memory::format_size(status.swap_total),
+        status.pressure_level
+    ));
+    
+    if status.is_under_heavy_pressure() && !config.force {
+        let suggestion = match status.suggested_max() {
+            Some(size) => format!("Suggested maximum size right now: {}.", size),
+            None => "No RAM disk is safe to create right now.".to_string(),
+        };
+        return Err(format!(
+            "System is already under heavy memory pressure; creating a {} RAM disk could freeze it.\n\
+             {} Use --force to create it anyway.",
+            config.size, suggestion
+        ));
+    }
+    if bytes > status.safe_max_bytes() {
+        warn(config, &format!(
+            "A {} RAM disk is more than half of the {} available; the system may start swapping",
+            config.size,
+            memory::format_size(status.available)
+        ))?;
+    }
+    Ok(())
+}
+
+pub fn create_ramdisk(config: &Config) -> Result<pipeline::Created, String> {
+    // Convert size to sectors
+    log_verbose(config, &format!("Converting size '{}' to sectors...", config.size));
+    let sectors = disk_sectors(config)?;
+    log_verbose(config, &format!("Size: {} = {} sectors", config.size, sectors));
+    
+    check_memory_headroom(config, sectors * 512)?;
+    
+    let provider = provider::select_provider(&config.backend, &config.name)?;
+    let diskutil_format = diskutil_format(config)?;
+    
+    // Check if volume name already exists
+    let names = config.partitions.iter().map(|p| p.name.as_str()).chain(std::iter::once(config.name.as_str()));
+    for name in names {
+        let mount_point = provider.mount_point(name);
+        if mount_point.exists() {
+            return Err(format!("Volume '{}' already exists at {}", name, mount_point.display()));
+        }
+    }
+    
+    let mut span = trace::start("create");
+    span.attribute("mkramdisk.name", &config.name);
+    span.attribute("mkramdisk.size", &config.size);
+    span.attribute("mkramdisk.backend", &config.backend);
+    // One operation up to the announcement, so nobody else's commands land between its steps
+    let operation = pool::operation();
+    let created = pipeline::create(config, provider.as_ref(), sectors, &diskutil_format);
+    span.end(created.as_ref().err().map(String::as_str));
+    let created = created?;
+    // Whatever can still fail, --strict warnings included, happens before the disk is
+    // announced and takes it down again rather than leaving it attached behind an error
+    if let Err(e) = settle(config, provider.as_ref(), &created, sectors) {
+        log_verbose(config, &format!("Detaching {} again...", created.device));
+        let _ = provider.detach(&created.device);
+        let _ = registry::update(|registry| registry.forget(&config.backend, Some(&created.device), &config.name));
+        return Err(format!("{}\n{} was detached again", e, created.device));
+    }
+    drop(operation);
+    progress::emit(serde_json::json!({
+        "event": "created",
+        "name": config.name,
+        "device": created.device,
+        "mount_point": created.mount_point,
+        "size": config.size,
+        "filesystem": created.filesystem,
+        "backend": config.backend,
+    }));
+    
+    say(config, "\x1b[1;32m RAM disk created successfully\x1b[0m");
+    say(config, &format!("  Device:     {}", created.device));
+    say(config, &format!("  Size:       {}", config.size));
+    say(config, &format!("  Filesystem: {}", created.filesystem));
+    if config.encrypt.is_some() {
+        say(config, "  Encrypted:  yes");
+    }
+    match &created.mount_point {
+        Some(_) if !created.partitions.is_empty() => {
+            for (partition, mount_point) in config.partitions.iter().zip(&created.partitions) {
+                say(config, &format!("  Partition:  {} ({}) at {}", partition.name, partition.personality, mount_point.display()));
+            }
+        }
+        Some(mount_point) => say(config, &format!("  Mount point: {}", mount_point.display())),
+        None => say(config, "  Mount point: none (raw device)"),
+    }
+    if let Some(node) = &created.readonly_device {
+        say(config, &format!("  Read-only:  {}", node));
+    }
+    say(config, &format!("  Name:       {}", config.name));
+    say(config, "");
+    let actions = provider.actions(&created.device, created.mount_point.as_deref());
+    if config.actions_json {
+        let actions: Vec<_> = actions
+            .iter()
+            .map(|action| serde_json::json!({
+                "id": action.id,
+                "label": action.label,
+                "argv": action.argv,
+                "command": action.command(),
+            }))
+            .collect();
+        println!("{}", api::stamp(serde_json::json!({
+            "device": created.device,
+            "mount_point": created.mount_point,
+            "partitions": created.partitions,
+            "actions": actions,
+        })));
+    } else {
+        for action in &actions {
+            say(config, &format!("{:<12}\x1b[1m{}\x1b[0m", format!("{}:", action.label), action.command()));
+        }
+    }
+    
+    Ok(created)
+}
+
+// Protect, record (in the state file and on the volume) and copy the path of a disk
+// just created
+fn settle(config: &Config, provider: &dyn provider::DeviceProvider, created: &pipeline::Created, sectors: u64) -> Result<(), String> {
+    if config.protected {
+        let mount_point = created.mount_point.as_deref().ok_or("Only a disk with a filesystem can be protected")?;
+        presence::protect(mount_point)?;
+    }
+    let entry = registry::Entry {
+        device: created.device.clone(),
+        identity: provider.identity(&created.device),
+        name: config.name.clone(),
+        backend: config.backend.clone(),
+        size: config.size.clone(),
+        size_bytes: sectors * 512,
+        filesystem: created.filesystem.clone(),
+        mount_point: created.mount_point.clone(),
+        created: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+        flags: registry_flags(config),
+        shadow: None,
+        directory: None,
+        tags: config.tags.clone(),
+        timers: None,
+    };
+    if let Some(mount_point) = &created.mount_point
+        && let Err(e) = registry::write_marker(mount_point, &entry)
+    {
+        warn(config, &format!("{}; `registry rebuild` won't recognise {} as mkramdisk's", e, config.name))?;
+    }
+    if let Err(e) = registry::update(|registry| registry.record(entry)) {
+        warn(config, &format!("Failed to record {} in the state file: {}", config.name, e))?;
+    }
+    if config.copy_path {
+        match &created.mount_point {
+            Some(mount_point) => copy_to_clipboard(config, &mount_point.to_string_lossy())?,
+            None => copy_to_clipboard(config, &created.device)?,
+        }
+    }
+    Ok(())
+}
+
+// The options a disk was created with that are worth knowing about it later
+fn registry_flags(config: &Config) -> Vec<String> {
+    let flags = [
+        (config.protected, "protected"),
+        (config.readonly_export, "readonly-export"),
+        (config.bootable, "bootable"),
+        (!config.partitions.is_empty(), "partitioned"),
+        (config.source_image.is_some(), "from-image"),
+        (config.encrypt.is_some(), "encrypted"),
+    ];
+    flags.iter().filter(|(set, _)| *set).map(|(_, flag)| flag.to_string()).collect()
+}
+
+fn copy_to_clipboard(config: &Config, text: &str) -> Result<(), String> {
+    match runner::output_with_input(&mut std::process::Command::new("pbcopy"), text.as_bytes()) {
+        Ok(output) if output.status.success() => {
+            log_verbose(config, &format!("Copied {} to the clipboard", text));
+            Ok(())
+        }
+        Ok(output) => warn(config, &format!(
+            "Failed to copy the mount point to the clipboard: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) => warn(config, &format!("Failed to copy the mount point to the clipboard: pbcopy: {}", e)),
+    }
+}
+
+/// Unmount and detach a RAM disk given by volume name, mount point or device, listing
+/// the files that hold it if it is busy.
+pub fn eject(config: &Config, target: &str) -> Result<(), String> {
+    // A directory is a mount point and any other path a device; anything else names a
+    // volume, with "disk5" short for /dev/disk5
+    if !target.contains('/') {
+        validate_volume_name(target)?;
+    }
+    let provider = provider::select_provider(&config.backend, target)?;
+    let named = provider.mount_point(target);
+    let (path, device, mount_point) = if target.contains('/') && Path::new(target).is_dir() {
+        let mount_point = PathBuf::from(target);
+        (mount_point.clone(), provider.device_at(&mount_point), Some(mount_point))
+    } else if target.contains('/') {
+        (PathBuf::from(target), Some(target.to_string()), provider.locate_mount_point(target, ""))
+    } else if named.exists() {
+        let device = provider.device_at(&named).or_else(|| provider.find_device(target));
+        (named.clone(), device, Some(named))
+    } else if is_disk_identifier(target) {
+        let device = format!("/dev/{}", target);
+        let mount_point = provider.locate_mount_point(&device, "");
+        (PathBuf::from(&device), Some(device), mount_point)
+    } else if let Some(device) = provider.find_device(target) {
+        // Mounted somewhere else, e.g. at "/Volumes/Scratch 1", or not at all
+        let mount_point = provider.locate_mount_point(&device, target);
+        (mount_point.clone().unwrap_or_else(|| PathBuf::from(&device)), Some(device), mount_point)
+    } else {
+        return Err(format!("No RAM disk named '{}' is mounted at {}", target, named.display()));
+    };
+    if let Some(device) = device.as_deref().filter(|device| *device != target) {
+        log_verbose(config, &format!("{} is on {}", target, device));
+    }
+
+    let name = match target.contains('/') {
+        true => path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+        false => target.to_string(),
+    };
+    let registry = registry::load()?;
+    let entry = match &device {
+        Some(device) => registry.find(&config.backend, device, provider.identity(device).as_deref()),
+        None => registry.find_named(&config.backend, &name),
+    };
+    let shadow = entry.and_then(|entry| entry.shadow.clone());
+    check_ejectable(config, provider.as_ref(), target, device.as_deref(), entry.is_some())?;
+    if let Some(mount_point) = mount_point.as_deref().filter(|mount_point| presence::is_protected(mount_point)) {
+        presence::require(target)?;
+        log_verbose(config, &format!("{} is protected; ejecting it was confirmed", mount_point.display()));
+    }
+    if let Some(hook) = &config.before_eject {
+        run_hook(config, "before_eject", hook, target, device.as_deref(), mount_point.as_deref())?;
+    }
+    // Forcing it out from under whatever has files open is the user's call, but say what that is
+    if let Some(mount_point) = mount_point.as_deref().filter(|_| config.force) {
+        let open = provider::open_files(mount_point).unwrap_or_default();
+        let (holders, _) = provider::holders(&open);
+        if !holders.is_empty() {
+            say(config, &format!("Forcing {} out from under {}", target, holders.join(", ")));
+        }
+    }
+    log_verbose(config, &format!("Ejecting {}...", path.display()));
+    let operation = pool::operation();
+    if let Err(mut e) = trace::in_span("eject", || provider.destroy(&path, config.force)) {
+        if let Some(mount_point) = mount_point.as_deref() {
+            e.push_str(&busy_diagnostics(target, mount_point, e.contains("busy")));
+        }
+        return Err(e);
+    }
+    // An overlay's shadow file is of no use without it
+    if let Some(shadow) = &shadow {
+        log_verbose(config, &format!("Ejecting the shadow disk {}...", shadow));
+        provider.detach(shadow)?;
+    }
+    drop(operation);
+    let forgotten = registry::update(|registry| {
+        registry.forget(&config.backend, device.as_deref(), &name);
+        if let Some(shadow) = &shadow {
+            registry.forget(&config.backend, Some(shadow), "");
+        }
+    });
+    if let Err(e) = forgotten {
+        warn(config, &format!("Failed to remove {} from the state file: {}", target, e))?;
+    }
+    progress::emit(serde_json::json!({ "event": "ejected", "target": target, "backend": config.backend }));
+    say(config, &format!("Ejected {}", target));
+    Ok(())
+}
+
+// Only the backend's own disks are ever ejected, so a name or device that turns out to
+// be some other disk, like a USB drive, is left alone; of those, one mkramdisk didn't
+// create needs --unmanaged. A backend that cannot list its disks relies on that alone
+fn check_ejectable(config: &Config, provider: &dyn provider::DeviceProvider, target: &str, device: Option<&str>, managed: bool) -> Result<(), String> {
+    let listed = provider.list().ok().map(|disks| device.is_some_and(|device| disks.iter().any(|disk| disk.device == device)));
+    if listed == Some(false) {
+        return Err(format!(
+            "'{}'{} is not a RAM disk of the {} backend; mkramdisk only ejects its own disks",
+            target,
+            device.filter(|device| *device != target).map(|device| format!(" ({})", device)).unwrap_or_default(),
+            config.backend
+        ));
+    }
+    if !managed && !config.unmanaged {
+        return Err(format!(
+            "'{}' was not created by mkramdisk; pass --unmanaged to eject it anyway, or adopt its device first",
+            target
+        ));
+    }
+    if !managed {
+        log_verbose(config, &format!("'{}' was not created by mkramdisk; ejecting it as --unmanaged asks", target));
+    }
+    Ok(())
+}
+
+// What has files open on a disk that failed to eject, from lsof, and what to do about
+// it: quit or kill those processes, or --force
+fn busy_diagnostics(target: &str, mount_point: &Path, busy: bool) -> String {
+    let open = match provider::open_files(mount_point) {
+        Ok(open) => open,
+        Err(e) if busy => return format!("\nCould not list the files open on {}: {}\nUse --force to eject anyway", target, e),
+        Err(_) => return String::new(),
+    };
+    let (holders, pids) = provider::holders(&open);
+    if holders.is_empty() && busy {
+        return "\nClose any files open on it, or use --force to eject anyway".to_string();
+    }
+    if holders.is_empty() {
+        return String::new();
+    }
+    let files: Vec<String> = open.iter().map(ToString::to_string).collect();
+    let pids: Vec<String> = pids.iter().map(ToString::to_string).collect();
+    format!(
+        "\nFiles still open on {}:\n  {}\nQuit {} (or run `kill {}`), or use --force to eject anyway",
+        target,
+        files.join("\n  "),
+        holders.join(", "),
+        pids.join(" ")
+    )
+}
+
+/// Run a profile hook through the shell with the disk it is for in its environment, as
+/// MKRAMDISK_NAME, MKRAMDISK_DEVICE and MKRAMDISK_MOUNT_POINT. Its output goes to stderr
+/// so stdout keeps only the summary.
+pub fn run_hook(config: &Config, label: &str, hook: &str, name: &str, device: Option<&str>, mount_point: Option<&Path>) -> Result<(), String> {
+    log_verbose(config, &format!("Running {} hook: {}", label, hook));
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(hook);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(hook);
+        command
+    };
+    command.env("MKRAMDISK_NAME", name).stdout(io::stderr());
+    if let Some(device) = device {
+        command.env("MKRAMDISK_DEVICE", device);
+    }
+    if let Some(mount_point) = mount_point {
+        command.env("MKRAMDISK_MOUNT_POINT", mount_point);
+    }
+    let status = runner::status(&mut command).map_err(|e| format!("Failed to run {} hook: {}", label, e))?;
+    if !status.success() {
+        return Err(format!("The {} hook failed ({}): {}", label, status, hook));
+    }
+    Ok(())
+}
+
+fn is_disk_identifier(target: &str) -> bool {
+    target.strip_prefix("disk").is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_disk_sectors_rounds_up() {
+        let mut config = Config { size: "67109000".to_string(), ..Config::default() };
+        assert_eq!(disk_sectors(&config).unwrap(), 131073);
+        config.strict = true;
+        assert!(disk_sectors(&config).unwrap_err().contains("rounded up to 67109376 bytes"));
+        config.size = "64M".to_string();
+        assert_eq!(disk_sectors(&config).unwrap(), 131072);
+    }
+    
+    #[test]
+    fn test_filesystem_minimums() {
+        let mut config = Config { size: "4M".to_string(), backend: "ram".to_string(), ..Config::default() };
+        assert_eq!(
+            disk_sectors(&config).unwrap_err(),
+            "A 4M RAM disk is too small for apfs; it needs at least 32M (or use --auto-min)"
+        );
+        config.filesystem = "exfat".to_string();
+        assert_eq!(disk_sectors(&config).unwrap(), 8192);
+        // A directory or a simulated disk has no filesystem to fit
+        for backend in ["dir", "mock"] {
+            let config = Config { size: "4M".to_string(), backend: backend.to_string(), ..Config::default() };
+            assert_eq!(disk_sectors(&config).unwrap(), 8192);
+        }
+    }
+    
+    #[test]
+    fn test_get_diskutil_format() {
+        assert_eq!(get_diskutil_format("apfs").unwrap(), "APFS");
+        assert_eq!(get_diskutil_format("hfs+").unwrap(), "HFS+");
+        assert_eq!(get_diskutil_format("fat32").unwrap(), "MS-DOS FAT32");
+        assert_eq!(get_diskutil_format("exfat").unwrap(), "ExFAT");
+        
+        assert_eq!(get_diskutil_format("Case-sensitive APFS").unwrap(), "Case-sensitive APFS");
+        assert!(get_diskutil_format("invalid").is_err());
+    }
+    
+    #[test]
+    fn test_validate_volume_name() {
+        assert!(validate_volume_name("Build Cache").is_ok());
+        assert!(validate_volume_name("").is_err());
+        assert!(validate_volume_name("   ").is_err());
+        assert!(validate_volume_name("..").unwrap_err().contains("outside"));
+        assert!(validate_volume_name(".").is_err());
+        assert!(validate_volume_name("a/b").is_err());
+        assert!(validate_volume_name(&"x".repeat(VOLUME_NAME_MAX)).is_ok());
+        assert!(validate_volume_name(&"é".repeat(128)).unwrap_err().contains("256 bytes"));
+    }
+    
+    #[test]
+    fn test_validate_tag() {
+        assert!(validate_tag("ci").is_ok());
+        assert!(validate_tag("build-2.1_x").is_ok());
+        assert!(validate_tag("").is_err());
+        assert!(validate_tag("ci build").is_err());
+        assert!(validate_tag("ci,build").is_err());
+        assert_eq!(tagged(&[]), "");
+        assert_eq!(tagged(&["ci".to_string(), "build".to_string()]), " tagged ci and build");
+    }
+    
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+        let latin1 = OsString::from_vec(b"caf\xe9".to_vec());
+        assert_eq!(path_bytes(Path::new(&latin1)), b"caf\xe9");
+        assert_eq!(utf8_arg(OsString::from("1G").as_os_str()).unwrap(), "1G");
+        assert_eq!(utf8_arg(&latin1).unwrap_err(), "Argument 'caf\u{FFFD}' is not valid UTF-8 (only paths may be)");
+    }
+    
+    #[test]
+    fn test_check_volume_name() {
+        assert!(check_volume_name("Scratch", false).is_ok());
+        assert!(check_volume_name("Recovery", false).unwrap_err().contains("--allow-system-name"));
+        assert!(check_volume_name("Macintosh HD", false).is_err());
+        assert!(check_volume_name("data", false).is_ok());
+        assert!(check_volume_name("Data", true).is_ok());
+        assert!(check_volume_name("Data Cache", false).is_ok());
+    }
+    
+    #[test]
+    fn test_validate_filesystem() {
+        assert!(validate_filesystem("apfs").is_ok());
+        assert!(validate_filesystem("hfs+").is_ok());
+        assert!(validate_filesystem("fat32").is_ok());
+        assert!(validate_filesystem("exfat").is_ok());
+        assert!(validate_filesystem("invalid").is_err());
+    }
+}
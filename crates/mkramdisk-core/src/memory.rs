@@ -0,0 +1,231 @@
+use std::process::Command;
+use std::str;
+
+use crate::runner;
+
+/// Snapshot of the host's memory and swap state, as reported by sysctl/vm_stat.
+#[derive(Debug, Default)]
+pub struct MemoryStatus {
+    pub swap_used: u64,
+    pub swap_total: u64,
+    pub pressure_level: u32,
+    pub available: u64,
+    /// Bytes of memory held compressed by the VM compressor, and the bytes it occupies.
+    pub compressed: u64,
+    pub compressor_occupied: u64,
+}
+
+// Values of kern.memorystatus_vm_pressure_level
+const PRESSURE_WARN: u32 = 2;
+const PRESSURE_CRITICAL: u32 = 4;
+
+impl MemoryStatus {
+    /// True when the system is already short enough of memory that pinning more is risky:
+    /// the kernel says pressure is critical, or it warns while the compressor takes up
+    /// more than is left free. Swap in use is no sign of it, as swap stays allocated
+    /// long after the pressure that filled it has passed.
+    pub fn is_under_heavy_pressure(&self) -> bool {
+        self.pressure_level >= PRESSURE_CRITICAL || (self.pressure_level >= PRESSURE_WARN && self.compressor_occupied > self.available)
+    }
+
+    /// Largest RAM disk we'd suggest creating: half of the memory that is free or reclaimable.
+    pub fn safe_max_bytes(&self) -> u64 {
+        self.available / 2
+    }
+
+    /// `safe_max_bytes` as a size to pass, or None when that is less than a megabyte
+    /// and there is no size worth suggesting.
+    pub fn suggested_max(&self) -> Option<String> {
+        Some(self.safe_max_bytes()).filter(|bytes| *bytes >= 1024 * 1024).map(format_size)
+    }
+
+    /// Current compression ratio of the VM compressor, if it holds anything.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.compressor_occupied == 0 {
+            return None;
+        }
+        Some(self.compressed as f64 / self.compressor_occupied as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+struct VmStat {
+    available: u64,
+    compressed: u64,
+    compressor_occupied: u64,
+}
+
+fn sysctl(name: &str) -> Option<String> {
+    let output = runner::output(Command::new("sysctl").args(["-n", name])).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    str::from_utf8(&output.stdout).ok().map(|s| s.trim().to_string())
+}
+
+/// Installed physical memory in bytes, if the system reports it.
+pub fn physical_memory() -> Option<u64> {
+    sysctl("hw.memsize")?.parse().ok()
+}
+
+/// Query the current memory status. Returns None on systems without the macOS sysctls.
+pub fn query_memory_status() -> Option<MemoryStatus> {
+    let (swap_used, swap_total) = parse_swapusage(&sysctl("vm.swapusage")?)?;
+    let pressure_level = sysctl("kern.memorystatus_vm_pressure_level")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    let vm_stat = runner::output(&mut Command::new("vm_stat")).ok()?;
+    let vm = parse_vm_stat(str::from_utf8(&vm_stat.stdout).ok()?)?;
+
+    Some(MemoryStatus {
+        swap_used,
+        swap_total,
+        pressure_level,
+        available: vm.available,
+        compressed: vm.compressed,
+        compressor_occupied: vm.compressor_occupied,
+    })
+}
+
+/// What creating a disk would do to memory, as `plan` shows it.
+#[derive(Debug)]
+pub struct Plan {
+    pub sectors: u64,
+    pub bytes: u64,
+    pub status: MemoryStatus,
+    /// How many disks in the state file hold RAM, and how much between them.
+    pub managed: usize,
+    pub committed: u64,
+}
+
+/// Weigh the disk `config` describes against memory as it is now.
+pub fn plan(config: &crate::Config) -> Result<Plan, String> {
+    let sectors = crate::disk_sectors(config)?;
+    let status = query_memory_status().ok_or("Unable to read memory statistics (sysctl/vm_stat) on this system")?;
+    // What mkramdisk's disks already hold, which is no longer available
+    let (managed, committed) = crate::registry::load()?.in_ram().fold((0, 0), |(count, total), disk| (count + 1, total + disk.size_bytes));
+    Ok(Plan { sectors, bytes: sectors * 512, status, managed, committed })
+}
+
+fn parse_megabytes(value: &str) -> Option<u64> {
+    let value = value.trim().trim_end_matches('M');
+    let mb: f64 = value.parse().ok()?;
+    Some((mb * 1024.0 * 1024.0) as u64)
+}
+
+/// Parse `sysctl vm.swapusage` output, e.g.
+/// "total = 2048.00M  used = 1024.50M  free = 1023.50M  (encrypted)".
+/// Returns (used, total) in bytes.
+fn parse_swapusage(output: &str) -> Option<(u64, u64)> {
+    let mut used = None;
+    let mut total = None;
+    let mut tokens = output.split_whitespace();
+    while let Some(key) = tokens.next() {
+        if tokens.next() != Some("=") {
+            continue;
+        }
+        let value = tokens.next()?;
+        match key {
+            "total" => total = parse_megabytes(value),
+            "used" => used = parse_megabytes(value),
+            _ => {}
+        }
+    }
+    Some((used?, total?))
+}
+
+/// Parse `vm_stat` output. Available memory is what is free or cheaply reclaimable
+/// (free + inactive + speculative pages).
+fn parse_vm_stat(output: &str) -> Option<VmStat> {
+    let mut lines = output.lines();
+    let header = lines.next()?;
+    let page_size: u64 = header
+        .split("page size of ")
+        .nth(1)?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+
+    let mut stat = VmStat::default();
+    for line in lines {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let Ok(pages) = value.trim().trim_end_matches('.').parse::<u64>() else {
+            continue;
+        };
+        match key {
+            "Pages free" | "Pages inactive" | "Pages speculative" => stat.available += pages * page_size,
+            "Pages stored in compressor" => stat.compressed = pages * page_size,
+            "Pages occupied by compressor" => stat.compressor_occupied = pages * page_size,
+            _ => {}
+        }
+    }
+    Some(stat)
+}
+
+/// Format a byte count as a size argument mkramdisk accepts, rounded down (e.g. "3G", "512M").
+pub fn format_size(bytes: u64) -> String {
+    const MB: u64 = 1024 * 1024;
+    const GB: u64 = 1024 * MB;
+    if bytes >= GB {
+        format!("{}G", bytes / GB)
+    } else {
+        format!("{}M", bytes / MB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_swapusage() {
+        let (used, total) =
+            parse_swapusage("total = 2048.00M  used = 1024.50M  free = 1023.50M  (encrypted)").unwrap();
+        assert_eq!(total, 2048 * 1024 * 1024);
+        assert_eq!(used, 1024 * 1024 * 1024 + 512 * 1024);
+        assert!(parse_swapusage("garbage").is_none());
+    }
+
+    #[test]
+    fn test_parse_vm_stat() {
+        let output = "Mach Virtual Memory Statistics: (page size of 16384 bytes)\n\
+                      Pages free:                               10.\n\
+                      Pages active:                            500.\n\
+                      Pages inactive:                           20.\n\
+                      Pages speculative:                         2.\n\
+                      Pages stored in compressor:               30.\n\
+                      Pages occupied by compressor:             10.\n";
+        let stat = parse_vm_stat(output).unwrap();
+        assert_eq!(stat.available, 32 * 16384);
+        assert_eq!(stat.compressed, 30 * 16384);
+        assert_eq!(stat.compressor_occupied, 10 * 16384);
+    }
+
+    #[test]
+    fn test_is_under_heavy_pressure() {
+        // Swap left over from earlier pressure is no reason to refuse
+        let mut status = MemoryStatus { swap_used: 900, swap_total: 1000, available: 100, compressor_occupied: 200, ..Default::default() };
+        assert!(!status.is_under_heavy_pressure());
+        status.pressure_level = PRESSURE_WARN;
+        assert!(status.is_under_heavy_pressure());
+        status.available = 300;
+        assert!(!status.is_under_heavy_pressure());
+        status.pressure_level = PRESSURE_CRITICAL;
+        assert!(status.is_under_heavy_pressure());
+    }
+
+    #[test]
+    fn test_suggested_max() {
+        assert_eq!(MemoryStatus { available: 4 << 30, ..Default::default() }.suggested_max().as_deref(), Some("2G"));
+        assert_eq!(MemoryStatus { available: 1 << 20, ..Default::default() }.suggested_max(), None);
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024 + 1), "3G");
+        assert_eq!(format_size(512 * 1024 * 1024), "512M");
+    }
+}
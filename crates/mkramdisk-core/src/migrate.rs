@@ -0,0 +1,118 @@
+//! Upgrades of the files mkramdisk keeps between runs, for when their format changes
+//! between versions: the state file (see `registry`) and config.toml (see
+//! `user_config`). The old file is copied aside before it is rewritten, so a disk
+//! created by an older mkramdisk stays known to a newer one and a downgrade can put
+//! the old file back.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{registry, user_config};
+
+/// A file that needs upgrading, and what the upgrade changes.
+#[derive(Debug)]
+pub struct Migration {
+    pub path: PathBuf,
+    pub changes: Vec<String>,
+    upgraded: String,
+}
+
+impl Migration {
+    /// Copy the file aside and write the upgrade in its place, returning the copy.
+    pub fn apply(&self) -> Result<PathBuf, String> {
+        let backup = backup_path(&self.path);
+        fs::copy(&self.path, &backup).map_err(|e| format!("Failed to back up {} to {}: {}", self.path.display(), backup.display(), e))?;
+        // Written aside and renamed into place, as the state file always is
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        fs::write(&partial, &self.upgraded).map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        fs::rename(&partial, &self.path).map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        Ok(backup)
+    }
+}
+
+/// Every file at its usual place that an older mkramdisk wrote. Missing files need
+/// nothing.
+pub fn pending() -> Result<Vec<Migration>, String> {
+    let mut migrations = Vec::new();
+    if let Some(path) = registry::path().filter(|path| path.exists()) {
+        migrations.extend(state_migration(&path)?);
+    }
+    if let Some(path) = user_config::path().filter(|path| path.exists()) {
+        migrations.extend(config_migration(&path)?);
+    }
+    Ok(migrations)
+}
+
+fn state_migration(path: &Path) -> Result<Option<Migration>, String> {
+    let contents = read(path)?;
+    let mut value = serde_json::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    let steps = registry::upgrade(&mut value).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if steps.is_empty() {
+        return Ok(None);
+    }
+    let upgraded = serde_json::to_string_pretty(&value).map_err(|e| e.to_string())? + "\n";
+    Ok(Some(Migration { path: path.to_path_buf(), changes: steps, upgraded }))
+}
+
+fn config_migration(path: &Path) -> Result<Option<Migration>, String> {
+    let contents = read(path)?;
+    let (upgraded, changes) = user_config::upgrade(&contents);
+    Ok((upgraded != contents).then(|| Migration { path: path.to_path_buf(), changes, upgraded }))
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+// state.json.bak, or state.json.bak.1 and so on when earlier upgrades left one
+fn backup_path(path: &Path) -> PathBuf {
+    let mut n = 0;
+    loop {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(if n == 0 { ".bak".to_string() } else { format!(".bak.{}", n) });
+        let backup = PathBuf::from(backup);
+        if !backup.exists() {
+            return backup;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_state_migration() {
+        let dir = env::temp_dir().join(format!("mkramdisk-migrate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        fs::write(&path, "{\"disks\": []}\n").unwrap();
+
+        let migration = state_migration(&path).unwrap().unwrap();
+        assert_eq!(migration.changes, ["schema 0 to 1: number the schema"]);
+        assert_eq!(migration.apply().unwrap(), dir.join("state.json.bak"));
+        assert_eq!(fs::read_to_string(dir.join("state.json.bak")).unwrap(), "{\"disks\": []}\n");
+        assert!(state_migration(&path).unwrap().is_none());
+        assert_eq!(backup_path(&path), dir.join("state.json.bak.1"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_config_migration() {
+        let dir = env::temp_dir().join(format!("mkramdisk-migrate-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "size = \"2G\"\nafter_create = \"\"\"\nfs = 1\n\"\"\"\n").unwrap();
+        assert!(config_migration(&path).unwrap().is_none());
+
+        fs::write(&path, "fs = \"apfs\"\n").unwrap();
+        config_migration(&path).unwrap().unwrap().apply().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "filesystem = \"apfs\"\n");
+        // Once upgraded, later runs leave the file (and its backups) alone
+        assert!(config_migration(&path).unwrap().is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
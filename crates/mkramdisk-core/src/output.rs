@@ -0,0 +1,239 @@
+use serde_json::Value;
+
+use crate::api;
+
+/// How results are printed for scripts (`--output`, or `--json`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Csv,
+    Tsv,
+    /// A line per result from a template such as "{device} {mount_point}" (`--format`).
+    Template(String),
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            other => Err(format!("Unknown output format: {} (expected json, yaml, csv or tsv)", other)),
+        }
+    }
+}
+
+/// One result: its fields in the order they are printed (the columns, for CSV and TSV).
+pub type Record = Vec<(&'static str, Value)>;
+
+/// A single result: an object, a mapping, a header and one row, or a filled-in template.
+/// JSON and YAML results carry the `api_version` they follow. Fails only for a template
+/// naming a field the result does not have.
+pub fn render_one(format: &Format, record: &Record) -> Result<String, String> {
+    Ok(match format {
+        Format::Json => format!("{}\n", object(record)),
+        Format::Yaml => yaml_mapping(&versioned(record), ""),
+        Format::Csv | Format::Tsv => table(format, std::slice::from_ref(record)),
+        Format::Template(template) => fill(template, record)? + "\n",
+    })
+}
+
+/// Several results of the same shape: an array, a sequence, a header and a row each, or
+/// the template filled in once each. CSV and TSV print nothing at all for no results,
+/// having no columns to name.
+pub fn render_many(format: &Format, records: &[Record]) -> Result<String, String> {
+    Ok(match format {
+        Format::Json => format!("{}\n", Value::Array(records.iter().map(object).collect())),
+        Format::Yaml if records.is_empty() => "[]\n".to_string(),
+        Format::Yaml => records
+            .iter()
+            .map(|record| {
+                let mapping = yaml_mapping(&versioned(record), "  ");
+                format!("- {}", &mapping[2..])
+            })
+            .collect(),
+        Format::Csv | Format::Tsv => table(format, records),
+        Format::Template(template) => records.iter().map(|record| Ok(fill(template, record)? + "\n")).collect::<Result<String, String>>()?,
+    })
+}
+
+/// Check that `template` is well formed and names only `fields`, before there is a
+/// result to fill it in with.
+pub fn check_template(template: &str, fields: &[&str]) -> Result<(), String> {
+    for piece in pieces(template)? {
+        if let Piece::Field(name) = piece
+            && !fields.contains(&name)
+        {
+            return Err(unknown_field(name, fields.iter().copied()));
+        }
+    }
+    Ok(())
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Field(&'a str),
+}
+
+// "{name}" is a field and "{{" and "}}" are literal braces
+fn pieces(template: &str) -> Result<Vec<Piece<'_>>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(at) = rest.find(['{', '}']) {
+        pieces.push(Piece::Text(&rest[..at]));
+        let brace = &rest[at..at + 1];
+        if rest[at + 1..].starts_with(brace) {
+            pieces.push(Piece::Text(brace));
+            rest = &rest[at + 2..];
+        } else if brace == "}" {
+            return Err(format!("Unmatched '}}' in template: {} (write '}}}}' for a brace)", template));
+        } else {
+            let end = rest[at..].find('}').ok_or_else(|| format!("Unclosed '{{' in template: {}", template))?;
+            let name = rest[at + 1..at + end].trim();
+            if name.is_empty() || name.contains('{') {
+                return Err(format!("Invalid field in template: {}", template));
+            }
+            pieces.push(Piece::Field(name));
+            rest = &rest[at + end + 1..];
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    Ok(pieces)
+}
+
+fn unknown_field<'a>(name: &str, fields: impl Iterator<Item = &'a str>) -> String {
+    format!("Unknown field in template: {{{}}}\nFields: {}", name, fields.collect::<Vec<_>>().join(", "))
+}
+
+fn fill(template: &str, record: &Record) -> Result<String, String> {
+    let mut text = String::new();
+    for piece in pieces(template)? {
+        match piece {
+            Piece::Text(literal) => text.push_str(literal),
+            Piece::Field(name) => {
+                let (_, value) = record
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .ok_or_else(|| unknown_field(name, record.iter().map(|(key, _)| *key)))?;
+                text.push_str(&plain(value));
+            }
+        }
+    }
+    Ok(text)
+}
+
+fn object(record: &Record) -> Value {
+    api::stamp(Value::Object(record.iter().map(|(key, value)| (key.to_string(), value.clone())).collect()))
+}
+
+// Last, so a mapping still starts with the fields it always has
+fn versioned(record: &Record) -> Record {
+    let mut record = record.clone();
+    record.push(("api_version", Value::from(api::version())));
+    record
+}
+
+// Strings are written as JSON strings, which YAML reads as double-quoted scalars, so
+// nothing ("no", "1e3", "") changes type on the way through a YAML parser
+fn yaml_mapping(record: &Record, indent: &str) -> String {
+    record.iter().map(|(key, value)| format!("{}{}: {}\n", indent, key, value)).collect()
+}
+
+fn table(format: &Format, records: &[Record]) -> String {
+    let Some(first) = records.first() else {
+        return String::new();
+    };
+    let separator = if *format == Format::Csv { "," } else { "\t" };
+    let mut lines = vec![first.iter().map(|(key, _)| key.to_string()).collect::<Vec<_>>().join(separator)];
+    for record in records {
+        let fields: Vec<String> = record.iter().map(|(_, value)| field(format, value)).collect();
+        lines.push(fields.join(separator));
+    }
+    lines.join("\n") + "\n"
+}
+
+// A value as text: strings as they are, nothing for null
+fn plain(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        // A list (a disk's partitions) shares its cell, one item after another
+        Value::Array(items) => items.iter().map(plain).collect::<Vec<_>>().join(";"),
+        other => other.to_string(),
+    }
+}
+
+fn field(format: &Format, value: &Value) -> String {
+    let text = plain(value);
+    match format {
+        Format::Csv if text.contains([',', '"', '\n', '\r']) => format!("\"{}\"", text.replace('"', "\"\"")),
+        Format::Tsv => text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r"),
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn disk(name: &str, mount_point: Value) -> Record {
+        vec![("name", json!(name)), ("device", json!("/dev/disk5")), ("mount_point", mount_point), ("sectors", json!(2048))]
+    }
+
+    #[test]
+    fn test_render() {
+        let scratch = disk("Scratch", json!("/Volumes/Scratch"));
+        let odd = disk("a,\"b\"\tc", Value::Null);
+        let one = |format: Format, record: &Record| render_one(&format, record).unwrap();
+        let many = |format: Format, records: &[Record]| render_many(&format, records).unwrap();
+
+        assert_eq!(
+            one(Format::Yaml, &scratch),
+            "name: \"Scratch\"\ndevice: \"/dev/disk5\"\nmount_point: \"/Volumes/Scratch\"\nsectors: 2048\napi_version: 1\n"
+        );
+        assert_eq!(
+            many(Format::Yaml, &[scratch.clone(), odd.clone()]),
+            concat!(
+                "- name: \"Scratch\"\n  device: \"/dev/disk5\"\n  mount_point: \"/Volumes/Scratch\"\n  sectors: 2048\n  api_version: 1\n",
+                "- name: \"a,\\\"b\\\"\\tc\"\n  device: \"/dev/disk5\"\n  mount_point: null\n  sectors: 2048\n  api_version: 1\n",
+            )
+        );
+        assert_eq!(many(Format::Yaml, &[]), "[]\n");
+
+        assert_eq!(
+            many(Format::Csv, &[scratch.clone(), odd.clone()]),
+            "name,device,mount_point,sectors\nScratch,/dev/disk5,/Volumes/Scratch,2048\n\"a,\"\"b\"\"\tc\",/dev/disk5,,2048\n"
+        );
+        assert_eq!(
+            one(Format::Tsv, &odd),
+            "name\tdevice\tmount_point\tsectors\na,\"b\"\\tc\t/dev/disk5\t\t2048\n"
+        );
+        assert_eq!(many(Format::Csv, &[]), "");
+
+        let json: Value = serde_json::from_str(&many(Format::Json, &[scratch])).unwrap();
+        assert_eq!(json[0]["sectors"], 2048);
+        assert_eq!(json[0]["api_version"], 1);
+        assert!(Format::parse("xml").is_err());
+    }
+
+    #[test]
+    fn test_template() {
+        let scratch = disk("Scratch", json!("/Volumes/Scratch"));
+        let unmounted = disk("Build", Value::Null);
+        let template = |template: &str| Format::Template(template.to_string());
+
+        assert_eq!(render_one(&template("{device} {mount_point}"), &scratch).unwrap(), "/dev/disk5 /Volumes/Scratch\n");
+        assert_eq!(render_one(&template("{{{ name }}}: {sectors}"), &scratch).unwrap(), "{Scratch}: 2048\n");
+        assert_eq!(render_many(&template("{name}={mount_point}"), &[scratch.clone(), unmounted]).unwrap(), "Scratch=/Volumes/Scratch\nBuild=\n");
+        assert!(render_one(&template("{uuid}"), &scratch).unwrap_err().contains("Fields: name, device, mount_point, sectors"));
+
+        assert!(check_template("{device}", &["device"]).is_ok());
+        assert!(check_template("{size}", &["device"]).is_err());
+        for invalid in ["{device", "device}", "{}", "{a{b}"] {
+            assert!(check_template(invalid, &["device"]).is_err(), "{}", invalid);
+        }
+    }
+}
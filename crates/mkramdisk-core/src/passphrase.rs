@@ -0,0 +1,158 @@
+//! Passphrases for encrypted volumes (`--encrypt`). They are read once, just before
+//! the disk is created, from a prompt, stdin or an environment variable, or the login
+//! Keychain with `--keychain`, and handed to diskutil on its stdin so they never
+//! appear on a command line.
+
+use std::env;
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+use crate::keychain;
+
+/// The environment variable `--passphrase-from env` reads.
+pub const ENV_VAR: &str = "MKRAMDISK_PASSPHRASE";
+
+/// Where the passphrase comes from (`--passphrase-from`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Asked for twice at the terminal, without echoing it.
+    Prompt,
+    /// The first line of stdin.
+    Stdin,
+    /// The named environment variable.
+    Env(String),
+    /// The login Keychain's `item`; failing that, `otherwise`, which is then kept as
+    /// `item` for next time once the disk it encrypts exists (`--keychain`).
+    Keychain { item: String, otherwise: Box<Source> },
+}
+
+impl Source {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "prompt" => Ok(Self::Prompt),
+            "stdin" => Ok(Self::Stdin),
+            "env" => Ok(Self::Env(ENV_VAR.to_string())),
+            _ => match value.strip_prefix("env:") {
+                Some(var) if !var.is_empty() => Ok(Self::Env(var.to_string())),
+                _ => Err(format!("Unknown passphrase source: {} (expected prompt, stdin, env or env:VAR)", value)),
+            },
+        }
+    }
+}
+
+/// The Keychain item `--keychain` keeps the passphrase of the disk `name` as.
+pub fn keychain_item(name: &str) -> String {
+    format!("volume:{}", name)
+}
+
+/// A passphrase, kept out of `Debug` output so it cannot end up in a log.
+#[derive(Clone, PartialEq)]
+pub struct Passphrase(String);
+
+impl Passphrase {
+    pub fn new(passphrase: &str) -> Result<Self, String> {
+        if passphrase.is_empty() {
+            return Err("The passphrase is empty".to_string());
+        }
+        Ok(Self(passphrase.to_string()))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// Read the passphrase from `source`, with the Keychain item it is still to be kept
+/// as if `--keychain` didn't have it: see `keep`.
+pub fn read(source: &Source) -> Result<(Passphrase, Option<String>), String> {
+    match source {
+        Source::Keychain { item, otherwise } => match keychain::lookup(item)? {
+            Some(secret) => Ok((Passphrase::new(&secret).map_err(|e| format!("{} (Keychain item '{}')", e, item))?, None)),
+            None => Ok((read(otherwise)?.0, Some(item.clone()))),
+        },
+        _ => read_from(source).map(|passphrase| (passphrase, None)),
+    }
+}
+
+/// Store `passphrase` as the Keychain `item`, once the disk it encrypts was created:
+/// a disk that failed is no reason to keep a passphrase nothing uses.
+pub fn keep(item: &str, passphrase: &Passphrase) -> Result<(), String> {
+    keychain::store(item, passphrase.expose())
+}
+
+fn read_from(source: &Source) -> Result<Passphrase, String> {
+    match source {
+        Source::Prompt => prompt(),
+        Source::Stdin => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line).map_err(|e| format!("Failed to read the passphrase from stdin: {}", e))?;
+            Passphrase::new(line.trim_end_matches(['\r', '\n']))
+        }
+        Source::Env(var) => match env::var(var) {
+            Ok(value) => Passphrase::new(&value).map_err(|e| format!("{} (from {})", e, var)),
+            Err(_) => Err(format!("{} is not set; set it to the passphrase or use --passphrase-from prompt", var)),
+        },
+        Source::Keychain { .. } => read(source).map(|(passphrase, _)| passphrase),
+    }
+}
+
+fn prompt() -> Result<Passphrase, String> {
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err("No terminal to ask for the passphrase at; use --passphrase-from stdin or env".to_string());
+    }
+    let first = read_hidden("Passphrase for the encrypted volume: ")?;
+    let second = read_hidden("Repeat the passphrase: ")?;
+    if first != second {
+        return Err("The passphrases did not match".to_string());
+    }
+    Passphrase::new(&first)
+}
+
+// stty turns echo off on the terminal stdin is attached to, and back on afterwards
+// even if reading fails
+fn read_hidden(label: &str) -> Result<String, String> {
+    eprint!("{}", label);
+    let _ = io::stderr().flush();
+    let echo_off = stty("-echo");
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    if echo_off {
+        stty("echo");
+        eprintln!();
+    }
+    read.map_err(|e| format!("Failed to read the passphrase: {}", e))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn stty(setting: &str) -> bool {
+    Command::new("stty").arg(setting).stdin(Stdio::inherit()).status().is_ok_and(|status| status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(Source::parse("prompt").unwrap(), Source::Prompt);
+        assert_eq!(Source::parse("stdin").unwrap(), Source::Stdin);
+        assert_eq!(Source::parse("env").unwrap(), Source::Env(ENV_VAR.to_string()));
+        assert_eq!(Source::parse("env:VAULT_PASS").unwrap(), Source::Env("VAULT_PASS".to_string()));
+        assert!(Source::parse("env:").is_err());
+        assert!(Source::parse("file").is_err());
+    }
+
+    #[test]
+    fn test_passphrase_is_not_debug_printed() {
+        let passphrase = Passphrase::new("hunter2").unwrap();
+        assert!(!format!("{:?}", passphrase).contains("hunter2"));
+        assert!(Passphrase::new("").is_err());
+    }
+}
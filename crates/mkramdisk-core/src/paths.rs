@@ -0,0 +1,111 @@
+//! Where mkramdisk keeps its files: the state file, logs, backups and config.toml. They
+//! follow the platform's conventions unless `set` (`--state-dir`) puts them elsewhere,
+//! as an app sandboxed to its group container has to.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+static PATHS: Mutex<Option<StatePaths>> = Mutex::new(None);
+
+/// The directories and files mkramdisk reads and writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatePaths {
+    /// Holds `state.json` and what mkramdisk remembers between runs.
+    pub state_dir: PathBuf,
+    pub log_dir: PathBuf,
+    /// The backup store, when neither `--store` nor `backup_dir` in config.toml names one.
+    pub backup_dir: PathBuf,
+    pub config_file: PathBuf,
+}
+
+impl StatePaths {
+    /// Everything in `dir`, config.toml included, as for an app's group container.
+    pub fn in_dir(dir: &Path) -> Self {
+        Self {
+            state_dir: dir.to_path_buf(),
+            log_dir: dir.join("logs"),
+            backup_dir: dir.join("backups"),
+            config_file: dir.join("config.toml"),
+        }
+    }
+
+    /// Where a sandboxed app keeps them in the app group container the host app was
+    /// given (`containerURLForSecurityApplicationGroupIdentifier:`), so the app and its
+    /// companions share one state file: the container's
+    /// `Library/Application Support/mkramdisk`, with logs in `Library/Logs/mkramdisk`.
+    pub fn app_group(container: &Path) -> Self {
+        let library = container.join("Library");
+        Self {
+            log_dir: library.join("Logs").join("mkramdisk"),
+            ..Self::in_dir(&library.join("Application Support").join("mkramdisk"))
+        }
+    }
+
+    pub fn state_file(&self) -> PathBuf {
+        self.state_dir.join("state.json")
+    }
+}
+
+/// Use `paths` from now on, in place of the platform's. `$MKRAMDISK_STATE` and
+/// `$MKRAMDISK_CONFIG` still take precedence for the files they name.
+pub fn set(paths: StatePaths) {
+    *PATHS.lock().unwrap_or_else(|e| e.into_inner()) = Some(paths);
+}
+
+/// The paths in force: those `set`, else the platform's. None without a home directory.
+pub fn get() -> Option<StatePaths> {
+    match PATHS.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+        Some(paths) => Some(paths),
+        None => platform(),
+    }
+}
+
+/// On macOS, `~/Library/Application Support/mkramdisk` for state and backups and
+/// `~/Library/Logs/mkramdisk` for logs; elsewhere `$XDG_STATE_HOME/mkramdisk` (logs in
+/// its `logs`) and `$XDG_DATA_HOME/mkramdisk/backups`, with `~/.local/state` and
+/// `~/.local/share` standing in for them. config.toml is
+/// `$XDG_CONFIG_HOME/mkramdisk/config.toml`, or under `~/.config`, everywhere, and
+/// backups stay in `$XDG_DATA_HOME` on macOS too when it is set or they are already there.
+pub fn platform() -> Option<StatePaths> {
+    let home = env::var_os("HOME").map(PathBuf::from).filter(|home| home.is_absolute());
+    let base = |var: &str, fallback: &str| xdg(var).or_else(|| Some(home.as_ref()?.join(fallback)));
+    let config_file = base("XDG_CONFIG_HOME", ".config")?.join("mkramdisk").join("config.toml");
+    let backups = base("XDG_DATA_HOME", ".local/share").map(|dir| dir.join("mkramdisk").join("backups"));
+    if cfg!(target_os = "macos") {
+        let library = home?.join("Library");
+        let support = library.join("Application Support").join("mkramdisk");
+        return Some(StatePaths {
+            log_dir: library.join("Logs").join("mkramdisk"),
+            // Where an older mkramdisk kept them
+            backup_dir: backups.filter(|dir| xdg("XDG_DATA_HOME").is_some() || dir.is_dir()).unwrap_or_else(|| support.join("backups")),
+            state_dir: support,
+            config_file,
+        });
+    }
+    let state_dir = base("XDG_STATE_HOME", ".local/state")?.join("mkramdisk");
+    Some(StatePaths { log_dir: state_dir.join("logs"), backup_dir: backups?, state_dir, config_file })
+}
+
+// An XDG base directory; the spec has relative ones ignored
+fn xdg(var: &str) -> Option<PathBuf> {
+    env::var_os(var).map(PathBuf::from).filter(|dir| dir.is_absolute())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_dir() {
+        let paths = StatePaths::in_dir(Path::new("/var/mkramdisk"));
+        assert_eq!(paths.state_file(), Path::new("/var/mkramdisk/state.json"));
+        assert_eq!(paths.log_dir, Path::new("/var/mkramdisk/logs"));
+        assert_eq!(paths.backup_dir, Path::new("/var/mkramdisk/backups"));
+        assert_eq!(paths.config_file, Path::new("/var/mkramdisk/config.toml"));
+
+        let paths = StatePaths::app_group(Path::new("/Group Containers/TEAM.mkramdisk"));
+        assert_eq!(paths.state_file(), Path::new("/Group Containers/TEAM.mkramdisk/Library/Application Support/mkramdisk/state.json"));
+        assert_eq!(paths.log_dir, Path::new("/Group Containers/TEAM.mkramdisk/Library/Logs/mkramdisk"));
+    }
+}
@@ -0,0 +1,583 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::partitions::{Partition, Scheme};
+use crate::passphrase::{self, Passphrase};
+use crate::provider::DeviceProvider;
+use crate::{fat_label, formats, get_diskutil_format, log_verbose, progress, trace, Config};
+
+/// Stages of creating a RAM disk, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Attach,
+    Format,
+    Mount,
+    Verify,
+}
+
+/// A blank device has been attached.
+pub struct Attached {
+    device: String,
+}
+
+/// The device carries a filesystem, which may still be mounting.
+pub struct Formatted {
+    device: String,
+    mount_point: PathBuf,
+    filesystem: String,
+}
+
+/// The volume is visible at its mount point.
+pub struct Mounted {
+    device: String,
+    mount_point: PathBuf,
+    filesystem: String,
+}
+
+/// A verified, ready-to-use RAM disk.
+#[derive(Debug)]
+pub struct Created {
+    pub device: String,
+    /// None for a raw device, which has no filesystem to mount.
+    pub mount_point: Option<PathBuf>,
+    /// The filesystem the disk ended up with, which is the fallback if the requested one failed.
+    pub filesystem: String,
+    /// Mount points of every partition, first one included, when `--partitions` was used.
+    pub partitions: Vec<PathBuf>,
+    /// The second, read-only node the device is exposed at, with `--readonly-export`.
+    pub readonly_device: Option<String>,
+}
+
+/// Runs the stages in order, remembering what completed so a failure part way
+/// through can be rolled back.
+pub struct Pipeline<'a> {
+    config: &'a Config,
+    provider: &'a dyn DeviceProvider,
+    completed: Vec<Stage>,
+    device: Option<String>,
+    /// Format encrypted with this passphrase, read before anything was attached.
+    passphrase: Option<Passphrase>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(config: &'a Config, provider: &'a dyn DeviceProvider) -> Self {
+        Self { config, provider, completed: Vec::new(), device: None, passphrase: None }
+    }
+
+    pub fn attach(&mut self, sectors: u64) -> Result<Attached, String> {
+        log_verbose(self.config, &format!(
+            "Creating RAM disk with {} sectors using the {} backend...",
+            sectors, self.config.backend
+        ));
+        progress::emit(serde_json::json!({ "event": "attach_started", "sectors": sectors, "backend": self.config.backend }));
+        let device = self.provider.attach(sectors)?;
+        log_verbose(self.config, &format!("RAM disk device: {}", device));
+        progress::emit(serde_json::json!({ "event": "device_acquired", "device": device }));
+
+        self.device = Some(device.clone());
+        self.completed.push(Stage::Attach);
+        Ok(Attached { device })
+    }
+
+    pub fn format(&mut self, attached: Attached, diskutil_format: &str) -> Result<Formatted, String> {
+        log_verbose(self.config, &format!(
+            "Formatting RAM disk as {} with name '{}'...",
+            self.config.filesystem, self.config.name
+        ));
+        let mut filesystem = self.config.filesystem.clone();
+        let mut name = self.config.name.clone();
+        progress::emit(serde_json::json!({ "event": "format_started", "device": attached.device, "filesystem": filesystem }));
+        if let Some(passphrase) = &self.passphrase {
+            self.provider.format_encrypted(&attached.device, diskutil_format, &self.config.name, passphrase.expose(), self.config.verbose)?;
+        } else if let Err(e) = self.provider.format(&attached.device, diskutil_format, &self.config.name, self.config.verbose) {
+            let Some(fallback) = &self.config.fallback_format else {
+                return Err(e);
+            };
+            eprintln!("Note: formatting as {} failed ({}); retrying as {}", filesystem, e, fallback);
+            let fallback_format = get_diskutil_format(fallback)?;
+            if fallback_format.starts_with("MS-DOS") {
+                name = fat_label(&name);
+            }
+            self.provider
+                .format(&attached.device, &fallback_format, &name, self.config.verbose)
+                .map_err(|fallback_error| format!("{}\nFallback to {} also failed: {}", e, fallback, fallback_error))?;
+            filesystem = fallback.clone();
+        }
+
+        self.completed.push(Stage::Format);
+        Ok(Formatted {
+            device: attached.device,
+            mount_point: self.provider.mount_point(&name),
+            filesystem,
+        })
+    }
+
+    /// Write a partition map and format its partitions. The partition named after the
+    /// disk stands in for its volume in the later stages.
+    pub fn partition(&mut self, attached: Attached, scheme: Scheme, partitions: &[Partition]) -> Result<Formatted, String> {
+        log_verbose(self.config, &format!(
+            "Partitioning RAM disk ({}) into {}...",
+            scheme,
+            partitions.iter().map(|p| format!("{} ({})", p.name, p.personality)).collect::<Vec<_>>().join(", ")
+        ));
+        progress::emit(serde_json::json!({ "event": "format_started", "device": attached.device, "filesystem": scheme.to_string() }));
+        self.provider.partition(&attached.device, scheme, partitions, self.config.verbose)?;
+
+        self.completed.push(Stage::Format);
+        Ok(Formatted {
+            mount_point: self.provider.mount_point(&self.config.name),
+            device: attached.device,
+            // A single partition wrapping the whole disk is still described by its filesystem
+            filesystem: match partitions {
+                [_] if self.config.partitions.is_empty() => self.config.filesystem.clone(),
+                _ => scheme.to_string(),
+            },
+        })
+    }
+
+    /// Copy a disk image or volume onto the device in place of formatting it. Its volume
+    /// comes up under the source's name, so mounting finds it by device and renames it.
+    pub fn restore(&mut self, attached: Attached, source: &Path) -> Result<Formatted, String> {
+        log_verbose(self.config, &format!("Restoring {} onto {}...", source.display(), attached.device));
+        progress::emit(serde_json::json!({ "event": "format_started", "device": attached.device, "filesystem": "image", "source": source }));
+        self.provider.restore(&attached.device, source, self.config.verbose)?;
+
+        self.completed.push(Stage::Format);
+        Ok(Formatted {
+            mount_point: self.provider.mount_point(&self.config.name),
+            device: attached.device,
+            filesystem: self.config.filesystem.clone(),
+        })
+    }
+
+    pub fn mount(&mut self, formatted: Formatted) -> Result<Mounted, String> {
+        // Formatting also mounts, so normally we just need to wait for the volume to appear
+        log_verbose(self.config, "Waiting for RAM disk to mount...");
+        let mount_point = if self.config.source_image.is_some() {
+            // A restored volume comes up under the source's name, so look for it by device
+            poll(self.config.mount_timeout, || self.located(&formatted.device))
+        } else if wait_for_mount(&formatted.mount_point, self.config.mount_timeout) {
+            Some(formatted.mount_point.clone())
+        } else {
+            self.located(&formatted.device)
+                .or_else(|| self.mount_with_fallbacks(&formatted))
+        };
+
+        let Some(mut mount_point) = mount_point else {
+            return Err("RAM disk was formatted but failed to mount properly".to_string());
+        };
+        if mount_point != formatted.mount_point && self.config.source_image.is_some() {
+            log_verbose(self.config, &format!("Renaming {} to '{}'...", mount_point.display(), self.config.name));
+            mount_point = self.provider.rename(&formatted.device, &mount_point, &self.config.name)?;
+        } else if mount_point != formatted.mount_point {
+            eprintln!("Note: volume mounted at {} instead of {}", mount_point.display(), formatted.mount_point.display());
+        }
+        if !self.config.mount_options.is_empty() {
+            log_verbose(self.config, &format!("Applying mount options {}...", self.config.mount_options.join(",")));
+            self.provider.set_mount_options(&mount_point, &self.config.mount_options)?;
+        }
+
+        progress::emit(serde_json::json!({ "event": "mounted", "device": formatted.device, "mount_point": mount_point }));
+        self.completed.push(Stage::Mount);
+        Ok(Mounted { device: formatted.device, mount_point, filesystem: formatted.filesystem })
+    }
+
+    fn mount_with_fallbacks(&self, formatted: &Formatted) -> Option<PathBuf> {
+        for &strategy in self.provider.mount_strategies() {
+            log_verbose(self.config, &format!("Volume not mounted, trying {}...", strategy));
+            if let Err(e) = self.provider.remount(&formatted.device, strategy) {
+                log_verbose(self.config, &e);
+                continue;
+            }
+            let found = if wait_for_mount(&formatted.mount_point, self.config.mount_timeout) {
+                Some(formatted.mount_point.clone())
+            } else {
+                self.located(&formatted.device)
+            };
+            if found.is_some() {
+                eprintln!("Note: volume was mounted using {}", strategy);
+                return found;
+            }
+        }
+        None
+    }
+
+    fn located(&self, device: &str) -> Option<PathBuf> {
+        self.provider
+            .locate_mount_point(device, &self.config.name)
+            .filter(|path| path.is_dir())
+    }
+
+    pub fn verify(&mut self, mounted: Mounted) -> Result<Created, String> {
+        if !mounted.mount_point.is_dir() {
+            return Err("RAM disk creation completed but verification failed".to_string());
+        }
+        let mut partitions = Vec::new();
+        for partition in &self.config.partitions {
+            let mount_point = if partition.name == self.config.name {
+                mounted.mount_point.clone()
+            } else {
+                self.provider.mount_point(&partition.name)
+            };
+            if !wait_for_mount(&mount_point, self.config.mount_timeout) {
+                return Err(format!("Partition '{}' did not mount at {}", partition.name, mount_point.display()));
+            }
+            partitions.push(mount_point);
+        }
+
+        self.completed.push(Stage::Verify);
+        Ok(Created {
+            device: mounted.device,
+            mount_point: Some(mounted.mount_point),
+            filesystem: mounted.filesystem,
+            partitions,
+            readonly_device: None,
+        })
+    }
+
+    /// Expose the device read-only at a second node too, for tools that inspect its blocks
+    /// while the volume stays mounted read-write.
+    pub fn export(&mut self, mut created: Created) -> Result<Created, String> {
+        log_verbose(self.config, &format!("Exposing {} read-only...", created.device));
+        let node = self.provider.export_readonly(&created.device)?;
+        log_verbose(self.config, &format!("Read-only node: {}", node));
+        created.readonly_device = Some(node);
+        Ok(created)
+    }
+
+    /// Mount the EFI system partition of a `--bootable` disk and lay out EFI/BOOT on it,
+    /// ready for a boot loader.
+    pub fn prepare_esp(&mut self, created: Created) -> Result<Created, String> {
+        let esp = self.provider.mount_esp(&created.device)?;
+        let boot = esp.join("EFI").join("BOOT");
+        std::fs::create_dir_all(&boot).map_err(|e| format!("Failed to create {}: {}", boot.display(), e))?;
+        log_verbose(self.config, &format!("Created {}", boot.display()));
+        Ok(created)
+    }
+
+    /// Finish with the bare attached device, skipping format, mount and verify.
+    pub fn raw(&mut self, attached: Attached) -> Created {
+        log_verbose(self.config, &format!("Leaving {} without a filesystem", attached.device));
+        Created {
+            device: attached.device,
+            mount_point: None,
+            filesystem: self.config.filesystem.clone(),
+            partitions: Vec::new(),
+            readonly_device: None,
+        }
+    }
+
+    /// Undo completed stages in reverse order.
+    pub fn rollback(&mut self) {
+        while let Some(stage) = self.completed.pop() {
+            match stage {
+                Stage::Attach => {
+                    if let Some(device) = self.device.take() {
+                        log_verbose(self.config, &format!("Cleaning up device {}...", device));
+                        let _ = self.provider.detach(&device);
+                    }
+                }
+                // Detaching the device unmounts it and discards the filesystem
+                Stage::Format | Stage::Mount | Stage::Verify => {}
+            }
+        }
+    }
+
+    /// Decide what to do with a half-created disk after `error`: roll back, unless
+    /// asked to keep it or it turns out to be mounted and usable after all, at a mount
+    /// point whose volume is on its device rather than some other disk's.
+    pub fn fail(&mut self, error: String) -> String {
+        let Some(device) = self.device.clone() else {
+            return error;
+        };
+        if let Some(details) = self.provider.describe(&device) {
+            log_verbose(self.config, &format!("State of {} after failure:\n{}", device, details.trim_end()));
+        }
+
+        if self.config.keep_on_failure {
+            return format!("{}\nLeaving {} attached for debugging (--keep-on-failure)", error, device);
+        }
+        // Past verify the disk is fine, and it was a step asked for on top that failed
+        if self.completed.contains(&Stage::Format)
+            && !self.completed.contains(&Stage::Verify)
+            && let Some(mount_point) = self.located(&device)
+            && self.provider.device_at(&mount_point).as_deref() == Some(device.as_str())
+        {
+            return format!(
+                "{}\n{} still looks usable (mounted at {}), so it was left attached",
+                error, device, mount_point.display()
+            );
+        }
+
+        log_verbose(self.config, &format!("Rolling back completed stages: {:?}", self.completed()));
+        self.rollback();
+        error
+    }
+
+    pub fn completed(&self) -> &[Stage] {
+        &self.completed
+    }
+}
+
+fn wait_for_mount(mount_point: &Path, timeout: Duration) -> bool {
+    poll(timeout, || mount_point.exists().then_some(())).is_some()
+}
+
+// Poll with exponential backoff (50ms doubling up to 1s) until `check` finds something
+// or the timeout runs out. A timeout too long to count down to never runs out.
+fn poll<T>(timeout: Duration, check: impl Fn() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now().checked_add(timeout);
+    let mut delay = Duration::from_millis(50);
+    loop {
+        if let Some(found) = check() {
+            return Some(found);
+        }
+        let now = Instant::now();
+        let remaining = match deadline {
+            Some(deadline) if now >= deadline => return None,
+            Some(deadline) => deadline - now,
+            None => delay,
+        };
+        thread::sleep(delay.min(remaining));
+        delay = (delay * 2).min(Duration::from_secs(1));
+    }
+}
+
+/// Run every stage, rolling back whatever completed if one fails.
+pub fn create(config: &Config, provider: &dyn DeviceProvider, sectors: u64, diskutil_format: &str) -> Result<Created, String> {
+    let mut pipeline = Pipeline::new(config, provider);
+    let (passphrase, keychain_item) = config.encrypt.as_ref().map(passphrase::read).transpose()?.unzip();
+    pipeline.passphrase = passphrase;
+    let result = trace::in_span("attach", || pipeline.attach(sectors)).and_then(|attached| {
+        if diskutil_format == formats::RAW {
+            return Ok(pipeline.raw(attached));
+        }
+        let scheme = config.scheme.unwrap_or(Scheme::Gpt);
+        let formatted = if let Some(image) = &config.source_image {
+            trace::in_span("restore", || pipeline.restore(attached, image))
+        } else if !config.partitions.is_empty() {
+            trace::in_span("partition", || pipeline.partition(attached, scheme, &config.partitions))
+        } else if scheme != Scheme::Bare && config.scheme.is_some() {
+            let whole = Partition {
+                name: config.name.clone(),
+                personality: diskutil_format.to_string(),
+                bytes: None,
+            };
+            trace::in_span("partition", || pipeline.partition(attached, scheme, &[whole]))
+        } else {
+            trace::in_span("format", || pipeline.format(attached, diskutil_format))
+        };
+        formatted
+            .and_then(|formatted| trace::in_span("mount", || pipeline.mount(formatted)))
+            .and_then(|mounted| trace::in_span("verify", || pipeline.verify(mounted)))
+    });
+    let result = match result {
+        Ok(created) if config.bootable => trace::in_span("esp", || pipeline.prepare_esp(created)),
+        result => result,
+    };
+    let result = match result {
+        Ok(created) if config.readonly_export => trace::in_span("export", || pipeline.export(created)),
+        result => result,
+    };
+    // Kept only now the disk it encrypts exists, and the disk goes if it can't be
+    let result = match (result, keychain_item.flatten(), &pipeline.passphrase) {
+        (Ok(created), Some(item), Some(passphrase)) => passphrase::keep(&item, passphrase).map(|()| created),
+        (result, _, _) => result,
+    };
+
+    result.map_err(|e| pipeline.fail(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::MockProvider;
+    use std::env;
+    use std::fs;
+
+    fn mock_root(test: &str) -> PathBuf {
+        env::temp_dir().join(format!("mkramdisk-pipeline-{}-{}", test, std::process::id()))
+    }
+
+    fn config() -> Config {
+        Config { size: "1M".to_string(), name: "Test".to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn test_poll_with_a_huge_timeout() {
+        let tries = std::cell::Cell::new(0);
+        let found = poll(Duration::MAX, || {
+            tries.set(tries.get() + 1);
+            (tries.get() == 2).then_some("mounted")
+        });
+        assert_eq!(found, Some("mounted"));
+        assert_eq!(poll(Duration::ZERO, || None::<()>), None);
+    }
+
+    #[test]
+    fn test_stages_run_in_order() {
+        let root = mock_root("order");
+        let provider = MockProvider::new(root.clone(), None);
+        let config = config();
+        let mut pipeline = Pipeline::new(&config, &provider);
+
+        let attached = pipeline.attach(2048).unwrap();
+        let formatted = pipeline.format(attached, "APFS").unwrap();
+        let mounted = pipeline.mount(formatted).unwrap();
+        let created = pipeline.verify(mounted).unwrap();
+
+        assert_eq!(pipeline.completed(), [Stage::Attach, Stage::Format, Stage::Mount, Stage::Verify]);
+        assert_eq!(created.mount_point, Some(root.join("Volumes/Test")));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_detaches_device() {
+        let root = mock_root("rollback");
+        let provider = MockProvider::new(root.clone(), Some("format".to_string()));
+        let config = config();
+
+        assert!(create(&config, &provider, 2048, "APFS").is_err());
+        assert_eq!(fs::read_dir(root.join("dev")).unwrap().count(), 0);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_fallback_format() {
+        let root = mock_root("fallback");
+        let provider = MockProvider::new(root.clone(), Some("format:ExFAT".to_string()));
+        let config = Config { filesystem: "exfat".to_string(), fallback_format: Some("hfs+".to_string()), ..config() };
+
+        let created = create(&config, &provider, 2048, "ExFAT").unwrap();
+        assert_eq!(created.filesystem, "hfs+");
+        assert!(fs::read_to_string(&created.device).unwrap().contains("HFS+"));
+
+        // A FAT fallback labels the volume as FAT does
+        let config = Config { name: "Scratch Space".to_string(), fallback_format: Some("fat32".to_string()), ..config };
+        let created = create(&config, &provider, 2048, "ExFAT").unwrap();
+        assert_eq!(created.mount_point, Some(root.join("Volumes/SCRATCH SPA")));
+
+        let config = Config { fallback_format: None, ..config };
+        assert!(create(&Config { name: "Other".to_string(), ..config }, &provider, 2048, "ExFAT").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_raw_device() {
+        let root = mock_root("raw");
+        let provider = MockProvider::new(root.clone(), None);
+
+        let created = create(&config(), &provider, 2048, formats::RAW).unwrap();
+        assert_eq!(created.mount_point, None);
+        assert_eq!(fs::read_to_string(&created.device).unwrap(), "2048\n");
+        assert!(!root.join("Volumes").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_partitions() {
+        let root = mock_root("partitions");
+        let provider = MockProvider::new(root.clone(), None);
+        let config = Config {
+            name: "p1".to_string(),
+            partitions: crate::partitions::parse("p1:apfs:1G,p2:fat32:512M").unwrap(),
+            ..config()
+        };
+
+        let created = create(&config, &provider, 2048, "GPT").unwrap();
+        assert_eq!(created.filesystem, "GPT");
+        assert_eq!(created.partitions, [root.join("Volumes/p1"), root.join("Volumes/p2")]);
+
+        provider.detach(&created.device).unwrap();
+        assert!(!root.join("Volumes/p2").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_scheme_wraps_single_volume() {
+        let root = mock_root("scheme");
+        let provider = MockProvider::new(root.clone(), None);
+        let config = Config { scheme: Some(Scheme::Mbr), ..config() };
+
+        let created = create(&config, &provider, 2048, "APFS").unwrap();
+        assert_eq!(created.filesystem, "apfs");
+        assert_eq!(fs::read_to_string(&created.device).unwrap(), "2048\nMBR\nTest\n");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_restore_image() {
+        let root = mock_root("restore");
+        let provider = MockProvider::new(root.clone(), None);
+        let image = root.join("Fixture.dmg");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&image, "").unwrap();
+        let config = Config { filesystem: "image".to_string(), source_image: Some(image), ..config() };
+
+        // The volume comes up as "Fixture" and is renamed to the disk's name
+        let created = create(&config, &provider, 2048, "image").unwrap();
+        assert_eq!(created.mount_point, Some(root.join("Volumes/Test")));
+        assert_eq!(fs::read_to_string(&created.device).unwrap(), "2048\nimage\nTest\n");
+        assert!(!root.join("Volumes/Fixture").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_keep_on_failure_leaves_device() {
+        let root = mock_root("keep");
+        let provider = MockProvider::new(root.clone(), Some("format".to_string()));
+        let config = Config { keep_on_failure: true, ..config() };
+
+        let error = create(&config, &provider, 2048, "APFS").unwrap_err();
+        assert!(error.contains("--keep-on-failure"));
+        assert_eq!(fs::read_dir(root.join("dev")).unwrap().count(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_usable_device_is_not_rolled_back() {
+        let root = mock_root("usable");
+        let provider = MockProvider::new(root.clone(), None);
+        let config = config();
+        let mut pipeline = Pipeline::new(&config, &provider);
+
+        let attached = pipeline.attach(2048).unwrap();
+        pipeline.format(attached, "APFS").unwrap();
+        let error = pipeline.fail("verification failed".to_string());
+
+        assert!(error.contains("still looks usable"));
+        assert!(root.join("Volumes/Test").is_dir());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_another_disks_volume_is_no_reason_to_keep() {
+        let root = mock_root("another");
+        // disk0 had the volume name first, so the mount point is its volume
+        fs::create_dir_all(root.join("dev")).unwrap();
+        fs::write(root.join("dev/disk0"), "2048\nAPFS\nTest\n").unwrap();
+        let provider = MockProvider::new(root.clone(), None);
+        let config = config();
+        let mut pipeline = Pipeline::new(&config, &provider);
+
+        let attached = pipeline.attach(2048).unwrap();
+        assert!(attached.device.ends_with("disk1"));
+        pipeline.format(attached, "APFS").unwrap();
+        let error = pipeline.fail("verification failed".to_string());
+
+        assert_eq!(error, "verification failed");
+        assert!(!root.join("dev/disk1").exists());
+        assert!(root.join("dev/disk0").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_mount_times_out() {
+        let missing = mock_root("missing");
+        let start = Instant::now();
+        assert!(!wait_for_mount(&missing, Duration::from_millis(200)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(wait_for_mount(&env::temp_dir(), Duration::ZERO));
+    }
+}
@@ -0,0 +1,170 @@
+//! User presence for ejecting protected disks. A disk created with `--protected` gets a
+//! marker file at its root, and tearing it down then needs Touch ID or the account
+//! password (LocalAuthentication) on macOS, or its name typed at a terminal elsewhere.
+//! Deleting the marker lifts the protection.
+
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+/// The marker at the root of a protected disk.
+pub use crate::copier::PROTECTED_FILE;
+
+/// Mark the disk mounted at `mount_point` as protected.
+pub fn protect(mount_point: &Path) -> Result<(), String> {
+    let path = mount_point.join(PROTECTED_FILE);
+    let note = "Ejecting this disk with mkramdisk needs Touch ID or a password. Delete this file to allow it.\n";
+    fs::write(&path, note).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn is_protected(mount_point: &Path) -> bool {
+    mount_point.join(PROTECTED_FILE).is_file()
+}
+
+/// Ask the user to confirm tearing down the protected disk `name`, failing unless they do.
+pub fn require(name: &str) -> Result<(), String> {
+    let reason = format!("eject the protected RAM disk '{}'", name);
+    #[cfg(target_os = "macos")]
+    if let Some(result) = local_authentication::evaluate(&reason) {
+        return result.map_err(|e| format!("Not allowed to {}: {}", reason, e));
+    }
+    confirm_at_terminal(name, &reason)
+}
+
+// Without LocalAuthentication, typing the disk's name shows someone is there
+fn confirm_at_terminal(name: &str, reason: &str) -> Result<(), String> {
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err(format!(
+            "Not allowed to {} without confirming at a terminal; delete its {} to lift the protection",
+            reason, PROTECTED_FILE
+        ));
+    }
+    eprint!("'{}' is protected. Type its name to eject it: ", name);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).map_err(|e| format!("Failed to read the confirmation: {}", e))?;
+    match answer.trim_end_matches(['\r', '\n']) == name {
+        true => Ok(()),
+        false => Err(format!("Not allowed to {}: the name did not match", reason)),
+    }
+}
+
+// LAContext's evaluatePolicy:localizedReason:reply:, called through the Objective-C
+// runtime with a hand-built block for the reply
+#[cfg(target_os = "macos")]
+mod local_authentication {
+    use std::ffi::{CString, c_void};
+    use std::sync::mpsc::{self, Sender};
+
+    type Id = *mut c_void;
+    type Sel = *const c_void;
+
+    // LAPolicyDeviceOwnerAuthentication: biometrics, a watch or the account password
+    const DEVICE_OWNER_AUTHENTICATION: isize = 2;
+
+    #[link(name = "objc")]
+    unsafe extern "C" {
+        fn objc_getClass(name: *const u8) -> Id;
+        fn sel_registerName(name: *const u8) -> Sel;
+        fn objc_msgSend();
+    }
+
+    #[link(name = "System")]
+    unsafe extern "C" {
+        static _NSConcreteStackBlock: *const c_void;
+    }
+
+    #[link(name = "LocalAuthentication", kind = "framework")]
+    #[link(name = "Foundation", kind = "framework")]
+    unsafe extern "C" {}
+
+    #[repr(C)]
+    struct BlockDescriptor {
+        reserved: usize,
+        size: usize,
+    }
+
+    // A block's memory layout, with what it captures after the standard fields
+    #[repr(C)]
+    struct Block {
+        isa: *const c_void,
+        flags: i32,
+        reserved: i32,
+        invoke: unsafe extern "C" fn(*mut Block, u8, Id),
+        descriptor: *const BlockDescriptor,
+        reply: *const Sender<Result<(), String>>,
+    }
+
+    static DESCRIPTOR: BlockDescriptor = BlockDescriptor { reserved: 0, size: std::mem::size_of::<Block>() };
+
+    fn selector(name: &str) -> Sel {
+        unsafe { sel_registerName(format!("{}\0", name).as_ptr()) }
+    }
+
+    // A message with no arguments that returns an object
+    unsafe fn send(receiver: Id, name: &str) -> Id {
+        let send: unsafe extern "C" fn(Id, Sel) -> Id = unsafe { std::mem::transmute(objc_msgSend as unsafe extern "C" fn()) };
+        unsafe { send(receiver, selector(name)) }
+    }
+
+    unsafe fn description(error: Id) -> String {
+        if error.is_null() {
+            return "authentication failed".to_string();
+        }
+        let text = unsafe { send(send(error, "localizedDescription"), "UTF8String") } as *const std::ffi::c_char;
+        match text.is_null() {
+            true => "authentication failed".to_string(),
+            false => unsafe { std::ffi::CStr::from_ptr(text) }.to_string_lossy().into_owned(),
+        }
+    }
+
+    unsafe extern "C" fn replied(block: *mut Block, success: u8, error: Id) {
+        let result = match success {
+            0 => Err(unsafe { description(error) }),
+            _ => Ok(()),
+        };
+        let _ = unsafe { &*(*block).reply }.send(result);
+    }
+
+    /// Ask for Touch ID or the password, waiting for the answer; None if this Mac can't.
+    pub fn evaluate(reason: &str) -> Option<Result<(), String>> {
+        let reason = CString::new(reason).ok()?;
+        unsafe {
+            let class = objc_getClass(c"LAContext".as_ptr().cast());
+            if class.is_null() {
+                return None;
+            }
+            let context = send(send(class, "alloc"), "init");
+            let can_evaluate: unsafe extern "C" fn(Id, Sel, isize, *mut Id) -> u8 =
+                std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            let available = can_evaluate(context, selector("canEvaluatePolicy:error:"), DEVICE_OWNER_AUTHENTICATION, std::ptr::null_mut()) != 0;
+            if !available {
+                send(context, "release");
+                return None;
+            }
+
+            let string_class = objc_getClass(c"NSString".as_ptr().cast());
+            let with_utf8: unsafe extern "C" fn(Id, Sel, *const std::ffi::c_char) -> Id =
+                std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            let reason = with_utf8(string_class, selector("stringWithUTF8String:"), reason.as_ptr());
+
+            // The reply comes on another thread; the block is copied, sender pointer and all,
+            // and this waits for it, so the sender outlives every use
+            let (sender, receiver) = mpsc::channel();
+            let block = Block {
+                isa: &raw const _NSConcreteStackBlock as *const c_void,
+                flags: 0,
+                reserved: 0,
+                invoke: replied,
+                descriptor: &DESCRIPTOR,
+                reply: &sender,
+            };
+            let evaluate: unsafe extern "C" fn(Id, Sel, isize, Id, *const Block) =
+                std::mem::transmute(objc_msgSend as unsafe extern "C" fn());
+            evaluate(context, selector("evaluatePolicy:localizedReason:reply:"), DEVICE_OWNER_AUTHENTICATION, reason, &block);
+            let result = receiver.recv().unwrap_or_else(|_| Err("no answer from LocalAuthentication".to_string()));
+            send(context, "release");
+            Some(result)
+        }
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::progress::Progress;
+use crate::provider::{self, DeviceProvider};
+use crate::{copier, create_ramdisk, log_verbose, presence, registry, say, Config};
+
+pub const PROJECT_FILE: &str = ".mkramdisk.toml";
+
+/// A RAM disk a project declares in its `.mkramdisk.toml`: the whole file for a single
+/// disk, or one `[[disk]]` table of several.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub size: String,
+    /// Defaults to the name of the directory holding the project file.
+    pub name: Option<String>,
+    pub filesystem: Option<String>,
+    pub backend: Option<String>,
+    /// Directory inside the project copied onto the disk when it is created, along with
+    /// a SHA-256 manifest of the copy. A disk image or the root of a mounted volume is
+    /// restored onto it with asr instead.
+    pub seed: Option<PathBuf>,
+    /// Project paths to replace with symlinks into the disk: project path -> path on disk.
+    /// Both are relative and stay inside the project and the disk.
+    #[serde(default)]
+    pub links: BTreeMap<String, String>,
+    /// Environment variables to export; `{mount_point}` expands to the disk's mount point.
+    /// Names are letters, digits and underscores, not starting with a digit.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Make `down` (and `eject`) ask for the user's presence first, as `--protected` does.
+    #[serde(default)]
+    pub protected: bool,
+    /// Other `[[disk]]` tables, by name, that have to be up before this one, e.g. the
+    /// disk a seed is synced to. `up` follows them and `down` goes the other way.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+// A project file declaring several disks
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectFile {
+    disk: Vec<ProjectConfig>,
+}
+
+/// One of a project's disks together with the directory its file was found in.
+#[derive(Debug)]
+pub struct Project {
+    pub root: PathBuf,
+    pub config: ProjectConfig,
+}
+
+/// Walk up from `start` to the nearest directory containing a project file.
+pub fn discover(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Load a project file: one disk per `[[disk]]` table, or the file itself as one disk.
+/// The disks come each after those it depends on, and otherwise in the order declared.
+pub fn load(path: &Path) -> Result<Vec<Project>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let configs = parse(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    let root = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    for config in &configs {
+        check_paths(&root, config).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    }
+    Ok(configs.into_iter().map(|config| Project { root: root.clone(), config }).collect())
+}
+
+fn parse(contents: &str) -> Result<Vec<ProjectConfig>, String> {
+    let table: toml::Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+    if !table.contains_key("disk") {
+        let config: ProjectConfig = toml::from_str(contents).map_err(|e| e.to_string())?;
+        check_env(&config)?;
+        if !config.depends_on.is_empty() {
+            return Err("depends_on names other [[disk]] tables, and this file declares a single disk".to_string());
+        }
+        return Ok(vec![config]);
+    }
+    let file: ProjectFile = toml::from_str(contents)
+        .map_err(|e| format!("{}\nWith [[disk]] tables, every setting goes in a disk's table", e))?;
+    if file.disk.is_empty() {
+        return Err("declares no disks".to_string());
+    }
+    for disk in &file.disk {
+        check_env(disk)?;
+    }
+    // Only a lone disk can fall back to the directory's name
+    if file.disk.len() > 1 {
+        let mut names = Vec::new();
+        for (i, disk) in file.disk.iter().enumerate() {
+            let name = disk.name.as_deref().ok_or_else(|| format!("[[disk]] number {} needs a name", i + 1))?;
+            if names.contains(&name) {
+                return Err(format!("More than one [[disk]] is named '{}'", name));
+            }
+            names.push(name);
+        }
+    }
+    order(file.disk)
+}
+
+// `disks` with each after those it depends on, and otherwise as declared, refusing a
+// dependency on a disk that isn't there or a cycle of them
+fn order(disks: Vec<ProjectConfig>) -> Result<Vec<ProjectConfig>, String> {
+    let names: Vec<&str> = disks.iter().map(|disk| disk.name.as_deref().unwrap_or_default()).collect();
+    let mut dependencies = Vec::new();
+    for (disk, name) in disks.iter().zip(&names) {
+        let mut of_disk = Vec::new();
+        for dependency in &disk.depends_on {
+            let at = names.iter().position(|name| name == dependency)
+                .ok_or_else(|| format!("'{}' depends on '{}', but no [[disk]] is named that", name, dependency))?;
+            of_disk.push(at);
+        }
+        dependencies.push(of_disk);
+    }
+    let mut ordered = Vec::new();
+    let mut done = vec![false; disks.len()];
+    for at in 0..disks.len() {
+        visit(at, &dependencies, &names, &mut done, &mut Vec::new(), &mut ordered)?;
+    }
+    let mut disks: Vec<Option<ProjectConfig>> = disks.into_iter().map(Some).collect();
+    Ok(ordered.into_iter().filter_map(|at| disks[at].take()).collect())
+}
+
+// Add disk `at` to `ordered` after its dependencies, with `path` the disks that led to it
+fn visit(at: usize, dependencies: &[Vec<usize>], names: &[&str], done: &mut [bool], path: &mut Vec<usize>, ordered: &mut Vec<usize>) -> Result<(), String> {
+    if done[at] {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|&on_path| on_path == at) {
+        let cycle: Vec<&str> = path[start..].iter().chain([&at]).map(|&on_path| names[on_path]).collect();
+        return Err(format!("Disks depend on each other in a cycle: {}", cycle.join(" -> ")));
+    }
+    path.push(at);
+    for &dependency in &dependencies[at] {
+        visit(dependency, dependencies, names, done, path, ordered)?;
+    }
+    path.pop();
+    done[at] = true;
+    ordered.push(at);
+    Ok(())
+}
+
+// The names end up in `export NAME=...` lines a shell evaluates
+fn check_env(config: &ProjectConfig) -> Result<(), String> {
+    for key in config.env.keys() {
+        let mut chars = key.chars();
+        let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("'{}' is not a valid environment variable name", key));
+        }
+    }
+    Ok(())
+}
+
+// The seed and the project side of each link must stay inside the project, and the
+// disk side of each link inside the disk (checked by `create_links`)
+fn check_paths(root: &Path, config: &ProjectConfig) -> Result<(), String> {
+    if let Some(seed) = &config.seed {
+        confined(root, seed, "seed")?;
+    }
+    for (link, target) in &config.links {
+        let link = Path::new(link);
+        confined(root, link.parent().unwrap_or(Path::new("")), "link")?;
+        if link.file_name().is_none() {
+            return Err(format!("link {} names no file", link.display()));
+        }
+        relative(Path::new(target), "link target")?;
+    }
+    Ok(())
+}
+
+// `path` joined onto `base`, refusing one that is absolute or climbs out with "..",
+// or that leads out through a symlink already in place
+fn confined(base: &Path, path: &Path, what: &str) -> Result<PathBuf, String> {
+    relative(path, what)?;
+    let joined = base.join(path);
+    let existing = joined.ancestors().find(|ancestor| ancestor.exists()).and_then(|ancestor| ancestor.canonicalize().ok());
+    if let (Ok(base), Some(existing)) = (base.canonicalize(), existing)
+        && !existing.starts_with(&base)
+    {
+        return Err(format!("{} {} leads out of {} to {}", what, path.display(), base.display(), existing.display()));
+    }
+    Ok(joined)
+}
+
+fn relative(path: &Path, what: &str) -> Result<(), String> {
+    if path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        Ok(())
+    } else {
+        Err(format!("{} {} must be a relative path without ..", what, path.display()))
+    }
+}
+
+impl Project {
+    pub fn name(&self) -> String {
+        self.config.name.clone().unwrap_or_else(|| {
+            self.root
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "RAMDisk".to_string())
+        })
+    }
+
+    /// The project's disk expressed as command-line arguments, so it goes through
+    /// the same parsing and validation as a disk described on the command line.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![self.config.size.clone(), self.name()];
+        if let Some(filesystem) = &self.config.filesystem {
+            args.extend(["--format".to_string(), filesystem.clone()]);
+        }
+        if let Some(backend) = &self.config.backend {
+            args.extend(["--backend".to_string(), backend.clone()]);
+        }
+        if self.config.protected {
+            args.push("--protected".to_string());
+        }
+        args
+    }
+
+    pub fn seed_dir(&self) -> Option<PathBuf> {
+        self.config.seed.as_ref().map(|seed| self.root.join(seed))
+    }
+
+    /// Whether the seed can be restored block for block: a disk image, or a whole volume.
+    pub fn seed_is_restorable(&self) -> bool {
+        self.seed_dir().is_some_and(|seed| seed.extension().is_some_and(|ext| ext == "dmg") || is_volume_root(&seed))
+    }
+
+    /// Point each configured project path at its directory on the disk. Existing links
+    /// are refreshed; real files or directories are never replaced.
+    pub fn create_links(&self, mount_point: &Path) -> Result<Vec<PathBuf>, String> {
+        let mut created = Vec::new();
+        for (link, target) in &self.config.links {
+            let link = self.root.join(link);
+            let target = confined(mount_point, Path::new(target), "link target")?;
+            fs::create_dir_all(&target)
+                .map_err(|e| format!("Failed to create {}: {}", target.display(), e))?;
+
+            match fs::symlink_metadata(&link) {
+                Ok(meta) if meta.file_type().is_symlink() => {
+                    fs::remove_file(&link).map_err(|e| format!("Failed to replace {}: {}", link.display(), e))?;
+                }
+                Ok(_) => {
+                    return Err(format!(
+                        "{} already exists and is not a symlink; move it aside to link it to the RAM disk",
+                        link.display()
+                    ));
+                }
+                Err(_) => {}
+            }
+            symlink_dir(&target, &link).map_err(|e| format!("Failed to link {}: {}", link.display(), e))?;
+            created.push(link);
+        }
+        Ok(created)
+    }
+
+    /// Remove the symlinks made by `create_links` that point into `mount_point`.
+    pub fn remove_links(&self, mount_point: &Path) -> Result<(), String> {
+        for link in self.config.links.keys() {
+            let link = self.root.join(link);
+            if fs::read_link(&link).is_ok_and(|target| target.starts_with(mount_point)) {
+                fs::remove_file(&link).map_err(|e| format!("Failed to remove {}: {}", link.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Shell `export` lines for the configured environment.
+    pub fn env_exports(&self, mount_point: &Path) -> Vec<String> {
+        let mount_point = mount_point.to_string_lossy();
+        self.config
+            .env
+            .iter()
+            .map(|(key, value)| {
+                let value = value.replace("{mount_point}", &mount_point);
+                format!("export {}='{}'", key, value.replace('\'', r"'\''"))
+            })
+            .collect()
+    }
+}
+
+/// Bring a project's disk up as `config` describes it, creating and seeding it unless
+/// it is up already, and link the project into it. Returns where it is mounted.
+pub fn up(config: &Config, project: &Project) -> Result<PathBuf, String> {
+    let provider = provider::select_provider(&config.backend, &config.name)?;
+    let mut mount_point = provider.mount_point(&config.name);
+    if mount_point.exists() {
+        project_disk(config, provider.as_ref(), &mount_point)?;
+        say(config, &format!("RAM disk '{}' is already up at {}", config.name, mount_point.display()));
+    } else {
+        mount_point = create_seeded(config, project)?;
+    }
+
+    for link in project.create_links(&mount_point)? {
+        say(config, &format!("Linked {} -> {}", link.display(), mount_point.display()));
+    }
+    Ok(mount_point)
+}
+
+/// Create the project's disk with its seed on it. A disk image or whole volume is restored
+/// with asr, which is much faster than copying a large seed file by file; a volume asr
+/// can't restore, and any other directory, is copied after formatting.
+fn create_seeded(config: &Config, project: &Project) -> Result<PathBuf, String> {
+    let seed = project.seed_dir();
+    if let Some(seed) = seed.as_ref().filter(|_| project.seed_is_restorable()) {
+        log_verbose(config, &format!("Restoring seed {}...", seed.display()));
+        let restoring = Config { source_image: Some(seed.clone()), filesystem: "image".to_string(), ..config.clone() };
+        match create_ramdisk(&restoring) {
+            Ok(created) => {
+                say(config, &format!("Seeded from {} by block restore", seed.display()));
+                return created.mount_point.ok_or_else(|| "Restored disk has no mount point".to_string());
+            }
+            Err(e) if seed.is_dir() => {
+                say(config, &format!("Note: restoring {} failed ({}); copying its files instead", seed.display(), e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mount_point = create_ramdisk(config)?
+        .mount_point
+        .ok_or("A project disk needs a filesystem; it cannot be a raw device")?;
+    if let Some(seed) = seed {
+        log_verbose(config, &format!("Seeding from {}...", seed.display()));
+        let total = copier::scan(&seed, config.copy.links)?;
+        let mut progress = Progress::new("seed", total.files, total.bytes);
+        let (stats, manifest) = copier::copy_tree_with_manifest(&seed, &mount_point, &config.copy, &mut progress)?;
+        // Checksums of what was copied, so the disk can be checked later without the seed
+        manifest.write(&mount_point.join(copier::MANIFEST_FILE))?;
+        say(config, &format!("Seeded {} files ({} bytes) from {}", stats.files, stats.bytes, seed.display()));
+    }
+    Ok(mount_point)
+}
+
+/// Unlink the project from its disk and tear the disk down, if it is up.
+pub fn down(config: &Config, project: &Project) -> Result<(), String> {
+    let provider = provider::select_provider(&config.backend, &config.name)?;
+    let mount_point = provider.mount_point(&config.name);
+    project.remove_links(&mount_point)?;
+    if !mount_point.exists() {
+        say(config, &format!("RAM disk '{}' is not up", config.name));
+        return Ok(());
+    }
+    let device = project_disk(config, provider.as_ref(), &mount_point)?;
+    if presence::is_protected(&mount_point) {
+        presence::require(&config.name)?;
+    }
+    log_verbose(config, &format!("Tearing down {} ({})...", mount_point.display(), device));
+    provider.destroy(&mount_point, false)?;
+    say(config, &format!("RAM disk '{}' torn down", config.name));
+    Ok(())
+}
+
+// The device of the project's disk at `mount_point`, which must be one mkramdisk
+// created: a volume of the same name on any other disk is never used or torn down
+fn project_disk(config: &Config, provider: &dyn DeviceProvider, mount_point: &Path) -> Result<String, String> {
+    let device = provider.device_at(mount_point);
+    let registry = registry::load()?;
+    let managed = device
+        .as_deref()
+        .is_some_and(|device| registry.find(&config.backend, device, provider.identity(device).as_deref()).is_some());
+    match device {
+        Some(device) if managed => Ok(device),
+        _ => Err(format!(
+            "{} is not a RAM disk mkramdisk created; move it out of the way or give the disk another name",
+            mount_point.display()
+        )),
+    }
+}
+
+// A mount point sits on a different device from its parent directory
+#[cfg(unix)]
+fn is_volume_root(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let (Ok(meta), Some(parent)) = (fs::metadata(path), path.parent()) else {
+        return false;
+    };
+    meta.is_dir() && fs::metadata(parent).is_ok_and(|parent| parent.dev() != meta.dev())
+}
+
+#[cfg(windows)]
+fn is_volume_root(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub(crate) fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub(crate) fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_discover_walks_up() {
+        let root = env::temp_dir().join(format!("mkramdisk-project-{}", std::process::id()));
+        let nested = root.join("src/deeply/nested");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join(PROJECT_FILE), "size = \"1G\"\n").unwrap();
+
+        assert_eq!(discover(&nested), Some(root.join(PROJECT_FILE)));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_to_args() {
+        let project = Project {
+            root: PathBuf::from("/src/myapp"),
+            config: toml::from_str("size = \"2G\"\nfilesystem = \"hfs+\"").unwrap(),
+        };
+        assert_eq!(project.to_args(), ["2G", "myapp", "--format", "hfs+"]);
+        assert!(toml::from_str::<ProjectConfig>("size = \"2G\"\ncolour = \"red\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_disks() {
+        let disks = parse("size = \"1G\"").unwrap();
+        assert_eq!(disks.len(), 1);
+
+        let disks = parse(concat!(
+            "[[disk]]\nsize = \"4G\"\nname = \"Build\"\n[disk.links]\ntarget = \"target\"\n\n",
+            "[[disk]]\nsize = \"512M\"\nname = \"Fixtures\"\nseed = \"fixtures\"\n",
+        ))
+        .unwrap();
+        assert_eq!(disks.iter().map(|disk| disk.name.as_deref().unwrap()).collect::<Vec<_>>(), ["Build", "Fixtures"]);
+        assert_eq!(disks[0].links["target"], "target");
+        assert_eq!(disks[1].seed, Some(PathBuf::from("fixtures")));
+
+        assert!(parse("[[disk]]\nsize = \"1G\"\n[[disk]]\nsize = \"2G\"\nname = \"B\"").unwrap_err().contains("needs a name"));
+        assert!(parse("[[disk]]\nsize = \"1G\"\nname = \"A\"\n[[disk]]\nsize = \"2G\"\nname = \"A\"").unwrap_err().contains("named 'A'"));
+        assert!(parse("size = \"1G\"\n[[disk]]\nsize = \"2G\"").is_err());
+    }
+
+    #[test]
+    fn test_depends_on() {
+        let disks = parse(concat!(
+            "[[disk]]\nsize = \"1G\"\nname = \"Fixtures\"\ndepends_on = [\"Cache\"]\n",
+            "[[disk]]\nsize = \"1G\"\nname = \"Build\"\n",
+            "[[disk]]\nsize = \"1G\"\nname = \"Cache\"\ndepends_on = [\"Build\"]\n",
+        ))
+        .unwrap();
+        assert_eq!(disks.iter().map(|disk| disk.name.as_deref().unwrap()).collect::<Vec<_>>(), ["Build", "Cache", "Fixtures"]);
+
+        let cycle = concat!(
+            "[[disk]]\nsize = \"1G\"\nname = \"A\"\ndepends_on = [\"B\"]\n",
+            "[[disk]]\nsize = \"1G\"\nname = \"B\"\ndepends_on = [\"C\"]\n",
+            "[[disk]]\nsize = \"1G\"\nname = \"C\"\ndepends_on = [\"A\"]\n",
+        );
+        assert_eq!(parse(cycle).unwrap_err(), "Disks depend on each other in a cycle: A -> B -> C -> A");
+        assert!(parse("[[disk]]\nsize = \"1G\"\nname = \"A\"\ndepends_on = [\"A\"]").unwrap_err().contains("A -> A"));
+        assert!(parse("[[disk]]\nsize = \"1G\"\nname = \"A\"\ndepends_on = [\"B\"]").unwrap_err().contains("no [[disk]] is named that"));
+        assert!(parse("size = \"1G\"\ndepends_on = [\"B\"]").is_err());
+    }
+
+    #[test]
+    fn test_env_exports() {
+        let project = Project {
+            root: PathBuf::from("/src/myapp"),
+            config: toml::from_str("size = \"2G\"\n[env]\nCARGO_TARGET_DIR = \"{mount_point}/target\"").unwrap(),
+        };
+        assert_eq!(
+            project.env_exports(Path::new("/Volumes/my app")),
+            ["export CARGO_TARGET_DIR='/Volumes/my app/target'"]
+        );
+    }
+
+    #[test]
+    fn test_env_names_are_checked() {
+        assert!(parse("size = \"1G\"\n[env]\nTMPDIR_2 = \"x\"\n_X = \"y\"").is_ok());
+        for key in ["\"X; rm -rf ~\"", "\"2X\"", "\"A-B\"", "\"\""] {
+            let contents = format!("size = \"1G\"\n[env]\n{} = \"x\"", key);
+            assert!(parse(&contents).unwrap_err().contains("not a valid environment variable name"), "{}", key);
+        }
+        assert!(parse("[[disk]]\nsize = \"1G\"\n[disk.env]\n\"\\$(id)\" = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_paths_stay_inside() {
+        let root = env::temp_dir().join(format!("mkramdisk-confined-{}", std::process::id()));
+        fs::create_dir_all(root.join("myapp")).unwrap();
+        let project_file = root.join("myapp").join(PROJECT_FILE);
+        let load_with = |settings: &str| {
+            fs::write(&project_file, format!("size = \"1G\"\n{}", settings)).unwrap();
+            load(&project_file)
+        };
+        assert!(load_with("seed = \"fixtures\"\n[links]\n\"build/target\" = \"target\"").is_ok());
+        for settings in [
+            "seed = \"../secrets\"",
+            "seed = \"/etc\"",
+            "[links]\n\"../../.ssh\" = \"ssh\"",
+            "[links]\n\"/usr/local/bin\" = \"bin\"",
+            "[links]\ntarget = \"../../..\"",
+            "[links]\ntarget = \"/\"",
+        ] {
+            assert!(load_with(settings).unwrap_err().contains("must be a relative path"), "{}", settings);
+        }
+        // Nor through a symlink that leads out
+        symlink_dir(&root, &root.join("myapp/out")).unwrap();
+        assert!(load_with("seed = \"out/secrets\"").unwrap_err().contains("leads out of"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_links() {
+        let root = env::temp_dir().join(format!("mkramdisk-links-{}", std::process::id()));
+        let disk = root.join("disk");
+        let project = Project {
+            root: root.join("myapp"),
+            config: toml::from_str("size = \"2G\"\n[links]\ntarget = \"build/target\"").unwrap(),
+        };
+        fs::create_dir_all(&project.root).unwrap();
+
+        project.create_links(&disk).unwrap();
+        assert_eq!(fs::read_link(project.root.join("target")).unwrap(), disk.join("build/target"));
+        // Refreshing an existing link is fine
+        project.create_links(&disk).unwrap();
+
+        project.remove_links(&disk).unwrap();
+        assert!(fs::symlink_metadata(project.root.join("target")).is_err());
+
+        fs::create_dir(project.root.join("target")).unwrap();
+        assert!(project.create_links(&disk).is_err());
+
+        // The disk side stays on the disk, even through a symlink on it
+        symlink_dir(&root, &disk.join("out")).unwrap();
+        let project = Project {
+            root: root.join("myapp"),
+            config: toml::from_str("size = \"2G\"\n[links]\nescape = \"out/myapp\"").unwrap(),
+        };
+        assert!(project.create_links(&disk).unwrap_err().contains("leads out of"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
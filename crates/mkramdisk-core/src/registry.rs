@@ -0,0 +1,478 @@
+//! The disks mkramdisk has created, kept in a state file so `list`, `info` and `eject`
+//! can tell them from other attached images. A disk is forgotten when mkramdisk ejects
+//! it; one torn down some other way stays until its device is reused. Device numbers
+//! are reused as soon as a disk goes, so an entry also keeps the disk's identity (see
+//! `DeviceProvider::identity`) and only matches a device that still has it. Each
+//! volume also carries a copy of its entry in a marker file, so a state file that was
+//! lost or corrupted can be rebuilt from the disks themselves (`registry rebuild`).
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::copier::MARKER_FILE;
+
+/// The version of the state file's layout, stored in it as "schema". Files written
+/// before it was numbered are schema 0.
+pub const SCHEMA: u64 = 1;
+
+// A change to the layout, taking a file from the schema at its index in UPGRADES to
+// the next one
+struct Upgrade {
+    description: &'static str,
+    apply: fn(&mut Value),
+}
+
+const UPGRADES: &[Upgrade] = &[Upgrade { description: "number the schema", apply: |_| {} }];
+
+/// One disk mkramdisk created.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub device: String,
+    /// What told the disk apart from a later one on the same device when it was
+    /// recorded, e.g. its volume UUID; None if the backend had nothing.
+    #[serde(default)]
+    pub identity: Option<String>,
+    pub name: String,
+    pub backend: String,
+    /// The size as it was asked for, e.g. "2G".
+    pub size: String,
+    pub size_bytes: u64,
+    pub filesystem: String,
+    pub mount_point: Option<PathBuf>,
+    /// Seconds since the epoch.
+    pub created: u64,
+    /// Options it was created with that change how it behaves, e.g. "protected".
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// For an overlay, the device of the RAM disk holding its shadow file, which goes
+    /// when it does.
+    #[serde(default)]
+    pub shadow: Option<String>,
+    /// For a disk `accelerate` made, the directory it stands in for.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// The groups it was put in at creation (`--tag`).
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// For a disk mkramdiskd created with a TTL or a sync schedule, when it comes due.
+    #[serde(default)]
+    pub timers: Option<Timers>,
+}
+
+impl Entry {
+    /// Whether it carries every one of `tags`, as it does when there are none.
+    pub fn tagged(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.tags.contains(tag))
+    }
+}
+
+/// When mkramdiskd ejects a disk or snapshots it. These are times rather than what is
+/// left of them, so a daemon that restarts carries on where the last one stopped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Timers {
+    /// When its TTL runs out and it is ejected, in seconds since the epoch.
+    #[serde(default)]
+    pub expires: Option<u64>,
+    /// Seconds between snapshots into the backup store.
+    #[serde(default)]
+    pub sync_every: Option<u64>,
+    /// When it was last snapshotted on schedule, in seconds since the epoch.
+    #[serde(default)]
+    pub synced: Option<u64>,
+}
+
+impl Timers {
+    /// When the next snapshot is due, for a disk created at `created`.
+    pub fn next_sync(&self, created: u64) -> Option<u64> {
+        self.sync_every.map(|every| self.synced.unwrap_or(created).saturating_add(every))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Registry {
+    pub disks: Vec<Entry>,
+}
+
+/// `$MKRAMDISK_STATE`, else `state.json` in `paths::get()`'s state directory.
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("MKRAMDISK_STATE") {
+        return Some(PathBuf::from(path));
+    }
+    Some(crate::paths::get()?.state_file())
+}
+
+/// The registry at `path()`. A missing file is an empty registry.
+pub fn load() -> Result<Registry, String> {
+    match path() {
+        Some(path) => Registry::load(&path),
+        None => Ok(Registry::default()),
+    }
+}
+
+/// Change the registry at `path()` and write it back, holding its lock throughout so
+/// a change made meanwhile by another mkramdisk isn't lost.
+pub fn update(change: impl FnOnce(&mut Registry)) -> Result<(), String> {
+    let path = path().ok_or("No state file: neither MKRAMDISK_STATE nor HOME is set")?;
+    let _lock = lock(&path)?;
+    let mut registry = Registry::load(&path)?;
+    change(&mut registry);
+    registry.save(&path)
+}
+
+// An exclusive flock on state.json.lock beside the state file, released when the file
+// is dropped or the process dies
+fn lock(path: &Path) -> Result<File, String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    let lock_path = path.with_extension("json.lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| format!("Failed to open {}: {}", lock_path.display(), e))?;
+    file.lock().map_err(|e| format!("Failed to lock {}: {}", lock_path.display(), e))?;
+    Ok(file)
+}
+
+/// Write `entry` to the marker at the root of the volume at `mount_point`.
+pub fn write_marker(mount_point: &Path, entry: &Entry) -> Result<(), String> {
+    let path = mount_point.join(MARKER_FILE);
+    let json = serde_json::to_string_pretty(entry).map_err(|e| e.to_string())?;
+    fs::write(&path, json + "\n").map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// The entry the marker on the volume at `mount_point` holds, if it has one that reads.
+pub fn read_marker(mount_point: &Path) -> Option<Entry> {
+    serde_json::from_str(&fs::read_to_string(mount_point.join(MARKER_FILE)).ok()?).ok()
+}
+
+/// A disk the backend has attached now, as `rebuild` takes it.
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub device: String,
+    pub identity: Option<String>,
+    pub mount_point: Option<PathBuf>,
+    /// The entry its marker holds, if its volume has one.
+    pub marker: Option<Entry>,
+}
+
+/// What `rebuild` did to an entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Dropped, its disk having gone.
+    Forgotten(Entry),
+    /// Added back from the marker on its volume.
+    Recovered(Entry),
+}
+
+/// The disks `backend` has attached now, with the markers on their volumes, for `rebuild`.
+pub fn attached(backend: &str) -> Result<Vec<Found>, String> {
+    let provider = crate::provider::select_provider(backend, "")?;
+    Ok(provider
+        .list()?
+        .into_iter()
+        .map(|disk| Found {
+            identity: provider.identity(&disk.device),
+            marker: disk.mount_point.as_deref().and_then(read_marker),
+            device: disk.device,
+            mount_point: disk.mount_point,
+        })
+        .collect())
+}
+
+/// Rebuild the registry at `path()` from the disks of `backend` attached now (see
+/// `Registry::rebuild`), writing it back unless `dry_run`. A state file that can't be
+/// read at all is set aside as state.json.corrupt and rebuilt from nothing.
+pub fn rebuild(backend: &str, attached: &[Found], dry_run: bool) -> Result<Vec<Change>, String> {
+    let path = path().ok_or("No state file: neither MKRAMDISK_STATE nor HOME is set")?;
+    let _lock = lock(&path)?;
+    let (mut registry, corrupt) = match Registry::load(&path) {
+        Ok(registry) => (registry, false),
+        // Newer than this mkramdisk is not corrupt; it only can't be read here
+        Err(e) if !e.starts_with("Invalid ") => return Err(e),
+        Err(_) => (Registry::default(), true),
+    };
+    let changes = registry.rebuild(backend, attached);
+    if dry_run {
+        return Ok(changes);
+    }
+    if corrupt {
+        let aside = path.with_extension("json.corrupt");
+        fs::rename(&path, &aside).map_err(|e| format!("Failed to move {} aside: {}", path.display(), e))?;
+    }
+    registry.save(&path)?;
+    Ok(changes)
+}
+
+impl Registry {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let mut value = serde_json::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        // An older file is read as if upgraded; `migrate-state` writes the upgrade back
+        upgrade(&mut value).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_value(value).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let mut value = serde_json::to_value(self).map_err(|e| e.to_string())?;
+        value["schema"] = SCHEMA.into();
+        let json = serde_json::to_string_pretty(&value).map_err(|e| e.to_string())?;
+        // Written aside and renamed into place, so a crash never leaves half a file
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, json + "\n").map_err(|e| format!("Failed to write {}: {}", partial.display(), e))?;
+        fs::rename(&partial, path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// Add `entry`, in place of any entry for the same device, which must have gone.
+    pub fn record(&mut self, entry: Entry) {
+        self.disks.retain(|disk| !(disk.backend == entry.backend && disk.device == entry.device));
+        self.disks.push(entry);
+    }
+
+    /// The entry for the disk attached as `device`, which has `identity` now. An entry
+    /// that recorded an identity only matches the same one, since the disk it was for
+    /// may have gone and another taken its device.
+    pub fn find(&self, backend: &str, device: &str, identity: Option<&str>) -> Option<&Entry> {
+        self.disks.iter().find(|disk| {
+            disk.backend == backend
+                && disk.device == device
+                && disk.identity.as_deref().is_none_or(|recorded| Some(recorded) == identity)
+        })
+    }
+
+    /// The entry for the disk standing in for `directory`.
+    pub fn find_directory(&self, directory: &Path) -> Option<&Entry> {
+        self.disks.iter().find(|disk| disk.directory.as_deref() == Some(directory))
+    }
+
+    /// The entries of disks that hold memory: all but overlays, whose image stays where
+    /// it is and whose changes are on their shadow disk, and the file backend's, which
+    /// are images on disk.
+    pub fn in_ram(&self) -> impl Iterator<Item = &Entry> {
+        self.disks.iter().filter(|disk| disk.backend != "file" && !disk.flags.iter().any(|flag| flag == "overlay"))
+    }
+
+    /// The entry for the volume called `name`.
+    pub fn find_named(&self, backend: &str, name: &str) -> Option<&Entry> {
+        self.disks.iter().find(|disk| disk.backend == backend && disk.name == name)
+    }
+
+    /// Make the entries of `backend` match the disks `attached` now: forget those whose
+    /// disk has gone, and recover those whose volume carries a marker but which have no
+    /// entry. Where a disk is and what tells it apart come from the disk, not the marker.
+    pub fn rebuild(&mut self, backend: &str, attached: &[Found]) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for entry in std::mem::take(&mut self.disks) {
+            let present = entry.backend != backend
+                || attached.iter().any(|disk| {
+                    disk.device == entry.device && entry.identity.as_deref().is_none_or(|recorded| disk.identity.as_deref() == Some(recorded))
+                });
+            match present {
+                true => self.disks.push(entry),
+                false => changes.push(Change::Forgotten(entry)),
+            }
+        }
+        for disk in attached {
+            let Some(marker) = &disk.marker else {
+                continue;
+            };
+            if self.find(backend, &disk.device, disk.identity.as_deref()).is_some() {
+                continue;
+            }
+            let entry = Entry {
+                device: disk.device.clone(),
+                identity: disk.identity.clone(),
+                backend: backend.to_string(),
+                mount_point: disk.mount_point.clone(),
+                ..marker.clone()
+            };
+            self.record(entry.clone());
+            changes.push(Change::Recovered(entry));
+        }
+        changes
+    }
+
+    /// Drop the entries for `device` or, when it isn't known, the volume `name`.
+    pub fn forget(&mut self, backend: &str, device: Option<&str>, name: &str) {
+        self.disks.retain(|disk| {
+            let matches = match device {
+                Some(device) => disk.device == device,
+                None => disk.name == name,
+            };
+            disk.backend != backend || !matches
+        });
+    }
+}
+
+/// Bring the contents of a state file up to `SCHEMA`, returning the steps it took, none
+/// if it was current. A file from a newer mkramdisk is refused rather than misread.
+pub fn upgrade(value: &mut Value) -> Result<Vec<String>, String> {
+    let schema = value.get("schema").and_then(Value::as_u64).unwrap_or(0);
+    if schema > SCHEMA {
+        return Err(format!(
+            "written by a newer mkramdisk (schema {}; this one reads up to {}); upgrade mkramdisk",
+            schema, SCHEMA
+        ));
+    }
+    let mut steps = Vec::new();
+    for (from, upgrade) in UPGRADES.iter().enumerate().skip(schema as usize) {
+        (upgrade.apply)(value);
+        steps.push(format!("schema {} to {}: {}", from, from + 1, upgrade.description));
+    }
+    if let Some(object) = value.as_object_mut() {
+        object.insert("schema".to_string(), SCHEMA.into());
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(device: &str, name: &str) -> Entry {
+        Entry {
+            device: device.to_string(),
+            identity: None,
+            name: name.to_string(),
+            backend: "ram".to_string(),
+            size: "1G".to_string(),
+            size_bytes: 1 << 30,
+            filesystem: "apfs".to_string(),
+            mount_point: Some(PathBuf::from("/Volumes").join(name)),
+            created: 1_700_000_000,
+            flags: Vec::new(),
+            shadow: None,
+            directory: None,
+            tags: Vec::new(),
+            timers: None,
+        }
+    }
+
+    #[test]
+    fn test_record_and_forget() {
+        let mut registry = Registry::default();
+        registry.record(entry("/dev/disk4", "Scratch"));
+        registry.record(entry("/dev/disk5", "Build"));
+        // The device was reused, so the old entry is stale
+        registry.record(entry("/dev/disk4", "Cache"));
+        assert_eq!(registry.disks.len(), 2);
+        assert_eq!(registry.find("ram", "/dev/disk4", None).unwrap().name, "Cache");
+        assert!(registry.find("zram", "/dev/disk4", None).is_none());
+        assert_eq!(registry.find_named("ram", "Build").unwrap().device, "/dev/disk5");
+
+        registry.forget("ram", None, "Build");
+        registry.forget("ram", Some("/dev/disk4"), "Cache");
+        assert!(registry.disks.is_empty());
+    }
+
+    #[test]
+    fn test_find_checks_the_identity() {
+        let mut registry = Registry::default();
+        registry.record(Entry { identity: Some("0C3F6C1E".to_string()), ..entry("/dev/disk4", "Scratch") });
+        assert!(registry.find("ram", "/dev/disk4", Some("0C3F6C1E")).is_some());
+        // Another disk has the device now, or one whose identity can't be told
+        assert!(registry.find("ram", "/dev/disk4", Some("9A1B2C3D")).is_none());
+        assert!(registry.find("ram", "/dev/disk4", None).is_none());
+    }
+
+    #[test]
+    fn test_in_ram() {
+        let mut registry = Registry::default();
+        registry.record(entry("/dev/disk4", "Scratch"));
+        registry.record(Entry { flags: vec!["shadow".to_string()], ..entry("/dev/disk5", "base-shadow") });
+        registry.record(Entry { flags: vec!["overlay".to_string()], ..entry("/dev/disk6", "base") });
+        registry.record(Entry { backend: "file".to_string(), ..entry("/dev/disk7", "Image") });
+        let names: Vec<&str> = registry.in_ram().map(|disk| disk.name.as_str()).collect();
+        assert_eq!(names, ["Scratch", "base-shadow"]);
+    }
+
+    #[test]
+    fn test_tagged() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        let tagged = Entry { tags: tags(&["ci", "build"]), ..entry("/dev/disk4", "Scratch") };
+        assert!(tagged.tagged(&[]));
+        assert!(tagged.tagged(&tags(&["build"])));
+        assert!(tagged.tagged(&tags(&["build", "ci"])));
+        assert!(!tagged.tagged(&tags(&["ci", "cache"])));
+        assert!(!entry("/dev/disk5", "Build").tagged(&tags(&["ci"])));
+    }
+
+    #[test]
+    fn test_update_holds_the_lock() {
+        let dir = env::temp_dir().join(format!("mkramdisk-registry-lock-{}", std::process::id()));
+        let path = dir.join("state.json");
+        let held = lock(&path).unwrap();
+        assert!(matches!(File::open(dir.join("state.json.lock")).unwrap().try_lock(), Err(fs::TryLockError::WouldBlock)));
+        drop(held);
+        assert!(File::open(dir.join("state.json.lock")).unwrap().try_lock().is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_and_save() {
+        let dir = env::temp_dir().join(format!("mkramdisk-registry-{}", std::process::id()));
+        let path = dir.join("state").join("state.json");
+        assert_eq!(Registry::load(&path).unwrap(), Registry::default());
+
+        let mut registry = Registry::default();
+        registry.record(Entry { flags: vec!["protected".to_string()], ..entry("/dev/disk4", "Scratch") });
+        registry.save(&path).unwrap();
+        assert_eq!(Registry::load(&path).unwrap(), registry);
+
+        assert!(fs::read_to_string(&path).unwrap().contains("\"schema\": 1"));
+
+        fs::write(&path, "{").unwrap();
+        assert!(Registry::load(&path).unwrap_err().starts_with("Invalid "));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut registry = Registry::default();
+        registry.record(Entry { identity: Some("A".to_string()), ..entry("/dev/disk4", "Scratch") });
+        registry.record(Entry { identity: Some("B".to_string()), ..entry("/dev/disk5", "Gone") });
+        registry.record(Entry { backend: "file".to_string(), ..entry("/dev/disk6", "Other") });
+        let found = |device: &str, identity: &str, marker: Option<Entry>| Found {
+            device: device.to_string(),
+            identity: Some(identity.to_string()),
+            mount_point: None,
+            marker,
+        };
+        let marker = Entry { flags: vec!["protected".to_string()], ..entry("/dev/disk9", "Build") };
+        // disk5 was reused by another disk, which only a marker would make one of ours
+        let attached = [found("/dev/disk4", "A", None), found("/dev/disk5", "C", None), found("/dev/disk7", "D", Some(marker))];
+
+        let changes = registry.rebuild("ram", &attached);
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(&changes[0], Change::Forgotten(entry) if entry.name == "Gone"));
+        let Change::Recovered(recovered) = &changes[1] else { panic!("{:?}", changes) };
+        assert_eq!((recovered.device.as_str(), recovered.identity.as_deref(), recovered.flags.as_slice()), ("/dev/disk7", Some("D"), &["protected".to_string()][..]));
+        let names: Vec<&str> = registry.disks.iter().map(|disk| disk.name.as_str()).collect();
+        assert_eq!(names, ["Scratch", "Other", "Build"]);
+        assert!(registry.rebuild("ram", &attached).is_empty());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut old = serde_json::json!({ "disks": [] });
+        assert_eq!(upgrade(&mut old).unwrap(), ["schema 0 to 1: number the schema"]);
+        assert_eq!(old["schema"], SCHEMA);
+        assert!(upgrade(&mut old).unwrap().is_empty());
+
+        let mut newer = serde_json::json!({ "schema": SCHEMA + 1, "disks": [] });
+        assert!(upgrade(&mut newer).unwrap_err().contains("newer mkramdisk"));
+    }
+}
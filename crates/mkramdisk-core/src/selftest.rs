@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use sha2::{Digest, Sha256};
+
+use crate::{create_ramdisk, eject, memory, Config};
+
+// Written, read back and compared; big enough to span many pages
+const PATTERN_BYTES: usize = 1 << 20;
+const PATTERN_FILE: &str = "selftest.bin";
+
+/// How one step of the self-test went.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// Not run, because an earlier step failed.
+    Skip,
+}
+
+/// One step of the self-test and how it went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub step: &'static str,
+    pub outcome: Outcome,
+}
+
+impl Check {
+    fn new(step: &'static str, outcome: Outcome) -> Self {
+        Self { step, outcome }
+    }
+}
+
+/// Whether every step passed.
+pub fn passed(checks: &[Check]) -> bool {
+    checks.iter().all(|check| matches!(check.outcome, Outcome::Pass(_)))
+}
+
+fn pattern() -> Vec<u8> {
+    (0..PATTERN_BYTES).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// Create the disk `config` describes, write a test pattern to it, read it back,
+/// compare checksums and eject it again. The disk is ejected whatever fails after it
+/// was created, so a failed run leaves nothing attached.
+pub fn run(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let started = Instant::now();
+    let mount_point = match create_ramdisk(config) {
+        Ok(created) => match created.mount_point {
+            Some(mount_point) => {
+                checks.push(Check::new("create", Outcome::Pass(format!(
+                    "{} {} disk at {} ({:.1}s)",
+                    config.size,
+                    created.filesystem,
+                    mount_point.display(),
+                    started.elapsed().as_secs_f64()
+                ))));
+                mount_point
+            }
+            None => {
+                checks.push(Check::new("create", Outcome::Fail("the disk has no filesystem to test".to_string())));
+                let _ = eject(config, &created.device);
+                return skip_rest(checks);
+            }
+        },
+        Err(e) => {
+            checks.push(Check::new("create", Outcome::Fail(e)));
+            return skip_rest(checks);
+        }
+    };
+
+    checks.extend(write_and_verify(&mount_point));
+
+    let outcome = match eject(config, &config.name) {
+        Ok(()) if mount_point.exists() => Outcome::Fail(format!("{} is still there after ejecting", mount_point.display())),
+        Ok(()) => Outcome::Pass("disk ejected and its mount point removed".to_string()),
+        Err(e) => Outcome::Fail(e),
+    };
+    checks.push(Check::new("destroy", outcome));
+    checks
+}
+
+fn write_and_verify(mount_point: &Path) -> Vec<Check> {
+    let path = mount_point.join(PATTERN_FILE);
+    let written = pattern();
+    if let Err(e) = fs::write(&path, &written).and_then(|_| fs::File::open(&path)?.sync_all()) {
+        return vec![
+            Check::new("write", Outcome::Fail(format!("Failed to write {}: {}", path.display(), e))),
+            Check::new("read", Outcome::Skip),
+            Check::new("verify", Outcome::Skip),
+        ];
+    }
+    let mut checks = vec![Check::new("write", Outcome::Pass(format!("{} test pattern", memory::format_size(written.len() as u64))))];
+    let read = match fs::read(&path) {
+        Ok(read) => read,
+        Err(e) => {
+            checks.push(Check::new("read", Outcome::Fail(format!("Failed to read {}: {}", path.display(), e))));
+            checks.push(Check::new("verify", Outcome::Skip));
+            return checks;
+        }
+    };
+    checks.push(Check::new("read", Outcome::Pass(format!("read back {} bytes", read.len()))));
+    let verify = if Sha256::digest(&read) == Sha256::digest(&written) {
+        Outcome::Pass("checksums match".to_string())
+    } else {
+        Outcome::Fail(format!("what was read back differs from what was written ({} of {} bytes)", read.len(), written.len()))
+    };
+    checks.push(Check::new("verify", verify));
+    checks
+}
+
+fn skip_rest(mut checks: Vec<Check>) -> Vec<Check> {
+    for step in ["write", "read", "verify", "destroy"] {
+        checks.push(Check::new(step, Outcome::Skip));
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_and_verify() {
+        let dir = std::env::temp_dir().join(format!("mkramdisk-selftest-unit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let checks = write_and_verify(&dir);
+        assert!(passed(&checks), "{:?}", checks);
+        assert_eq!(fs::metadata(dir.join(PATTERN_FILE)).unwrap().len(), PATTERN_BYTES as u64);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let checks = write_and_verify(&dir);
+        assert!(matches!(checks[0].outcome, Outcome::Fail(_)));
+        assert_eq!(checks[2].outcome, Outcome::Skip);
+        assert!(!passed(&checks));
+    }
+}
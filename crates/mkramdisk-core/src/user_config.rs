@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// Defaults from the user's `config.toml`, used when the command line leaves them out.
+/// A `[profile.NAME]` table holds the same keys, and `--profile NAME` lays them over
+/// the top-level ones.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// Size used when none is given, e.g. "2G".
+    pub size: Option<String>,
+    /// Filesystem used when `-f` is not given, e.g. "hfs+".
+    #[serde(alias = "fs")]
+    pub filesystem: Option<String>,
+    /// Volume name used when none is given.
+    pub name: Option<String>,
+    /// Show detailed output, as `-v` does.
+    pub verbose: Option<bool>,
+    /// Mark every new disk protected, as `--protected` does.
+    pub protected: Option<bool>,
+    /// Options to remount every new volume with, as `--mount-options` takes them.
+    pub mount_options: Option<Vec<String>>,
+    /// Shell command run once a disk is created.
+    pub after_create: Option<String>,
+    /// Shell command run before a disk is ejected.
+    pub before_eject: Option<String>,
+    /// Where `backups` keeps its snapshots, instead of the default store.
+    pub backup_dir: Option<PathBuf>,
+    /// age recipients `backups save` encrypts snapshots to, as `--encrypt-to` takes them.
+    pub backup_recipients: Option<Vec<String>>,
+    /// age key files `backups restore` decrypts snapshots with, as `--identity` takes them.
+    pub backup_identities: Option<Vec<PathBuf>>,
+    /// Keychain item holding the age key backups are encrypted to and decrypted with,
+    /// as `--keychain-item` names it.
+    pub backup_keychain_item: Option<String>,
+    /// Experimental features to turn on, by name (see `mkramdisk features`).
+    pub features: Option<Vec<String>>,
+    /// Warn about deprecated forms on the command line (default true).
+    pub deprecation_warnings: Option<bool>,
+    /// hdiutil and diskutil commands allowed to run at once across every mkramdisk
+    /// process (see `pool`).
+    pub max_commands: Option<usize>,
+    /// mkramdiskd's settings. Only the top level has them.
+    pub daemon: Option<DaemonConfig>,
+    #[serde(default)]
+    pub profile: BTreeMap<String, UserConfig>,
+}
+
+/// Who may use mkramdiskd, from config.toml's `[daemon]` table. Without tokens, anyone
+/// the socket's permissions let connect may do anything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// The socket's permissions in octal, e.g. "0660" to let the group connect too
+    /// (default "0600": the owner only).
+    pub socket_mode: Option<String>,
+    /// Requests that create, eject or sync disks must carry this token.
+    pub manage_token: Option<String>,
+    /// A token for listing and inspecting disks only. With it set, those requests need
+    /// it or the manage token too.
+    pub read_token: Option<String>,
+    /// Requests each user (or the HTTP API) may send a minute (default 120; 0 for no
+    /// limit).
+    pub requests_per_minute: Option<u32>,
+    /// Where to log who created, ejected or synced disks, instead of
+    /// daemon-audit.log in the log directory.
+    pub audit_log: Option<PathBuf>,
+    /// Snapshot every disk into the backup store when the daemon stops (default false).
+    pub sync_on_stop: Option<bool>,
+    /// Eject every disk mkramdisk created when the daemon stops, rather than leaving
+    /// them attached for the next one (default false). Not after a sync that failed.
+    pub eject_on_stop: Option<bool>,
+}
+
+impl DaemonConfig {
+    pub fn socket_mode(&self) -> Result<u32, String> {
+        let Some(mode) = &self.socket_mode else {
+            return Ok(0o600);
+        };
+        u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| format!("socket_mode must be permissions in octal, such as \"0660\", not \"{}\"", mode))
+    }
+}
+
+impl UserConfig {
+    /// These defaults with the profile `name`'s own laid over them.
+    pub fn with_profile(&self, name: &str) -> Result<UserConfig, String> {
+        let Some(profile) = self.profile.get(name) else {
+            let known: Vec<&str> = self.profile.keys().map(String::as_str).collect();
+            return Err(match known.as_slice() {
+                [] => format!("No profile named '{}': config.toml has no [profile.*] tables", name),
+                _ => format!("No profile named '{}'\nProfiles: {}", name, known.join(", ")),
+            });
+        };
+        Ok(UserConfig {
+            size: profile.size.clone().or_else(|| self.size.clone()),
+            filesystem: profile.filesystem.clone().or_else(|| self.filesystem.clone()),
+            name: profile.name.clone().or_else(|| self.name.clone()),
+            verbose: profile.verbose.or(self.verbose),
+            protected: profile.protected.or(self.protected),
+            mount_options: profile.mount_options.clone().or_else(|| self.mount_options.clone()),
+            after_create: profile.after_create.clone().or_else(|| self.after_create.clone()),
+            before_eject: profile.before_eject.clone().or_else(|| self.before_eject.clone()),
+            backup_dir: profile.backup_dir.clone().or_else(|| self.backup_dir.clone()),
+            backup_recipients: profile.backup_recipients.clone().or_else(|| self.backup_recipients.clone()),
+            backup_identities: profile.backup_identities.clone().or_else(|| self.backup_identities.clone()),
+            backup_keychain_item: profile.backup_keychain_item.clone().or_else(|| self.backup_keychain_item.clone()),
+            features: profile.features.clone().or_else(|| self.features.clone()),
+            deprecation_warnings: profile.deprecation_warnings.or(self.deprecation_warnings),
+            max_commands: profile.max_commands.or(self.max_commands),
+            daemon: self.daemon.clone(),
+            profile: BTreeMap::new(),
+        })
+    }
+}
+
+/// Keys config.toml used to spell differently, old name first. The old names still
+/// load, but `migrate-state` rewrites them.
+pub const RENAMED_KEYS: &[(&str, &str)] = &[("fs", "filesystem")];
+
+/// `contents` with every renamed key, at the top level or in a profile, given its
+/// current name, and a note of each rename made. Only the keys themselves are
+/// rewritten, so comments, layout and strings that happen to mention an old name
+/// survive. A file that doesn't parse is left as it is, for `load` to report.
+pub fn upgrade(contents: &str) -> (String, Vec<String>) {
+    let Ok(document) = toml::de::DeTable::parse(contents) else {
+        return (contents.to_string(), Vec::new());
+    };
+    let mut renames = Vec::new();
+    renamed_keys(document.get_ref(), &mut renames);
+    if let Some(toml::de::DeValue::Table(profiles)) = document.get_ref().get("profile").map(|value| value.get_ref()) {
+        for profile in profiles.values() {
+            if let toml::de::DeValue::Table(profile) = profile.get_ref() {
+                renamed_keys(profile, &mut renames);
+            }
+        }
+    }
+    // Spliced in from the end, so the spans still ahead stay where they were
+    renames.sort_by_key(|(span, _)| span.start);
+    let mut upgraded = contents.to_string();
+    let mut changes = Vec::new();
+    for (span, (old, new)) in renames.into_iter().rev() {
+        upgraded.replace_range(span, new);
+        changes.push(format!("rename {} to {}", old, new));
+    }
+    changes.reverse();
+    (upgraded, changes)
+}
+
+// Where in the file each key of `table` with an old name is, and its renaming
+fn renamed_keys(table: &toml::de::DeTable, renames: &mut Vec<(std::ops::Range<usize>, &'static (&'static str, &'static str))>) {
+    for key in table.keys() {
+        if let Some(rename) = RENAMED_KEYS.iter().find(|(old, _)| key.get_ref() == old) {
+            renames.push((key.span(), rename));
+        }
+    }
+}
+
+fn parse(contents: &str) -> Result<UserConfig, String> {
+    let config: UserConfig = toml::from_str(contents).map_err(|e| e.to_string())?;
+    if let Some((name, _)) = config.profile.iter().find(|(_, profile)| !profile.profile.is_empty()) {
+        return Err(format!("profile '{}' cannot hold profiles of its own", name));
+    }
+    if let Some((name, _)) = config.profile.iter().find(|(_, profile)| profile.daemon.is_some()) {
+        return Err(format!("profile '{}' cannot hold [daemon] settings, which are mkramdiskd's own", name));
+    }
+    if let Some(daemon) = &config.daemon {
+        daemon.socket_mode().map_err(|e| format!("[daemon] {}", e))?;
+        if daemon.read_token.is_some() && daemon.manage_token.is_none() {
+            return Err("[daemon] read_token needs a manage_token too, or managing disks would need no token at all".to_string());
+        }
+    }
+    Ok(config)
+}
+
+/// `$MKRAMDISK_CONFIG`, else `paths::get()`'s config file.
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("MKRAMDISK_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    Some(crate::paths::get()?.config_file)
+}
+
+/// Load the user config. A missing file is the same as an empty one.
+pub fn load() -> Result<UserConfig, String> {
+    let Some(path) = path().filter(|path| path.exists()) else {
+        return Ok(UserConfig::default());
+    };
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user_config() {
+        let config: UserConfig = toml::from_str("size = \"2G\"").unwrap();
+        assert_eq!(config.size.as_deref(), Some("2G"));
+        assert!(config.filesystem.is_none() && config.name.is_none());
+
+        let config: UserConfig = toml::from_str(
+            "filesystem = \"hfs+\"\nname = \"Scratch\"\nverbose = true\nmount_options = [\"noatime\", \"nosuid\"]",
+        )
+        .unwrap();
+        assert_eq!(config.filesystem.as_deref(), Some("hfs+"));
+        assert_eq!(config.name.as_deref(), Some("Scratch"));
+        assert_eq!(config.verbose, Some(true));
+        assert_eq!(config.mount_options.unwrap(), ["noatime", "nosuid"]);
+        assert!(toml::from_str::<UserConfig>("colour = \"red\"").is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config = parse(
+            "size = \"1G\"\nverbose = true\n\n[profile.xcode]\nsize = \"8G\"\nfs = \"apfs\"\nname = \"DerivedData\"\nafter_create = \"echo ready\"\n",
+        )
+        .unwrap();
+        let xcode = config.with_profile("xcode").unwrap();
+        assert_eq!(xcode.size.as_deref(), Some("8G"));
+        assert_eq!(xcode.filesystem.as_deref(), Some("apfs"));
+        assert_eq!(xcode.name.as_deref(), Some("DerivedData"));
+        assert_eq!(xcode.after_create.as_deref(), Some("echo ready"));
+        // Anything the profile leaves out comes from the top level
+        assert_eq!(xcode.verbose, Some(true));
+        assert!(config.with_profile("rust").unwrap_err().contains("Profiles: xcode"));
+
+        assert!(parse("[profile.a.profile.b]\nsize = \"1G\"").is_err());
+        assert!(parse("[profile.a]\ncolour = \"red\"").is_err());
+    }
+
+    #[test]
+    fn test_daemon() {
+        let config = parse("[daemon]\nsocket_mode = \"0660\"\nmanage_token = \"m\"\nread_token = \"r\"\nrequests_per_minute = 30\n").unwrap();
+        let daemon = config.daemon.unwrap();
+        assert_eq!((daemon.socket_mode(), daemon.requests_per_minute), (Ok(0o660), Some(30)));
+        assert_eq!(DaemonConfig::default().socket_mode(), Ok(0o600));
+        assert!(parse("[daemon]\nsocket_mode = \"rw-rw----\"").unwrap_err().contains("octal"));
+        assert!(parse("[daemon]\nsocket_mode = \"01777\"").is_err());
+        assert!(parse("[daemon]\nread_token = \"r\"").unwrap_err().contains("manage_token"));
+        assert!(parse("[profile.a.daemon]\nmanage_token = \"m\"").is_err());
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let old = "# scratch space\nfs = \"hfs+\"\nsize = \"2G\"\n\n[profile.xcode]\n  fs=\"apfs\" # fast\nfsck = \"x\"\n";
+        let (upgraded, changes) = upgrade(old);
+        assert_eq!(upgraded, "# scratch space\nfilesystem = \"hfs+\"\nsize = \"2G\"\n\n[profile.xcode]\n  filesystem=\"apfs\" # fast\nfsck = \"x\"\n");
+        assert_eq!(changes, ["rename fs to filesystem", "rename fs to filesystem"]);
+        assert_eq!(upgrade(&upgraded).1.len(), 0);
+
+        // A key spelled the old way inside a string is part of the string
+        let old = "after_create = \"\"\"\nfs = \"x\"\n\"\"\"\n[profile.b]\n\"fs\" = \"apfs\"\n";
+        let (upgraded, changes) = upgrade(old);
+        assert_eq!(upgraded, "after_create = \"\"\"\nfs = \"x\"\n\"\"\"\n[profile.b]\nfilesystem = \"apfs\"\n");
+        assert_eq!(changes, ["rename fs to filesystem"]);
+        assert_eq!(upgrade("fs = ").0, "fs = ");
+    }
+}
@@ -0,0 +1,78 @@
+//! The audit log: a JSON line for each request to create, eject or sync disks, carried
+//! out or not, with who sent it.
+//!
+//! ```text
+//! {"time":"2026-10-15 09:30:00 UTC","client":{"via":"socket","uid":501,"gid":20,"pid":4242},"request":{"command":"eject","target":"Scratch","force":false},"ok":true}
+//! ```
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use mkramdisk_core::{format_timestamp, paths};
+
+use crate::clients::Client;
+use crate::protocol::{Request, Response};
+
+/// The log's name, in `paths::get()`'s log directory.
+pub const AUDIT_FILE: &str = "daemon-audit.log";
+
+pub fn default_path() -> Option<PathBuf> {
+    Some(paths::get()?.log_dir.join(AUDIT_FILE))
+}
+
+/// Add a line for `request` from `client` and what came of it. The request's token, if
+/// it had one, is never part of it.
+pub fn record(path: &Path, client: &Client, request: &Request, response: &Response) -> io::Result<()> {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    let mut line = json!({
+        "time": format_timestamp(seconds),
+        "client": client.to_json(),
+        "request": request,
+        "ok": response.ok,
+    });
+    if let Some(error) = &response.error {
+        line["error"] = json!(error);
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = open(path)?;
+    writeln!(file, "{}", line)
+}
+
+// The log, readable by its owner only, since it says who did what
+fn open(path: &Path) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::Peer;
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!("mkramdiskd-audit-{}/{}", std::process::id(), AUDIT_FILE));
+        let client = Client::Socket(Some(Peer { uid: 501, gid: 20, pid: Some(4242) }));
+        let eject = Request::Eject { target: "Scratch".to_string(), force: false };
+        record(&path, &client, &eject, &Response::ok(json!({ "result": "ok" }))).unwrap();
+        record(&path, &Client::Http, &eject, &Response::error("No RAM disk named 'Scratch'".to_string())).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["client"], json!({ "via": "socket", "uid": 501, "gid": 20, "pid": 4242 }));
+        assert_eq!((&lines[0]["request"]["command"], &lines[0]["ok"]), (&json!("eject"), &json!(true)));
+        assert!(lines[0].get("error").is_none());
+        assert_eq!((&lines[1]["client"]["via"], &lines[1]["error"]), (&json!("http"), &json!("No RAM disk named 'Scratch'")));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+//! The other end of mkramdiskd's socket, for the command line and anything else that
+//! talks to the daemon.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::protocol::{Envelope, Request, Response};
+
+/// Send `request` to the daemon listening on `socket` and return its result.
+pub fn request(socket: &Path, request: &Request) -> Result<Value, String> {
+    request_with_token(socket, request, None)
+}
+
+/// `request`, carrying `token` for a daemon that wants one.
+pub fn request_with_token(socket: &Path, request: &Request, token: Option<&str>) -> Result<Value, String> {
+    let unreachable = |e: std::io::Error| format!("Cannot reach mkramdiskd at {}: {}", socket.display(), e);
+    let mut stream = UnixStream::connect(socket).map_err(unreachable)?;
+    let envelope = Envelope { request: request.clone(), token: token.map(str::to_string) };
+    let line = serde_json::to_string(&envelope).map_err(|e| e.to_string())?;
+    writeln!(stream, "{}", line).map_err(unreachable)?;
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).map_err(unreachable)?;
+    if response.is_empty() {
+        return Err("mkramdiskd closed the connection without answering".to_string());
+    }
+    serde_json::from_str::<Response>(&response).map_err(|e| format!("Invalid response from mkramdiskd: {}", e))?.into_result()
+}
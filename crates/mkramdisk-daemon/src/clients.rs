@@ -0,0 +1,156 @@
+//! Who sent a request, and how many they have sent of late.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde_json::{json, Value};
+
+/// Requests a client may send a minute when config.toml doesn't say.
+pub const DEFAULT_PER_MINUTE: u32 = 120;
+
+/// The process at the other end of the socket, as the kernel reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    /// Not every system says which process it is.
+    pub pid: Option<i32>,
+}
+
+/// Where a request came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Client {
+    /// The socket, with its peer unless the system wouldn't say.
+    Socket(Option<Peer>),
+    /// The HTTP API, whose clients are only ever this machine's and can't be told apart.
+    Http,
+}
+
+impl Client {
+    /// What the client's requests are counted under: its user, for the socket.
+    pub fn key(&self) -> String {
+        match self {
+            Client::Socket(Some(peer)) => format!("uid {}", peer.uid),
+            Client::Socket(None) => "an unknown user".to_string(),
+            Client::Http => "HTTP".to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            Client::Socket(Some(peer)) => json!({ "via": "socket", "uid": peer.uid, "gid": peer.gid, "pid": peer.pid }),
+            Client::Socket(None) => json!({ "via": "socket" }),
+            Client::Http => json!({ "via": "http" }),
+        }
+    }
+}
+
+/// The credentials of the process at the other end of `stream`: SO_PEERCRED on Linux,
+/// getpeereid elsewhere (with LOCAL_PEERPID for the process on macOS).
+#[cfg(unix)]
+pub fn peer(stream: &std::os::unix::net::UnixStream) -> std::io::Result<Peer> {
+    use std::os::unix::io::AsRawFd;
+    let fd = stream.as_raw_fd();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+        let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let pointer = (&mut credentials as *mut libc::ucred).cast();
+        if unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, pointer, &mut length) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Peer { uid: credentials.uid, gid: credentials.gid, pid: Some(credentials.pid) })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let (mut uid, mut gid) = (0, 0);
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Peer { uid, gid, pid: peer_pid(fd) })
+    }
+}
+
+#[cfg(target_vendor = "apple")]
+fn peer_pid(fd: std::os::unix::io::RawFd) -> Option<i32> {
+    let mut pid: libc::pid_t = 0;
+    let mut length = std::mem::size_of::<libc::pid_t>() as libc::socklen_t;
+    let pointer = (&mut pid as *mut libc::pid_t).cast();
+    (unsafe { libc::getsockopt(fd, libc::SOL_LOCAL, libc::LOCAL_PEERPID, pointer, &mut length) } == 0).then_some(pid)
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android", target_vendor = "apple"))))]
+fn peer_pid(_fd: std::os::unix::io::RawFd) -> Option<i32> {
+    None
+}
+
+/// How many requests each client may send: `per_minute` a minute, in bursts of up to
+/// that many. None is no limit.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    per_minute: Option<u32>,
+    // Each client's requests still allowed now, and when that was worked out
+    allowances: HashMap<String, (f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// A limit of `per_minute` requests a minute; 0 is no limit.
+    pub fn new(per_minute: u32) -> Self {
+        Self { per_minute: (per_minute > 0).then_some(per_minute), allowances: HashMap::new() }
+    }
+
+    /// Count a request from `client`, refusing it if the client is over the limit.
+    pub fn check(&mut self, client: &Client) -> Result<(), String> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&mut self, client: &Client, now: Instant) -> Result<(), String> {
+        let Some(per_minute) = self.per_minute else {
+            return Ok(());
+        };
+        let limit = f64::from(per_minute);
+        let (allowance, since) = self.allowances.entry(client.key()).or_insert((limit, now));
+        *allowance = (*allowance + now.saturating_duration_since(*since).as_secs_f64() * limit / 60.0).min(limit);
+        *since = now;
+        if *allowance < 1.0 {
+            return Err(format!("Too many requests from {}: at most {} a minute", client.key(), per_minute));
+        }
+        *allowance -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter() {
+        let alice = Client::Socket(Some(Peer { uid: 501, gid: 20, pid: Some(42) }));
+        let bob = Client::Socket(Some(Peer { uid: 502, gid: 20, pid: None }));
+        let mut limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.check_at(&alice, start).is_ok());
+        assert!(limiter.check_at(&alice, start).is_ok());
+        assert_eq!(limiter.check_at(&alice, start).unwrap_err(), "Too many requests from uid 501: at most 2 a minute");
+        // Each client has an allowance of its own, which comes back over the minute
+        assert!(limiter.check_at(&bob, start).is_ok());
+        assert!(limiter.check_at(&alice, start + Duration::from_secs(30)).is_ok());
+        assert!(limiter.check_at(&alice, start + Duration::from_secs(30)).is_err());
+
+        let mut unlimited = RateLimiter::new(0);
+        assert!((0..1000).all(|_| unlimited.check(&alice).is_ok()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_peer() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let peer = peer(&ours).unwrap();
+        assert_eq!(peer.uid, unsafe { libc::getuid() });
+        #[cfg(target_os = "linux")]
+        assert_eq!(peer.pid, Some(std::process::id() as i32));
+        drop(theirs);
+    }
+}
@@ -0,0 +1,224 @@
+//! mkramdiskd's requests over HTTP, for clients that can't open a Unix socket. Built with
+//! `--features http`, `mkramdiskd --http 127.0.0.1:7878` also answers
+//!
+//! ```text
+//! POST /v1/request HTTP/1.1
+//! Authorization: Bearer <token>
+//! Content-Type: application/json
+//!
+//! {"command": "list"}
+//! ```
+//!
+//! with the response the socket would give as the body. Only loopback addresses are
+//! served, and each connection carries one request. Every request needs a token, even
+//! where the socket would take one without: anything on the machine can connect here.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::clients::Client;
+use crate::protocol::{serve as serve_request, Context, Refusal, Request, Response};
+
+/// Where requests are sent.
+pub const ENDPOINT: &str = "/v1/request";
+
+// The most a request may be, headers and all, and how long a client may take to send it
+const MAX_REQUEST: u64 = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listen on `address`, which has to be a loopback address such as 127.0.0.1:7878 or
+/// localhost:7878.
+pub fn bind(address: &str) -> Result<TcpListener, String> {
+    let resolved: Vec<_> = address.to_socket_addrs().map_err(|e| format!("Invalid address {}: {}", address, e))?.collect();
+    if resolved.is_empty() || resolved.iter().any(|resolved| !resolved.ip().is_loopback()) {
+        return Err(format!("The HTTP API listens on localhost only, and {} is not a loopback address", address));
+    }
+    TcpListener::bind(&resolved[..]).map_err(|e| format!("Failed to listen on {}: {}", address, e))
+}
+
+/// Answer the requests on `listener` carrying a token `context` grants their scope, for
+/// as long as it accepts connections, one at a time with anything else holding `context`.
+pub fn serve(listener: TcpListener, context: Arc<Mutex<Context>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Warning: failed to accept an HTTP connection: {}", e);
+                continue;
+            }
+        };
+        let context = Arc::clone(&context);
+        thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, &context) {
+                eprintln!("Warning: an HTTP connection failed: {}", e);
+            }
+        });
+    }
+}
+
+fn serve_connection(stream: TcpStream, context: &Mutex<Context>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let (status, response) = match read_request(&mut BufReader::new(stream.take(MAX_REQUEST))) {
+        Ok(request) => answer(&request, context),
+        Err((status, e)) => (status, Response::error(e)),
+    };
+    let body = serde_json::to_string(&response)?;
+    let challenge = if status == 401 { "WWW-Authenticate: Bearer\r\n" } else { "" };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        challenge,
+        body
+    )
+}
+
+// The parts of an HTTP request that matter here
+#[derive(Debug, Default)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+// Read one request, or the status and reason to turn it away with
+fn read_request(reader: &mut impl BufRead) -> Result<HttpRequest, (u16, String)> {
+    let malformed = |e: io::Error| (400, format!("Malformed HTTP request: {}", e));
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(malformed)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err((400, "Malformed HTTP request line".to_string()));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err((400, format!("Unsupported HTTP version: {}", version)));
+    }
+    let mut request = HttpRequest { method: method.to_string(), path: path.to_string(), ..HttpRequest::default() };
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(malformed)? == 0 {
+            return Err((400, "The request ended inside its headers".to_string()));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err((400, format!("Malformed header: {}", header)));
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.trim().parse().map_err(|_| (400, format!("Invalid Content-Length: {}", value.trim())))?,
+            "authorization" => request.authorization = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    if length > MAX_REQUEST {
+        return Err((413, format!("Requests are at most {} bytes", MAX_REQUEST)));
+    }
+    request.body = vec![0; length as usize];
+    reader.read_exact(&mut request.body).map_err(malformed)?;
+    Ok(request)
+}
+
+// The status and response for a request read whole
+fn answer(request: &HttpRequest, context: &Mutex<Context>) -> (u16, Response) {
+    let mut context = context.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(token) = request.authorization.as_deref().and_then(|value| value.strip_prefix("Bearer ")) else {
+        return (401, Response::error("A token is required, as Authorization: Bearer <token>".to_string()));
+    };
+    if request.path != ENDPOINT {
+        return (404, Response::error(format!("No such endpoint: {} (requests go to POST {})", request.path, ENDPOINT)));
+    }
+    if request.method != "POST" {
+        return (405, Response::error(format!("Requests go to POST {}", ENDPOINT)));
+    }
+    let parsed = match serde_json::from_slice::<Request>(&request.body) {
+        Ok(parsed) => parsed,
+        Err(e) => return (400, Response::error(format!("Invalid request: {}", e))),
+    };
+    match serve_request(&mut context, &Client::Http, &parsed, Some(token.trim())) {
+        Ok(response) => (200, response),
+        Err((Refusal::Token, e)) => (401, Response::error(e)),
+        Err((Refusal::Scope, e)) => (403, Response::error(e)),
+        Err((Refusal::RateLimit, e)) => (429, Response::error(e)),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(text: &str) -> Result<HttpRequest, (u16, String)> {
+        read_request(&mut text.as_bytes())
+    }
+
+    #[test]
+    fn test_read_request() {
+        let read = request("POST /v1/request HTTP/1.1\r\nAuthorization: Bearer s3cret\r\ncontent-length: 19\r\n\r\n{\"command\": \"ping\"}").unwrap();
+        assert_eq!((read.method.as_str(), read.path.as_str(), read.authorization.as_deref()), ("POST", ENDPOINT, Some("Bearer s3cret")));
+        assert_eq!(read.body, b"{\"command\": \"ping\"}");
+        assert_eq!(request("POST /v1/request HTTP/1.1\r\nContent-Length: 20\r\n\r\n{}").unwrap_err().0, 400);
+        assert_eq!(request("POST /v1/request HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n").unwrap_err().0, 413);
+        assert_eq!(request("POST /v1/request SPDY/3\r\n\r\n").unwrap_err().0, 400);
+        assert_eq!(request("POST /v1/request HTTP/1.1\r\nHost").unwrap_err().0, 400);
+    }
+
+    #[test]
+    fn test_answer() {
+        use crate::protocol::Auth;
+        let auth = Auth { manage_token: Some("s3cret".to_string()), read_token: Some("peek".to_string()) };
+        let context = Mutex::new(Context { auth, ..Context::default() });
+        let send = |authorization: Option<&str>, method: &str, path: &str, body: &str| {
+            let request = HttpRequest {
+                method: method.to_string(),
+                path: path.to_string(),
+                authorization: authorization.map(str::to_string),
+                body: body.as_bytes().to_vec(),
+            };
+            answer(&request, &context)
+        };
+        let ping = |authorization, method, path| send(authorization, method, path, r#"{"command": "ping"}"#);
+        let (status, response) = ping(Some("Bearer s3cret"), "POST", ENDPOINT);
+        assert_eq!(status, 200);
+        assert_eq!(response.into_result().unwrap()["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(ping(None, "POST", ENDPOINT).0, 401);
+        assert_eq!(ping(Some("Bearer s3cre"), "POST", ENDPOINT).0, 401);
+        assert_eq!(ping(Some("s3cret"), "POST", ENDPOINT).0, 401);
+        assert_eq!(ping(Some("Bearer s3cret"), "GET", ENDPOINT).0, 405);
+        assert_eq!(ping(Some("Bearer s3cret"), "POST", "/").0, 404);
+        assert_eq!(ping(Some("Bearer peek"), "POST", ENDPOINT).0, 200);
+        assert_eq!(send(Some("Bearer peek"), "POST", ENDPOINT, r#"{"command": "eject", "target": "Scratch"}"#).0, 403);
+        // No token is no token here, even for a scope the socket would grant without one
+        let open = Mutex::new(Context::default());
+        let request = HttpRequest { method: "POST".to_string(), path: ENDPOINT.to_string(), body: br#"{"command": "ping"}"#.to_vec(), ..HttpRequest::default() };
+        assert_eq!(answer(&request, &open).0, 401);
+    }
+
+    #[test]
+    fn test_bind() {
+        assert!(bind("0.0.0.0:0").unwrap_err().contains("localhost only"));
+        assert!(bind("127.0.0.1:0").is_ok());
+    }
+}
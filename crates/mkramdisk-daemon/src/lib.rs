@@ -0,0 +1,42 @@
+//! mkramdiskd, which serves mkramdisk's operations to other processes on a Unix
+//! socket: one JSON request per line in, one JSON response per line out.
+//!
+//! ```text
+//! → {"command": "create", "size": "2G", "name": "Scratch"}
+//! ← {"ok": true, "result": {"result": "ok", "device": "/dev/disk5", "mount_point": "/Volumes/Scratch", ...}}
+//! → {"command": "eject", "target": "Scratch"}
+//! ← {"ok": true, "result": {"result": "ok"}}
+//! ```
+//!
+//! `protocol` has the requests and what each does, `server` the socket and `client`
+//! the other end of it. `clients` says who sent a request and limits how often they
+//! may, and `audit` logs the requests that change disks. `schedule` ejects and snapshots
+//! disks as their TTLs and sync schedules come due. Built with `--features http`, `http`
+//! serves the same requests over HTTP on localhost.
+
+use std::env;
+use std::path::PathBuf;
+
+use mkramdisk_core::paths;
+
+pub mod audit;
+#[cfg(unix)]
+pub mod client;
+pub mod clients;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod protocol;
+pub mod schedule;
+#[cfg(unix)]
+pub mod server;
+
+/// The socket's name, beside the state file.
+pub const SOCKET_FILE: &str = "daemon.sock";
+
+/// `$MKRAMDISK_SOCKET`, else `SOCKET_FILE` in `paths::get()`'s state directory.
+pub fn socket_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("MKRAMDISK_SOCKET") {
+        return Some(PathBuf::from(path));
+    }
+    Some(paths::get()?.state_dir.join(SOCKET_FILE))
+}
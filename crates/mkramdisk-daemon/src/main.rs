@@ -0,0 +1,141 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use mkramdisk_core::{paths, provider};
+
+fn main() {
+    if let Err(e) = run(env::args_os().skip(1).collect()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn print_usage() {
+    println!(r#"
+Usage: mkramdiskd [--socket PATH] [--http ADDRESS] [--state-dir DIR] [-b BACKEND]
+
+Serve mkramdisk's commands to other processes on a Unix socket, one JSON request and
+one JSON response per line, e.g. {{"command": "list"}}.
+
+config.toml's [daemon] table sets the socket's permissions (socket_mode, default
+"0600"), a manage_token that creating, ejecting and syncing disks need, and a
+read_token that lets a client only list and inspect them; $MKRAMDISKD_TOKEN
+overrides the manage token. A request carries its token beside its command:
+{{"command": "eject", "target": "Scratch", "token": "..."}}. Each user may send
+requests_per_minute requests a minute (default 120), and every request to create,
+eject or sync disks is logged with who sent it to audit_log (default:
+daemon-audit.log in the log directory).
+
+SIGHUP (or mkramdisk daemon reload) reads config.toml again. SIGTERM (or mkramdisk
+daemon stop) stops the daemon, leaving the disks attached unless sync_on_stop or
+eject_on_stop in [daemon] says to snapshot or eject them first.
+
+A create request may carry a "ttl", after which the disk is ejected, and a
+"sync_every", how often it is snapshotted into the backup store, e.g. "2h" and
+"15m". These are kept in the state file and on each disk, so when the daemon starts
+it reconciles the state file with the disks attached, carries on with each timer
+where it left off, and reports what it recovered on stderr.
+
+Options:
+    --socket PATH      Listen on PATH (default: daemon.sock beside the state file, or
+                       $MKRAMDISK_SOCKET)
+    --http ADDRESS     Also answer POST /v1/request on ADDRESS, a loopback address
+                       such as 127.0.0.1:7878, for requests carrying a token as
+                       "Authorization: Bearer <token>" (only when built with
+                       --features http)
+    --state-dir DIR    Keep the state file, logs and backups in DIR
+    -b, --backend B    Device provider to use: {} (default: {})
+    -h, --help         Show this help message
+    -V, --version      Show the version
+"#, provider::BACKENDS.join(", "), provider::default_backend());
+}
+
+fn run(args: Vec<OsString>) -> Result<(), String> {
+    let mut socket = None;
+    let mut http = None;
+    let mut backend = provider::default_backend().to_string();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |option: &str| args.next().ok_or_else(|| format!("{} requires a value", option));
+        match arg.to_str() {
+            Some("--socket") => socket = Some(PathBuf::from(value("--socket")?)),
+            Some("--http") => http = Some(value("--http")?.into_string().map_err(|_| "The address is not valid UTF-8".to_string())?),
+            Some("--state-dir") => {
+                let dir = PathBuf::from(value("--state-dir")?);
+                // config.toml is the user's settings rather than state, so it stays put
+                let config_file = paths::get().map_or_else(|| dir.join("config.toml"), |paths| paths.config_file);
+                paths::set(paths::StatePaths { config_file, ..paths::StatePaths::in_dir(&dir) });
+            }
+            Some("-b" | "--backend") => {
+                backend = value("--backend")?.into_string().map_err(|_| "The backend is not valid UTF-8".to_string())?;
+                if !provider::BACKENDS.contains(&backend.as_str()) {
+                    return Err(format!("Unsupported backend: {}\nSupported backends: {}", backend, provider::BACKENDS.join(", ")));
+                }
+            }
+            Some("-h" | "--help") => {
+                print_usage();
+                return Ok(());
+            }
+            Some("-V" | "--version") => {
+                println!("mkramdiskd {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            _ => return Err(format!("Unknown option: {}", arg.to_string_lossy())),
+        }
+    }
+    serve(socket, http, backend)
+}
+
+#[cfg(unix)]
+fn serve(socket: Option<PathBuf>, http: Option<String>, backend: String) -> Result<(), String> {
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use mkramdisk_daemon::{protocol, schedule, server, socket_path};
+    let socket = socket.or_else(socket_path).ok_or("No socket: pass --socket or set MKRAMDISK_SOCKET")?;
+    let context = protocol::Context::load(backend)?;
+    match schedule::recover(&context.backend) {
+        Ok(recovery) => recovery.report(schedule::now()).iter().for_each(|line| eprintln!("{}", line)),
+        Err(e) => eprintln!("Warning: failed to reconcile the state file with the attached disks: {}", e),
+    }
+    let listener = server::bind(&socket, context.defaults.daemon.clone().unwrap_or_default().socket_mode()?)?;
+    let context = Arc::new(Mutex::new(context));
+    if let Some(address) = http {
+        serve_http(&address, Arc::clone(&context))?;
+    }
+    server::handle_signals();
+    std::thread::spawn({
+        let context = Arc::clone(&context);
+        move || schedule::run(context)
+    });
+    eprintln!("mkramdiskd {} listening on {}", env!("CARGO_PKG_VERSION"), socket.display());
+    server::serve(listener, Arc::clone(&context))?;
+    
+    eprintln!("mkramdiskd stopping");
+    let _ = fs::remove_file(&socket);
+    context.lock().unwrap_or_else(|e| e.into_inner()).shut_down();
+    Ok(())
+}
+
+// Answer HTTP requests on `address` from another thread
+#[cfg(all(unix, feature = "http"))]
+fn serve_http(address: &str, context: std::sync::Arc<std::sync::Mutex<mkramdisk_daemon::protocol::Context>>) -> Result<(), String> {
+    use mkramdisk_daemon::http;
+    if context.lock().unwrap_or_else(|e| e.into_inner()).auth.manage_token.is_none() {
+        return Err("--http needs a token: set manage_token in config.toml's [daemon] table, or MKRAMDISKD_TOKEN".to_string());
+    }
+    let listener = http::bind(address)?;
+    eprintln!("mkramdiskd {} listening on http://{}{}", env!("CARGO_PKG_VERSION"), address, http::ENDPOINT);
+    std::thread::spawn(move || http::serve(listener, context));
+    Ok(())
+}
+
+#[cfg(all(unix, not(feature = "http")))]
+fn serve_http(_address: &str, _context: std::sync::Arc<std::sync::Mutex<mkramdisk_daemon::protocol::Context>>) -> Result<(), String> {
+    Err("This mkramdiskd was built without the HTTP API (--features http)".to_string())
+}
+
+#[cfg(not(unix))]
+fn serve(_socket: Option<PathBuf>, _http: Option<String>, _backend: String) -> Result<(), String> {
+    Err("mkramdiskd listens on a Unix socket, which this system does not have".to_string())
+}
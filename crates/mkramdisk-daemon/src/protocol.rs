@@ -0,0 +1,523 @@
+//! The requests mkramdiskd answers. Each does what the mkramdisk command of the same
+//! name does and answers with the fields that command's `--json` output has. A request
+//! may carry a token beside its command, `{"command": "eject", "target": "Scratch",
+//! "token": "..."}`, for the scope `Auth` grants it.
+
+use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use mkramdisk_core::registry::Timers;
+use mkramdisk_core::{
+    backup, create_ramdisk, disks, eject, formats, parse_size, sanitize_volume_name, user_config, validate_filesystem,
+    validate_tag, validate_volume_name, check_volume_name, Config,
+};
+
+use crate::audit;
+use crate::clients::{self, Client, RateLimiter};
+use crate::schedule;
+
+/// One request, as `{"command": "<name>", ...}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Whether the daemon is there, and its version.
+    Ping,
+    List {
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    Status {
+        name: String,
+    },
+    Info {
+        name: String,
+    },
+    /// A size and filesystem left out come from config.toml, and its profile `profile`.
+    Create {
+        #[serde(default)]
+        size: Option<String>,
+        name: String,
+        #[serde(default)]
+        filesystem: Option<String>,
+        #[serde(default)]
+        profile: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        /// How long until the daemon ejects it, e.g. "2h".
+        #[serde(default)]
+        ttl: Option<String>,
+        /// How often the daemon snapshots it into the backup store, e.g. "15m".
+        #[serde(default)]
+        sync_every: Option<String>,
+    },
+    /// A volume name, mount point or device, as `mkramdisk eject` takes it.
+    Eject {
+        target: String,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Snapshot every mounted disk with all of `tags` into the backup store.
+    Sync {
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        store: Option<PathBuf>,
+    },
+    /// Read config.toml again, as SIGHUP does.
+    Reload,
+    /// Stop the daemon, as SIGTERM does, doing with the disks what config.toml says.
+    Stop,
+}
+
+/// What a request may do: only look at disks, or change them too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Read,
+    Manage,
+}
+
+impl Request {
+    /// The scope it takes to carry the request out.
+    pub fn scope(&self) -> Scope {
+        match self {
+            Request::Ping | Request::List { .. } | Request::Status { .. } | Request::Info { .. } => Scope::Read,
+            Request::Create { .. } | Request::Eject { .. } | Request::Sync { .. } | Request::Reload | Request::Stop => Scope::Manage,
+        }
+    }
+
+    pub fn command(&self) -> &'static str {
+        match self {
+            Request::Ping => "ping",
+            Request::List { .. } => "list",
+            Request::Status { .. } => "status",
+            Request::Info { .. } => "info",
+            Request::Create { .. } => "create",
+            Request::Eject { .. } => "eject",
+            Request::Sync { .. } => "sync",
+            Request::Reload => "reload",
+            Request::Stop => "stop",
+        }
+    }
+}
+
+/// Why a request was turned away before it was carried out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// It carried no token, or not a valid one.
+    Token,
+    /// Its token doesn't grant the request's scope.
+    Scope,
+    /// Its client has sent too many requests of late.
+    RateLimit,
+}
+
+/// A request as it is sent, with the token it carries, if any.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(flatten)]
+    pub request: Request,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// The tokens config.toml's `[daemon]` table sets. Where it sets none, a scope needs no
+/// token: the socket's permissions already decided who may connect.
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    pub manage_token: Option<String>,
+    pub read_token: Option<String>,
+}
+
+impl Auth {
+    pub fn new(config: &user_config::DaemonConfig) -> Self {
+        Self { manage_token: config.manage_token.clone(), read_token: config.read_token.clone() }
+    }
+
+    /// The scope `token` grants, or none for a token that isn't one of these.
+    pub fn granted(&self, token: Option<&str>) -> Option<Scope> {
+        let matches = |expected: &Option<String>| token.zip(expected.as_deref()).is_some_and(|(token, expected)| same(token, expected));
+        if matches(&self.manage_token) || (token.is_none() && self.manage_token.is_none()) {
+            return Some(Scope::Manage);
+        }
+        if matches(&self.read_token) || (token.is_none() && self.read_token.is_none()) {
+            return Some(Scope::Read);
+        }
+        None
+    }
+
+    /// Refuse `request` unless `token` grants its scope.
+    pub fn check(&self, request: &Request, token: Option<&str>) -> Result<(), (Refusal, String)> {
+        match self.granted(token) {
+            None if token.is_none() => Err((Refusal::Token, "A token is required".to_string())),
+            None => Err((Refusal::Token, "The token is not valid".to_string())),
+            Some(scope) if scope < request.scope() => Err((Refusal::Scope, format!("'{}' changes disks, and needs the manage token", request.command()))),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+// Compare tokens in the same time wherever they differ
+fn same(presented: &str, token: &str) -> bool {
+    presented.len() == token.len() && presented.bytes().zip(token.bytes()).fold(0, |differ, (a, b)| differ | (a ^ b)) == 0
+}
+
+/// What came of a request: `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    pub fn ok(result: Value) -> Self {
+        Self { ok: true, result: Some(result), error: None }
+    }
+
+    pub fn error(error: String) -> Self {
+        Self { ok: false, result: None, error: Some(error) }
+    }
+
+    pub fn into_result(self) -> Result<Value, String> {
+        match self {
+            Self { ok: true, result, .. } => Ok(result.unwrap_or(Value::Null)),
+            Self { error, .. } => Err(error.unwrap_or_else(|| "The daemon gave no reason".to_string())),
+        }
+    }
+}
+
+/// What every request is carried out with.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub backend: String,
+    /// config.toml as it was when the daemon read it.
+    pub defaults: user_config::UserConfig,
+    pub auth: Auth,
+    pub limiter: RateLimiter,
+    /// Where requests that change disks are logged, if anywhere.
+    pub audit_log: Option<PathBuf>,
+}
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Have the daemon stop once it has answered the requests in hand. Safe to call from a
+/// signal handler.
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+impl Context {
+    /// What config.toml says, for disks on `backend`. $MKRAMDISKD_TOKEN stands in for
+    /// its manage token.
+    pub fn load(backend: String) -> Result<Self, String> {
+        let defaults = user_config::load()?;
+        let daemon = defaults.daemon.clone().unwrap_or_default();
+        let mut auth = Auth::new(&daemon);
+        if let Some(token) = env::var("MKRAMDISKD_TOKEN").ok().filter(|token| !token.is_empty()) {
+            auth.manage_token = Some(token);
+        }
+        let limiter = RateLimiter::new(daemon.requests_per_minute.unwrap_or(clients::DEFAULT_PER_MINUTE));
+        let audit_log = daemon.audit_log.clone().or_else(audit::default_path);
+        Ok(Self { backend, defaults, auth, limiter, audit_log })
+    }
+
+    /// Read config.toml again: its defaults, profiles, tokens, limits and what to do on
+    /// stopping. A file that doesn't load leaves everything as it was, and the socket
+    /// keeps the permissions it was made with.
+    pub fn reload(&mut self) -> Result<(), String> {
+        *self = Self::load(self.backend.clone())?;
+        Ok(())
+    }
+
+    /// The backup store config.toml names, else the default one.
+    pub fn backup_store(&self) -> Result<PathBuf, String> {
+        self.defaults.backup_dir.clone().or_else(backup::default_root).ok_or_else(|| "No backup store: set backup_dir in config.toml".to_string())
+    }
+
+    /// Do what config.toml says with the disks as the daemon stops: snapshot them
+    /// (sync_on_stop), then eject them (eject_on_stop). By default they stay attached,
+    /// as they are, for the next daemon. What was done, or failed, goes to stderr.
+    pub fn shut_down(&self) {
+        let daemon = self.defaults.daemon.clone().unwrap_or_default();
+        let mut synced = true;
+        if daemon.sync_on_stop.unwrap_or(false) {
+            match run(&Request::Sync { tags: Vec::new(), store: None }, self) {
+                Ok(snapshots) => {
+                    let snapshots = snapshots.as_array().cloned().unwrap_or_default();
+                    for failed in snapshots.iter().filter(|snapshot| snapshot.get("error").is_some()) {
+                        eprintln!("Warning: failed to sync '{}': {}", failed["name"].as_str().unwrap_or_default(), failed["error"].as_str().unwrap_or_default());
+                        synced = false;
+                    }
+                    eprintln!("Synced {} disks", snapshots.len());
+                }
+                Err(e) => {
+                    eprintln!("Warning: failed to sync the disks: {}", e);
+                    synced = false;
+                }
+            }
+        }
+        if daemon.eject_on_stop.unwrap_or(false) {
+            if !synced {
+                eprintln!("Warning: leaving the disks attached, since they didn't all sync");
+                return;
+            }
+            match disks::eject_all(&Config { backend: self.backend.clone(), summary: false, ..Config::default() }) {
+                Ok(ejected) => eprintln!("Ejected {} disks", ejected),
+                Err(e) => eprintln!("Warning: failed to eject the disks: {}", e),
+            }
+        }
+    }
+}
+
+/// Carry out `request` from `client`, unless its token doesn't grant its scope or the
+/// client is over its rate limit. A request that would change disks goes in the audit
+/// log either way.
+pub fn serve(context: &mut Context, client: &Client, request: &Request, token: Option<&str>) -> Result<Response, (Refusal, String)> {
+    let outcome = context.limiter.check(client).map_err(|e| (Refusal::RateLimit, e));
+    let outcome = outcome.and_then(|()| context.auth.check(request, token)).map(|()| match request {
+        Request::Reload => match context.reload() {
+            Ok(()) => Response::ok(json!({ "result": "ok" })),
+            Err(e) => Response::error(e),
+        },
+        Request::Stop => {
+            stop();
+            Response::ok(json!({ "result": "ok" }))
+        }
+        _ => handle(request, context),
+    });
+    if request.scope() == Scope::Manage
+        && let Some(path) = &context.audit_log
+    {
+        let response = match &outcome {
+            Ok(response) => response.clone(),
+            Err((_, e)) => Response::error(e.clone()),
+        };
+        if let Err(e) = audit::record(path, client, request, &response) {
+            eprintln!("Warning: failed to write to {}: {}", path.display(), e);
+        }
+    }
+    outcome
+}
+
+/// Carry out `request`.
+pub fn handle(request: &Request, context: &Context) -> Response {
+    match run(request, context) {
+        Ok(result) => Response::ok(result),
+        Err(e) => Response::error(e),
+    }
+}
+
+fn run(request: &Request, context: &Context) -> Result<Value, String> {
+    let config = Config { backend: context.backend.clone(), summary: false, ..Config::default() };
+    match request {
+        Request::Ping => Ok(json!({ "version": env!("CARGO_PKG_VERSION") })),
+        Request::List { tags } => {
+            for tag in tags {
+                validate_tag(tag)?;
+            }
+            let config = Config { tags: tags.clone(), ..config };
+            let listed = disks::list(&config)?;
+            Ok(listed
+                .iter()
+                .map(|listed| json!({
+                    "name": listed.disk.name(),
+                    "device": listed.disk.device,
+                    "mount_point": listed.disk.mount_point,
+                    "managed": listed.entry.is_some(),
+                    "tags": listed.entry.as_ref().map(|entry| entry.tags.clone()).unwrap_or_default(),
+                }))
+                .collect())
+        }
+        Request::Status { name } => {
+            let status = disks::status(&config, name)?;
+            Ok(json!({
+                "name": name,
+                "mounted": status.mounted,
+                "attached": status.device.is_some() || status.mounted,
+                "device": status.device,
+                "mount_point": status.mounted.then_some(&status.mount_point),
+            }))
+        }
+        Request::Info { name } => {
+            let disks::Info { mount_point, details: info, created, entry, .. } = disks::info(&config, name)?;
+            Ok(json!({
+                "name": name,
+                "device": info.device,
+                "mount_point": mount_point,
+                "sectors": info.sectors,
+                "size_bytes": info.sectors.map(|sectors| sectors * 512),
+                "filesystem": info.filesystem,
+                "mount_options": info.mount_options,
+                "uuid": info.uuid,
+                "created": created,
+                "managed": entry.is_some(),
+                "flags": entry.as_ref().map(|entry| &entry.flags),
+                "timers": entry.as_ref().and_then(|entry| entry.timers.as_ref()),
+                "total_bytes": info.total_bytes,
+                "free_bytes": info.free_bytes,
+            }))
+        }
+        Request::Create { size, name, filesystem, profile, tags, ttl, sync_every } => {
+            let defaults = match profile {
+                Some(profile) => context.defaults.with_profile(profile)?,
+                None => context.defaults.clone(),
+            };
+            let size = size.clone().or(defaults.size.clone()).ok_or("A size is required, and config.toml has none")?;
+            let filesystem = filesystem.clone().or(defaults.filesystem.clone());
+            let config = Config {
+                protected: defaults.protected.unwrap_or(false),
+                mount_options: defaults.mount_options.clone().unwrap_or_default(),
+                after_create: defaults.after_create.clone(),
+                before_eject: defaults.before_eject.clone(),
+                ..config
+            };
+            let config = create_config(config, &size, name, filesystem.as_deref(), tags)?;
+            let ttl = ttl.as_deref().map(schedule::parse_interval).transpose()?;
+            let sync_every = sync_every.as_deref().map(schedule::parse_interval).transpose()?;
+            if sync_every.is_some() {
+                context.backup_store()?;
+            }
+            let created = create_ramdisk(&config)?;
+            let now = schedule::now();
+            let timers = Timers { expires: ttl.map(|ttl| now + ttl), sync_every, synced: None };
+            if ttl.is_some() || sync_every.is_some() {
+                schedule::update(&config.backend, &created.device, |recorded| *recorded = timers.clone())
+                    .map_err(|e| format!("Created {}, but failed to set its TTL and sync schedule: {}", config.name, e))?;
+            }
+            Ok(json!({
+                "result": "ok",
+                "device": created.device,
+                "mount_point": created.mount_point,
+                "name": config.name,
+                "size": config.size,
+                "size_bytes": parse_size(&config.size)?,
+                "filesystem": created.filesystem,
+                "backend": config.backend,
+                "partitions": created.partitions,
+                "readonly_device": created.readonly_device,
+                "encrypted": false,
+                "expires": timers.expires,
+                "sync_every": timers.sync_every,
+            }))
+        }
+        Request::Eject { target, force } => {
+            eject(&Config { force: *force, before_eject: context.defaults.before_eject.clone(), ..config }, target)?;
+            Ok(json!({ "result": "ok" }))
+        }
+        Request::Sync { tags, store } => {
+            for tag in tags {
+                validate_tag(tag)?;
+            }
+            let root = match store {
+                Some(store) => store.clone(),
+                None => context.backup_store()?,
+            };
+            let recipients = context.defaults.backup_recipients.clone().unwrap_or_default();
+            let config = Config { tags: tags.clone(), ..config };
+            let synced = backup::sync(&config, &backup::Store::new(root), &recipients)?;
+            Ok(synced
+                .into_iter()
+                .map(|backup::Synced { name, saved }| match saved {
+                    Ok(snapshot) => json!({ "name": name, "snapshot": snapshot.id }),
+                    Err(e) => json!({ "name": name, "error": e }),
+                })
+                .collect())
+        }
+        Request::Reload | Request::Stop => Err(format!("'{}' is for the daemon itself, not its context", request.command())),
+    }
+}
+
+// The disk `mkramdisk create SIZE NAME -f FILESYSTEM --tag ...` would make, checked
+// the way the command line checks it
+fn create_config(config: Config, size: &str, name: &str, filesystem: Option<&str>, tags: &[String]) -> Result<Config, String> {
+    parse_size(size)?;
+    for tag in tags {
+        validate_tag(tag)?;
+    }
+    let sanitized = sanitize_volume_name(name);
+    validate_volume_name(&sanitized)?;
+    check_volume_name(&sanitized, false)?;
+    let mut config = Config { size: size.to_string(), name: sanitized, tags: tags.to_vec(), ..config };
+    // zram and md disks are formatted with mkfs or newfs, which take their own filesystems
+    match formats::backend_filesystems(&config.backend) {
+        Some(filesystems) => {
+            let filesystem = match filesystem {
+                Some(filesystem) => formats::backend_filesystem(&config.backend, filesystem)?,
+                None => filesystems[0],
+            };
+            config.filesystem = filesystem.to_string();
+            config.personality = Some(filesystem.to_string());
+        }
+        None => {
+            if let Some(filesystem) = filesystem {
+                config.filesystem = filesystem.to_string();
+            }
+            validate_filesystem(&config.filesystem)?;
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let request: Request = serde_json::from_str(r#"{"command": "create", "size": "2G", "name": "Scratch", "tags": ["ci"]}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Create { size: Some("2G".to_string()), name: "Scratch".to_string(), filesystem: None, profile: None, tags: vec!["ci".to_string()], ttl: None, sync_every: None }
+        );
+        assert_eq!(serde_json::to_string(&Request::Ping).unwrap(), r#"{"command":"ping"}"#);
+        assert!(serde_json::from_str::<Request>(r#"{"command": "format-disk"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"command": "status"}"#).is_err());
+
+        let envelope: Envelope = serde_json::from_str(r#"{"command": "eject", "target": "Scratch", "token": "m"}"#).unwrap();
+        assert_eq!((envelope.request.scope(), envelope.token.as_deref()), (Scope::Manage, Some("m")));
+        let envelope = Envelope { request: Request::Ping, token: None };
+        assert_eq!(serde_json::to_string(&envelope).unwrap(), r#"{"command":"ping"}"#);
+    }
+
+    #[test]
+    fn test_auth() {
+        let eject = Request::Eject { target: "Scratch".to_string(), force: false };
+        let list = Request::List { tags: Vec::new() };
+        let open = Auth::default();
+        assert!(open.check(&eject, None).is_ok());
+
+        let managed = Auth { manage_token: Some("m".to_string()), read_token: None };
+        assert!(managed.check(&list, None).is_ok());
+        assert_eq!(managed.check(&eject, None).unwrap_err().0, Refusal::Scope);
+        assert!(managed.check(&eject, Some("m")).is_ok());
+        assert_eq!(managed.check(&list, Some("x")).unwrap_err(), (Refusal::Token, "The token is not valid".to_string()));
+
+        let both = Auth { manage_token: Some("m".to_string()), read_token: Some("r".to_string()) };
+        assert_eq!(both.check(&list, None).unwrap_err(), (Refusal::Token, "A token is required".to_string()));
+        assert!(both.check(&list, Some("r")).is_ok() && both.check(&list, Some("m")).is_ok());
+        assert!(both.check(&eject, Some("r")).is_err());
+        assert_eq!(both.granted(Some("mm")), None);
+    }
+
+    #[test]
+    fn test_create_config() {
+        let config = Config { backend: "mock".to_string(), ..Config::default() };
+        let created = create_config(config.clone(), "64M", "Scratch", None, &[]).unwrap();
+        assert_eq!((created.size.as_str(), created.name.as_str(), created.filesystem.as_str()), ("64M", "Scratch", "apfs"));
+        assert!(create_config(config.clone(), "lots", "Scratch", None, &[]).is_err());
+        assert!(create_config(config.clone(), "64M", "..", None, &[]).is_err());
+        assert!(create_config(config.clone(), "64M", "Recovery", None, &[]).is_err());
+        assert!(create_config(config.clone(), "64M", "Scratch", Some("zfs"), &[]).is_err());
+        assert!(create_config(config, "64M", "Scratch", None, &["no spaces".to_string()]).is_err());
+    }
+}
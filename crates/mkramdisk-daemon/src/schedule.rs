@@ -0,0 +1,265 @@
+//! Disks the daemon ejects once their TTL runs out, or snapshots on a schedule, as
+//! `{"command": "create", ..., "ttl": "2h", "sync_every": "15m"}` asks. The deadlines
+//! are kept in the state file and in each disk's marker (see `registry::Timers`), so a
+//! daemon that starts again, after a crash or not, carries on with the time each had left.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mkramdisk_core::registry::{self, Change, Entry, Timers};
+use mkramdisk_core::{backup, eject, Config};
+
+use crate::protocol::{self, Context};
+
+// How often the deadlines are checked
+const TICK: Duration = Duration::from_secs(1);
+// How long a disk that failed to eject or snapshot waits before it is tried again
+const RETRY: u64 = 60;
+
+/// Seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// A TTL or sync interval such as "90s", "15m", "2h" or "1d", in seconds.
+pub fn parse_interval(value: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid interval '{}': use a number with s, m, h or d, e.g. 15m", value);
+    let split = value.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (count, unit) = value.split_at(split);
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match count.checked_mul(unit) {
+        Some(0) => Err(format!("Invalid interval '{}': it has to be longer than nothing", value)),
+        Some(seconds) => Ok(seconds),
+        None => Err(invalid()),
+    }
+}
+
+/// `seconds` as the two largest units it has, e.g. "1h 59m" or "45s".
+pub fn format_interval(seconds: u64) -> String {
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(seconds, |left, &(unit, size)| {
+            let count = *left / size;
+            *left %= size;
+            Some((count, unit))
+        })
+        .skip_while(|&(count, _)| count == 0)
+        .take(2)
+        .filter(|&(count, _)| count > 0)
+        .map(|(count, unit)| format!("{}{}", count, unit))
+        .collect();
+    if parts.is_empty() { "0s".to_string() } else { parts.join(" ") }
+}
+
+/// Change the timers of the disk attached as `device` on `backend`, in the state file
+/// and in the marker on its volume.
+pub fn update(backend: &str, device: &str, change: impl FnOnce(&mut Timers)) -> Result<(), String> {
+    let mut changed = None;
+    registry::update(|registry| {
+        if let Some(entry) = registry.disks.iter_mut().find(|entry| entry.backend == backend && entry.device == device) {
+            change(entry.timers.get_or_insert_with(Timers::default));
+            changed = Some(entry.clone());
+        }
+    })?;
+    let entry = changed.ok_or_else(|| format!("{} is not in the state file", device))?;
+    match &entry.mount_point {
+        Some(mount_point) => registry::write_marker(mount_point, &entry),
+        None => Ok(()),
+    }
+}
+
+/// What the daemon found on starting: how the state file changed to match the disks
+/// attached, and the disks whose timers it carries on with.
+#[derive(Debug, Clone, Default)]
+pub struct Recovery {
+    pub changes: Vec<Change>,
+    pub timed: Vec<Entry>,
+}
+
+/// Make the state file match the disks `backend` has attached now, as `mkramdisk
+/// registry rebuild` does, and find the disks among them with timers.
+pub fn recover(backend: &str) -> Result<Recovery, String> {
+    let changes = registry::rebuild(backend, &registry::attached(backend)?, false)?;
+    let timed = registry::load()?.disks.into_iter().filter(|entry| entry.backend == backend && entry.timers.is_some()).collect();
+    Ok(Recovery { changes, timed })
+}
+
+impl Recovery {
+    /// A line on what was recovered, then one for each timer with the time it has left at
+    /// `now`. Those already due are said to be, and run on the daemon's first tick.
+    pub fn report(&self, now: u64) -> Vec<String> {
+        let forgotten = self.changes.iter().filter(|change| matches!(change, Change::Forgotten(_))).count();
+        let resumed: usize = self.timed.iter().map(|entry| pending(entry).len()).sum();
+        let mut lines = vec![format!(
+            "Reconciled the state file with the attached disks: {} forgotten, {} recovered, {} timers resumed",
+            forgotten,
+            self.changes.len() - forgotten,
+            resumed
+        )];
+        for change in &self.changes {
+            lines.push(match change {
+                Change::Forgotten(entry) => format!("Forgot '{}' ({}), which is no longer attached", entry.name, entry.device),
+                Change::Recovered(entry) => format!("Recovered '{}' ({}) from the marker on its volume", entry.name, entry.device),
+            });
+        }
+        for entry in &self.timed {
+            for (what, due) in pending(entry) {
+                lines.push(match due.checked_sub(now) {
+                    Some(left) if left > 0 => format!("'{}' {} in {}", entry.name, what, format_interval(left)),
+                    _ => format!("'{}' {} now, {} overdue", entry.name, what, format_interval(now - due)),
+                });
+            }
+        }
+        lines
+    }
+}
+
+// What `entry`'s timers will do, and when
+fn pending(entry: &Entry) -> Vec<(&'static str, u64)> {
+    let Some(timers) = &entry.timers else {
+        return Vec::new();
+    };
+    let mut pending = Vec::new();
+    if let Some(next) = timers.next_sync(entry.created) {
+        pending.push(("syncs", next));
+    }
+    if let Some(expires) = timers.expires {
+        pending.push(("expires", expires));
+    }
+    pending
+}
+
+/// Eject and snapshot disks as their timers come due, until the daemon stops.
+pub fn run(context: Arc<Mutex<Context>>) {
+    while !protocol::stopping() {
+        thread::sleep(TICK);
+        tick(&context.lock().unwrap_or_else(|e| e.into_inner()), now());
+    }
+}
+
+/// Snapshot the disks on `context`'s backend due a snapshot at `now`, then eject those
+/// whose TTL has run out, snapshotting any with a schedule a last time first. What was
+/// done, or failed, goes to stderr; a disk that failed is tried again a minute later.
+pub fn tick(context: &Context, now: u64) {
+    let registry = match registry::load() {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            return;
+        }
+    };
+    for entry in registry.disks.iter().filter(|entry| entry.backend == context.backend) {
+        let Some(timers) = &entry.timers else {
+            continue;
+        };
+        let expired = timers.expires.is_some_and(|expires| expires <= now);
+        if (expired && timers.sync_every.is_some()) || timers.next_sync(entry.created).is_some_and(|next| next <= now) {
+            let saved = snapshot(context, entry);
+            let synced = match &saved {
+                Ok(id) => {
+                    eprintln!("Synced '{}' to snapshot {}", entry.name, id);
+                    now
+                }
+                Err(e) => {
+                    eprintln!("Warning: failed to sync '{}': {}", entry.name, e);
+                    // Due again in RETRY, rather than a whole interval on
+                    (now + RETRY).saturating_sub(timers.sync_every.unwrap_or_default())
+                }
+            };
+            if let Err(e) = update(&context.backend, &entry.device, |timers| timers.synced = Some(synced)) {
+                eprintln!("Warning: failed to record syncing '{}': {}", entry.name, e);
+            }
+            // A disk that didn't sync stays attached, whatever its TTL
+            if saved.is_err() {
+                continue;
+            }
+        }
+        if expired {
+            let config = Config { backend: context.backend.clone(), summary: false, before_eject: context.defaults.before_eject.clone(), ..Config::default() };
+            match eject(&config, &entry.device) {
+                Ok(()) => eprintln!("Ejected '{}', its TTL having run out", entry.name),
+                Err(e) => {
+                    eprintln!("Warning: failed to eject '{}' at the end of its TTL: {}", entry.name, e);
+                    if let Err(e) = update(&context.backend, &entry.device, |timers| timers.expires = Some(now + RETRY)) {
+                        eprintln!("Warning: failed to record ejecting '{}': {}", entry.name, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Snapshot `entry`'s disk into the backup store, returning the snapshot's ID
+fn snapshot(context: &Context, entry: &Entry) -> Result<String, String> {
+    let config = Config { backend: context.backend.clone(), summary: false, ..Config::default() };
+    let recipients = context.defaults.backup_recipients.clone().unwrap_or_default();
+    let saved = backup::save_disk(&config, &backup::Store::new(context.backup_store()?), &entry.name, &recipients)?;
+    Ok(saved.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90s").unwrap(), 90);
+        assert_eq!(parse_interval("15m").unwrap(), 15 * 60);
+        assert_eq!(parse_interval("2h").unwrap(), 2 * 60 * 60);
+        assert_eq!(parse_interval("1d").unwrap(), 24 * 60 * 60);
+        for invalid in ["", "15", "m", "1.5h", "-1m", "2w", "0s", "99999999999999999d"] {
+            assert!(parse_interval(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_format_interval() {
+        assert_eq!(format_interval(0), "0s");
+        assert_eq!(format_interval(45), "45s");
+        assert_eq!(format_interval(7140), "1h 59m");
+        assert_eq!(format_interval(7200), "2h");
+        assert_eq!(format_interval(90_061), "1d 1h");
+    }
+
+    #[test]
+    fn test_report() {
+        let entry = |name: &str, timers| Entry {
+            device: "/dev/disk5".to_string(),
+            identity: None,
+            name: name.to_string(),
+            backend: "ram".to_string(),
+            size: "1G".to_string(),
+            size_bytes: 1 << 30,
+            filesystem: "apfs".to_string(),
+            mount_point: None,
+            created: 1_000,
+            flags: Vec::new(),
+            shadow: None,
+            directory: None,
+            tags: Vec::new(),
+            timers: Some(timers),
+        };
+        let scratch = entry("Scratch", Timers { expires: Some(1_000 + 7200), ..Timers::default() });
+        let cache = entry("Cache", Timers { sync_every: Some(600), synced: Some(1_500), ..Timers::default() });
+        let recovery = Recovery { changes: vec![Change::Recovered(scratch.clone()), Change::Forgotten(entry("Gone", Timers::default()))], timed: vec![scratch, cache] };
+        assert_eq!(
+            recovery.report(2_200),
+            [
+                "Reconciled the state file with the attached disks: 1 forgotten, 1 recovered, 2 timers resumed",
+                "Recovered 'Scratch' (/dev/disk5) from the marker on its volume",
+                "Forgot 'Gone' (/dev/disk5), which is no longer attached",
+                "'Scratch' expires in 1h 40m",
+                "'Cache' syncs now, 1m 40s overdue",
+            ]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! The Unix socket mkramdiskd listens on. Each connection is read on its own thread,
+//! but requests are carried out one at a time, as they would be from the command line.
+//! Who may connect is up to the socket's permissions, and what they may do up to the
+//! tokens their requests carry. SIGHUP reloads config.toml, and SIGTERM or SIGINT stops
+//! the daemon.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::clients::{self, Client};
+use crate::protocol::{self, serve as serve_request, Context, Envelope, Response};
+
+// How often the accept loop looks for a signal between connections
+const POLL: Duration = Duration::from_millis(100);
+
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => protocol::stop(),
+    }
+}
+
+/// Reload config.toml on SIGHUP, and stop on SIGTERM or SIGINT, both between requests.
+pub fn handle_signals() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT] {
+        unsafe { libc::signal(signal, handler) };
+    }
+}
+
+/// Listen at `path` with permissions `mode`, replacing a socket left there by a daemon
+/// that has gone.
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("mkramdiskd is already listening on {}", path.display()));
+    }
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Failed to remove {}: {}", path.display(), e)),
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    // The socket is created with no more than `mode` allows, so there is no moment it
+    // allows more, then set to exactly `mode`
+    let umask = unsafe { libc::umask((!mode & 0o777) as libc::mode_t) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| format!("Failed to set permissions on {}: {}", path.display(), e))?;
+    Ok(listener)
+}
+
+/// Answer the requests on `listener`, one at a time with anything else holding `context`,
+/// until the daemon is asked to stop.
+pub fn serve(listener: UnixListener, context: Arc<Mutex<Context>>) -> Result<(), String> {
+    listener.set_nonblocking(true).map_err(|e| format!("Failed to set up the socket: {}", e))?;
+    while !protocol::stopping() {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            match context.lock().unwrap_or_else(|e| e.into_inner()).reload() {
+                Ok(()) => eprintln!("Reloaded config.toml"),
+                Err(e) => eprintln!("Warning: kept the settings as they were: {}", e),
+            }
+        }
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Warning: failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        // Some systems hand on the listener's nonblocking mode
+        if let Err(e) = stream.set_nonblocking(false) {
+            eprintln!("Warning: failed to set up a connection: {}", e);
+            continue;
+        }
+        let context = Arc::clone(&context);
+        thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, &context) {
+                eprintln!("Warning: a connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Answer each line `stream` sends until it closes
+fn serve_connection(stream: UnixStream, context: &Mutex<Context>) -> io::Result<()> {
+    let client = match clients::peer(&stream) {
+        Ok(peer) => Client::Socket(Some(peer)),
+        Err(e) => {
+            eprintln!("Warning: cannot tell who connected: {}", e);
+            Client::Socket(None)
+        }
+    };
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Envelope>(&line) {
+            Ok(Envelope { request, token }) => {
+                let mut context = context.lock().unwrap_or_else(|e| e.into_inner());
+                serve_request(&mut context, &client, &request, token.as_deref()).unwrap_or_else(|(_, e)| Response::error(e))
+            }
+            Err(e) => Response::error(format!("Invalid request: {}", e)),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
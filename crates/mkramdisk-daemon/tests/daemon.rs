@@ -0,0 +1,310 @@
+#![cfg(unix)]
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+use mkramdisk_daemon::client;
+use mkramdisk_daemon::protocol::Request;
+
+/// mkramdiskd on the mock backend in a scratch root, killed and removed when the test finishes.
+struct Daemon {
+    root: PathBuf,
+    child: Child,
+}
+
+impl Daemon {
+    fn start(test: &str) -> Self {
+        Self::start_with(test, "", &[], &[])
+    }
+
+    // Reads have to be open to everyone for the ping that waits for the daemon
+    fn start_with(test: &str, config: &str, args: &[&str], envs: &[(&str, &str)]) -> Self {
+        let root = env::temp_dir().join(format!("mkramdiskd-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("config.toml"), config).unwrap();
+        let child = Self::spawn(&root, args, envs);
+        let daemon = Self { root, child };
+        daemon.wait_for_ping();
+        daemon
+    }
+
+    fn spawn(root: &Path, args: &[&str], envs: &[(&str, &str)]) -> Child {
+        let log = OpenOptions::new().create(true).append(true).open(root.join("daemon.log")).unwrap();
+        Command::new(env!("CARGO_BIN_EXE_mkramdiskd"))
+            .args(["--backend", "mock", "--socket"])
+            .arg(root.join("daemon.sock"))
+            .arg("--state-dir")
+            .arg(root)
+            .env("MKRAMDISK_MOCK_ROOT", root)
+            .env("MKRAMDISK_CONFIG", root.join("config.toml"))
+            .env("MKRAMDISK_STATE", root.join("state.json"))
+            .args(args)
+            .envs(envs.iter().copied())
+            .stderr(log)
+            .spawn()
+            .expect("failed to start mkramdiskd")
+    }
+
+    fn wait_for_ping(&self) {
+        for _ in 0..100 {
+            if self.request(&Request::Ping).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("mkramdiskd did not start listening");
+    }
+
+    // Start another daemon in the same root, with the default options, once this one
+    // has exited
+    fn start_again(&mut self) {
+        self.child = Self::spawn(&self.root, &[], &[]);
+        self.wait_for_ping();
+    }
+
+    // What the daemon has written to stderr
+    fn log(&self) -> String {
+        fs::read_to_string(self.root.join("daemon.log")).unwrap()
+    }
+
+    fn request(&self, request: &Request) -> Result<serde_json::Value, String> {
+        client::request(&self.root.join("daemon.sock"), request)
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        assert_eq!(unsafe { libc::kill(self.child.id() as libc::pid_t, signal) }, 0);
+    }
+
+    // Whether the daemon exited successfully, once it has
+    fn exited(&mut self) -> bool {
+        for _ in 0..250 {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status.success();
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("mkramdiskd did not stop");
+    }
+
+    fn devices(&self) -> usize {
+        fs::read_dir(self.root.join("dev")).map(|dir| dir.count()).unwrap_or(0)
+    }
+}
+
+fn create(name: &str, profile: Option<&str>) -> Request {
+    Request::Create { size: None, name: name.to_string(), filesystem: None, profile: profile.map(str::to_string), tags: Vec::new(), ttl: None, sync_every: None }
+}
+
+fn create_timed(name: &str, ttl: Option<&str>, sync_every: Option<&str>) -> Request {
+    let timers = (ttl.map(str::to_string), sync_every.map(str::to_string));
+    Request::Create { size: Some("32M".to_string()), name: name.to_string(), filesystem: None, profile: None, tags: Vec::new(), ttl: timers.0, sync_every: timers.1 }
+}
+
+// Wait up to five seconds for `done`
+fn eventually(done: impl Fn() -> bool) -> bool {
+    (0..250).any(|_| {
+        thread::sleep(Duration::from_millis(20));
+        done()
+    })
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+#[test]
+fn test_requests() {
+    let daemon = Daemon::start("requests");
+    assert_eq!(daemon.request(&Request::Ping).unwrap()["version"], env!("CARGO_PKG_VERSION"));
+
+    let create = Request::Create { size: Some("64M".to_string()), name: "Scratch".to_string(), filesystem: None, profile: None, tags: vec!["ci".to_string()], ttl: None, sync_every: None };
+    let created = daemon.request(&create).unwrap();
+    assert_eq!(created["name"], "Scratch");
+    assert!(daemon.root.join("Volumes/Scratch").is_dir());
+    // The same disk again is refused, as on the command line
+    assert!(daemon.request(&create).is_err());
+
+    let listed = daemon.request(&Request::List { tags: vec!["ci".to_string()] }).unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["device"], created["device"]);
+    assert_eq!(daemon.request(&Request::List { tags: vec!["build".to_string()] }).unwrap().as_array().unwrap().len(), 0);
+    assert_eq!(daemon.request(&Request::Status { name: "Scratch".to_string() }).unwrap()["mounted"], true);
+
+    daemon.request(&Request::Eject { target: "Scratch".to_string(), force: false }).unwrap();
+    assert_eq!(daemon.request(&Request::Status { name: "Scratch".to_string() }).unwrap()["mounted"], false);
+    assert!(daemon.request(&Request::Eject { target: "Scratch".to_string(), force: false }).unwrap_err().contains("Scratch"));
+}
+
+#[test]
+fn test_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+    let daemon = Daemon::start_with("permissions", "[daemon]\nmanage_token = \"s3cret\"\n", &[], &[]);
+    let socket = daemon.root.join("daemon.sock");
+    assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // Looking needs no token, but changing disks needs the manage token
+    assert!(daemon.request(&Request::List { tags: Vec::new() }).is_ok());
+    let create = Request::Create { size: Some("64M".to_string()), name: "Scratch".to_string(), filesystem: None, profile: None, tags: Vec::new(), ttl: None, sync_every: None };
+    assert!(daemon.request(&create).unwrap_err().contains("needs the manage token"));
+    assert!(client::request_with_token(&socket, &create, Some("guess")).unwrap_err().contains("not valid"));
+    assert!(!daemon.root.join("Volumes/Scratch").exists());
+    client::request_with_token(&socket, &create, Some("s3cret")).unwrap();
+    assert!(daemon.root.join("Volumes/Scratch").is_dir());
+
+    // Each attempt to change disks is logged with who made it; looking isn't
+    let audit = fs::read_to_string(daemon.root.join("logs/daemon-audit.log")).unwrap();
+    let lines: Vec<serde_json::Value> = audit.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(lines.iter().map(|line| line["ok"].as_bool().unwrap()).collect::<Vec<_>>(), [false, false, true]);
+    assert_eq!(lines[2]["request"]["command"], "create");
+    assert_eq!(lines[2]["client"]["uid"], unsafe { libc::getuid() });
+    assert!(!audit.contains("s3cret"));
+}
+
+#[test]
+fn test_rate_limit() {
+    let daemon = Daemon::start_with("rate-limit", "[daemon]\nrequests_per_minute = 3\n", &[], &[]);
+    // The ping that found the daemon listening was the first
+    assert!(daemon.request(&Request::Ping).is_ok());
+    assert!(daemon.request(&Request::Ping).is_ok());
+    assert!(daemon.request(&Request::Ping).unwrap_err().starts_with("Too many requests from uid"));
+}
+
+#[test]
+fn test_reload_and_stop() {
+    let mut daemon = Daemon::start("reload");
+    assert!(daemon.request(&create("Scratch", Some("small"))).unwrap_err().contains("No profile named 'small'"));
+
+    fs::write(daemon.root.join("config.toml"), "[profile.small]\nsize = \"32M\"\n").unwrap();
+    daemon.signal(libc::SIGHUP);
+    let mut created = Err(String::new());
+    for _ in 0..50 {
+        created = daemon.request(&create("Scratch", Some("small")));
+        if created.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(created.unwrap()["size"], "32M");
+
+    // A config.toml that doesn't load leaves the one loaded before in place
+    fs::write(daemon.root.join("config.toml"), "colour = \"red\"\n").unwrap();
+    assert!(daemon.request(&Request::Reload).unwrap_err().contains("colour"));
+    daemon.request(&create("Other", Some("small"))).unwrap();
+
+    // By default the disks outlive the daemon
+    daemon.request(&Request::Stop).unwrap();
+    assert!(daemon.exited());
+    assert!(!daemon.root.join("daemon.sock").exists());
+    assert_eq!(daemon.devices(), 2);
+}
+
+#[test]
+fn test_eject_on_stop() {
+    let mut daemon = Daemon::start_with("eject-on-stop", "size = \"32M\"\n[daemon]\neject_on_stop = true\n", &[], &[]);
+    daemon.request(&create("Scratch", None)).unwrap();
+    assert_eq!(daemon.devices(), 1);
+    daemon.signal(libc::SIGTERM);
+    assert!(daemon.exited());
+    assert_eq!(daemon.devices(), 0);
+}
+
+#[test]
+fn test_ttl_and_sync_schedule() {
+    let daemon = Daemon::start("timers");
+    assert!(daemon.request(&create_timed("Scratch", Some("soon"), None)).unwrap_err().contains("Invalid interval 'soon'"));
+    assert_eq!(daemon.devices(), 0);
+
+    let created = daemon.request(&create_timed("Scratch", Some("1s"), None)).unwrap();
+    assert!(created["expires"].is_u64());
+    assert!(eventually(|| daemon.devices() == 0));
+    assert!(!daemon.root.join("Volumes/Scratch").exists());
+
+    daemon.request(&create_timed("Cache", None, Some("1s"))).unwrap();
+    let snapshots = daemon.root.join("backups/Cache/snapshots");
+    assert!(eventually(|| fs::read_dir(&snapshots).is_ok_and(|mut dir| dir.next().is_some())));
+    let info = daemon.request(&Request::Info { name: "Cache".to_string() }).unwrap();
+    assert_eq!(info["timers"]["sync_every"], 1);
+    assert!(info["timers"]["synced"].is_u64());
+    assert_eq!(daemon.devices(), 1);
+}
+
+#[test]
+fn test_recovery() {
+    let mut daemon = Daemon::start("recovery");
+    daemon.request(&create_timed("Scratch", Some("1h"), None)).unwrap();
+    daemon.request(&create_timed("Cache", None, Some("10m"))).unwrap();
+    let gone = daemon.request(&create_timed("Gone", None, None)).unwrap();
+    daemon.request(&Request::Stop).unwrap();
+    assert!(daemon.exited());
+
+    // With the state file lost, the disks are recovered from their markers, and their
+    // timers carry on with the time they had left
+    fs::remove_file(daemon.root.join("state.json")).unwrap();
+    daemon.start_again();
+    let log = daemon.log();
+    assert!(log.contains("0 forgotten, 3 recovered, 2 timers resumed"), "{}", log);
+    assert!(log.contains("Recovered 'Scratch'"), "{}", log);
+    assert!(log.contains("'Scratch' expires in 59m") || log.contains("'Scratch' expires in 1h"), "{}", log);
+    assert!(log.contains("'Cache' syncs in 9m") || log.contains("'Cache' syncs in 10m"), "{}", log);
+    assert!(daemon.request(&Request::Info { name: "Scratch".to_string() }).unwrap()["timers"]["expires"].is_u64());
+
+    // A disk that went while the daemon was away is forgotten
+    daemon.request(&Request::Stop).unwrap();
+    assert!(daemon.exited());
+    fs::remove_file(gone["device"].as_str().unwrap()).unwrap();
+    fs::remove_dir_all(daemon.root.join("Volumes/Gone")).unwrap();
+    daemon.start_again();
+    let log = daemon.log();
+    assert!(log.contains("1 forgotten, 0 recovered, 2 timers resumed"), "{}", log);
+    assert!(log.contains("Forgot 'Gone'"), "{}", log);
+}
+
+#[test]
+fn test_invalid_request() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    let daemon = Daemon::start("invalid");
+    let mut stream = UnixStream::connect(daemon.root.join("daemon.sock")).unwrap();
+    writeln!(stream, "{{\"command\": \"format-everything\"}}").unwrap();
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).unwrap();
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["ok"], false);
+    assert!(response["error"].as_str().unwrap().starts_with("Invalid request"));
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_http() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let address = format!("127.0.0.1:{}", port);
+    let daemon = Daemon::start_with("http", "", &["--http", &address], &[("MKRAMDISKD_TOKEN", "s3cret")]);
+    let post = |token: &str, body: &str| {
+        let mut stream = TcpStream::connect(&address).unwrap();
+        write!(stream, "POST /v1/request HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}", token, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str::<serde_json::Value>(body).unwrap())
+    };
+
+    let (status, created) = post("s3cret", r#"{"command": "create", "size": "64M", "name": "Scratch"}"#);
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(created["result"]["name"], "Scratch");
+    // The socket sees what HTTP did
+    assert_eq!(daemon.request(&Request::Status { name: "Scratch".to_string() }).unwrap()["mounted"], true);
+    let (status, refused) = post("guess", r#"{"command": "eject", "target": "Scratch"}"#);
+    assert_eq!((status.as_str(), &refused["ok"]), ("HTTP/1.1 401 Unauthorized", &serde_json::json!(false)));
+    assert!(daemon.root.join("Volumes/Scratch").is_dir());
+}